@@ -0,0 +1,11 @@
+// Only the grpc feature needs a build step, the service in proto/federation.proto is
+// compiled with a protoc that comes with the build dependencies
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/federation.proto");
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("no protoc for this platform");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::compile_protos("proto/federation.proto").expect("federation.proto");
+    }
+}
@@ -0,0 +1,75 @@
+use clap::Parser;
+
+use virtual_time::phold::PholdConfig;
+use virtual_time::time::message::{Delay, VirtualTime};
+
+// PHOLD benchmark (see phold.rs), for measuring what a change to the queues or the
+// scheduler does to throughput and rollbacks. Build it in release mode, for example
+//
+//   cargo run --release --example phold -- --machines 64 --remote 0.9 --repeat 5
+//
+// Every repetition runs the same model, the numbers printed at the end are the medians.
+#[derive(Parser)]
+struct Args {
+    #[arg(long, default_value_t = 16)]
+    machines: usize,
+    #[arg(long, default_value_t = 4, help = "Events per machine")]
+    population: usize,
+    #[arg(long, default_value_t = 0.5, help = "Fraction of events sent to another machine")]
+    remote: f64,
+    #[arg(long, default_value_t = Delay::new(1))]
+    lookahead: Delay,
+    #[arg(long, default_value_t = 100.0)]
+    mean_delay: f64,
+    #[arg(long, default_value_t = VirtualTime::new(100_000))]
+    end_time: VirtualTime,
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    #[arg(long, default_value_t = 3)]
+    repeat: usize,
+}
+
+fn main() {
+    let args = Args::parse();
+    let config = PholdConfig {
+        machines: args.machines,
+        population: args.population,
+        remote_fraction: args.remote,
+        lookahead: args.lookahead,
+        mean_delay: args.mean_delay,
+        end_time: args.end_time,
+        seed: args.seed,
+    };
+
+    println!(
+        "{:>4} {:>10} {:>10} {:>10} {:>12} {:>10} {:>10}",
+        "run", "committed", "processed", "rollbacks", "events/s", "rb/event", "efficiency"
+    );
+    let mut reports = Vec::new();
+    for run in 0..args.repeat.max(1) {
+        let report = config.run();
+        let total = &report.stats.total;
+        println!(
+            "{:>4} {:>10} {:>10} {:>10} {:>12.0} {:>10.4} {:>10.3}",
+            run,
+            total.events_committed,
+            total.events_processed,
+            total.rollbacks,
+            report.throughput(),
+            report.rollback_rate(),
+            total.efficiency()
+        );
+        reports.push(report);
+    }
+
+    let median = |mut values: Vec<f64>| {
+        values.sort_by(f64::total_cmp);
+        values[values.len() / 2]
+    };
+    println!(
+        "median {:.0} events/s, {:.4} rollbacks per event, {:.3} efficiency",
+        median(reports.iter().map(|report| report.throughput()).collect()),
+        median(reports.iter().map(|report| report.rollback_rate()).collect()),
+        median(reports.iter().map(|report| report.stats.total.efficiency()).collect())
+    );
+}
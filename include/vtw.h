@@ -0,0 +1,73 @@
+/* C API of the virtual-time engine, see src/ffi.rs. Link against the cdylib the crate
+ * builds (libvirtual_time.so / .dylib / virtual_time.dll). Times and delays are
+ * uint64_t, machine ids are size_t, payloads are bytes that dont need to be nul
+ * terminated and are read as UTF-8.
+ */
+#ifndef VTW_H
+#define VTW_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct vtw_simulation vtw_simulation;
+/* Only valid for the duration of a handler call */
+typedef struct vtw_context vtw_context;
+
+typedef struct vtw_event {
+    uint64_t now;
+    size_t machine_id;
+    size_t sender;
+    const uint8_t *payload;
+    size_t payload_len;
+} vtw_event;
+
+typedef struct vtw_stats {
+    uint64_t events_processed;
+    uint64_t events_committed;
+    uint64_t events_rolled_back;
+    uint64_t rollbacks;
+    uint64_t antimessages_sent;
+} vtw_stats;
+
+/* Called for every event. Everything that has to be rolled back lives in state, which
+ * is saved and restored by the engine, and may not hold pointers. */
+typedef void (*vtw_handler)(void *user_data, uint8_t *state, size_t state_len,
+                            const vtw_event *event, vtw_context *ctx);
+
+vtw_simulation *vtw_simulation_new(void);
+void vtw_simulation_free(vtw_simulation *simulation);
+
+/* 0, or -1 if the id is taken or handler is NULL. The state starts as state_len zero
+ * bytes. */
+int vtw_add_machine(vtw_simulation *simulation, size_t machine_id, size_t state_len,
+                    vtw_handler handler, void *user_data);
+/* 0, -1 if there is no such machine, or -2 if rec_time is before what the machine
+ * already committed (it would be dropped) */
+int vtw_inject(vtw_simulation *simulation, size_t receiver, uint64_t rec_time,
+               const uint8_t *payload, size_t payload_len);
+
+void vtw_run(vtw_simulation *simulation);
+void vtw_run_until(vtw_simulation *simulation, uint64_t end_time);
+
+/* Only from inside a handler */
+void vtw_send(vtw_context *ctx, size_t receiver, uint64_t delay, const uint8_t *payload,
+              size_t payload_len);
+
+/* -1 while there is no GVT */
+int64_t vtw_gvt(const vtw_simulation *simulation);
+void vtw_total_stats(const vtw_simulation *simulation, vtw_stats *stats);
+/* 0, or -1 if there is no such machine */
+int vtw_machine_stats(const vtw_simulation *simulation, size_t machine_id, vtw_stats *stats);
+/* Copies up to buffer_len bytes, returns the full state length or -1 */
+ptrdiff_t vtw_machine_state(const vtw_simulation *simulation, size_t machine_id,
+                            uint8_t *buffer, size_t buffer_len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,34 @@
+# The Python side of the vtw plugin pipe, see PipeModule in src/plugin.rs. A model is a
+# function getting the machines state (None the first time) and the event it runs, and
+# giving back the new state and what to send. The state can be anything pickle can
+# handle, it is saved and rolled back by the engine so the function shouldnt keep any
+# of its own between calls.
+#
+#     from vtw_plugin import run, send
+#
+#     def on_message(count, event):
+#         count = (count or 0) + 1
+#         return count, [send(event["sender"], 1, str(count))]
+#
+#     run(on_message)
+#
+# The machine is started with PipeModule::spawn(Command::new("python3").arg("model.py"))
+# and an event looks like PluginEvent: now, machine_id, sender, port and payload.
+
+import json
+import pickle
+import sys
+
+
+def send(receiver, delay, payload, port=None):
+    return {"receiver": receiver, "delay": delay, "payload": payload, "port": port}
+
+
+def run(on_message):
+    for line in sys.stdin:
+        request = json.loads(line)
+        state = pickle.loads(bytes.fromhex(request["state"])) if request["state"] else None
+        state, sends = on_message(state, request["event"])
+        reply = {"state": pickle.dumps(state).hex(), "sends": sends}
+        sys.stdout.write(json.dumps(reply) + "\n")
+        sys.stdout.flush()
@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::time::message::{Delay, MachineId, VirtualTime};
+
+// Links with a bandwidth (see Latencies::set_bandwidth) take time to put a message on
+// the wire, its payload size over the bandwidth rounded up, and carry one message at a
+// time in the order they were sent. A message sent while the link is still busy waits
+// for it, so it arrives after
+//
+//   max(send time, link free) + size / bandwidth + delay
+//
+// where delay is whatever the process sent it with (the propagation delay, from
+// link_delay or its own). That only ever makes messages later, so lookahead stays safe.
+//
+// When the link is free again depends on what was sent before, which a rollback can
+// undo, so every machine keeps the history of its outgoing links by send time and rolls
+// it back with its output queue.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LinkQueues {
+    // For every receiver, (send time, link free again after it) in the order sent
+    links: BTreeMap<MachineId, Vec<(VirtualTime, VirtualTime)>>,
+}
+
+impl LinkQueues {
+    // When the link to the receiver is free again, the start of time if it never was busy
+    pub fn free_at(&self, receiver: MachineId) -> VirtualTime {
+        self.links
+            .get(&receiver)
+            .and_then(|sent| sent.last())
+            .map_or(VirtualTime::ZERO, |(_, free)| *free)
+    }
+
+    // Queues a message of size bytes on the link, returns when it arrives
+    pub fn transmit(
+        &mut self,
+        receiver: MachineId,
+        send_time: VirtualTime,
+        size: usize,
+        bytes_per_tick: u64,
+        delay: Delay,
+    ) -> VirtualTime {
+        let start = send_time.max(self.free_at(receiver));
+        let on_the_wire = Delay::new((size as u64).div_ceil(bytes_per_tick.max(1)));
+        let free = start + on_the_wire;
+        self.links.entry(receiver).or_default().push((send_time, free));
+        free + delay
+    }
+
+    // Forgets everything sent at or after the rollback target
+    pub fn roll_back(&mut self, rollback_target: VirtualTime) {
+        for sent in self.links.values_mut() {
+            let kept = sent.partition_point(|(send_time, _)| *send_time < rollback_target);
+            sent.truncate(kept);
+        }
+        self.links.retain(|_, sent| !sent.is_empty());
+    }
+
+    // Nothing before the horizon is rolled back anymore, only the latest of those sends
+    // still matters for when the link is free
+    pub fn commit(&mut self, horizon: VirtualTime) {
+        for sent in self.links.values_mut() {
+            let committed = sent.partition_point(|(send_time, _)| *send_time < horizon);
+            sent.drain(..committed.saturating_sub(1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_busy_links_queue_messages() {
+        let mut links = LinkQueues::default();
+        let at = VirtualTime::new;
+        let delay = Delay::new(2);
+        // 10 bytes at 4 a tick take 3 ticks
+        assert_eq!(links.transmit(1, at(0), 10, 4, delay), 5);
+        assert_eq!(links.transmit(1, at(1), 4, 4, delay), 6);
+        assert_eq!(links.transmit(2, at(1), 4, 4, delay), 4);
+        assert_eq!(links.transmit(1, at(9), 0, 4, delay), 11);
+
+        links.roll_back(at(2));
+        assert_eq!(links.free_at(1), 4);
+        links.commit(at(5));
+        assert_eq!(links.links[&1], [(at(1), at(4))]);
+        links.roll_back(at(0));
+        assert_eq!(links.free_at(1), VirtualTime::ZERO);
+    }
+}
@@ -0,0 +1,246 @@
+// Hooking a simulation into a streaming pipeline: timestamped records from a broker go
+// in as events and committed events go back out as records, so a model can sit between
+// two topics (replaying production traffic against it, say). Only committed events are
+// published, anything still speculative could be rolled back after a consumer acted on it.
+//
+// The broker side is two small traits so any client fits. NATS is built in (nats.rs, it
+// is a plain text protocol over TCP), for Kafka wrap the consumer and producer of a Kafka
+// client in RecordSource and RecordSink.
+
+pub mod nats;
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::Arc;
+
+use crate::machine::Machine;
+use crate::process::TimeWarpProcess;
+use crate::runtime::Simulation;
+use crate::sink::{CommittedEvent, EventSink};
+use crate::time::message::{MachineId, Message, Sign, VirtualTime};
+use crate::trace::trace_warn;
+
+// Where records come from, one subscription or topic partition
+pub trait RecordSource: Send {
+    // The records that arrived since the last poll, without waiting for more
+    fn poll(&mut self) -> io::Result<Vec<Vec<u8>>>;
+}
+
+// Where records go
+pub trait RecordSink: Send {
+    fn publish(&mut self, record: &[u8]) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A record coming in, JSON like {"to": 3, "at": 120, "payload": "..."}. The event is
+// received by machine to at virtual time at, on the port if there is one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IngressRecord {
+    pub to: MachineId,
+    pub at: VirtualTime,
+    pub payload: String,
+    #[serde(default)]
+    pub port: Option<String>,
+}
+
+// A committed event going out, the message the machine processed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EgressRecord {
+    pub machine: MachineId,
+    pub at: VirtualTime,
+    pub from: MachineId,
+    pub sent_at: VirtualTime,
+    pub payload: String,
+    #[serde(default)]
+    pub port: Option<String>,
+}
+
+impl From<&CommittedEvent> for EgressRecord {
+    fn from(event: &CommittedEvent) -> Self {
+        let message = &event.message;
+        Self {
+            machine: event.machine_id,
+            at: message.rec_time,
+            from: message.sender,
+            sent_at: message.send_time,
+            payload: message.message.as_ref().clone(),
+            port: message.port().map(str::to_string),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IngressStats {
+    pub injected: u64,
+    // Records for a time their machine already committed, dropped since nothing can be
+    // received there anymore
+    pub late: u64,
+    // Records that werent an IngressRecord
+    pub malformed: u64,
+}
+
+// Feeds the records of a source into a simulation, call pump between runs. Records come
+// in as messages from outside (sender and receiver are the machine they are for) at their
+// own timestamp, so one for a time a machine already got past is a straggler like any
+// other and rolls it back.
+pub struct Ingress<S: RecordSource> {
+    source: S,
+    stats: IngressStats,
+}
+
+impl<S: RecordSource> Ingress<S> {
+    pub fn new(source: S) -> Self {
+        Self {
+            source,
+            stats: IngressStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &IngressStats {
+        &self.stats
+    }
+
+    pub fn source_mut(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    // Injects whatever arrived since the last pump, returns how many were injected
+    pub fn pump<P: TimeWarpProcess>(
+        &mut self,
+        simulation: &mut Simulation<P>,
+    ) -> io::Result<usize> {
+        let mut injected = 0;
+        for record in self.source.poll()? {
+            let Ok(record) = serde_json::from_slice::<IngressRecord>(&record) else {
+                self.stats.malformed += 1;
+                trace_warn!(len = record.len(), "Dropped a record that isnt an ingress record");
+                continue;
+            };
+            let horizon = simulation.machine(record.to).map(Machine::commit_horizon);
+            if horizon.is_some_and(|horizon| record.at < horizon) {
+                self.stats.late += 1;
+                trace_warn!(
+                    to = record.to,
+                    at = %record.at,
+                    "Dropped a record for a time that is already committed"
+                );
+                continue;
+            }
+            let payload = Arc::new(record.payload);
+            let mut message =
+                Message::new(record.at, record.at, record.to, record.to, Sign::Message, payload);
+            if let Some(port) = record.port {
+                message = message.with_port(port);
+            }
+            simulation.inject(message);
+            injected += 1;
+        }
+        self.stats.injected += injected as u64;
+        Ok(injected)
+    }
+}
+
+// Publishes every committed event as an EgressRecord, add it to the simulation like any
+// other sink. on_commit cant fail so the first error is kept and returned from flush,
+// nothing is published after it.
+pub struct Egress<S: RecordSink> {
+    sink: S,
+    published: u64,
+    error: Option<io::Error>,
+}
+
+impl<S: RecordSink> Egress<S> {
+    pub fn new(sink: S) -> Self {
+        Self {
+            sink,
+            published: 0,
+            error: None,
+        }
+    }
+
+    pub fn published(&self) -> u64 {
+        self.published
+    }
+}
+
+impl<S: RecordSink> EventSink for Egress<S> {
+    fn on_commit(&mut self, event: &CommittedEvent) {
+        if self.error.is_some() {
+            return;
+        }
+        let record = serde_json::to_vec(&EgressRecord::from(event)).expect("records are json");
+        match self.sink.publish(&record) {
+            Ok(()) => self.published += 1,
+            Err(error) => self.error = Some(error),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.sink.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Records(Vec<Vec<u8>>);
+
+    impl RecordSource for Records {
+        fn poll(&mut self) -> io::Result<Vec<Vec<u8>>> {
+            Ok(std::mem::take(&mut self.0))
+        }
+    }
+
+    #[derive(Default, Clone)]
+    struct Published(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl RecordSink for Published {
+        fn publish(&mut self, record: &[u8]) -> io::Result<()> {
+            self.0.lock().unwrap().push(record.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_records_go_in_and_committed_events_come_out() {
+        let mut simulation = ring(2);
+        let published = Published::default();
+        simulation.add_sink(Box::new(Egress::new(published.clone())));
+        let mut ingress = Ingress::new(Records::default());
+        ingress.source_mut().0 = vec![
+            br#"{"to": 0, "at": 1, "payload": "2"}"#.to_vec(),
+            b"not json".to_vec(),
+        ];
+        assert_eq!(ingress.pump(&mut simulation).unwrap(), 1);
+        simulation.run();
+
+        ingress.source_mut().0 = vec![
+            br#"{"to": 1, "at": 2, "payload": "0"}"#.to_vec(),
+            br#"{"to": 1, "at": 20, "payload": "0"}"#.to_vec(),
+        ];
+        ingress.pump(&mut simulation).unwrap();
+        simulation.run();
+        simulation.flush_sinks().unwrap();
+        let stats = ingress.stats();
+        assert_eq!((stats.injected, stats.late, stats.malformed), (2, 1, 1));
+
+        let published = published.0.lock().unwrap();
+        let records: Vec<EgressRecord> =
+            published.iter().map(|record| serde_json::from_slice(record).unwrap()).collect();
+        let events: Vec<_> =
+            records.iter().map(|record| (record.machine, record.at.ticks())).collect();
+        assert_eq!(events, [(0, 1), (1, 4), (0, 7), (1, 20)]);
+        assert_eq!(records[1].from, 0);
+        assert_eq!(records[1].payload, "1");
+    }
+}
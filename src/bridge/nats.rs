@@ -0,0 +1,221 @@
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::{RecordSink, RecordSource};
+use crate::trace::trace_warn;
+
+// Biggest message taken from the server, anything bigger ends the connection
+const MAX_PAYLOAD: usize = 64 << 20;
+
+// Just enough of the NATS client protocol (https://docs.nats.io, "Client Protocol") to
+// subscribe to subjects and publish to one: no auth, TLS, headers or reconnects. The
+// protocol is lines of text over a plain TCP connection, a thread reads what the server
+// sends, answers its pings and hands the messages of the subscriptions to poll.
+pub struct NatsClient {
+    writer: Arc<Mutex<BufWriter<TcpStream>>>,
+    stream: TcpStream,
+    records: Receiver<Vec<u8>>,
+    subscriptions: u64,
+    // Where publish sends records, see publish_on
+    subject: Option<String>,
+}
+
+impl NatsClient {
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        // The server starts with an INFO line, nothing in it is needed here
+        let mut info = String::new();
+        reader.read_line(&mut info)?;
+        if !info.starts_with("INFO") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected INFO from the server, got {:?}", info.trim()),
+            ));
+        }
+        let mut writer = BufWriter::new(stream.try_clone()?);
+        writer.write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false}\r\n")?;
+        writer.flush()?;
+        let writer = Arc::new(Mutex::new(writer));
+        let (records, received) = mpsc::channel();
+        spawn_reader(reader, writer.clone(), records);
+        Ok(Self {
+            writer,
+            stream,
+            records: received,
+            subscriptions: 0,
+            subject: None,
+        })
+    }
+
+    // Records published to the subject show up in poll from now on
+    pub fn subscribe(&mut self, subject: &str) -> io::Result<()> {
+        self.subscriptions += 1;
+        self.send(format!("SUB {} {}\r\n", subject, self.subscriptions).as_bytes())
+    }
+
+    // Where the RecordSink publishes to
+    pub fn publish_on(mut self, subject: &str) -> Self {
+        self.subject = Some(subject.to_string());
+        self
+    }
+
+    pub fn publish_to(&mut self, subject: &str, record: &[u8]) -> io::Result<()> {
+        let mut message = format!("PUB {} {}\r\n", subject, record.len()).into_bytes();
+        message.extend_from_slice(record);
+        message.extend_from_slice(b"\r\n");
+        self.send(&message)
+    }
+
+    fn send(&self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.lock().unwrap().write_all(bytes)
+    }
+}
+
+impl RecordSource for NatsClient {
+    fn poll(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        // Whatever was written still has to get to the server, SUB lines included
+        self.writer.lock().unwrap().flush()?;
+        let mut records = Vec::new();
+        loop {
+            match self.records.try_recv() {
+                Ok(record) => records.push(record),
+                Err(TryRecvError::Empty) => return Ok(records),
+                Err(TryRecvError::Disconnected) if records.is_empty() => {
+                    return Err(io::ErrorKind::ConnectionAborted.into());
+                }
+                Err(TryRecvError::Disconnected) => return Ok(records),
+            }
+        }
+    }
+}
+
+impl RecordSink for NatsClient {
+    fn publish(&mut self, record: &[u8]) -> io::Result<()> {
+        let Some(subject) = self.subject.clone() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no subject to publish on, see NatsClient::publish_on",
+            ));
+        };
+        self.publish_to(&subject, record)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+impl Drop for NatsClient {
+    fn drop(&mut self) {
+        let _ = self.writer.lock().unwrap().flush();
+        // Ends the reader thread as well
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+fn spawn_reader(
+    mut reader: BufReader<TcpStream>,
+    writer: Arc<Mutex<BufWriter<TcpStream>>>,
+    records: Sender<Vec<u8>>,
+) {
+    thread::spawn(move || {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if !matches!(reader.read_line(&mut line), Ok(read) if read > 0) {
+                return;
+            }
+            let mut words = line.split_whitespace();
+            match words.next() {
+                // MSG <subject> <sid> [reply-to] <#bytes>, then the payload and a newline
+                Some("MSG") => {
+                    let Some(len) = words.last().and_then(|len| len.parse::<usize>().ok())
+                    else {
+                        return;
+                    };
+                    if len > MAX_PAYLOAD {
+                        trace_warn!(len, "NATS message is too big, closing the connection");
+                        return;
+                    }
+                    let mut payload = vec![0; len + 2];
+                    if reader.read_exact(&mut payload).is_err() {
+                        return;
+                    }
+                    payload.truncate(len);
+                    if records.send(payload).is_err() {
+                        return;
+                    }
+                }
+                Some("PING") => {
+                    let mut writer = writer.lock().unwrap();
+                    if writer.write_all(b"PONG\r\n").and_then(|_| writer.flush()).is_err() {
+                        return;
+                    }
+                }
+                Some("-ERR") => {
+                    trace_warn!(error = line.trim(), "NATS server sent an error");
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    // Plays the server: checks what the client sends, pings it and delivers one message
+    fn fake_server(listener: TcpListener) -> thread::JoinHandle<Vec<String>> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            writer.write_all(b"INFO {\"server_id\":\"fake\"}\r\n").unwrap();
+            let mut lines = Vec::new();
+            let mut line = String::new();
+            while lines.len() < 5 {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim().to_string();
+                if line.starts_with("SUB") {
+                    writer.write_all(b"PING\r\nMSG events 1 5\r\nhello\r\n").unwrap();
+                }
+                lines.push(line);
+            }
+            lines
+        })
+    }
+
+    #[test]
+    fn test_nats_records_go_both_ways() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = fake_server(listener.try_clone().unwrap());
+        let mut client = NatsClient::connect(listener.local_addr().unwrap())
+            .unwrap()
+            .publish_on("committed");
+        client.subscribe("events").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut records = Vec::new();
+        while records.is_empty() {
+            assert!(Instant::now() < deadline, "the message never arrived");
+            records = client.poll().unwrap();
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(records, [b"hello".to_vec()]);
+        client.publish(b"{}").unwrap();
+        RecordSink::flush(&mut client).unwrap();
+
+        let lines = server.join().unwrap();
+        assert!(lines[0].starts_with("CONNECT"));
+        assert_eq!(lines[1..], ["SUB events 1", "PONG", "PUB committed 2", "{}"]);
+    }
+}
@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+
+// Keeps a single badly behaved machine from dominating a run. max_depth caps how many
+// uncommitted events a machine can have at once, which is also the deepest a rollback
+// of it can ever get. Once a machine has been rolled back penalty_after times in a row
+// its budget is halved on every further rollback (down to 1) and doubled back up each
+// time some of its work commits, until it is back to max_depth.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RollbackLimits {
+    pub max_depth: Option<usize>,
+    pub penalty_after: u64,
+}
+
+impl Default for RollbackLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: None,
+            penalty_after: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RollbackBudget {
+    limits: RollbackLimits,
+    rollbacks_in_row: u64,
+    // The penalized budget, None while there is no penalty
+    penalty: Option<usize>,
+    // What the budget grows back to when there is no max_depth, the depth of the
+    // rollback the penalty started with
+    relaxed: usize,
+}
+
+impl RollbackBudget {
+    pub fn new(limits: RollbackLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    pub fn limits(&self) -> RollbackLimits {
+        self.limits
+    }
+
+    // How many uncommitted events the machine may have right now, None for no limit
+    pub fn budget(&self) -> Option<usize> {
+        match (self.penalty, self.limits.max_depth) {
+            (Some(penalty), Some(max_depth)) => Some(penalty.min(max_depth)),
+            (penalty, max_depth) => penalty.or(max_depth),
+        }
+    }
+
+    pub fn allows(&self, uncommitted: usize) -> bool {
+        self.budget().is_none_or(|budget| uncommitted < budget)
+    }
+
+    pub fn record_rollback(&mut self, depth: usize) {
+        self.rollbacks_in_row += 1;
+        if self.rollbacks_in_row < self.limits.penalty_after {
+            return;
+        }
+        let current = match self.budget() {
+            Some(budget) => budget,
+            None => {
+                self.relaxed = depth.max(1);
+                self.relaxed
+            }
+        };
+        self.penalty = Some((current / 2).max(1));
+    }
+
+    pub fn record_progress(&mut self) {
+        self.rollbacks_in_row = 0;
+        let Some(penalty) = self.penalty else {
+            return;
+        };
+        let grown = penalty.saturating_mul(2);
+        let full = self.limits.max_depth.unwrap_or(self.relaxed);
+        self.penalty = (grown < full).then_some(grown);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_backs_off_and_recovers() {
+        let mut budget = RollbackBudget::new(RollbackLimits {
+            max_depth: Some(16),
+            penalty_after: 2,
+        });
+        assert_eq!(budget.budget(), Some(16));
+        budget.record_rollback(5);
+        assert_eq!(budget.budget(), Some(16));
+        budget.record_rollback(5);
+        assert_eq!(budget.budget(), Some(8));
+        budget.record_rollback(5);
+        assert_eq!(budget.budget(), Some(4));
+        assert!(!budget.allows(4));
+
+        budget.record_progress();
+        assert_eq!(budget.budget(), Some(8));
+        budget.record_progress();
+        assert_eq!(budget.budget(), Some(16));
+
+        let mut unlimited = RollbackBudget::new(RollbackLimits {
+            max_depth: None,
+            penalty_after: 1,
+        });
+        unlimited.record_rollback(6);
+        assert_eq!(unlimited.budget(), Some(3));
+        unlimited.record_progress();
+        assert_eq!(unlimited.budget(), None);
+    }
+}
@@ -0,0 +1,344 @@
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::time::message::Message;
+
+// Wire format used when messages (and antimessages) leave the process. Every frame is
+//
+//   [ length: u32 big endian ][ version: u8 ][ flags: u8 ][ bincode body ]
+//
+// where the length counts the version and flags bytes and the body. The version lets two
+// nodes running different builds notice they cant talk to each other instead of decoding
+// garbage. The only flag is COMPRESSED, the body is then deflated (see Compression).
+pub const CODEC_VERSION: u8 = 6;
+pub const HEADER_LEN: usize = 4;
+// Anything bigger than this is assumed to be a corrupt length prefix. A compressed frame
+// has to fit uncompressed as well, so the body a frame can carry is the same either way
+// and whatever is written can be read back.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+pub const COMPRESSED: u8 = 1;
+
+// Deflates bodies of at least threshold bytes, for models that ship big state blobs in
+// their messages. Smaller ones arent worth the time and go as they are. Reading doesnt
+// need to know, a frame says whether it is compressed, so a writer can turn this on
+// without the other side doing anything. Level goes from 1 (fastest) to 10 (smallest).
+//
+// Deflate because it is what builds without a C toolchain, zstd or lz4 would be faster
+// at the same ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Compression {
+    pub threshold: usize,
+    pub level: u8,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            threshold: 64 * 1024,
+            level: 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(io::Error),
+    Serialization(bincode::Error),
+    UnsupportedVersion(u8),
+    FrameTooLarge(usize),
+    EmptyFrame,
+    UnknownFlags(u8),
+    // The compressed body is broken or inflates past what fits in MAX_FRAME_LEN
+    Decompression(String),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(error) => write!(f, "io error: {}", error),
+            CodecError::Serialization(error) => write!(f, "serialization error: {}", error),
+            CodecError::UnsupportedVersion(version) => {
+                write!(f, "unsupported codec version {} (expected {})", version, CODEC_VERSION)
+            }
+            CodecError::FrameTooLarge(len) => {
+                write!(f, "frame of {} bytes exceeds the limit of {}", len, MAX_FRAME_LEN)
+            }
+            CodecError::EmptyFrame => write!(f, "frame is missing the version byte"),
+            CodecError::UnknownFlags(flags) => write!(f, "frame has unknown flags {:#04x}", flags),
+            CodecError::Decompression(error) => write!(f, "cant inflate frame: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<io::Error> for CodecError {
+    fn from(error: io::Error) -> Self {
+        CodecError::Io(error)
+    }
+}
+
+impl From<bincode::Error> for CodecError {
+    fn from(error: bincode::Error) -> Self {
+        CodecError::Serialization(error)
+    }
+}
+
+// Encodes anything serializable into a single frame, messages are the main user
+// but transports can frame their own envelopes the same way
+pub fn encode_frame<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+    encode_frame_with(value, None)
+}
+
+// encode_frame compressing big bodies, see Compression
+pub fn encode_frame_with<T: Serialize>(
+    value: &T,
+    compression: Option<Compression>,
+) -> Result<Vec<u8>, CodecError> {
+    let mut body = bincode::serialize(value)?;
+    // Checked before compressing, the reader wont inflate past it
+    if body.len() + 2 > MAX_FRAME_LEN {
+        return Err(CodecError::FrameTooLarge(body.len() + 2));
+    }
+    let flags = compress(&mut body, compression);
+    let len = body.len() + 2;
+    let mut frame = Vec::with_capacity(HEADER_LEN + len);
+    frame.extend_from_slice(&(len as u32).to_be_bytes());
+    frame.extend_from_slice(&[CODEC_VERSION, flags]);
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+// Returns the flags for the body
+fn compress(body: &mut Vec<u8>, compression: Option<Compression>) -> u8 {
+    let Some(compression) = compression.filter(|c| body.len() >= c.threshold) else {
+        return 0;
+    };
+    let deflated = compress_to_vec(body, compression.level.min(10));
+    // Not everything gets smaller
+    if deflated.len() >= body.len() {
+        return 0;
+    }
+    *body = deflated;
+    COMPRESSED
+}
+
+// Tries to decode a frame from the front of a buffer. Returns None when the buffer doesnt
+// hold a whole frame yet, otherwise the value and how many bytes were used so the caller
+// can drain them.
+pub fn decode_frame<T: DeserializeOwned>(buffer: &[u8]) -> Result<Option<(T, usize)>, CodecError> {
+    if buffer.len() < HEADER_LEN {
+        return Ok(None);
+    }
+    let len = frame_len(&buffer[..HEADER_LEN])?;
+    if buffer.len() < HEADER_LEN + len {
+        return Ok(None);
+    }
+    let value = decode_body(&buffer[HEADER_LEN..HEADER_LEN + len], MAX_FRAME_LEN - 2)?;
+    Ok(Some((value, HEADER_LEN + len)))
+}
+
+// Blocking helpers for streams
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, value: &T) -> Result<(), CodecError> {
+    write_frame_with(writer, value, None)
+}
+
+pub fn write_frame_with<W: Write, T: Serialize>(
+    writer: &mut W,
+    value: &T,
+    compression: Option<Compression>,
+) -> Result<(), CodecError> {
+    writer.write_all(&encode_frame_with(value, compression)?)?;
+    Ok(())
+}
+
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<T, CodecError> {
+    let mut header = [0u8; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    let len = frame_len(&header)?;
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body)?;
+    decode_body(&body, MAX_FRAME_LEN - 2)
+}
+
+// Checkpoints and other files dont go over the wire, so they arent held to MAX_FRAME_LEN.
+// A file is the version and flags of a frame and then the body up to the end, without
+// the length in front.
+pub fn write_file<W: Write, T: Serialize>(
+    writer: &mut W,
+    value: &T,
+    compression: Option<Compression>,
+) -> Result<(), CodecError> {
+    if compression.is_none() {
+        writer.write_all(&[CODEC_VERSION, 0])?;
+        bincode::serialize_into(writer, value)?;
+        return Ok(());
+    }
+    let mut body = bincode::serialize(value)?;
+    let flags = compress(&mut body, compression);
+    writer.write_all(&[CODEC_VERSION, flags])?;
+    writer.write_all(&body)?;
+    Ok(())
+}
+
+pub fn read_file<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<T, CodecError> {
+    let mut contents = Vec::new();
+    reader.read_to_end(&mut contents)?;
+    if contents.is_empty() {
+        return Err(CodecError::EmptyFrame);
+    }
+    decode_body(&contents, usize::MAX)
+}
+
+pub fn encode(message: &Message) -> Result<Vec<u8>, CodecError> {
+    encode_frame(message)
+}
+
+pub fn decode(buffer: &[u8]) -> Result<Option<(Message, usize)>, CodecError> {
+    decode_frame(buffer)
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &Message) -> Result<(), CodecError> {
+    write_frame(writer, message)
+}
+
+pub fn read_message<R: Read>(reader: &mut R) -> Result<Message, CodecError> {
+    read_frame(reader)
+}
+
+fn frame_len(header: &[u8]) -> Result<usize, CodecError> {
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(CodecError::FrameTooLarge(len));
+    }
+    if len == 0 {
+        return Err(CodecError::EmptyFrame);
+    }
+    Ok(len)
+}
+
+// limit is how far a compressed body may inflate
+fn decode_body<T: DeserializeOwned>(body: &[u8], limit: usize) -> Result<T, CodecError> {
+    if body[0] != CODEC_VERSION {
+        return Err(CodecError::UnsupportedVersion(body[0]));
+    }
+    let (flags, body) = body[1..].split_first().ok_or(CodecError::EmptyFrame)?;
+    match *flags {
+        0 => Ok(bincode::deserialize(body)?),
+        COMPRESSED => {
+            let inflated = decompress_to_vec_with_limit(body, limit)
+                .map_err(|error| CodecError::Decompression(error.to_string()))?;
+            Ok(bincode::deserialize(&inflated)?)
+        }
+        flags => Err(CodecError::UnknownFlags(flags)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::message::Sign;
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_round_trip() {
+        let message = Message::new(1, 4, 1, 2, Sign::Message, Arc::new("payload".to_string()));
+        let mut antimessage = message.clone();
+        antimessage.sign = Sign::Antimessage;
+
+        let mut stream = Vec::new();
+        write_message(&mut stream, &message).unwrap();
+        write_message(&mut stream, &antimessage).unwrap();
+
+        let mut reader = Cursor::new(stream);
+        let decoded = read_message(&mut reader).unwrap();
+        let decoded_anti = read_message(&mut reader).unwrap();
+
+        assert_eq!(decoded, message);
+        assert_eq!(decoded.sign, Sign::Message);
+        assert_eq!(decoded_anti, message);
+        assert_eq!(decoded_anti.sign, Sign::Antimessage);
+        assert_eq!(decoded_anti.message, message.message);
+    }
+
+    #[test]
+    fn test_partial_frames() {
+        let message = Message::new(2, 9, 3, 4, Sign::Message, Arc::new("partial".to_string()));
+        let frame = encode(&message).unwrap();
+
+        assert!(decode(&frame[..2]).unwrap().is_none());
+        assert!(decode(&frame[..frame.len() - 1]).unwrap().is_none());
+
+        let mut buffer = frame.clone();
+        buffer.extend_from_slice(&frame[..3]);
+        let (decoded, used) = decode(&buffer).unwrap().unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(used, frame.len());
+    }
+
+    #[test]
+    fn test_big_bodies_are_compressed_transparently() {
+        let blob = "state ".repeat(1000);
+        let message = Message::new(2, 9, 3, 4, Sign::Message, Arc::new(blob));
+        let small = Message::new(2, 9, 3, 4, Sign::Message, Arc::new("small".to_string()));
+        let compression = Some(Compression {
+            threshold: 1024,
+            level: 1,
+        });
+
+        let plain = encode(&message).unwrap();
+        let compressed = encode_frame_with(&message, compression).unwrap();
+        assert_eq!(compressed[HEADER_LEN + 1], COMPRESSED);
+        assert!(compressed.len() < plain.len() / 10);
+        let (decoded, used): (Message, _) = decode(&compressed).unwrap().unwrap();
+        assert_eq!(decoded.message, message.message);
+        assert_eq!(used, compressed.len());
+
+        // Under the threshold nothing changes
+        assert_eq!(encode_frame_with(&small, compression).unwrap(), encode(&small).unwrap());
+
+        let mut reader = Cursor::new(compressed.clone());
+        assert_eq!(read_message(&mut reader).unwrap(), message);
+        let mut corrupt = compressed;
+        corrupt.truncate(HEADER_LEN + 10);
+        corrupt[..HEADER_LEN].copy_from_slice(&10u32.to_be_bytes());
+        assert!(matches!(decode(&corrupt), Err(CodecError::Decompression(_))));
+    }
+
+    #[test]
+    fn test_compressed_frames_have_the_same_limit() {
+        // bincode puts the length of a Vec in front, 8 bytes
+        let at_limit = vec![0u8; MAX_FRAME_LEN - 2 - 8];
+        let compression = Some(Compression::default());
+        let frame = encode_frame_with(&at_limit, compression).unwrap();
+        assert_eq!(frame[HEADER_LEN + 1], COMPRESSED);
+        let (decoded, _): (Vec<u8>, _) = decode_frame(&frame).unwrap().unwrap();
+        assert_eq!(decoded, at_limit);
+
+        // Would compress to next to nothing, but couldnt be inflated again
+        let over = vec![0u8; MAX_FRAME_LEN - 2 - 7];
+        let error = encode_frame_with(&over, compression).unwrap_err();
+        assert!(matches!(error, CodecError::FrameTooLarge(len) if len == MAX_FRAME_LEN + 1));
+    }
+
+    #[test]
+    fn test_rejects_other_versions() {
+        let message = Message::new(2, 9, 3, 4, Sign::Message, Arc::new("old".to_string()));
+        let mut frame = encode(&message).unwrap();
+        frame[HEADER_LEN] = CODEC_VERSION + 1;
+
+        assert!(matches!(
+            decode(&frame),
+            Err(CodecError::UnsupportedVersion(version)) if version == CODEC_VERSION + 1
+        ));
+
+        let bogus_len = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes();
+        assert!(matches!(decode(&bogus_len), Err(CodecError::FrameTooLarge(_))));
+    }
+}
@@ -0,0 +1,837 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::budget::RollbackLimits;
+use crate::latency::{mix, Latencies, LatencyConfig};
+use crate::machine::{ExecutionPolicy, Machine, SnapshotLimits};
+use crate::saving::SavingConfig;
+use crate::throttle::ThrottleLimits;
+use crate::process::{Context, TimeWarpProcess};
+use crate::router::Router;
+use crate::runtime::conservative::ConservativeSimulation;
+use crate::runtime::sequential::SequentialSimulation;
+use crate::runtime::Simulation;
+use crate::time::input_queue::DuplicatePolicy;
+use crate::time::message::{Delay, MachineId, Message, Sign, VirtualTime};
+
+// A simulation described as data instead of code, this is what the vtw binary runs.
+// The machines are wired together by directed links with a fixed delay, and unless
+// built with another process every machine runs a TopologyProcess. The same layout
+// works in json, toml or yaml (picked by the file extension), in toml:
+//
+//   seed = 7
+//   end_time = 100
+//
+//   [[machines]]
+//   id = 0
+//   state = 10
+//
+//   [[machines]]
+//   id = 1
+//
+//   [[links]]
+//   from = 0
+//   to = 1
+//   delay = 3
+//
+//   [[initial]]
+//   to = 0
+//   at = 1
+//   payload = "10"
+//
+// The scenarios/ directory has the hand written examples declared this way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    // Anything without a toml or yaml extension is read as json
+    pub fn from_path<T: AsRef<Path>>(path: T) -> Self {
+        match path.as_ref().extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("yaml") | Some("yml") => Format::Yaml,
+            _ => Format::Json,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SimulationConfig {
+    pub machines: Vec<MachineConfig>,
+    #[serde(default)]
+    pub links: Vec<LinkConfig>,
+    #[serde(default)]
+    pub initial: Vec<InitialMessage>,
+    #[serde(default)]
+    pub end_time: Option<VirtualTime>,
+    #[serde(default)]
+    pub seed: u64,
+    // Statistics start over once GVT gets here, see runtime/warm_up.rs
+    #[serde(default)]
+    pub warm_up: Option<VirtualTime>,
+    // Rollbacks at least this deep warn the other machines, see runtime/wolf.rs
+    #[serde(default)]
+    pub wolf_calls: Option<usize>,
+    // Rollbacks take back messages without antimessages, see runtime/cancellation.rs.
+    // Only the optimistic single threaded run does it.
+    #[serde(default)]
+    pub direct_cancellation: bool,
+    // Stragglers that leave the state as it was dont roll back, see
+    // Machine::set_lazy_reevaluation
+    #[serde(default)]
+    pub lazy_reevaluation: bool,
+}
+
+// The state is whatever the process uses as its state written out in the config format,
+// machines without one start from the default state. The policy is "optimistic" (the
+// default) or "conservative". With fifo set the machine sees the messages from each
+// sender in the order they were sent, even if a later one has an earlier receive time.
+// duplicates is what its input queue does with a message it got twice, one of
+// "annihilate_opposite_signs_only" (drop the copy, the default), "reject_duplicates" or
+// "allow_duplicates", see DuplicatePolicy.
+// limits (max_depth and penalty_after) keep the machine from running too far ahead of
+// what is committed, see budget.rs, and throttle (penalty_after, recover_at and
+// min_window) from running far ahead while it keeps being rolled back, see throttle.rs.
+// snapshots (max_saved_states and coast_forward) bound how many states it keeps, see
+// SnapshotLimits in machine.rs, and saving how often it saves one, see SavingConfig in
+// saving.rs. max_pending is the most messages it can have waiting before its senders are
+// held back, see MachineBuilder::max_pending. A machine with a name can be called by it
+// instead of its id in links and initial messages, and shows up with it in traces (see
+// router.rs).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MachineConfig {
+    pub id: MachineId,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub state: Option<serde_json::Value>,
+    #[serde(default)]
+    pub policy: ExecutionPolicy,
+    #[serde(default)]
+    pub fifo: bool,
+    #[serde(default)]
+    pub duplicates: DuplicatePolicy,
+    #[serde(default)]
+    pub limits: RollbackLimits,
+    #[serde(default)]
+    pub throttle: Option<ThrottleLimits>,
+    #[serde(default)]
+    pub snapshots: SnapshotLimits,
+    #[serde(default)]
+    pub saving: SavingConfig,
+    #[serde(default)]
+    pub max_pending: Option<usize>,
+}
+
+// The delay is the smallest the link ever takes, and with no latency model all it takes.
+// A latency model is written inline with the link, for example in toml
+//
+//   [[links]]
+//   from = 0
+//   to = 1
+//   delay = 2
+//   latency = { model = "uniform", min = 2, max = 8 }
+//
+// where the model is one of constant (delay), uniform (min, max), exponential (mean, on
+// top of the delay) or trace (points as [send time, delay] pairs). Samples below the
+// delay are raised to it so it stays safe to use as the lookahead. A link with a
+// bandwidth (bytes per time unit) also queues its messages, see bandwidth.rs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LinkConfig {
+    pub from: MachineId,
+    pub to: MachineId,
+    pub delay: Delay,
+    #[serde(default)]
+    pub latency: Option<LatencyConfig>,
+    // Payload bytes per time unit, see bandwidth.rs. None is a link without a limit.
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+}
+
+// A message put in from outside the simulation. The sender defaults to the receiver and
+// the send time to 0. Messages with `after` set are held back until that many events
+// have been executed, which is how a scenario gets a straggler to show up late.
+// A TopologyProcess reads the payload as how many more times to forward the message.
+// The port is the input of the receiver the message goes to, if it has several.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InitialMessage {
+    pub to: MachineId,
+    pub at: VirtualTime,
+    #[serde(default)]
+    pub payload: String,
+    #[serde(default)]
+    pub from: Option<MachineId>,
+    #[serde(default)]
+    pub send_time: VirtualTime,
+    #[serde(default)]
+    pub after: usize,
+    #[serde(default)]
+    pub port: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Toml(toml::de::Error),
+    Yaml(serde_yaml::Error),
+    InvalidState { machine: MachineId, error: serde_json::Error },
+    DuplicateMachine(MachineId),
+    UnknownMachine(MachineId),
+    ZeroDelay { from: MachineId, to: MachineId },
+    SendTimeAfterReceive { to: MachineId, at: VirtualTime },
+    DuplicateName(String),
+    UnknownName(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(error) => write!(f, "io error: {}", error),
+            ConfigError::Json(error) => write!(f, "invalid json config: {}", error),
+            ConfigError::Toml(error) => write!(f, "invalid toml config: {}", error),
+            ConfigError::Yaml(error) => write!(f, "invalid yaml config: {}", error),
+            ConfigError::InvalidState { machine, error } => {
+                write!(f, "invalid state for machine {}: {}", machine, error)
+            }
+            ConfigError::DuplicateMachine(id) => write!(f, "machine {} is defined twice", id),
+            ConfigError::UnknownMachine(id) => write!(f, "machine {} is not defined", id),
+            ConfigError::SendTimeAfterReceive { to, at } => {
+                write!(f, "message to {} at {} is sent after it is received", to, at)
+            }
+            ConfigError::ZeroDelay { from, to } => {
+                write!(f, "link from {} to {} needs a delay of at least 1", from, to)
+            }
+            ConfigError::DuplicateName(name) => write!(f, "two machines are called {}", name),
+            ConfigError::UnknownName(name) => write!(f, "no machine is called {}", name),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(error: io::Error) -> Self {
+        ConfigError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from(error: serde_json::Error) -> Self {
+        ConfigError::Json(error)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(error: toml::de::Error) -> Self {
+        ConfigError::Toml(error)
+    }
+}
+
+impl From<serde_yaml::Error> for ConfigError {
+    fn from(error: serde_yaml::Error) -> Self {
+        ConfigError::Yaml(error)
+    }
+}
+
+// A machine in a link or initial message of a config file, by id or by name
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum MachineRef {
+    Id(MachineId),
+    Name(String),
+}
+
+// The config as written, before the machine names are swapped for their ids
+#[derive(Debug, Deserialize)]
+struct RawConfig {
+    machines: Vec<MachineConfig>,
+    #[serde(default)]
+    links: Vec<RawLink>,
+    #[serde(default)]
+    initial: Vec<RawInitial>,
+    #[serde(default)]
+    end_time: Option<VirtualTime>,
+    #[serde(default)]
+    seed: u64,
+    #[serde(default)]
+    warm_up: Option<VirtualTime>,
+    #[serde(default)]
+    wolf_calls: Option<usize>,
+    #[serde(default)]
+    direct_cancellation: bool,
+    #[serde(default)]
+    lazy_reevaluation: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawLink {
+    from: MachineRef,
+    to: MachineRef,
+    delay: Delay,
+    #[serde(default)]
+    latency: Option<LatencyConfig>,
+    #[serde(default)]
+    bandwidth: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawInitial {
+    to: MachineRef,
+    at: VirtualTime,
+    #[serde(default)]
+    payload: String,
+    #[serde(default)]
+    from: Option<MachineRef>,
+    #[serde(default)]
+    send_time: VirtualTime,
+    #[serde(default)]
+    after: usize,
+    #[serde(default)]
+    port: Option<String>,
+}
+
+impl RawConfig {
+    fn resolve(self) -> Result<SimulationConfig, ConfigError> {
+        let mut config = SimulationConfig {
+            machines: self.machines,
+            links: Vec::new(),
+            initial: Vec::new(),
+            end_time: self.end_time,
+            seed: self.seed,
+            warm_up: self.warm_up,
+            wolf_calls: self.wolf_calls,
+            direct_cancellation: self.direct_cancellation,
+            lazy_reevaluation: self.lazy_reevaluation,
+        };
+        let router = config.router()?;
+        let id = |machine: MachineRef| match machine {
+            MachineRef::Id(machine_id) => Ok(machine_id),
+            MachineRef::Name(name) => router
+                .lookup_by_name(&name)
+                .ok_or(ConfigError::UnknownName(name)),
+        };
+        for link in self.links {
+            config.links.push(LinkConfig {
+                from: id(link.from)?,
+                to: id(link.to)?,
+                delay: link.delay,
+                latency: link.latency,
+                bandwidth: link.bandwidth,
+            });
+        }
+        for initial in self.initial {
+            config.initial.push(InitialMessage {
+                to: id(initial.to)?,
+                at: initial.at,
+                payload: initial.payload,
+                from: initial.from.map(id).transpose()?,
+                send_time: initial.send_time,
+                after: initial.after,
+                port: initial.port,
+            });
+        }
+        Ok(config)
+    }
+}
+
+impl SimulationConfig {
+    pub fn load<T: AsRef<Path>>(path: T) -> Result<Self, ConfigError> {
+        Self::parse(&fs::read_to_string(&path)?, Format::from_path(&path))
+    }
+
+    pub fn parse(text: &str, format: Format) -> Result<Self, ConfigError> {
+        let raw: RawConfig = match format {
+            Format::Json => serde_json::from_str(text)?,
+            Format::Toml => toml::from_str(text)?,
+            Format::Yaml => serde_yaml::from_str(text)?,
+        };
+        let config = raw.resolve()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Every link and initial message has to point at a defined machine, and links need a
+    // delay since a message sent with no delay would land in the senders own past
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.router()?;
+        let mut ids = BTreeSet::new();
+        for machine in &self.machines {
+            if !ids.insert(machine.id) {
+                return Err(ConfigError::DuplicateMachine(machine.id));
+            }
+        }
+        for link in &self.links {
+            for id in [link.from, link.to] {
+                if !ids.contains(&id) {
+                    return Err(ConfigError::UnknownMachine(id));
+                }
+            }
+            if link.delay == 0 {
+                return Err(ConfigError::ZeroDelay {
+                    from: link.from,
+                    to: link.to,
+                });
+            }
+        }
+        for message in &self.initial {
+            for id in [Some(message.to), message.from].into_iter().flatten() {
+                if !ids.contains(&id) {
+                    return Err(ConfigError::UnknownMachine(id));
+                }
+            }
+            if message.send_time > message.at {
+                return Err(ConfigError::SendTimeAfterReceive {
+                    to: message.to,
+                    at: message.at,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // The names of the machines that have one
+    pub fn router(&self) -> Result<Router<()>, ConfigError> {
+        let mut router = Router::new();
+        for machine in &self.machines {
+            if let Some(name) = &machine.name {
+                router
+                    .set_name(machine.id, name)
+                    .map_err(|_| ConfigError::DuplicateName(name.clone()))?;
+            }
+        }
+        Ok(router)
+    }
+
+    // Builds every machine with the process make_process returns for it, it is handed the
+    // machine and its outgoing links as (receiver, delay)
+    pub fn build_machines_with<P, F>(&self, mut make_process: F) -> Result<Vec<Machine<P>>, ConfigError>
+    where
+        P: TimeWarpProcess,
+        P::State: DeserializeOwned,
+        F: FnMut(&MachineConfig, &[(MachineId, Delay)]) -> P,
+    {
+        let mut links: BTreeMap<MachineId, Vec<(MachineId, Delay)>> = BTreeMap::new();
+        for link in &self.links {
+            links.entry(link.from).or_default().push((link.to, link.delay));
+        }
+        let latencies = self.latencies().map(Arc::new);
+        self.machines
+            .iter()
+            .map(|machine| {
+                let process = make_process(machine, links.get(&machine.id).map_or(&[], Vec::as_slice));
+                let state = match &machine.state {
+                    Some(state) => serde_json::from_value(state.clone()).map_err(|error| {
+                        ConfigError::InvalidState {
+                            machine: machine.id,
+                            error,
+                        }
+                    })?,
+                    None => P::State::default(),
+                };
+                let mut builder = Machine::builder(machine.id, process)
+                    .state(state)
+                    .policy(machine.policy)
+                    .fifo(machine.fifo)
+                    .duplicates(machine.duplicates)
+                    .rollback_limits(machine.limits)
+                    .snapshots(machine.snapshots)
+                    .seed(mix(self.seed ^ machine.id as u64));
+                if let Some(latencies) = &latencies {
+                    builder = builder.latencies(latencies.clone());
+                }
+                if let Some(limits) = machine.throttle {
+                    builder = builder.throttle(limits);
+                }
+                if let Some(policy) = machine.saving.policy() {
+                    builder = builder.save_policy(policy);
+                }
+                if let Some(max_pending) = machine.max_pending {
+                    builder = builder.max_pending(max_pending);
+                }
+                Ok(builder.build())
+            })
+            .collect()
+    }
+
+    // The latency models of the links that have one, None if none do
+    pub fn latencies(&self) -> Option<Latencies> {
+        let mut latencies = Latencies::new(self.seed);
+        let mut any = false;
+        for link in &self.links {
+            if let Some(latency) = &link.latency {
+                latencies.set_link(link.from, link.to, latency.build(link.delay));
+                any = true;
+            }
+            if let Some(bandwidth) = link.bandwidth {
+                latencies.set_bandwidth(link.from, link.to, bandwidth);
+                any = true;
+            }
+        }
+        any.then_some(latencies)
+    }
+
+    pub fn build_machines(&self) -> Result<Vec<Machine<TopologyProcess>>, ConfigError> {
+        self.build_machines_with(|_, links| TopologyProcess {
+            links: links.to_vec(),
+            seed: self.seed,
+        })
+    }
+
+    // Every initial message paired with how many events have to run before it is put in,
+    // in the order they should go in
+    pub fn initial_messages(&self) -> Vec<(usize, Message)> {
+        let mut messages: Vec<_> = self
+            .initial
+            .iter()
+            .map(|initial| {
+                let message = Message::new(
+                    initial.send_time,
+                    initial.at,
+                    initial.from.unwrap_or(initial.to),
+                    initial.to,
+                    Sign::Message,
+                    Arc::new(initial.payload.clone()),
+                );
+                let message = match &initial.port {
+                    Some(port) => message.with_port(port.as_str()),
+                    None => message,
+                };
+                (initial.after, message)
+            })
+            .collect();
+        messages.sort_by_key(|(after, _)| *after);
+        messages
+    }
+
+    pub fn build_with<P, F>(&self, make_process: F) -> Result<Simulation<P>, ConfigError>
+    where
+        P: TimeWarpProcess,
+        P::State: DeserializeOwned,
+        F: FnMut(&MachineConfig, &[(MachineId, Delay)]) -> P,
+    {
+        let mut simulation = Simulation::new();
+        for machine in self.build_machines_with(make_process)? {
+            simulation.add_machine(machine);
+        }
+        for (machine_id, name) in self.router()?.names() {
+            // Cant be taken, the router only has each name once
+            let _ = simulation.name_machine(*machine_id, name);
+        }
+        if let Some(at) = self.warm_up {
+            simulation.set_warm_up(at);
+        }
+        if let Some(min_depth) = self.wolf_calls {
+            simulation.set_wolf_calls(min_depth);
+        }
+        simulation.set_direct_cancellation(self.direct_cancellation);
+        simulation.set_lazy_reevaluation(self.lazy_reevaluation);
+        Ok(simulation)
+    }
+
+    pub fn build(&self) -> Result<Simulation<TopologyProcess>, ConfigError> {
+        self.build_with(|_, links| TopologyProcess {
+            links: links.to_vec(),
+            seed: self.seed,
+        })
+    }
+
+    // The same model for the conservative executor, every link becomes a channel with its
+    // delay as the lookahead. A conservative run cant take stragglers so held back
+    // messages are put in right away along with the rest.
+    pub fn build_conservative(&self) -> Result<ConservativeSimulation<TopologyProcess>, ConfigError> {
+        let mut simulation = ConservativeSimulation::new();
+        for machine in self.build_machines()? {
+            simulation.add_machine(machine);
+        }
+        let mut lookaheads: BTreeMap<(MachineId, MachineId), Delay> = BTreeMap::new();
+        for link in &self.links {
+            let lookahead = lookaheads.entry((link.from, link.to)).or_insert(link.delay);
+            *lookahead = (*lookahead).min(link.delay);
+        }
+        for ((from, to), lookahead) in lookaheads {
+            simulation.connect(from, to, lookahead);
+        }
+        for (_, message) in self.initial_messages() {
+            simulation.inject(message);
+        }
+        Ok(simulation)
+    }
+
+    // The same model for the sequential executor, everything initial goes in right away
+    pub fn build_sequential(&self) -> Result<SequentialSimulation<TopologyProcess>, ConfigError> {
+        let mut simulation = SequentialSimulation::new();
+        for machine in self.build_machines()? {
+            simulation.add_machine(machine);
+        }
+        for (_, message) in self.initial_messages() {
+            simulation.inject(message);
+        }
+        Ok(simulation)
+    }
+
+    // Runs a simulation built from this config, putting in the initial messages as their
+    // turn comes up. Stops at end_time (or the one in the config) if there is one.
+    pub fn run<P: TimeWarpProcess>(&self, simulation: &mut Simulation<P>, end_time: Option<VirtualTime>) {
+        let end_time = end_time.or(self.end_time);
+        let mut steps = 0;
+        for (after, message) in self.initial_messages() {
+            while steps < after && step_within(simulation, end_time) {
+                steps += 1;
+            }
+            simulation.inject(message);
+        }
+        match end_time {
+            Some(end_time) => simulation.run_until(end_time),
+            None => simulation.run(),
+        }
+    }
+
+}
+
+fn step_within<P: TimeWarpProcess>(simulation: &mut Simulation<P>, end_time: Option<VirtualTime>) -> bool {
+    match end_time {
+        Some(end_time) => simulation.step_until(end_time),
+        None => {
+            simulation.deliver_pending();
+            simulation.next_machine().is_some() && simulation.step()
+        }
+    }
+}
+
+// Handles a message by forwarding it down one of the machines outgoing links until the
+// hop count in the payload runs out. Which link is picked depends only on the seed and
+// the event being handled, so re-executing an event after a rollback picks the same one
+// and runs with the same seed come out the same. The state is how many messages the
+// machine has handled.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopologyProcess {
+    pub links: Vec<(MachineId, Delay)>,
+    pub seed: u64,
+}
+
+impl TimeWarpProcess for TopologyProcess {
+    type State = usize;
+
+    fn on_message(&self, state: &mut usize, message: &Message, ctx: &mut Context) {
+        *state += 1;
+        let hops_left: usize = message.message.trim().parse().unwrap_or(0);
+        if hops_left == 0 || self.links.is_empty() {
+            return;
+        }
+        let pick = [ctx.machine_id() as u64, ctx.now().ticks(), message.sender as u64]
+            .into_iter()
+            .chain([hops_left as u64])
+            .fold(self.seed, |hash, value| mix(hash ^ value));
+        let (to, delay) = self.links[(pick % self.links.len() as u64) as usize];
+        let delay = ctx.link_delay(to).map_or(delay, |sampled| sampled.max(delay));
+        ctx.send(to, delay, (hops_left - 1).to_string());
+    }
+
+    fn lookahead(&self) -> Delay {
+        self.links.iter().map(|(_, delay)| *delay).min().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RING: &str = r#"{
+        "machines": [{ "id": 0 }, { "id": 1 }, { "id": 2 }],
+        "links": [
+            { "from": 0, "to": 1, "delay": 3 },
+            { "from": 1, "to": 2, "delay": 3 },
+            { "from": 2, "to": 0, "delay": 3 }
+        ],
+        "initial": [{ "to": 0, "at": 1, "payload": "5" }]
+    }"#;
+
+    #[test]
+    fn test_ring_config_runs_like_the_ring() {
+        let config = SimulationConfig::parse(RING, Format::Json).unwrap();
+        assert_eq!(config.end_time, None);
+
+        let mut simulation = config.build().unwrap();
+        config.run(&mut simulation, None);
+        let handled: Vec<_> = simulation.machines().map(|machine| machine.state).collect();
+        assert_eq!(handled, vec![2, 2, 2]);
+        assert_eq!(simulation.machine(2).unwrap().local_virtual_time(), 16);
+    }
+
+    #[test]
+    fn test_links_sample_their_latency_model() {
+        let mut config = SimulationConfig::parse(RING, Format::Json).unwrap();
+        // Gets slower from time 10 on, and a uniform model never goes below the delay
+        config.links[0].latency = Some(LatencyConfig::Trace {
+            points: vec![
+                (VirtualTime::ZERO, Delay::new(3)),
+                (VirtualTime::new(10), Delay::new(5)),
+            ],
+        });
+        config.links[1].latency = Some(LatencyConfig::Trace {
+            points: vec![
+                (VirtualTime::ZERO, Delay::new(3)),
+                (VirtualTime::new(10), Delay::new(5)),
+            ],
+        });
+        config.links[2].latency = Some(LatencyConfig::Uniform {
+            min: Delay::ZERO,
+            max: Delay::new(1),
+        });
+
+        let mut simulation = config.build().unwrap();
+        config.run(&mut simulation, None);
+        assert_eq!(simulation.machine(0).unwrap().local_virtual_time(), 10);
+        assert_eq!(simulation.machine(2).unwrap().local_virtual_time(), 20);
+    }
+
+    #[test]
+    fn test_links_with_a_bandwidth_take_longer() {
+        let with_bandwidth = RING.replace(
+            r#"{ "from": 0, "to": 1, "delay": 3 }"#,
+            r#"{ "from": 0, "to": 1, "delay": 3, "bandwidth": 1 }"#,
+        );
+        let config = SimulationConfig::parse(&with_bandwidth, Format::Json).unwrap();
+        assert_eq!(config.links[0].bandwidth, Some(1));
+
+        // Both times over the link the one byte payload takes a tick more
+        let mut simulation = config.build().unwrap();
+        config.run(&mut simulation, None);
+        assert_eq!(simulation.machine(2).unwrap().local_virtual_time(), 18);
+    }
+
+    #[test]
+    fn test_double_rollback_scenario() {
+        let config = SimulationConfig::parse(
+            include_str!("../scenarios/send_message_double_rollback.toml"),
+            Format::Toml,
+        )
+        .unwrap();
+        let mut simulation = config.build().unwrap();
+        config.run(&mut simulation, None);
+
+        let stats = simulation.stats();
+        assert_eq!(stats.machines[&1].rollbacks, 1);
+        assert_eq!(stats.machines[&1].antimessages_sent, 2);
+        assert_eq!(stats.machines[&2].rollbacks, 1);
+        assert_eq!(stats.machines[&2].events_rolled_back, 2);
+        assert_eq!(simulation.machine(1).unwrap().state, 3);
+        assert_eq!(simulation.machine(2).unwrap().state, 2);
+        assert_eq!(simulation.gvt(), None);
+    }
+
+    #[test]
+    fn test_conservative_run_of_the_scenario_never_rolls_back() {
+        let config = SimulationConfig::parse(
+            include_str!("../scenarios/send_message_double_rollback.toml"),
+            Format::Toml,
+        )
+        .unwrap();
+        let mut simulation = config.build_conservative().unwrap();
+        simulation.run();
+
+        assert_eq!(simulation.stats().total.rollbacks, 0);
+        assert_eq!(simulation.machine(1).unwrap().state, 3);
+        assert_eq!(simulation.machine(2).unwrap().state, 2);
+    }
+
+    #[test]
+    fn test_yaml_initial_states() {
+        let config =
+            SimulationConfig::parse(include_str!("../scenarios/ring.yaml"), Format::Yaml).unwrap();
+        let mut simulation = config.build().unwrap();
+        config.run(&mut simulation, Some(VirtualTime::new(7)));
+
+        // Events at 1 on machine 0, 4 on machine 1 and 7 on machine 2
+        assert_eq!(simulation.machine(0).unwrap().state, 101);
+        assert_eq!(simulation.machine(1).unwrap().state, 1);
+        assert_eq!(simulation.machine(2).unwrap().state, 1);
+        assert_eq!(simulation.gvt(), Some(VirtualTime::new(10)));
+
+        let bad_state = "machines:\n  - id: 0\n    state: lots\n";
+        let config = SimulationConfig::parse(bad_state, Format::Yaml).unwrap();
+        assert!(matches!(
+            config.build(),
+            Err(ConfigError::InvalidState { machine: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_machines_can_be_named() {
+        let named = r#"
+            [[machines]]
+            id = 0
+            name = "source"
+
+            [[machines]]
+            id = 1
+            name = "sink"
+
+            [[links]]
+            from = "source"
+            to = 1
+            delay = 2
+
+            [[initial]]
+            to = "source"
+            at = 1
+            payload = "1"
+        "#;
+        let config = SimulationConfig::parse(named, Format::Toml).unwrap();
+        assert_eq!((config.links[0].from, config.links[0].to), (0, 1));
+        assert_eq!(config.initial[0].to, 0);
+
+        let mut simulation = config.build().unwrap();
+        simulation.start_recording();
+        config.run(&mut simulation, None);
+        let sink = simulation.lookup_by_name("sink").unwrap();
+        assert_eq!(simulation.machine(sink).unwrap().state, 1);
+        assert_eq!(simulation.trace().unwrap().label(0), "source");
+
+        let unknown = r#"{ "machines": [{ "id": 0 }], "initial": [{ "to": "nobody", "at": 1 }] }"#;
+        assert!(matches!(
+            SimulationConfig::parse(unknown, Format::Json),
+            Err(ConfigError::UnknownName(name)) if name == "nobody"
+        ));
+        let twice = r#"{ "machines": [{ "id": 0, "name": "a" }, { "id": 1, "name": "a" }] }"#;
+        assert!(matches!(
+            SimulationConfig::parse(twice, Format::Json),
+            Err(ConfigError::DuplicateName(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_configs_are_rejected() {
+        let unknown = r#"{ "machines": [{ "id": 0 }], "links": [{ "from": 0, "to": 4, "delay": 1 }] }"#;
+        assert!(matches!(
+            SimulationConfig::parse(unknown, Format::Json),
+            Err(ConfigError::UnknownMachine(4))
+        ));
+
+        let zero = "[[machines]]\nid = 0\n[[links]]\nfrom = 0\nto = 0\ndelay = 0\n";
+        assert!(matches!(
+            SimulationConfig::parse(zero, Format::Toml),
+            Err(ConfigError::ZeroDelay { from: 0, to: 0 })
+        ));
+
+        let duplicate = r#"{ "machines": [{ "id": 1 }, { "id": 1 }] }"#;
+        assert!(matches!(
+            SimulationConfig::parse(duplicate, Format::Json),
+            Err(ConfigError::DuplicateMachine(1))
+        ));
+
+        assert_eq!(Format::from_path("a/b.yml"), Format::Yaml);
+        assert_eq!(Format::from_path("b.toml"), Format::Toml);
+        assert_eq!(Format::from_path("config"), Format::Json);
+    }
+}
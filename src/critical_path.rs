@@ -0,0 +1,148 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::sink::{CommittedEvent, EventSink};
+use crate::time::message::{MachineId, MessageId};
+
+// The critical path of a run is the longest chain of committed events that each had to
+// wait for the one before. An event waits for the event that sent its message (the
+// causal chain of VecSink::causal_chain) and for the event its machine ran before it,
+// since that one made the state it starts from. However many threads there are and
+// however few rollbacks, the run cant take fewer steps than the path is long, so events
+// divided by the length is the most any parallel executor can speed the model up by.
+//
+// Like VecSink it is a handle, give a clone to the runtime with add_sink and get the
+// report from the other one afterwards. It keeps a few words for every committed event.
+#[derive(Debug, Clone, Default)]
+pub struct CriticalPath {
+    inner: Arc<Mutex<Chains>>,
+}
+
+#[derive(Debug, Default)]
+struct Chains {
+    // The longest chain ending at each committed event
+    events: HashMap<MessageId, Link>,
+    // The event each machine committed last
+    last: HashMap<MachineId, MessageId>,
+    // The event the longest chain so far ends at
+    end: Option<MessageId>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Link {
+    machine_id: MachineId,
+    length: u64,
+    // The event before in the chain, the one that was waited for longest
+    before: Option<MessageId>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CriticalPathReport {
+    pub events: u64,
+    // Events on the critical path
+    pub length: u64,
+    // How many events of the path each machine ran, machines that ran none are left out
+    pub machines: BTreeMap<MachineId, u64>,
+}
+
+impl CriticalPathReport {
+    // 0.0 when nothing committed
+    pub fn speedup_ceiling(&self) -> f64 {
+        if self.length == 0 {
+            return 0.0;
+        }
+        self.events as f64 / self.length as f64
+    }
+
+    // Part of the path that ran on the machine, from 0.0 to 1.0
+    pub fn share(&self, machine_id: MachineId) -> f64 {
+        match self.machines.get(&machine_id) {
+            Some(events) => *events as f64 / self.length as f64,
+            None => 0.0,
+        }
+    }
+}
+
+impl CriticalPath {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn report(&self) -> CriticalPathReport {
+        let chains = self.inner.lock().unwrap();
+        let mut report = CriticalPathReport {
+            events: chains.events.len() as u64,
+            ..CriticalPathReport::default()
+        };
+        let mut next = chains.end;
+        while let Some(link) = next.and_then(|message_id| chains.events.get(&message_id)) {
+            report.length += 1;
+            *report.machines.entry(link.machine_id).or_default() += 1;
+            next = link.before;
+        }
+        report
+    }
+}
+
+impl EventSink for CriticalPath {
+    fn on_commit(&mut self, event: &CommittedEvent) {
+        let mut chains = self.inner.lock().unwrap();
+        let chains = &mut *chains;
+        // Messages from outside and parents committed before the sink was added start
+        // a chain of their own
+        let before = [event.message.parent, chains.last.get(&event.machine_id).copied()]
+            .into_iter()
+            .flatten()
+            .filter_map(|message_id| Some((message_id, chains.events.get(&message_id)?.length)))
+            .max_by_key(|(_, length)| *length);
+        let link = Link {
+            machine_id: event.machine_id,
+            length: before.map_or(0, |(_, length)| length) + 1,
+            before: before.map(|(message_id, _)| message_id),
+        };
+        let longest = chains.end.and_then(|end| chains.events.get(&end));
+        let longest = longest.map_or(0, |end| end.length);
+        if link.length > longest {
+            chains.end = Some(event.message.id);
+        }
+        chains.events.insert(event.message.id, link);
+        chains.last.insert(event.machine_id, event.message.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use crate::time::message::{Message, Sign};
+
+    #[test]
+    fn test_critical_paths_wait_for_senders_and_the_machines_last_event() {
+        // Two chains through 4 machines that dont meet, the path is one of them
+        let mut simulation = ring(4);
+        let path = CriticalPath::new();
+        simulation.add_sink(Box::new(path.clone()));
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("3".into())));
+        simulation.inject(Message::new(0, 2, 2, 2, Sign::Message, Arc::new("3".into())));
+        simulation.run();
+        let report = path.report();
+        assert_eq!((report.events, report.length), (8, 4));
+        assert_eq!(report.speedup_ceiling(), 2.0);
+        assert_eq!(report.machines.values().sum::<u64>(), 4);
+        assert_eq!(report.machines.len(), 4);
+        assert_eq!(report.share(0), 0.25);
+
+        // On 2 machines they go through the same ones and have to wait for each other,
+        // every other event on a machine is a turn of the other chain
+        let mut simulation = ring(2);
+        let path = CriticalPath::new();
+        simulation.add_sink(Box::new(path.clone()));
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("3".into())));
+        simulation.inject(Message::new(0, 2, 0, 0, Sign::Message, Arc::new("3".into())));
+        simulation.run();
+        let report = path.report();
+        assert_eq!((report.events, report.length), (8, 5));
+        assert_eq!(report.machines.values().sum::<u64>(), 5);
+        assert_eq!(CriticalPathReport::default().speedup_ceiling(), 0.0);
+    }
+}
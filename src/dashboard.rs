@@ -0,0 +1,242 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use ratatui::backend::{CrosstermBackend, TestBackend};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::widgets::{Block, Gauge, List, ListItem, Paragraph, Row, Table};
+use ratatui::{Frame, Terminal};
+
+use crate::bridge::EgressRecord;
+use crate::live::LiveSink;
+use crate::metrics::{MetricsHandle, MetricsSnapshot};
+use crate::time::message::VirtualTime;
+
+// How often the dashboard is redrawn
+pub const DEFAULT_REFRESH: Duration = Duration::from_millis(250);
+// Committed events kept for the log at the bottom
+pub const LOG_LINES: usize = 12;
+const PAYLOAD_WIDTH: usize = 40;
+
+// A ratatui dashboard for watching a run from the terminal: GVT as a gauge filling up to
+// the end time, a table of every machines LVT and rollback counters, and a log of the
+// latest committed events scrolling by at the bottom.
+//
+// Like the live feed it reads the metrics snapshots (give metrics() to
+// Simulation::set_metrics) and the committed events (add sink() to the simulation), and
+// draws from a thread of its own so the run doesnt wait for the terminal. It draws on the
+// normal screen and not the alternate one, so what it showed last stays there after.
+pub struct Dashboard {
+    metrics: MetricsHandle,
+    events: LiveSink,
+    end_time: Option<VirtualTime>,
+    log: VecDeque<EgressRecord>,
+}
+
+impl Dashboard {
+    // The end time is what the GVT gauge fills up to, without one it only shows the GVT
+    pub fn new(end_time: Option<VirtualTime>) -> Self {
+        Self::with_metrics(MetricsHandle::new(), end_time)
+    }
+
+    // Reads the snapshots from a handle the simulation already publishes to
+    pub fn with_metrics(metrics: MetricsHandle, end_time: Option<VirtualTime>) -> Self {
+        Self {
+            metrics,
+            events: LiveSink::default(),
+            end_time,
+            log: VecDeque::new(),
+        }
+    }
+
+    pub fn metrics(&self) -> MetricsHandle {
+        self.metrics.clone()
+    }
+
+    pub fn sink(&self) -> LiveSink {
+        self.events.clone()
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        for event in self.events.take() {
+            if self.log.len() >= LOG_LINES {
+                self.log.pop_front();
+            }
+            self.log.push_back(event);
+        }
+        draw(frame, &self.metrics.latest(), self.end_time, &self.log);
+    }
+
+    // The dashboard as it would be drawn now on a terminal of that size, as text
+    pub fn render(&mut self, width: u16, height: u16) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(width, height)).unwrap();
+        terminal.draw(|frame| self.draw(frame)).unwrap();
+        let buffer = terminal.backend().buffer();
+        let cells = buffer.content.chunks(width.max(1) as usize);
+        let lines = cells.map(|row| row.iter().map(|cell| cell.symbol()).collect::<String>());
+        lines.map(|line| line.trim_end().to_string() + "\n").collect()
+    }
+
+    // Redraws to out, a terminal, every refresh until the handle is stopped or dropped
+    pub fn spawn(mut self, refresh: Duration, out: impl Write + Send + 'static) -> Redraw {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            let mut terminal = Terminal::new(CrosstermBackend::new(out))?;
+            terminal.clear()?;
+            loop {
+                let done = stopped.load(Ordering::Relaxed);
+                terminal.draw(|frame| self.draw(frame))?;
+                if done {
+                    // Whatever gets printed next goes below the dashboard
+                    let size = terminal.size()?;
+                    terminal.set_cursor_position((0, size.height.saturating_sub(1)))?;
+                    terminal.show_cursor()?;
+                    return writeln!(terminal.backend_mut());
+                }
+                thread::sleep(refresh);
+            }
+        });
+        Redraw {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+// The thread drawing a dashboard, it draws one last time when stopped so the final
+// numbers stay on the screen
+pub struct Redraw {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl Redraw {
+    pub fn stop(mut self) -> io::Result<()> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(io::Error::other("the dashboard thread panicked")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Redraw {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+fn draw(
+    frame: &mut Frame,
+    snapshot: &MetricsSnapshot,
+    end_time: Option<VirtualTime>,
+    log: &VecDeque<EgressRecord>,
+) {
+    let log_height = LOG_LINES as u16 + 2;
+    let [top, middle, bottom] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Min(3),
+        Constraint::Length(log_height),
+    ])
+    .areas(frame.area());
+
+    let gvt = snapshot.gvt.map_or("done".to_string(), |gvt| gvt.to_string());
+    let block = Block::bordered().title(" vtw ");
+    match end_time {
+        Some(end_time) => {
+            // No GVT means nothing is left to do, as far as the gauge goes that is the end
+            let at = snapshot.gvt.unwrap_or(end_time).min(end_time).ticks();
+            let share = match end_time.ticks() {
+                0 => 1.0,
+                end => at as f64 / end as f64,
+            };
+            let label = format!("GVT {} / {}  {:.0}%", gvt, end_time, share * 100.0);
+            frame.render_widget(Gauge::default().block(block).ratio(share).label(label), top);
+        }
+        None => frame.render_widget(Paragraph::new(format!("GVT {}", gvt)).block(block), top),
+    }
+
+    let header = ["machine", "lvt", "committed", "undone", "rollbacks", "input", "output"];
+    let rows = snapshot.machines.iter().map(|(machine_id, metrics)| {
+        Row::new([
+            machine_id.to_string(),
+            metrics.local_virtual_time.to_string(),
+            metrics.stats.events_committed.to_string(),
+            metrics.stats.events_rolled_back.to_string(),
+            metrics.stats.rollbacks.to_string(),
+            metrics.input_queue.to_string(),
+            metrics.output_queue.to_string(),
+        ])
+    });
+    let widths = [Constraint::Length(10); 7];
+    let table = Table::new(rows, widths)
+        .header(Row::new(header))
+        .block(Block::bordered().title(" machines "));
+    frame.render_widget(table, middle);
+
+    let events = log.iter().map(|event| {
+        let mut payload: String = event.payload.chars().take(PAYLOAD_WIDTH).collect();
+        if payload.len() < event.payload.len() {
+            payload.push_str("..");
+        }
+        ListItem::new(format!(
+            "{:>10}  {:>4} -> {:<4} {}",
+            event.at, event.from, event.machine, payload
+        ))
+    });
+    let events = List::new(events).block(Block::bordered().title(" committed events "));
+    frame.render_widget(events, bottom);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use crate::time::message::{Message, Sign};
+
+    // The words on a line, without the borders
+    fn words(line: &str) -> Vec<&str> {
+        let words = line.split(|c: char| c.is_whitespace() || c == '│');
+        words.filter(|word| !word.is_empty()).collect()
+    }
+
+    #[test]
+    fn test_dashboard_shows_progress_and_the_latest_events() {
+        let mut dashboard = Dashboard::new(Some(VirtualTime::new(20)));
+        let mut simulation = ring(2);
+        simulation.set_metrics(dashboard.metrics());
+        simulation.add_sink(Box::new(dashboard.sink()));
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("20".to_string())));
+        simulation.run_until(10);
+
+        let screen = dashboard.render(80, 24);
+        let lines: Vec<_> = screen.lines().collect();
+        assert!(lines[0].starts_with("┌ vtw "));
+        assert!(lines[1].contains("GVT 13 / 20  65%"));
+        let header = ["machine", "lvt", "committed", "undone", "rollbacks", "input", "output"];
+        assert_eq!(words(lines[4]), header);
+        assert_eq!(words(lines[5]), ["0", "7", "2", "0", "0", "3", "0"]);
+
+        // Only the latest LOG_LINES are kept, and all of them fit
+        simulation.run();
+        let screen = dashboard.render(80, 24);
+        let log: Vec<_> = screen
+            .lines()
+            .skip_while(|line| !line.contains("committed events"))
+            .skip(1)
+            .filter_map(|line| line.strip_prefix("│"))
+            .collect();
+        assert_eq!(log.len(), LOG_LINES);
+        assert_eq!(words(log[LOG_LINES - 1]), ["61", "1", "->", "0", "0"]);
+        assert!(screen.contains("GVT done / 20  100%"));
+    }
+}
@@ -0,0 +1,344 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Debug};
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+use crate::config::{ConfigError, SimulationConfig};
+use crate::latency::mix;
+use crate::time::message::{Delay, MachineId, VirtualTime};
+
+// For catching process code that isnt deterministic (iterating a HashMap, reading the
+// clock, thread_rng, ...), which Time Warp silently turns into wrong results since a
+// rolled back event doesnt come out the same when it runs again. Every committed state is
+// hashed into the digest of the epoch of virtual time it falls in, two runs of the same
+// model should come out with the same digests and the first epoch where they dont is
+// where to start looking. An epoch length of 1 gives the exact time.
+//
+// States are hashed through their Debug output since that is all TimeWarpProcess asks
+// of them, which also means a state holding a HashMap shows up as a divergence even if
+// the process is fine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateDigests {
+    epoch_length: Delay,
+    epochs: BTreeMap<VirtualTime, u64>,
+    // Every machine over the whole run, events and their digest
+    machines: BTreeMap<MachineId, (u64, u64)>,
+}
+
+// The whole run in one number, for regression tests that want to know a model still
+// does exactly what it did when the test was written without keeping a trace of it
+// around. Written out it is "<events> events <digest in hex>", which is what to paste
+// into the test:
+//
+//   simulation.record_digests(1);
+//   simulation.run();
+//   assert_eq!(simulation.run_digest().unwrap().to_string(), "24 events 3f09c1e2a4b0d587");
+//
+// It doesnt depend on the epoch length or on how the executor batched the commits, so
+// the same model gives the same digest optimistic, conservative or sequential. Only the
+// total is in the string, the digests of the machines are there to find out which one
+// changed once it doesnt match anymore.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunDigest {
+    pub events: u64,
+    pub digest: u64,
+    #[serde(default)]
+    pub machines: BTreeMap<MachineId, u64>,
+}
+
+impl RunDigest {
+    // The machines whose digests arent the same in both, ones only one of them has
+    // included
+    pub fn differing_machines(&self, other: &RunDigest) -> Vec<MachineId> {
+        let ids: BTreeSet<_> = self.machines.keys().chain(other.machines.keys()).collect();
+        ids.into_iter()
+            .filter(|id| self.machines.get(id) != other.machines.get(id))
+            .copied()
+            .collect()
+    }
+}
+
+// Only the totals, two digests that print the same are the same run
+impl fmt::Display for RunDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} events {:016x}", self.events, self.digest)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseDigestError {
+    Format,
+    Number(ParseIntError),
+}
+
+impl fmt::Display for ParseDigestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseDigestError::Format => write!(f, "expected \"<events> events <hex digest>\""),
+            ParseDigestError::Number(error) => write!(f, "invalid number: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for ParseDigestError {}
+
+impl From<ParseIntError> for ParseDigestError {
+    fn from(error: ParseIntError) -> Self {
+        ParseDigestError::Number(error)
+    }
+}
+
+// Without the machines, which the string doesnt have
+impl FromStr for RunDigest {
+    type Err = ParseDigestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<_> = s.split_whitespace().collect();
+        let [events, "events", digest] = words.as_slice() else {
+            return Err(ParseDigestError::Format);
+        };
+        Ok(Self {
+            events: events.parse()?,
+            digest: u64::from_str_radix(digest, 16)?,
+            machines: BTreeMap::new(),
+        })
+    }
+}
+
+// Where two runs came apart, None for an epoch one of the runs has nothing committed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    pub epoch_start: VirtualTime,
+    pub first: Option<u64>,
+    pub second: Option<u64>,
+}
+
+impl StateDigests {
+    pub fn new(epoch_length: impl Into<Delay>) -> Self {
+        Self {
+            epoch_length: epoch_length.into().max(Delay::new(1)),
+            epochs: BTreeMap::new(),
+            machines: BTreeMap::new(),
+        }
+    }
+
+    pub fn epoch_length(&self) -> Delay {
+        self.epoch_length
+    }
+
+    // Start of every epoch with something committed in it, along with its digest
+    pub fn epochs(&self) -> &BTreeMap<VirtualTime, u64> {
+        &self.epochs
+    }
+
+    // The state of the machine right after its event at rec_time. Executors commit
+    // events in different batches, so the digest of an epoch doesnt depend on the order
+    // its events were recorded in.
+    pub fn record<S: Debug>(&mut self, machine_id: MachineId, rec_time: VirtualTime, state: &S) {
+        let state = state_hash(state);
+        let event = mix(mix(machine_id as u64 ^ mix(rec_time.ticks())) ^ state);
+        let epoch = rec_time.align_down(self.epoch_length);
+        let digest = self.epochs.entry(epoch).or_insert(0);
+        *digest = digest.wrapping_add(event);
+        let (events, digest) = self.machines.entry(machine_id).or_insert((0, 0));
+        *events += 1;
+        *digest = digest.wrapping_add(event);
+    }
+
+    // Events recorded so far and the state hash of every machine over all of them
+    pub fn machine_digest(&self, machine_id: MachineId) -> Option<(u64, u64)> {
+        self.machines.get(&machine_id).copied()
+    }
+
+    pub fn run_digest(&self) -> RunDigest {
+        let events = self.machines.values().map(|(events, _)| events).sum();
+        let total = self
+            .machines
+            .values()
+            .fold(0u64, |total, (_, digest)| total.wrapping_add(*digest));
+        RunDigest {
+            events,
+            digest: mix(total ^ mix(events)),
+            machines: self.machines.iter().map(|(id, (_, digest))| (*id, *digest)).collect(),
+        }
+    }
+
+    pub fn first_divergence(&self, other: &StateDigests) -> Option<Divergence> {
+        let starts: BTreeSet<_> = self.epochs.keys().chain(other.epochs.keys()).collect();
+        starts.into_iter().find_map(|start| {
+            let first = self.epochs.get(start).copied();
+            let second = other.epochs.get(start).copied();
+            (first != second).then_some(Divergence {
+                epoch_start: *start,
+                first,
+                second,
+            })
+        })
+    }
+}
+
+// States are hashed through their Debug output, see above
+pub(crate) fn state_hash<S: Debug>(state: &S) -> u64 {
+    fnv(format!("{:?}", state).as_bytes())
+}
+
+fn fnv(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// What the config is checked against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reference {
+    // A second optimistic run
+    Optimistic,
+    Conservative,
+    // The ground truth, see runtime/sequential.rs
+    Sequential,
+}
+
+// Runs the config optimistically and then again with the reference executor and compares
+// the committed states, None when they agree everywhere
+pub fn check_determinism(
+    config: &SimulationConfig,
+    end_time: Option<VirtualTime>,
+    reference: Reference,
+    epoch_length: Delay,
+) -> Result<Option<Divergence>, ConfigError> {
+    let mut first = config.build()?;
+    first.record_digests(epoch_length);
+    config.run(&mut first, end_time);
+
+    let second = match reference {
+        Reference::Optimistic => {
+            let mut second = config.build()?;
+            second.record_digests(epoch_length);
+            config.run(&mut second, end_time);
+            second.take_digests()
+        }
+        Reference::Conservative => {
+            let mut second = config.build_conservative()?;
+            second.record_digests(epoch_length);
+            match end_time.or(config.end_time) {
+                Some(end_time) => second.run_until(end_time),
+                None => second.run(),
+            }
+            second.take_digests()
+        }
+        Reference::Sequential => {
+            let mut second = config.build_sequential()?;
+            second.record_digests(epoch_length);
+            match end_time.or(config.end_time) {
+                Some(end_time) => second.run_until(end_time),
+                None => second.run(),
+            }
+            second.take_digests()
+        }
+    };
+    let (Some(first), Some(second)) = (first.take_digests(), second) else {
+        return Ok(None);
+    };
+    Ok(first.first_divergence(&second))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Format;
+
+    #[test]
+    fn test_runs_agree_and_divergence_is_found() {
+        let config = SimulationConfig::parse(
+            r#"
+            seed = 4
+            [[machines]]
+            id = 0
+            [[machines]]
+            id = 1
+            [[machines]]
+            id = 2
+            [[links]]
+            from = 0
+            to = 1
+            delay = 2
+            [[links]]
+            from = 1
+            to = 2
+            delay = 3
+            [[links]]
+            from = 2
+            to = 0
+            delay = 1
+            [[links]]
+            from = 1
+            to = 0
+            delay = 4
+            [[initial]]
+            to = 0
+            at = 1
+            payload = "20"
+            [[initial]]
+            to = 2
+            at = 2
+            payload = "15"
+            "#,
+            Format::Toml,
+        )
+        .unwrap();
+        for reference in [Reference::Optimistic, Reference::Conservative, Reference::Sequential] {
+            assert_eq!(check_determinism(&config, None, reference, Delay::new(5)).unwrap(), None);
+        }
+
+        let mut first = StateDigests::new(10);
+        let mut second = StateDigests::new(10);
+        for (rec_time, state) in [(3, 1), (12, 2), (25, 3)] {
+            let rec_time = VirtualTime::new(rec_time);
+            first.record(0, rec_time, &state);
+            second.record(0, rec_time, &if rec_time == 25 { 4 } else { state });
+        }
+        // Order within an epoch doesnt matter
+        second.record(1, VirtualTime::new(14), &7);
+        first.record(1, VirtualTime::new(14), &7);
+        let divergence = first.first_divergence(&second).unwrap();
+        assert_eq!(divergence.epoch_start, 20);
+        assert_ne!(divergence.first, divergence.second);
+    }
+
+    #[test]
+    fn test_run_digests_only_depend_on_what_was_committed() {
+        let mut config = SimulationConfig::parse(
+            r#"
+            machines = [{ id = 0 }, { id = 1 }]
+            links = [{ from = 0, to = 1, delay = 2 }, { from = 1, to = 0, delay = 3 }]
+            initial = [{ to = 0, at = 1, payload = "12" }]
+            "#,
+            Format::Toml,
+        )
+        .unwrap();
+        let mut optimistic = config.build().unwrap();
+        optimistic.record_digests(1);
+        config.run(&mut optimistic, None);
+        let mut sequential = config.build_sequential().unwrap();
+        sequential.record_digests(7);
+        sequential.run();
+        let digest = optimistic.run_digest().unwrap();
+        assert_eq!(Some(&digest), sequential.run_digest().as_ref());
+        assert_eq!(digest.events, 13);
+
+        let printed: RunDigest = digest.to_string().parse().unwrap();
+        assert_eq!((printed.events, printed.digest), (digest.events, digest.digest));
+        assert!(printed.machines.is_empty());
+        assert_eq!("13 events".parse::<RunDigest>(), Err(ParseDigestError::Format));
+
+        config.initial[0].payload = "11".to_string();
+        let mut changed = config.build().unwrap();
+        changed.record_digests(1);
+        config.run(&mut changed, None);
+        let changed = changed.run_digest().unwrap();
+        assert_ne!(changed.to_string(), digest.to_string());
+        // One hop less is one event less on machine 0, machine 1 sees the same ones
+        assert_eq!(changed.differing_machines(&digest), [0]);
+    }
+}
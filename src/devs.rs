@@ -0,0 +1,255 @@
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::process::{Context, TimeWarpProcess};
+use crate::time::message::{Delay, MachineId, Message, MessagePayload, Sign, VirtualTime};
+
+// Runs classic DEVS atomic models as Time Warp machines. An atomic model has a time
+// advance (how long until its next internal event, None for never), an output function
+// called right before an internal event, and internal and external transitions. The
+// adapter keeps the models state together with the time of its last event as the
+// machines state, so rolling back the machine rolls back the model too.
+//
+// Internal events are messages a machine sends to itself on the WAKEUP port. When an
+// external event changes the time advance the wakeup already sent isnt taken back, it
+// is ignored when it shows up at a time that no longer matches the schedule. Outputs
+// are sent along the couplings of the output port they come out of, each coupling has
+// its own delay which doubles as the lookahead.
+//
+// An external event at the same time as an internal one runs before or after it in the
+// order the input queue has them (see InputQueue), DEVS would call that a confluent
+// transition and this adapter doesnt have one. A time advance of 0 wakes the model up
+// again right after the event at the same time.
+pub trait AtomicModel: Send + 'static {
+    type State: Clone + Debug + Default + Send + Sync + 'static;
+
+    fn time_advance(&self, state: &Self::State) -> Option<Delay>;
+
+    // The (port, value) pairs sent out before the internal transition
+    fn output(&self, state: &Self::State) -> Vec<(String, MessagePayload)>;
+
+    fn internal_transition(&self, state: &mut Self::State);
+
+    // elapsed is the time since the last event of the model
+    fn external_transition(
+        &self,
+        state: &mut Self::State,
+        elapsed: Delay,
+        port: Option<&str>,
+        value: &str,
+    );
+}
+
+pub const WAKEUP: &str = "devs:wakeup";
+
+// Connects an output port of this model to an input port of another machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coupling {
+    pub from_port: String,
+    pub to: MachineId,
+    pub to_port: String,
+    pub delay: Delay,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DevsState<S> {
+    pub model: S,
+    pub last_event: VirtualTime,
+    // Times of the wakeups sent and not received yet, so the same one isnt sent twice
+    wakeups: BTreeSet<VirtualTime>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DevsProcess<M> {
+    model: M,
+    couplings: Vec<Coupling>,
+}
+
+impl<M: AtomicModel> DevsProcess<M> {
+    pub fn new(model: M) -> Self {
+        Self {
+            model,
+            couplings: Vec::new(),
+        }
+    }
+
+    pub fn couple(
+        mut self,
+        from_port: impl Into<String>,
+        to: MachineId,
+        to_port: impl Into<String>,
+        delay: impl Into<Delay>,
+    ) -> Self {
+        self.couplings.push(Coupling {
+            from_port: from_port.into(),
+            to,
+            to_port: to_port.into(),
+            delay: delay.into(),
+        });
+        self
+    }
+
+    pub fn model(&self) -> &M {
+        &self.model
+    }
+
+    // The starting machine state for a model and the wakeup for its first internal
+    // event, if it has one, which has to be put in the simulation along with the machine
+    pub fn start(
+        &self,
+        machine_id: MachineId,
+        model: M::State,
+    ) -> (DevsState<M::State>, Option<Message>) {
+        let mut state = DevsState {
+            model,
+            last_event: VirtualTime::ZERO,
+            wakeups: BTreeSet::new(),
+        };
+        let wakeup = self.time_advance(&state.model).map(|delay| {
+            let at = VirtualTime::ZERO + delay;
+            state.wakeups.insert(at);
+            let payload = Arc::new(String::new());
+            Message::new(0, at, machine_id, machine_id, Sign::Message, payload).with_port(WAKEUP)
+        });
+        (state, wakeup)
+    }
+
+    fn time_advance(&self, model: &M::State) -> Option<Delay> {
+        self.model.time_advance(model)
+    }
+}
+
+impl<M: AtomicModel> TimeWarpProcess for DevsProcess<M> {
+    type State = DevsState<M::State>;
+
+    fn on_message(&self, state: &mut Self::State, message: &Message, ctx: &mut Context) {
+        let now = ctx.now();
+        if message.port() == Some(WAKEUP) {
+            state.wakeups.remove(&now);
+            let scheduled = self.time_advance(&state.model).map(|delay| state.last_event + delay);
+            if scheduled != Some(now) {
+                return;
+            }
+            for (port, value) in self.model.output(&state.model) {
+                let couplings = self.couplings.iter().filter(|coupling| coupling.from_port == port);
+                for coupling in couplings {
+                    ctx.send_to_port(coupling.to, &coupling.to_port, coupling.delay, value.clone());
+                }
+            }
+            self.model.internal_transition(&mut state.model);
+        } else {
+            let elapsed = now.saturating_since(state.last_event);
+            self.model
+                .external_transition(&mut state.model, elapsed, message.port(), &message.message);
+        }
+        state.last_event = now;
+        if let Some(delay) = self.time_advance(&state.model) {
+            if state.wakeups.insert(now + delay) {
+                ctx.send_to_port(ctx.machine_id(), WAKEUP, delay, String::new());
+            }
+        }
+    }
+
+    fn lookahead(&self) -> Delay {
+        self.couplings
+            .iter()
+            .map(|coupling| coupling.delay)
+            .min()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::runtime::Simulation;
+
+    // A generator sending a job every 5 units to a processor that takes 3 per job
+    enum Shop {
+        Generator { jobs: usize },
+        Processor,
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct ShopState {
+        generated: usize,
+        busy_for: Option<Delay>,
+        queued: usize,
+        done: Vec<usize>,
+    }
+
+    impl AtomicModel for Shop {
+        type State = ShopState;
+
+        fn time_advance(&self, state: &ShopState) -> Option<Delay> {
+            match self {
+                Shop::Generator { jobs } => (state.generated < *jobs).then_some(Delay::new(5)),
+                Shop::Processor => state.busy_for,
+            }
+        }
+
+        fn output(&self, state: &ShopState) -> Vec<(String, MessagePayload)> {
+            match self {
+                Shop::Generator { .. } => vec![("out".to_string(), state.generated.to_string())],
+                Shop::Processor => Vec::new(),
+            }
+        }
+
+        fn internal_transition(&self, state: &mut ShopState) {
+            match self {
+                Shop::Generator { .. } => state.generated += 1,
+                Shop::Processor => {
+                    state.done.push(state.done.len());
+                    state.busy_for = None;
+                    if state.queued > 0 {
+                        state.queued -= 1;
+                        state.busy_for = Some(Delay::new(3));
+                    }
+                }
+            }
+        }
+
+        fn external_transition(
+            &self,
+            state: &mut ShopState,
+            elapsed: Delay,
+            port: Option<&str>,
+            _value: &str,
+        ) {
+            assert_eq!(port, Some("in"));
+            match state.busy_for {
+                Some(left) => {
+                    state.busy_for = Some(left.saturating_sub(elapsed));
+                    state.queued += 1;
+                }
+                None => state.busy_for = Some(Delay::new(3)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_atomic_models_run_as_machines() {
+        let mut simulation = Simulation::new();
+        let generator = DevsProcess::new(Shop::Generator { jobs: 3 }).couple("out", 1, "in", 1);
+        let processor = DevsProcess::new(Shop::Processor);
+        for (machine_id, process) in [(0, generator), (1, processor)] {
+            let (state, wakeup) = process.start(machine_id, ShopState::default());
+            simulation.add_machine(Machine::with_state(machine_id, 0, process, state));
+            if let Some(wakeup) = wakeup {
+                simulation.inject(wakeup);
+            }
+        }
+        simulation.run();
+
+        let generator = simulation.machine(0).unwrap();
+        assert_eq!(generator.state.model.generated, 3);
+        assert_eq!(generator.lookahead(), 1);
+        // Jobs arrive at 6, 11 and 16 and take until 9, 14 and 19
+        let processor = simulation.machine(1).unwrap();
+        assert_eq!(processor.state.model.done, vec![0, 1, 2]);
+        assert_eq!(processor.state.last_event, 19);
+        assert_eq!(processor.stats().events_processed, 6);
+    }
+}
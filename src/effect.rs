@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::time::message::VirtualTime;
+
+// Side effects on the world outside the simulation (a write to a database, an order sent
+// to an exchange, a light turned on) that cant wait for their event to commit. The action
+// runs right away while the event executes, and if the event gets rolled back the
+// compensation runs to undo it. The compensations of a rollback run latest event first,
+// and within an event in the reverse order the effects were made. Once the event commits
+// its compensations are dropped without running.
+//
+// Effects that can wait until they are certain are better off in a sink (see sink.rs),
+// those only ever see committed events and dont need anything to undo them.
+pub struct SideEffect {
+    action: Box<dyn FnOnce() + Send>,
+    compensation: Compensation,
+}
+
+impl SideEffect {
+    pub fn new(
+        action: impl FnOnce() + Send + 'static,
+        compensation: impl FnOnce() + Send + 'static,
+    ) -> Self {
+        Self {
+            action: Box::new(action),
+            compensation: Compensation(Box::new(compensation)),
+        }
+    }
+
+    // Runs the action, what is left is what undoes it
+    pub(crate) fn perform(self) -> Compensation {
+        (self.action)();
+        self.compensation
+    }
+}
+
+impl fmt::Debug for SideEffect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SideEffect")
+    }
+}
+
+pub(crate) struct Compensation(Box<dyn FnOnce() + Send>);
+
+impl fmt::Debug for Compensation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Compensation")
+    }
+}
+
+// The compensations of the events a machine processed that havent committed yet, by the
+// time of the event
+#[derive(Debug, Default)]
+pub(crate) struct Compensations {
+    pending: BTreeMap<VirtualTime, Vec<Compensation>>,
+}
+
+impl Compensations {
+    pub(crate) fn add(&mut self, rec_time: VirtualTime, compensations: Vec<Compensation>) {
+        if !compensations.is_empty() {
+            self.pending.entry(rec_time).or_default().extend(compensations);
+        }
+    }
+
+    // Undoes the effects of every event at or after from, returns how many ran
+    pub(crate) fn roll_back(&mut self, from: VirtualTime) -> usize {
+        let undone = self.pending.split_off(&from);
+        let mut ran = 0;
+        for (_, compensations) in undone.into_iter().rev() {
+            for compensation in compensations.into_iter().rev() {
+                (compensation.0)();
+                ran += 1;
+            }
+        }
+        ran
+    }
+
+    // Events before the horizon are committed, their effects stay
+    pub(crate) fn commit(&mut self, horizon: VirtualTime) {
+        self.pending = self.pending.split_off(&horizon);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::process::{Context, TimeWarpProcess};
+    use crate::time::message::{Message, Sign};
+    use std::sync::{Arc, Mutex};
+
+    // Tells the outside world about every event it runs
+    struct Announcer {
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl TimeWarpProcess for Announcer {
+        type State = ();
+
+        fn on_message(&self, _state: &mut (), message: &Message, ctx: &mut Context) {
+            let (log, undo_log) = (self.log.clone(), self.log.clone());
+            let rec_time = message.rec_time;
+            ctx.side_effect(SideEffect::new(
+                move || log.lock().unwrap().push(format!("did {rec_time}")),
+                move || undo_log.lock().unwrap().push(format!("undid {rec_time}")),
+            ));
+        }
+    }
+
+    fn message_at(rec_time: usize) -> Message {
+        Message::new(0, rec_time, 0, 1, Sign::Message, Arc::new(String::new()))
+    }
+
+    #[test]
+    fn test_rolled_back_effects_are_compensated() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let process = Announcer { log: log.clone() };
+        let mut machine = Machine::with_process(1, 0, process);
+        for rec_time in [2, 6, 8] {
+            machine.recieve_outer(message_at(rec_time));
+            machine.recieve_inner();
+        }
+
+        // The straggler undoes 8 and then 6, and they run again after it
+        machine.recieve_outer(message_at(4));
+        while !machine.is_blocked() {
+            machine.recieve_inner();
+        }
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["did 2", "did 6", "did 8", "undid 8", "undid 6", "did 4", "did 6", "did 8"]
+        );
+        assert_eq!(machine.stats().compensations, 2);
+
+        // Committed effects are never undone, a crash only takes back the rest
+        machine.commit(Some(VirtualTime::new(7)));
+        machine.crash();
+        assert_eq!(log.lock().unwrap().last().unwrap(), "undid 8");
+        assert_eq!(machine.stats().compensations, 3);
+    }
+}
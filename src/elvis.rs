@@ -0,0 +1,221 @@
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Write};
+
+use crate::process::{Context, TimeWarpProcess};
+use crate::time::message::{Delay, MachineId, Message, VirtualTime};
+
+// Glue for running ELVIS style protocol stacks on virtual time. In ELVIS a machine is a
+// stack of protocols with sessions that hand packets to each other and to the network,
+// here every protocol machine becomes one Time Warp machine: its sessions are the state
+// (so a rollback restores the sessions as they were), packets between machines are
+// messages and timers are messages a machine sends itself.
+//
+// The ELVIS crates arent a dependency of this one, so the adapter works against the
+// Protocol trait below which has the same shape as an ELVIS protocol (something that
+// gets packets and timer callbacks and sends packets to other machines). Wrapping an
+// ELVIS protocol is a matter of implementing Protocol for it with its session type as
+// the Session, which has to be cloneable since that is how it gets saved.
+//
+// Packets travel as the binary payload (see Message::data) on the PACKET port, one put
+// in from outside as text can be hex encoded instead. Timers on the TIMER port carry the
+// token they were set with. Anything else arriving at the
+// machine (like a message put in from outside) goes to Protocol::application.
+pub trait Protocol: Send + 'static {
+    type Session: Clone + Debug + Default + Send + Sync + 'static;
+
+    fn receive(
+        &self,
+        session: &mut Self::Session,
+        from: MachineId,
+        packet: &[u8],
+        net: &mut Network,
+    );
+
+    fn timer(&self, _session: &mut Self::Session, _token: u64, _net: &mut Network) {}
+
+    fn application(&self, _session: &mut Self::Session, _payload: &str, _net: &mut Network) {}
+}
+
+pub const PACKET: &str = "elvis:packet";
+pub const TIMER: &str = "elvis:timer";
+
+// What a protocol gets to talk to the rest of the simulation with
+pub struct Network<'a> {
+    ctx: &'a mut Context,
+    links: &'a BTreeMap<MachineId, Delay>,
+}
+
+impl Network<'_> {
+    pub fn now(&self) -> VirtualTime {
+        self.ctx.now()
+    }
+
+    pub fn machine_id(&self) -> MachineId {
+        self.ctx.machine_id()
+    }
+
+    // Sends a packet over the link to a neighbour, taking the links latency model if it
+    // has one. Returns false if there is no link to it.
+    pub fn send(&mut self, to: MachineId, packet: &[u8]) -> bool {
+        let Some(delay) = self.links.get(&to).copied() else {
+            return false;
+        };
+        let delay = self.ctx.link_delay(to).unwrap_or(delay);
+        self.ctx.send_bytes_to_port(to, PACKET, delay, Bytes::copy_from_slice(packet));
+        true
+    }
+
+    // Calls Protocol::timer with the token after the given time
+    pub fn set_timer(&mut self, after: impl Into<Delay>, token: u64) {
+        let machine_id = self.ctx.machine_id();
+        let after = after.into().max(Delay::new(1));
+        self.ctx.send_to_port(machine_id, TIMER, after, token.to_string());
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProtocolProcess<P> {
+    protocol: P,
+    // Neighbours and the smallest delay of the link to them
+    links: BTreeMap<MachineId, Delay>,
+}
+
+impl<P: Protocol> ProtocolProcess<P> {
+    pub fn new(protocol: P) -> Self {
+        Self {
+            protocol,
+            links: BTreeMap::new(),
+        }
+    }
+
+    pub fn link(mut self, to: MachineId, delay: impl Into<Delay>) -> Self {
+        self.links.insert(to, delay.into());
+        self
+    }
+
+    pub fn protocol(&self) -> &P {
+        &self.protocol
+    }
+}
+
+impl<P: Protocol> TimeWarpProcess for ProtocolProcess<P> {
+    type State = P::Session;
+
+    fn on_message(&self, session: &mut P::Session, message: &Message, ctx: &mut Context) {
+        let mut net = Network {
+            ctx,
+            links: &self.links,
+        };
+        match message.port() {
+            Some(PACKET) => match (&message.data, from_hex(&message.message)) {
+                (Some(packet), _) => {
+                    self.protocol.receive(session, message.sender, packet, &mut net)
+                }
+                (None, Some(packet)) => {
+                    self.protocol.receive(session, message.sender, &packet, &mut net)
+                }
+                (None, None) => panic!("packet from {} isnt hex encoded", message.sender),
+            },
+            Some(TIMER) => {
+                let token = message.message.parse().expect("timer token isnt a number");
+                self.protocol.timer(session, token, &mut net);
+            }
+            _ => self.protocol.application(session, &message.message, &mut net),
+        }
+    }
+
+    fn lookahead(&self) -> Delay {
+        // Timers count too, they are at least 1 out
+        self.links.values().copied().min().unwrap_or(Delay::new(1)).min(Delay::new(1))
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|start| u8::from_str_radix(hex.get(start..start + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::runtime::Simulation;
+    use crate::time::message::Sign;
+    use std::sync::Arc;
+
+    // Bounces a counter back and forth until it runs out, with a timer that gives up
+    // waiting for the other side after a while
+    struct Bounce;
+
+    #[derive(Debug, Clone, Default)]
+    struct BounceSession {
+        received: Vec<u8>,
+        gave_up: u32,
+    }
+
+    impl Protocol for Bounce {
+        type Session = BounceSession;
+
+        fn receive(
+            &self,
+            session: &mut BounceSession,
+            from: MachineId,
+            packet: &[u8],
+            net: &mut Network,
+        ) {
+            session.received.push(packet[0]);
+            if packet[0] > 0 {
+                net.send(from, &[packet[0] - 1]);
+                net.set_timer(50, packet[0] as u64);
+            }
+        }
+
+        fn timer(&self, session: &mut BounceSession, token: u64, _net: &mut Network) {
+            // Still waiting on the answer to what was sent with this token
+            if session.received.last().is_some_and(|last| *last as u64 >= token) {
+                session.gave_up += 1;
+            }
+        }
+
+        fn application(&self, _session: &mut BounceSession, payload: &str, net: &mut Network) {
+            let to = if net.machine_id() == 0 { 1 } else { 0 };
+            assert!(net.send(to, &[payload.parse().unwrap()]));
+            assert!(!net.send(7, &[0]));
+        }
+    }
+
+    #[test]
+    fn test_protocol_sessions_exchange_packets() {
+        assert_eq!(from_hex(&to_hex(&[0, 15, 255])), Some(vec![0, 15, 255]));
+        assert_eq!(from_hex("abc"), None);
+
+        let mut simulation = Simulation::new();
+        for (machine_id, to, delay) in [(0, 1, 2), (1, 0, 3)] {
+            let process = ProtocolProcess::new(Bounce).link(to, delay);
+            simulation.add_machine(Machine::with_process(machine_id, 0, process));
+        }
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("4".to_string())));
+        simulation.run();
+
+        let session = |machine_id| simulation.machine(machine_id).unwrap().state.clone();
+        assert_eq!(session(1).received, vec![4, 2, 0]);
+        assert_eq!(session(0).received, vec![3, 1]);
+        // Every answer came back long before its timer, only the last packet has none
+        assert_eq!(session(0).gave_up, 1);
+        assert_eq!(session(1).gave_up, 0);
+    }
+}
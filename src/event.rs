@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::process::Context;
+use crate::time::message::{Delay, MachineId, Message, MessagePayload};
+
+// Typed events on top of the string payloads, for models where a machine gets several
+// kinds of events. Every event type has a tag and is sent as
+//
+//   tag:json
+//
+// so it still goes through the queues, checkpoints and the wire like any other payload
+// and a trace stays readable. A process keeps an EventRegistry with a handler per event
+// type and hands every message to it in on_message, the registry looks at the tag,
+// decodes the rest into the right type and calls its handler.
+pub trait Event: Serialize + DeserializeOwned + 'static {
+    // Has to be unique within a registry and cant contain ':'
+    const TAG: &'static str;
+}
+
+#[derive(Debug)]
+pub enum EventError {
+    // The payload doesnt start with a tag
+    Untagged,
+    UnknownTag(String),
+    Decode {
+        tag: String,
+        error: serde_json::Error,
+    },
+}
+
+impl fmt::Display for EventError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventError::Untagged => write!(f, "payload has no event tag"),
+            EventError::UnknownTag(tag) => write!(f, "no handler for events tagged {}", tag),
+            EventError::Decode { tag, error } => write!(f, "invalid {} event: {}", tag, error),
+        }
+    }
+}
+
+impl std::error::Error for EventError {}
+
+pub fn encode<E: Event>(event: &E) -> MessagePayload {
+    // Serializing plain data to json only fails for maps with non string keys
+    let body = serde_json::to_string(event).expect("event cant be written as json");
+    format!("{}:{}", E::TAG, body)
+}
+
+// The tag of a payload and the encoded event after it
+pub fn split(payload: &str) -> Result<(&str, &str), EventError> {
+    payload.split_once(':').ok_or(EventError::Untagged)
+}
+
+// Decodes a payload as one particular event type, None if it has a different tag
+pub fn decode<E: Event>(payload: &str) -> Option<Result<E, EventError>> {
+    let (tag, body) = split(payload).ok()?;
+    if tag != E::TAG {
+        return None;
+    }
+    Some(serde_json::from_str(body).map_err(|error| EventError::Decode {
+        tag: tag.to_string(),
+        error,
+    }))
+}
+
+impl Context {
+    // Sends a typed event, see event.rs
+    pub fn send_event<E: Event>(
+        &mut self,
+        receiver: MachineId,
+        delay: impl Into<Delay>,
+        event: &E,
+    ) {
+        self.send(receiver, delay, encode(event));
+    }
+}
+
+type Handler<S> =
+    Box<dyn Fn(&mut S, &str, &Message, &mut Context) -> Result<(), EventError> + Send + Sync>;
+
+pub struct EventRegistry<S> {
+    handlers: HashMap<&'static str, Handler<S>>,
+}
+
+impl<S> Default for EventRegistry<S> {
+    fn default() -> Self {
+        Self {
+            handlers: HashMap::new(),
+        }
+    }
+}
+
+impl<S> fmt::Debug for EventRegistry<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut tags: Vec<_> = self.handlers.keys().collect();
+        tags.sort();
+        f.debug_struct("EventRegistry").field("tags", &tags).finish()
+    }
+}
+
+impl<S> EventRegistry<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Registers the handler for one event type, replacing any earlier one for its tag
+    pub fn on<E, F>(mut self, handler: F) -> Self
+    where
+        E: Event,
+        F: Fn(&mut S, E, &Message, &mut Context) + Send + Sync + 'static,
+    {
+        self.handlers.insert(
+            E::TAG,
+            Box::new(move |state, body, message, ctx| {
+                let event = serde_json::from_str(body).map_err(|error| EventError::Decode {
+                    tag: E::TAG.to_string(),
+                    error,
+                })?;
+                handler(state, event, message, ctx);
+                Ok(())
+            }),
+        );
+        self
+    }
+
+    pub fn handles(&self, tag: &str) -> bool {
+        self.handlers.contains_key(tag)
+    }
+
+    // Decodes the message and runs its handler. Nothing is run on an error so the state
+    // is untouched, what to do about it (ignore it, log it, panic) is up to the process.
+    pub fn dispatch(
+        &self,
+        state: &mut S,
+        message: &Message,
+        ctx: &mut Context,
+    ) -> Result<(), EventError> {
+        let (tag, body) = split(&message.message)?;
+        let handler = self
+            .handlers
+            .get(tag)
+            .ok_or_else(|| EventError::UnknownTag(tag.to_string()))?;
+        handler(state, body, message, ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::process::TimeWarpProcess;
+    use crate::time::message::Sign;
+    use serde::Deserialize;
+    use std::sync::Arc;
+
+    #[derive(Serialize, Deserialize)]
+    struct Deposit {
+        amount: i64,
+    }
+
+    impl Event for Deposit {
+        const TAG: &'static str = "deposit";
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Transfer {
+        amount: i64,
+        to: MachineId,
+    }
+
+    impl Event for Transfer {
+        const TAG: &'static str = "transfer";
+    }
+
+    struct Bank {
+        events: EventRegistry<i64>,
+    }
+
+    impl Bank {
+        fn new() -> Self {
+            let events = EventRegistry::new()
+                .on(|balance: &mut i64, deposit: Deposit, _, _| *balance += deposit.amount)
+                .on(|balance: &mut i64, transfer: Transfer, _, ctx: &mut Context| {
+                    *balance -= transfer.amount;
+                    ctx.send_event(transfer.to, 1, &Deposit {
+                        amount: transfer.amount,
+                    });
+                });
+            Self { events }
+        }
+    }
+
+    impl TimeWarpProcess for Bank {
+        type State = i64;
+
+        fn on_message(&self, balance: &mut i64, message: &Message, ctx: &mut Context) {
+            self.events.dispatch(balance, message, ctx).unwrap();
+        }
+    }
+
+    fn event_at(rec_time: usize, payload: MessagePayload) -> Message {
+        Message::new(0, rec_time, 0, 0, Sign::Message, Arc::new(payload))
+    }
+
+    #[test]
+    fn test_registry_dispatches_on_the_tag() {
+        let mut machine = Machine::with_process(0, 0, Bank::new());
+        machine.recieve_outer(event_at(1, encode(&Deposit { amount: 50 })));
+        machine.recieve_outer(event_at(2, encode(&Transfer { amount: 20, to: 3 })));
+        machine.recieve_inner();
+        let sent = machine.recieve_inner();
+        assert_eq!(machine.state, 30);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].receiver, 3);
+        assert_eq!(*sent[0].message, r#"deposit:{"amount":20}"#);
+        assert_eq!(decode::<Deposit>(&sent[0].message).unwrap().unwrap().amount, 20);
+        assert!(decode::<Transfer>(&sent[0].message).is_none());
+
+        let bank = Bank::new();
+        let mut balance = 0;
+        let mut ctx = Context::new(0, 3);
+        for (payload, expected) in [("withdraw:{}", "withdraw"), ("deposit:{}", "deposit")] {
+            let message = event_at(3, payload.to_string());
+            let error = bank.events.dispatch(&mut balance, &message, &mut ctx).unwrap_err();
+            assert!(error.to_string().contains(expected));
+        }
+        let untagged = bank.events.dispatch(&mut balance, &event_at(3, "50".to_string()), &mut ctx);
+        assert!(matches!(untagged, Err(EventError::Untagged)));
+        assert_eq!(balance, 0);
+    }
+}
@@ -0,0 +1,191 @@
+use serde_json::{json, Value};
+use std::io::{self, Write};
+
+use crate::recorder::{Trace, TraceEvent};
+
+// Chrome trace event format (the JSON flavour Perfetto and chrome://tracing load). Every
+// machine gets a track in the "executions" process with a slice per message it executed,
+// and a matching track in the "rollbacks" process so rollbacks dont have to nest inside
+// the executions. The x axis is either virtual time, where one unit shows up as one
+// microsecond, or the wall clock time the events were recorded at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeAxis {
+    Virtual,
+    Wall,
+}
+
+const EXECUTIONS_PID: u64 = 0;
+const ROLLBACKS_PID: u64 = 1;
+
+pub fn to_chrome_json(trace: &Trace, axis: TimeAxis) -> Value {
+    let mut events = vec![
+        process_name(EXECUTIONS_PID, "executions"),
+        process_name(ROLLBACKS_PID, "rollbacks"),
+    ];
+    for machine in trace.machines() {
+        for pid in [EXECUTIONS_PID, ROLLBACKS_PID] {
+            events.push(json!({
+                "ph": "M",
+                "name": "thread_name",
+                "pid": pid,
+                "tid": machine,
+                "args": { "name": trace.label(machine) },
+            }));
+        }
+    }
+
+    for (index, event) in trace.events.iter().enumerate() {
+        match event {
+            TraceEvent::Execute {
+                machine,
+                message_id,
+                sender,
+                send_time,
+                rec_time,
+                wall_start,
+                wall_duration,
+                sent,
+            } => {
+                let (ts, dur) = match axis {
+                    TimeAxis::Virtual => (rec_time.ticks() as f64, 1.0),
+                    TimeAxis::Wall => (nanos_to_micros(*wall_start), nanos_to_micros(*wall_duration)),
+                };
+                events.push(json!({
+                    "ph": "X",
+                    "name": format!("message {}", message_id),
+                    "cat": "execute",
+                    "pid": EXECUTIONS_PID,
+                    "tid": machine,
+                    "ts": ts,
+                    "dur": dur,
+                    "args": {
+                        "sender": sender,
+                        "send_time": send_time,
+                        "rec_time": rec_time,
+                        "sent": sent.iter().map(|message| message.id).collect::<Vec<_>>(),
+                    },
+                }));
+            }
+            TraceEvent::Rollback {
+                machine,
+                from,
+                to,
+                cause_id,
+                cause_sender,
+                wall_time,
+                parent,
+                ..
+            } => {
+                let args = json!({
+                    "from": from,
+                    "to": to,
+                    "cause": cause_id,
+                    "cause_sender": cause_sender,
+                    "parent": parent,
+                });
+                match axis {
+                    // Rollbacks cover a stretch of virtual time and can overlap each
+                    // other, async slices are allowed to do that
+                    TimeAxis::Virtual => {
+                        for (phase, ts) in [("b", *to), ("e", *from)] {
+                            events.push(json!({
+                                "ph": phase,
+                                "name": "rollback",
+                                "cat": "rollback",
+                                "id": index,
+                                "pid": ROLLBACKS_PID,
+                                "tid": machine,
+                                "ts": ts.ticks() as f64,
+                                "args": args,
+                            }));
+                        }
+                    }
+                    TimeAxis::Wall => {
+                        events.push(json!({
+                            "ph": "i",
+                            "s": "t",
+                            "name": "rollback",
+                            "cat": "rollback",
+                            "pid": ROLLBACKS_PID,
+                            "tid": machine,
+                            "ts": nanos_to_micros(*wall_time),
+                            "args": args,
+                        }));
+                    }
+                }
+            }
+        }
+    }
+
+    json!({ "traceEvents": events, "displayTimeUnit": "ns" })
+}
+
+pub fn write_chrome_trace<W: Write>(trace: &Trace, axis: TimeAxis, writer: W) -> io::Result<()> {
+    serde_json::to_writer(writer, &to_chrome_json(trace, axis)).map_err(io::Error::from)
+}
+
+fn process_name(pid: u64, name: &str) -> Value {
+    json!({
+        "ph": "M",
+        "name": "process_name",
+        "pid": pid,
+        "args": { "name": name },
+    })
+}
+
+fn nanos_to_micros(nanos: u64) -> f64 {
+    nanos as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use crate::time::message::{Message, Sign};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_export_executions_and_rollbacks() {
+        let mut simulation = ring(2);
+        simulation.start_recording();
+        simulation.inject(Message::new(0, 3, 0, 0, Sign::Message, Arc::new("1".to_string())));
+        simulation.inject(Message::new(0, 9, 0, 0, Sign::Message, Arc::new("0".to_string())));
+        while simulation.step() {}
+        simulation.inject(Message::new(0, 5, 0, 0, Sign::Message, Arc::new("0".to_string())));
+        while simulation.step() {}
+        let trace = simulation.take_trace().unwrap();
+
+        let mut buffer = Vec::new();
+        write_chrome_trace(&trace, TimeAxis::Virtual, &mut buffer).unwrap();
+        let exported: Value = serde_json::from_slice(&buffer).unwrap();
+        let events = exported["traceEvents"].as_array().unwrap();
+
+        let executions: Vec<_> = events.iter().filter(|event| event["ph"] == "X").collect();
+        // 3, 9 on machine 0 and 6 on machine 1, then 5 and 9 again after the rollback
+        assert_eq!(executions.len(), 5);
+        assert_eq!(executions[0]["tid"], 0);
+        assert_eq!(executions[0]["ts"], 3.0);
+        assert_eq!(executions[2]["tid"], 0);
+        assert_eq!(executions[2]["ts"], 9.0);
+
+        let rollback: Vec<_> = events
+            .iter()
+            .filter(|event| event["cat"] == "rollback")
+            .collect();
+        assert_eq!(rollback.len(), 2);
+        assert_eq!(rollback[0]["ph"], "b");
+        assert_eq!(rollback[0]["ts"], 3.0);
+        assert_eq!(rollback[1]["ph"], "e");
+        assert_eq!(rollback[1]["ts"], 9.0);
+        assert_eq!(rollback[0]["pid"], ROLLBACKS_PID);
+
+        let wall = to_chrome_json(&trace, TimeAxis::Wall);
+        let instants = wall["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|event| event["ph"] == "i")
+            .count();
+        assert_eq!(instants, 1);
+    }
+}
@@ -0,0 +1,200 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::sink::{csv_field, CommittedEvent, EventSink};
+use crate::time::message::{Delay, VirtualTime};
+
+// Streams the committed event log to CSV files for looking at in pandas or Polars, one
+// row per event with who sent it to whom, when, and the start of its payload. Only
+// CSV for now, there is no parquet writer in the dependencies.
+//
+// With an epoch length the log is split into one file per epoch of virtual time, named
+// after the first time it covers (events-0.csv, events-100.csv, ...). Events come in
+// timestamp order so a file is only ever appended to until GVT gets past the end of its
+// epoch, at which point nothing can be committed into it anymore and it is closed, so
+// every file besides the last one is final and can be picked up while the run goes on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventLogOptions {
+    pub epoch_length: Option<Delay>,
+    // Payloads longer than this are cut off
+    pub summary_len: usize,
+    pub prefix: String,
+}
+
+impl Default for EventLogOptions {
+    fn default() -> Self {
+        Self {
+            epoch_length: None,
+            summary_len: 32,
+            prefix: "events".to_string(),
+        }
+    }
+}
+
+const HEADER: &str = "send_time,rec_time,sender,receiver,message_id,payload";
+
+pub struct EventLogExporter {
+    dir: PathBuf,
+    options: EventLogOptions,
+    // The open file along with the epoch it is for
+    current: Option<(VirtualTime, BufWriter<File>)>,
+    finished: Vec<PathBuf>,
+    error: Option<io::Error>,
+}
+
+impl EventLogExporter {
+    // Creates the directory if it isnt there, files are only created once they get an
+    // event
+    pub fn new(dir: impl AsRef<Path>, options: EventLogOptions) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            options,
+            current: None,
+            finished: Vec::new(),
+            error: None,
+        })
+    }
+
+    // Files that are closed and wont change anymore
+    pub fn finished(&self) -> &[PathBuf] {
+        &self.finished
+    }
+
+    fn path(&self, epoch: VirtualTime) -> PathBuf {
+        match self.options.epoch_length {
+            Some(_) => self.dir.join(format!("{}-{}.csv", self.options.prefix, epoch)),
+            None => self.dir.join(format!("{}.csv", self.options.prefix)),
+        }
+    }
+
+    fn epoch_of(&self, vt: VirtualTime) -> VirtualTime {
+        match self.options.epoch_length {
+            Some(length) if length > 0 => vt.align_down(length),
+            _ => VirtualTime::ZERO,
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        if let Some((epoch, mut writer)) = self.current.take() {
+            writer.flush()?;
+            self.finished.push(self.path(epoch));
+        }
+        Ok(())
+    }
+
+    fn writer_for(&mut self, epoch: VirtualTime) -> io::Result<&mut BufWriter<File>> {
+        if self.current.as_ref().is_some_and(|(open, _)| *open != epoch) {
+            self.close()?;
+        }
+        if self.current.is_none() {
+            let mut writer = BufWriter::new(File::create(self.path(epoch))?);
+            writeln!(writer, "{}", HEADER)?;
+            self.current = Some((epoch, writer));
+        }
+        Ok(&mut self.current.as_mut().unwrap().1)
+    }
+
+    fn write(&mut self, event: &CommittedEvent) -> io::Result<()> {
+        let message = &event.message;
+        let summary = summarize(&message.message, self.options.summary_len);
+        let writer = self.writer_for(self.epoch_of(message.rec_time))?;
+        writeln!(
+            writer,
+            "{},{},{},{},{},{}",
+            message.send_time,
+            message.rec_time,
+            message.sender,
+            message.receiver,
+            message.id,
+            csv_field(&summary)
+        )
+    }
+
+    fn keep_error(&mut self, result: io::Result<()>) {
+        if let Err(error) = result {
+            self.error.get_or_insert(error);
+        }
+    }
+}
+
+impl EventSink for EventLogExporter {
+    fn on_commit(&mut self, event: &CommittedEvent) {
+        if self.error.is_none() {
+            let written = self.write(event);
+            self.keep_error(written);
+        }
+    }
+
+    fn on_gvt(&mut self, gvt: Option<VirtualTime>) {
+        let done = match (&self.current, gvt, self.options.epoch_length) {
+            (Some((epoch, _)), Some(gvt), Some(length)) => gvt >= epoch.saturating_add(length),
+            (Some(_), None, _) => true,
+            _ => false,
+        };
+        if done && self.error.is_none() {
+            let closed = self.close();
+            self.keep_error(closed);
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        match &mut self.current {
+            Some((_, writer)) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn summarize(payload: &str, len: usize) -> String {
+    match payload.char_indices().nth(len) {
+        Some((cut, _)) => format!("{}...", &payload[..cut]),
+        None => payload.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use crate::time::message::{Message, Sign};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_log_rotates_by_epoch() {
+        let dir = std::env::temp_dir().join(format!("vtw-events-{}", std::process::id()));
+        let exporter = EventLogExporter::new(
+            &dir,
+            EventLogOptions {
+                epoch_length: Some(Delay::new(5)),
+                ..EventLogOptions::default()
+            },
+        )
+        .unwrap();
+        let mut simulation = ring(2);
+        simulation.add_sink(Box::new(exporter));
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("4".to_string())));
+        simulation.run();
+        simulation.flush_sinks().unwrap();
+
+        // Events at 1, 4, 7, 10 and 13
+        let read = |epoch: usize| {
+            std::fs::read_to_string(dir.join(format!("events-{}.csv", epoch))).unwrap()
+        };
+        let first = read(0);
+        let rows: Vec<_> = first.lines().collect();
+        assert_eq!(rows[0], HEADER);
+        assert_eq!(rows.len(), 3);
+        assert!(rows[2].starts_with("1,4,0,1,"));
+        assert_eq!(read(5).lines().count(), 2);
+        assert_eq!(read(10).lines().count(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(summarize("abcdef", 3), "abc...");
+        assert_eq!(summarize("abc", 3), "abc");
+    }
+}
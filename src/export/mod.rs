@@ -0,0 +1,6 @@
+// Turning recorded or committed runs into formats other tools (or people) understand
+
+pub mod chrome;
+pub mod events;
+pub mod pcap;
+pub mod svg;
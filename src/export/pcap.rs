@@ -0,0 +1,207 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::elvis;
+use crate::sink::{CommittedEvent, EventSink};
+use crate::time::message::{MachineId, Message};
+
+// Writes the committed messages between machines to a pcap file so the simulated
+// traffic can be looked at in Wireshark. Every message becomes an IPv4/UDP packet from
+// the sender to the receiver, machine n being 10.x.y.z with n = x.y.z in base 256, and
+// its timestamp is when it arrived in virtual time times micros_per_unit. Binary payloads
+// (see Message::data) and hex encoded packets for the ELVIS adapter (see elvis.rs) are
+// written as the bytes they carry, other payloads as their text. Messages a machine sends
+// itself (timers and the like) are left out unless include_self is set.
+//
+// Events are committed in receive time order so the timestamps in the file never go
+// backwards, which is what Wireshark expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcapOptions {
+    pub micros_per_unit: u64,
+    pub include_self: bool,
+}
+
+impl Default for PcapOptions {
+    fn default() -> Self {
+        Self {
+            micros_per_unit: 1000,
+            include_self: false,
+        }
+    }
+}
+
+const MAGIC: u32 = 0xa1b2_c3d4;
+const SNAPLEN: u32 = 65_535;
+// Packets start with the IP header, no link layer
+const LINKTYPE_RAW: u32 = 101;
+const UDP_PORT: u16 = 5_000;
+const HEADERS_LEN: usize = 20 + 8;
+
+pub struct PcapSink<W: Write + Send> {
+    writer: W,
+    options: PcapOptions,
+    packets: u64,
+    error: Option<io::Error>,
+}
+
+impl PcapSink<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>, options: PcapOptions) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), options)
+    }
+}
+
+impl<W: Write + Send> PcapSink<W> {
+    // Writes the file header right away
+    pub fn new(mut writer: W, options: PcapOptions) -> io::Result<Self> {
+        writer.write_all(&MAGIC.to_le_bytes())?;
+        writer.write_all(&2u16.to_le_bytes())?;
+        writer.write_all(&4u16.to_le_bytes())?;
+        // Time zone and timestamp accuracy, both always 0
+        writer.write_all(&[0; 8])?;
+        writer.write_all(&SNAPLEN.to_le_bytes())?;
+        writer.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+        Ok(Self {
+            writer,
+            options,
+            packets: 0,
+            error: None,
+        })
+    }
+
+    // Number of packets written so far
+    pub fn packets(&self) -> u64 {
+        self.packets
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write(&mut self, message: &Message) -> io::Result<()> {
+        let packet = packet(message);
+        let captured = packet.len().min(SNAPLEN as usize);
+        let micros = (message.rec_time.ticks()).saturating_mul(self.options.micros_per_unit);
+        self.writer.write_all(&((micros / 1_000_000) as u32).to_le_bytes())?;
+        self.writer.write_all(&((micros % 1_000_000) as u32).to_le_bytes())?;
+        self.writer.write_all(&(captured as u32).to_le_bytes())?;
+        self.writer.write_all(&(packet.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&packet[..captured])?;
+        self.packets += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> EventSink for PcapSink<W> {
+    fn on_commit(&mut self, event: &CommittedEvent) {
+        let message = &event.message;
+        let to_self = message.sender == message.receiver;
+        if self.error.is_some() || (to_self && !self.options.include_self) {
+            return;
+        }
+        if let Err(error) = self.write(message) {
+            self.error = Some(error);
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.writer.flush()
+    }
+}
+
+fn address(machine_id: MachineId) -> [u8; 4] {
+    let [_, x, y, z] = (machine_id as u32 & 0x00ff_ffff).to_be_bytes();
+    [10, x, y, z]
+}
+
+fn packet(message: &Message) -> Vec<u8> {
+    let packet_bytes = match message.port() {
+        Some(elvis::PACKET) if message.data.is_none() => elvis::from_hex(&message.message),
+        _ => None,
+    };
+    let payload = packet_bytes.unwrap_or_else(|| message.payload_bytes().to_vec());
+    let payload = &payload[..payload.len().min(u16::MAX as usize - HEADERS_LEN)];
+    let total_len = (HEADERS_LEN + payload.len()) as u16;
+
+    let mut packet = Vec::with_capacity(total_len as usize);
+    // IPv4 header: version and length, tos, total length, id, no fragmenting, ttl 64, UDP
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    packet.extend_from_slice(&(message.id as u16).to_be_bytes());
+    packet.extend_from_slice(&[0x40, 0, 64, 17, 0, 0]);
+    packet.extend_from_slice(&address(message.sender));
+    packet.extend_from_slice(&address(message.receiver));
+    let checksum = ipv4_checksum(&packet);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    // UDP header, a checksum of 0 means there isnt one
+    packet.extend_from_slice(&UDP_PORT.to_be_bytes());
+    packet.extend_from_slice(&UDP_PORT.to_be_bytes());
+    packet.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use crate::time::message::Sign;
+    use std::sync::{Arc, Mutex};
+
+    // Lets the test look at what was written after the sink went into the simulation
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_committed_messages_become_packets() {
+        let file = Shared::default();
+        let sink = PcapSink::new(file.clone(), PcapOptions::default()).unwrap();
+        let mut simulation = ring(2);
+        simulation.add_sink(Box::new(sink));
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("2".to_string())));
+        simulation.run();
+        simulation.flush_sinks().unwrap();
+
+        // The injected message was from machine 0 to itself, the two forwards count
+        let bytes = file.0.lock().unwrap().clone();
+        assert_eq!(&bytes[..4], &MAGIC.to_le_bytes());
+        assert_eq!(&bytes[20..24], &LINKTYPE_RAW.to_le_bytes());
+        let first = &bytes[24..];
+        let (seconds, micros) = (&first[0..4], &first[4..8]);
+        assert_eq!(u32::from_le_bytes(seconds.try_into().unwrap()), 0);
+        assert_eq!(u32::from_le_bytes(micros.try_into().unwrap()), 4_000);
+        let len = u32::from_le_bytes(first[8..12].try_into().unwrap()) as usize;
+        assert_eq!(len, HEADERS_LEN + 1);
+        let ip = &first[16..16 + len];
+        assert_eq!(&ip[12..16], &[10, 0, 0, 0]);
+        assert_eq!(&ip[16..20], &[10, 0, 0, 1]);
+        assert_eq!(ipv4_checksum(&ip[..20]), 0);
+        assert_eq!(&ip[28..], b"1");
+        assert_eq!(bytes.len(), 24 + 2 * (16 + len));
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use crate::recorder::{Trace, TraceEvent};
+use crate::time::message::{MachineId, VirtualTime};
+
+// Lamport style space-time diagram of a recorded trace. Every machine is a horizontal
+// line with virtual time running left to right, executed messages are dots on the line
+// and the messages they sent are arrows to the dot on the receivers line. Stretches of
+// virtual time a machine rolled back over are shaded, and executions that were undone
+// by a later rollback (plus whatever they sent) are drawn faded and dashed so the
+// speculative work stands out from what survived.
+#[derive(Debug, Clone)]
+pub struct SvgOptions {
+    // Pixels per unit of virtual time
+    pub time_scale: f64,
+    pub row_height: f64,
+    pub margin: f64,
+}
+
+impl Default for SvgOptions {
+    fn default() -> Self {
+        Self {
+            time_scale: 20.0,
+            row_height: 60.0,
+            margin: 40.0,
+        }
+    }
+}
+
+const LABEL_WIDTH: f64 = 80.0;
+const DOT_RADIUS: f64 = 4.0;
+
+struct Layout<'a> {
+    options: &'a SvgOptions,
+    rows: BTreeMap<MachineId, usize>,
+}
+
+impl Layout<'_> {
+    fn x(&self, time: VirtualTime) -> f64 {
+        self.options.margin + LABEL_WIDTH + time.ticks() as f64 * self.options.time_scale
+    }
+
+    fn y(&self, machine: MachineId) -> f64 {
+        self.options.margin + self.rows[&machine] as f64 * self.options.row_height
+    }
+}
+
+pub fn to_svg(trace: &Trace, options: &SvgOptions) -> String {
+    let layout = Layout {
+        options,
+        rows: trace
+            .machines()
+            .into_iter()
+            .enumerate()
+            .map(|(row, machine)| (machine, row))
+            .collect(),
+    };
+    let end = trace
+        .events
+        .iter()
+        .flat_map(|event| match event {
+            TraceEvent::Execute { rec_time, sent, .. } => {
+                let mut times = vec![*rec_time];
+                times.extend(sent.iter().map(|message| message.rec_time));
+                times
+            }
+            TraceEvent::Rollback { from, .. } => vec![*from],
+        })
+        .max()
+        .unwrap_or_default();
+    let width = layout.x(end.next()) + options.margin;
+    let height = options.margin * 2.0 + layout.rows.len().saturating_sub(1) as f64 * options.row_height;
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}" font-family="sans-serif" font-size="12">"#
+    );
+    svg.push_str(concat!(
+        r#"<defs><marker id="arrow" viewBox="0 0 10 10" refX="10" refY="5" markerWidth="6" markerHeight="6" orient="auto-start-reverse">"#,
+        r#"<path d="M 0 0 L 10 5 L 0 10 z"/></marker></defs>"#,
+        "\n"
+    ));
+
+    // Shading goes first so the lines and dots end up drawn on top of it
+    for event in &trace.events {
+        if let TraceEvent::Rollback { machine, from, to, .. } = event {
+            let _ = writeln!(
+                svg,
+                r#"<rect class="rollback" x="{}" y="{}" width="{}" height="{}" fill="red" fill-opacity="0.15"/>"#,
+                layout.x(*to),
+                layout.y(*machine) - options.row_height / 3.0,
+                layout.x(*from) - layout.x(*to),
+                options.row_height * 2.0 / 3.0,
+            );
+        }
+    }
+
+    for machine in layout.rows.keys() {
+        let y = layout.y(*machine);
+        let _ = writeln!(
+            svg,
+            r#"<text x="{}" y="{}" dominant-baseline="middle">{}</text>"#,
+            options.margin,
+            y,
+            escape(&trace.label(*machine))
+        );
+        let _ = writeln!(
+            svg,
+            r#"<line class="timeline" x1="{}" y1="{y}" x2="{}" y2="{y}" stroke="black"/>"#,
+            layout.x(VirtualTime::ZERO),
+            layout.x(end.next()),
+        );
+    }
+
+    for (index, event) in trace.events.iter().enumerate() {
+        let TraceEvent::Execute {
+            machine,
+            message_id,
+            rec_time,
+            sent,
+            ..
+        } = event
+        else {
+            continue;
+        };
+        let undone = rolled_back(trace, index, *machine, *rec_time);
+        let (x, y) = (layout.x(*rec_time), layout.y(*machine));
+        for message in sent {
+            if !layout.rows.contains_key(&message.receiver) {
+                continue;
+            }
+            let _ = writeln!(
+                svg,
+                r#"<line class="message{}" x1="{x}" y1="{y}" x2="{}" y2="{}" stroke="steelblue"{} marker-end="url(#arrow)"><title>message {}</title></line>"#,
+                if undone { " undone" } else { "" },
+                layout.x(message.rec_time),
+                layout.y(message.receiver),
+                if undone { r#" stroke-dasharray="4 3" stroke-opacity="0.4""# } else { "" },
+                message.id,
+            );
+        }
+        let _ = writeln!(
+            svg,
+            r#"<circle class="execution{}" cx="{x}" cy="{y}" r="{DOT_RADIUS}" {}><title>message {} at {}</title></circle>"#,
+            if undone { " undone" } else { "" },
+            if undone { r#"fill="white" stroke="grey""# } else { r#"fill="black""# },
+            message_id,
+            rec_time,
+        );
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+pub fn write_svg<W: Write>(trace: &Trace, options: &SvgOptions, mut writer: W) -> io::Result<()> {
+    writer.write_all(to_svg(trace, options).as_bytes())
+}
+
+// Names come from the user and can have anything in them
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+// An execution was undone if the same machine later rolled back to before it
+fn rolled_back(trace: &Trace, index: usize, machine: MachineId, rec_time: VirtualTime) -> bool {
+    trace.events[index + 1..].iter().any(|event| {
+        matches!(event, TraceEvent::Rollback { machine: rolled, to, .. } if *rolled == machine && *to < rec_time)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use crate::time::message::{Message, Sign};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_rollback_is_shaded_and_undone_work_faded() {
+        let mut simulation = ring(2);
+        simulation.start_recording();
+        simulation.inject(Message::new(0, 3, 0, 0, Sign::Message, Arc::new("1".to_string())));
+        simulation.inject(Message::new(0, 9, 0, 0, Sign::Message, Arc::new("0".to_string())));
+        while simulation.step() {}
+        simulation.inject(Message::new(0, 5, 0, 0, Sign::Message, Arc::new("0".to_string())));
+        while simulation.step() {}
+        let trace = simulation.take_trace().unwrap();
+
+        let svg = to_svg(&trace, &SvgOptions::default());
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        assert_eq!(svg.matches(r#"class="timeline""#).count(), 2);
+        assert_eq!(svg.matches(r#"class="rollback""#).count(), 1);
+        // 3 -> 6 on machine 1 survives, the first execution of 9 is undone by the straggler
+        assert_eq!(svg.matches("<circle").count(), 5);
+        assert_eq!(svg.matches(r#"class="execution undone""#).count(), 1);
+        assert_eq!(svg.matches(r#"class="message""#).count(), 1);
+    }
+}
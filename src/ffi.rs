@@ -0,0 +1,304 @@
+// The C API for embedding the engine, declared in include/vtw.h. A C program creates a
+// simulation, adds machines with a handler function each, injects messages, runs it and
+// reads back stats and states. A machine state is a fixed size block of bytes the
+// handler changes in place, the engine saves and restores it like any other state so a
+// handler must keep everything that has to roll back in there and nothing behind
+// pointers in it.
+//
+// All functions take the pointers they were handed by this API (or buffers of the length
+// passed with them) and none of them may be called from inside a handler except
+// vtw_send. A panic inside the engine aborts the program, it cant unwind into C.
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::{c_int, c_void};
+use std::slice;
+use std::sync::Arc;
+
+use crate::machine::Machine;
+use crate::process::{Context, TimeWarpProcess};
+use crate::runtime::Simulation;
+use crate::stats::MachineStats;
+use crate::time::message::{Delay, MachineId, Message, Sign, VirtualTime};
+
+pub type VtwSimulation = Simulation<FfiProcess>;
+
+// Called for every event, with the machines state and the event. Sends go through
+// vtw_send with the context pointer. A NULL handler from C is None here.
+pub type VtwHandler = Option<VtwHandlerFn>;
+
+pub type VtwHandlerFn = extern "C" fn(
+    user_data: *mut c_void,
+    state: *mut u8,
+    state_len: usize,
+    event: *const VtwEvent,
+    ctx: *mut Context,
+);
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct VtwEvent {
+    // Times and delays are plain ticks on this side
+    pub now: u64,
+    pub machine_id: MachineId,
+    pub sender: MachineId,
+    // Not nul terminated
+    pub payload: *const u8,
+    pub payload_len: usize,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VtwStats {
+    pub events_processed: u64,
+    pub events_committed: u64,
+    pub events_rolled_back: u64,
+    pub rollbacks: u64,
+    pub antimessages_sent: u64,
+}
+
+impl From<&MachineStats> for VtwStats {
+    fn from(stats: &MachineStats) -> Self {
+        Self {
+            events_processed: stats.events_processed,
+            events_committed: stats.events_committed,
+            events_rolled_back: stats.events_rolled_back,
+            rollbacks: stats.rollbacks,
+            antimessages_sent: stats.antimessages_sent,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct UserData(*mut c_void);
+
+// Whatever the handler does with it is up to the C side, machines can run on other threads
+// than the one that added them so it has to be fine with that
+unsafe impl Send for UserData {}
+
+#[derive(Debug)]
+pub struct FfiProcess {
+    handler: VtwHandlerFn,
+    user_data: UserData,
+}
+
+impl TimeWarpProcess for FfiProcess {
+    type State = Vec<u8>;
+
+    fn on_message(&self, state: &mut Vec<u8>, message: &Message, ctx: &mut Context) {
+        let event = VtwEvent {
+            now: ctx.now().ticks(),
+            machine_id: ctx.machine_id(),
+            sender: message.sender,
+            payload: message.message.as_ptr(),
+            payload_len: message.message.len(),
+        };
+        (self.handler)(self.user_data.0, state.as_mut_ptr(), state.len(), &event, ctx);
+    }
+
+    fn state_size(&self, state: &Vec<u8>) -> usize {
+        std::mem::size_of_val(state) + state.capacity()
+    }
+}
+
+unsafe fn payload(payload: *const u8, len: usize) -> String {
+    if payload.is_null() || len == 0 {
+        return String::new();
+    }
+    // Payloads are strings on the Rust side
+    String::from_utf8_lossy(slice::from_raw_parts(payload, len)).into_owned()
+}
+
+#[no_mangle]
+pub extern "C" fn vtw_simulation_new() -> *mut VtwSimulation {
+    Box::into_raw(Box::new(Simulation::new()))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vtw_simulation_free(simulation: *mut VtwSimulation) {
+    if !simulation.is_null() {
+        drop(Box::from_raw(simulation));
+    }
+}
+
+// Adds a machine whose state starts as state_len zero bytes. Returns 0, or -1 if there
+// already is a machine with the id or the handler is NULL.
+#[no_mangle]
+pub unsafe extern "C" fn vtw_add_machine(
+    simulation: *mut VtwSimulation,
+    machine_id: MachineId,
+    state_len: usize,
+    handler: VtwHandler,
+    user_data: *mut c_void,
+) -> c_int {
+    let simulation = &mut *simulation;
+    let Some(handler) = handler else {
+        return -1;
+    };
+    if simulation.machine(machine_id).is_some() {
+        return -1;
+    }
+    let process = FfiProcess {
+        handler,
+        user_data: UserData(user_data),
+    };
+    let state = vec![0; state_len];
+    simulation.add_machine(Machine::with_state(machine_id, 0, process, state));
+    0
+}
+
+// Puts a message for a machine in from outside. Returns 0, -1 if there is no such
+// machine, or -2 if rec_time is before the commit horizon of the machine, then the
+// machine would drop it.
+#[no_mangle]
+pub unsafe extern "C" fn vtw_inject(
+    simulation: *mut VtwSimulation,
+    receiver: MachineId,
+    rec_time: u64,
+    payload_ptr: *const u8,
+    payload_len: usize,
+) -> c_int {
+    let simulation = &mut *simulation;
+    let rec_time = VirtualTime::new(rec_time);
+    match simulation.machine(receiver) {
+        None => return -1,
+        Some(machine) if rec_time < machine.commit_horizon() => return -2,
+        Some(_) => {}
+    }
+    let payload = Arc::new(payload(payload_ptr, payload_len));
+    simulation.inject(Message::new(0, rec_time, receiver, receiver, Sign::Message, payload));
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vtw_run(simulation: *mut VtwSimulation) {
+    (*simulation).run();
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vtw_run_until(simulation: *mut VtwSimulation, end_time: u64) {
+    (*simulation).run_until(VirtualTime::new(end_time));
+}
+
+// Sends a message from inside a handler, ctx is the one the handler was called with
+#[no_mangle]
+pub unsafe extern "C" fn vtw_send(
+    ctx: *mut Context,
+    receiver: MachineId,
+    delay: u64,
+    payload_ptr: *const u8,
+    payload_len: usize,
+) {
+    (*ctx).send(receiver, Delay::new(delay), payload(payload_ptr, payload_len));
+}
+
+// GVT, or -1 while there isnt one
+#[no_mangle]
+pub unsafe extern "C" fn vtw_gvt(simulation: *const VtwSimulation) -> i64 {
+    (*simulation).gvt().map_or(-1, |gvt| gvt.ticks() as i64)
+}
+
+// Fills in the totals over all machines
+#[no_mangle]
+pub unsafe extern "C" fn vtw_total_stats(
+    simulation: *const VtwSimulation,
+    stats: *mut VtwStats,
+) {
+    *stats = VtwStats::from(&(*simulation).stats().total);
+}
+
+// Fills in the stats of one machine. Returns 0, or -1 if there is no such machine.
+#[no_mangle]
+pub unsafe extern "C" fn vtw_machine_stats(
+    simulation: *const VtwSimulation,
+    machine_id: MachineId,
+    stats: *mut VtwStats,
+) -> c_int {
+    match (*simulation).machine(machine_id) {
+        Some(machine) => {
+            *stats = VtwStats::from(machine.stats());
+            0
+        }
+        None => -1,
+    }
+}
+
+// Copies as much of the current state of a machine as fits into the buffer. Returns the
+// length of the whole state, or -1 if there is no such machine.
+#[no_mangle]
+pub unsafe extern "C" fn vtw_machine_state(
+    simulation: *const VtwSimulation,
+    machine_id: MachineId,
+    buffer: *mut u8,
+    buffer_len: usize,
+) -> isize {
+    let Some(machine) = (*simulation).machine(machine_id) else {
+        return -1;
+    };
+    let state = &machine.state;
+    let len = state.len().min(buffer_len);
+    if len > 0 {
+        slice::from_raw_parts_mut(buffer, len).copy_from_slice(&state[..len]);
+    }
+    state.len() as isize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+
+    // What a C handler would look like, a counter of events in the first byte of the state
+    // that passes the message on to the next of two machines until user_data says stop
+    extern "C" fn count(
+        user_data: *mut c_void,
+        state: *mut u8,
+        state_len: usize,
+        event: *const VtwEvent,
+        ctx: *mut Context,
+    ) {
+        unsafe {
+            let state = slice::from_raw_parts_mut(state, state_len);
+            let event = &*event;
+            let limit = *(user_data as *const u8);
+            state[0] += 1;
+            if state[0] < limit {
+                let payload = b"hop";
+                let next = (event.machine_id + 1) % 2;
+                vtw_send(ctx, next, 2, payload.as_ptr(), payload.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_c_api_runs_a_simulation() {
+        let mut limit = 3u8;
+        let user_data = &mut limit as *mut u8 as *mut c_void;
+        unsafe {
+            let simulation = vtw_simulation_new();
+            assert_eq!(vtw_add_machine(simulation, 0, 4, Some(count), user_data), 0);
+            assert_eq!(vtw_add_machine(simulation, 1, 4, Some(count), user_data), 0);
+            assert_eq!(vtw_add_machine(simulation, 1, 4, Some(count), user_data), -1);
+            assert_eq!(vtw_add_machine(simulation, 2, 4, None, user_data), -1);
+            assert_eq!(vtw_inject(simulation, 7, 1, ptr::null(), 0), -1);
+            let payload = b"go";
+            assert_eq!(vtw_inject(simulation, 0, 1, payload.as_ptr(), payload.len()), 0);
+            vtw_run(simulation);
+
+            // Machine 0 runs at 1, 5 and 9, machine 1 at 3 and 7
+            let mut stats = VtwStats::default();
+            vtw_total_stats(simulation, &mut stats);
+            assert_eq!(stats.events_processed, 5);
+            assert_eq!(vtw_machine_stats(simulation, 1, &mut stats), 0);
+            assert_eq!(stats.events_processed, 2);
+            let mut state = [0u8; 2];
+            assert_eq!(vtw_machine_state(simulation, 0, state.as_mut_ptr(), 2), 4);
+            assert_eq!(state, [3, 0]);
+            assert_eq!(vtw_machine_state(simulation, 5, state.as_mut_ptr(), 2), -1);
+
+            // Machine 0 committed through 9, a message there would be dropped
+            assert_eq!(vtw_inject(simulation, 0, 9, payload.as_ptr(), payload.len()), -2);
+            assert_eq!(vtw_inject(simulation, 0, 10, payload.as_ptr(), payload.len()), 0);
+            vtw_simulation_free(simulation);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::bridge::EgressRecord;
+use crate::sink::CommittedEvent;
+
+// Set to anything to write the golden files again instead of comparing against them
+pub const UPDATE_ENV: &str = "VTW_UPDATE_GOLDEN";
+
+// Golden traces for the test suites of models: the committed events of a run are written
+// to a file the first time and every later run is compared against it, so a change to
+// the model that changes what it does fails the test with where it first went different.
+// The file is one committed event per line as JSON (the form the bridge publishes them
+// in) so a change to it reads fine in a diff, check it in next to the test. Collect the
+// events with a VecSink:
+//
+//   let events = VecSink::new();
+//   simulation.add_sink(Box::new(events.clone()));
+//   simulation.run();
+//   golden::assert_matches("tests/golden/ring.jsonl", &events.events());
+//
+// Committed events come in timestamp order with ties going to the lower machine id, the
+// same for every executor, so the file doesnt change with how the run was done. Run the
+// tests with VTW_UPDATE_GOLDEN=1 once a change in behaviour is on purpose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    // There was no file yet (or it was asked to be updated) so it was written
+    Recorded,
+    Matched,
+}
+
+// The first event that isnt the same, None for the one that ran out of events first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    // Position in the trace, from 0
+    pub index: usize,
+    pub expected: Option<EgressRecord>,
+    pub actual: Option<EgressRecord>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "runs diverge at event {}", self.index)?;
+        match (&self.expected, &self.actual) {
+            (Some(expected), Some(actual)) => {
+                write!(f, "\n  expected {}\n  got      {}", describe(expected), describe(actual))
+            }
+            (Some(expected), None) => {
+                write!(f, ", the run ended before\n  expected {}", describe(expected))
+            }
+            (None, Some(actual)) => {
+                write!(f, ", the golden trace ends there\n  got      {}", describe(actual))
+            }
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+fn describe(event: &EgressRecord) -> String {
+    let port = event.port.as_ref().map_or(String::new(), |port| format!(" on port {}", port));
+    format!(
+        "VT {} machine {} payload {:?} from machine {} sent at {}{}",
+        event.at, event.machine, event.payload, event.from, event.sent_at, port
+    )
+}
+
+#[derive(Debug)]
+pub enum GoldenError {
+    Io(io::Error),
+    // A line of the golden file that isnt an event, from 1
+    Parse { line: usize, error: serde_json::Error },
+    Diverged(Box<Divergence>),
+}
+
+impl fmt::Display for GoldenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GoldenError::Io(error) => write!(f, "io error: {}", error),
+            GoldenError::Parse { line, error } => {
+                write!(f, "line {} of the golden trace is no event: {}", line, error)
+            }
+            GoldenError::Diverged(divergence) => divergence.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for GoldenError {}
+
+impl From<io::Error> for GoldenError {
+    fn from(error: io::Error) -> Self {
+        GoldenError::Io(error)
+    }
+}
+
+// Writes the events as the golden trace, making the directory if there is none
+pub fn record(path: impl AsRef<Path>, events: &[CommittedEvent]) -> io::Result<()> {
+    let path = path.as_ref();
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut out = BufWriter::new(File::create(path)?);
+    for event in events {
+        serde_json::to_writer(&mut out, &EgressRecord::from(event))?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()
+}
+
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<EgressRecord>, GoldenError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut events = Vec::new();
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = serde_json::from_str(&line)
+            .map_err(|error| GoldenError::Parse { line: n + 1, error })?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+// Where the events first differ from the golden trace, None if they dont
+pub fn compare(
+    path: impl AsRef<Path>,
+    events: &[CommittedEvent],
+) -> Result<Option<Divergence>, GoldenError> {
+    let expected = load(path)?;
+    let actual: Vec<_> = events.iter().map(EgressRecord::from).collect();
+    let index = (0..expected.len().max(actual.len()))
+        .find(|index| expected.get(*index) != actual.get(*index));
+    Ok(index.map(|index| Divergence {
+        index,
+        expected: expected.get(index).cloned(),
+        actual: actual.get(index).cloned(),
+    }))
+}
+
+// Compares against the golden trace, or records it if there is none yet or UPDATE_ENV
+// is set
+pub fn check(path: impl AsRef<Path>, events: &[CommittedEvent]) -> Result<Outcome, GoldenError> {
+    let path = path.as_ref();
+    if !path.exists() || std::env::var_os(UPDATE_ENV).is_some() {
+        record(path, events)?;
+        return Ok(Outcome::Recorded);
+    }
+    match compare(path, events)? {
+        Some(divergence) => Err(GoldenError::Diverged(Box::new(divergence))),
+        None => Ok(Outcome::Matched),
+    }
+}
+
+// check for tests, panics with the report
+pub fn assert_matches(path: impl AsRef<Path>, events: &[CommittedEvent]) {
+    let path = path.as_ref();
+    if let Err(error) = check(path, events) {
+        panic!("golden trace {} doesnt match: {}", path.display(), error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use crate::sink::VecSink;
+    use crate::time::message::{Message, Sign};
+    use std::sync::Arc;
+
+    fn run(hops: &str) -> Vec<CommittedEvent> {
+        let mut simulation = ring(2);
+        let events = VecSink::new();
+        simulation.add_sink(Box::new(events.clone()));
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new(hops.to_string())));
+        simulation.run();
+        events.events()
+    }
+
+    #[test]
+    fn test_golden_traces_report_the_first_divergence() {
+        let dir = std::env::temp_dir().join(format!("vtw-golden-{}", std::process::id()));
+        let path = dir.join("ring.jsonl");
+        assert_eq!(check(&path, &run("3")).unwrap(), Outcome::Recorded);
+        assert_eq!(check(&path, &run("3")).unwrap(), Outcome::Matched);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 4);
+
+        // The payloads are one hop off from the first event on
+        let Err(GoldenError::Diverged(divergence)) = check(&path, &run("4")) else {
+            panic!("a different run matched");
+        };
+        assert_eq!(divergence.index, 0);
+        assert_eq!(
+            divergence.to_string(),
+            "runs diverge at event 0\n  \
+             expected VT 1 machine 0 payload \"3\" from machine 0 sent at 0\n  \
+             got      VT 1 machine 0 payload \"4\" from machine 0 sent at 0"
+        );
+        let divergence = compare(&path, &run("2")[..3]).unwrap().unwrap();
+        assert_eq!(divergence.index, 0);
+
+        // Cut short, the golden trace has more
+        let short = &run("3")[..2];
+        let divergence = compare(&path, short).unwrap().unwrap();
+        assert_eq!((divergence.index, &divergence.actual), (2, &None));
+        assert!(divergence.to_string().contains("the run ended before"));
+
+        fs::write(&path, "{\"machine\": 0}\n").unwrap();
+        assert!(matches!(check(&path, &[]), Err(GoldenError::Parse { line: 1, .. })));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
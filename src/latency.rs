@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::time::message::{Delay, MachineId, VirtualTime};
+
+// How long a message takes over a link. Instead of hard coding a delay a process asks
+// its Context for link_delay(receiver) and the machine samples whatever model was set up
+// for that link. The draw is a pseudo random number the runtime derives from the link,
+// the send time and how many samples the event took before, so executing the same
+// event again after a rollback gets the same delays.
+pub trait LatencyModel: Debug + Send + Sync {
+    fn sample(&self, send_time: VirtualTime, draw: u64) -> Delay;
+
+    // Smallest delay the model can produce, what a process using it can declare as its
+    // lookahead
+    fn min_delay(&self) -> Delay;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Constant(pub Delay);
+
+impl LatencyModel for Constant {
+    fn sample(&self, _send_time: VirtualTime, _draw: u64) -> Delay {
+        self.0
+    }
+
+    fn min_delay(&self) -> Delay {
+        self.0
+    }
+}
+
+// Anything from min to max, both included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Uniform {
+    pub min: Delay,
+    pub max: Delay,
+}
+
+impl LatencyModel for Uniform {
+    fn sample(&self, _send_time: VirtualTime, draw: u64) -> Delay {
+        let span = self.max.saturating_sub(self.min).ticks().saturating_add(1);
+        self.min + Delay::new(draw % span)
+    }
+
+    fn min_delay(&self) -> Delay {
+        self.min
+    }
+}
+
+// min plus an exponentially distributed delay with the given mean, rounded down
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Exponential {
+    pub min: Delay,
+    pub mean: f64,
+}
+
+impl LatencyModel for Exponential {
+    fn sample(&self, _send_time: VirtualTime, draw: u64) -> Delay {
+        // Uniform in (0, 1], so the log is never infinite
+        let uniform = ((draw >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        self.min + Delay::new((-uniform.ln() * self.mean) as u64)
+    }
+
+    fn min_delay(&self) -> Delay {
+        self.min
+    }
+}
+
+// Replays measured latencies, each point is (from this send time on, the delay). Sends
+// before the first point get the first delay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceDriven {
+    points: Vec<(VirtualTime, Delay)>,
+}
+
+impl TraceDriven {
+    pub fn new(mut points: Vec<(VirtualTime, Delay)>) -> Self {
+        points.sort();
+        Self { points }
+    }
+}
+
+impl LatencyModel for TraceDriven {
+    fn sample(&self, send_time: VirtualTime, _draw: u64) -> Delay {
+        let after = self.points.partition_point(|(from, _)| *from <= send_time);
+        self.points
+            .get(after.saturating_sub(1))
+            .map_or(Delay::ZERO, |(_, delay)| *delay)
+    }
+
+    fn min_delay(&self) -> Delay {
+        self.points.iter().map(|(_, delay)| *delay).min().unwrap_or_default()
+    }
+}
+
+// The model for every link that has one. A sample is never less than 1, a message
+// received at the time it was sent would be too late for its receiver to process.
+// Links can have a bandwidth as well, see bandwidth.rs.
+#[derive(Debug, Clone, Default)]
+pub struct Latencies {
+    links: HashMap<(MachineId, MachineId), Arc<dyn LatencyModel>>,
+    // Bytes a link puts on the wire per time unit
+    bandwidths: HashMap<(MachineId, MachineId), u64>,
+    seed: u64,
+}
+
+impl Latencies {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            links: HashMap::new(),
+            bandwidths: HashMap::new(),
+            seed,
+        }
+    }
+
+    // A bandwidth of 0 is taken as 1
+    pub fn set_bandwidth(&mut self, from: MachineId, to: MachineId, bytes_per_tick: u64) {
+        self.bandwidths.insert((from, to), bytes_per_tick.max(1));
+    }
+
+    pub fn bandwidth(&self, from: MachineId, to: MachineId) -> Option<u64> {
+        self.bandwidths.get(&(from, to)).copied()
+    }
+
+    pub fn set_link(&mut self, from: MachineId, to: MachineId, model: Arc<dyn LatencyModel>) {
+        self.links.insert((from, to), model);
+    }
+
+    pub fn link(&self, from: MachineId, to: MachineId) -> Option<&Arc<dyn LatencyModel>> {
+        self.links.get(&(from, to))
+    }
+
+    // The nth sample taken while executing an event at send_time on the link
+    pub fn delay(
+        &self,
+        from: MachineId,
+        to: MachineId,
+        send_time: VirtualTime,
+        nth: usize,
+    ) -> Option<Delay> {
+        let model = self.links.get(&(from, to))?;
+        let draw = [from as u64, to as u64, send_time.ticks(), nth as u64]
+            .into_iter()
+            .fold(self.seed, |hash, value| mix(hash ^ value));
+        Some(model.sample(send_time, draw).max(Delay::new(1)))
+    }
+}
+
+// How a link's latency is written in a config, see config.rs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "model", rename_all = "lowercase")]
+pub enum LatencyConfig {
+    Constant { delay: Delay },
+    Uniform { min: Delay, max: Delay },
+    Exponential { mean: f64 },
+    Trace { points: Vec<(VirtualTime, Delay)> },
+}
+
+impl LatencyConfig {
+    // min is the links declared delay, exponential delays are added on top of it
+    pub fn build(&self, min: Delay) -> Arc<dyn LatencyModel> {
+        match self {
+            LatencyConfig::Constant { delay } => Arc::new(Constant(*delay)),
+            LatencyConfig::Uniform { min, max } => Arc::new(Uniform {
+                min: *min,
+                max: *max,
+            }),
+            LatencyConfig::Exponential { mean } => Arc::new(Exponential { min, mean: *mean }),
+            LatencyConfig::Trace { points } => Arc::new(TraceDriven::new(points.clone())),
+        }
+    }
+}
+
+// splitmix64 finalizer, std's hashers are allowed to change between releases and what
+// is picked for a seed shouldnt
+pub(crate) fn mix(mut value: u64) -> u64 {
+    value = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_models_stay_in_range_and_repeat() {
+        let mut latencies = Latencies::new(3);
+        let (min, max) = (Delay::new(2), Delay::new(6));
+        latencies.set_link(0, 1, Arc::new(Uniform { min, max }));
+        let min = Delay::new(1);
+        latencies.set_link(1, 0, Arc::new(Exponential { min, mean: 4.0 }));
+        let points = vec![
+            (VirtualTime::new(10), Delay::new(7)),
+            (VirtualTime::ZERO, Delay::new(2)),
+        ];
+        latencies.set_link(0, 2, Arc::new(TraceDriven::new(points)));
+
+        for send_time in (0..200).map(VirtualTime::new) {
+            let delay = latencies.delay(0, 1, send_time, 0).unwrap();
+            assert!((2..=6).contains(&delay.ticks()));
+            assert!(latencies.delay(1, 0, send_time, 0).unwrap() >= 1);
+            // Same event, same sample
+            assert_eq!(latencies.delay(0, 1, send_time, 0), Some(delay));
+        }
+        let at = VirtualTime::new;
+        assert_eq!(latencies.delay(0, 2, at(9), 0), Some(Delay::new(2)));
+        assert_eq!(latencies.delay(0, 2, at(10), 0), Some(Delay::new(7)));
+        assert_eq!(latencies.delay(2, 0, at(10), 0), None);
+    }
+}
@@ -0,0 +1,46 @@
+pub mod machine;
+pub mod process;
+pub mod time;
+pub mod bandwidth;
+pub mod bridge;
+pub mod budget;
+pub mod codec;
+pub mod config;
+pub mod critical_path;
+pub mod dashboard;
+pub mod determinism;
+pub mod devs;
+pub mod effect;
+pub mod elvis;
+pub mod event;
+pub mod export;
+pub mod ffi;
+pub mod golden;
+pub mod latency;
+pub mod live;
+pub mod metrics;
+pub mod nested;
+pub mod phold;
+pub mod placement;
+pub mod plugin;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod recorder;
+pub mod repl;
+pub mod replication;
+pub mod router;
+pub mod transport;
+pub mod runtime;
+pub mod saving;
+pub mod scenario;
+pub mod shared;
+pub mod sink;
+pub mod stats;
+pub mod storm;
+pub mod sweep;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod throttle;
+mod trace;
+#[cfg(feature = "wasm")]
+pub mod wasm;
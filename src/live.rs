@@ -0,0 +1,412 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::bridge::EgressRecord;
+use crate::metrics::{MetricsHandle, MetricsSnapshot};
+use crate::sink::{CommittedEvent, EventSink};
+use crate::time::message::{MachineId, VirtualTime};
+
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// How often the browsers get a frame
+pub const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+// Committed events kept for the next frame, the oldest go first when nobody is watching
+pub const RECENT_EVENTS: usize = 256;
+
+// A live view of a running simulation for a browser dashboard, over a WebSocket on
+// whatever path (ws://host:port/). Every FRAME_INTERVAL each connected browser gets a
+// text frame with a LiveFrame as JSON, like
+//
+//   {
+//     "gvt": 120,                      null once there is nothing left to do
+//     "machines": [
+//       {"id": 0, "lvt": 131, "input_queue": 4, "output_queue": 2, "in_flight": 0,
+//        "events_committed": 57, "rollbacks": 3}
+//     ],
+//     "events": [
+//       {"machine": 0, "at": 118, "from": 2, "sent_at": 115, "payload": "..", "port": null}
+//     ]
+//   }
+//
+// machines is the latest metrics snapshot (see metrics.rs), events are the events
+// committed since the previous frame in commit order, in the same form the bridge
+// publishes them. A browser that connects late starts with the last RECENT_EVENTS, while
+// any browser is connected nothing is dropped. Nothing is read from the browsers,
+// closing the tab is noticed on the next frame.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LiveFrame {
+    pub gvt: Option<VirtualTime>,
+    pub machines: Vec<LiveMachine>,
+    pub events: Vec<EgressRecord>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LiveMachine {
+    pub id: MachineId,
+    pub lvt: VirtualTime,
+    pub input_queue: usize,
+    pub output_queue: usize,
+    pub in_flight: usize,
+    pub events_committed: u64,
+    pub rollbacks: u64,
+}
+
+impl LiveFrame {
+    pub fn new(snapshot: &MetricsSnapshot, events: Vec<EgressRecord>) -> Self {
+        let machines = snapshot
+            .machines
+            .iter()
+            .map(|(id, metrics)| LiveMachine {
+                id: *id,
+                lvt: metrics.local_virtual_time,
+                input_queue: metrics.input_queue,
+                output_queue: metrics.output_queue,
+                in_flight: metrics.in_flight,
+                events_committed: metrics.stats.events_committed,
+                rollbacks: metrics.stats.rollbacks,
+            })
+            .collect();
+        Self {
+            gvt: snapshot.gvt,
+            machines,
+            events,
+        }
+    }
+}
+
+// Collects committed events for the frames, add it to the simulation like any other sink
+#[derive(Debug, Clone, Default)]
+pub struct LiveSink {
+    recent: Arc<Mutex<VecDeque<EgressRecord>>>,
+    // Set by the server while a browser is connected, then every event is kept until the
+    // next frame takes it
+    watched: Arc<AtomicBool>,
+}
+
+impl LiveSink {
+    // The events committed since the last take
+    pub fn take(&self) -> Vec<EgressRecord> {
+        self.recent.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl EventSink for LiveSink {
+    fn on_commit(&mut self, event: &CommittedEvent) {
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() >= RECENT_EVENTS && !self.watched.load(Ordering::Relaxed) {
+            recent.pop_front();
+        }
+        recent.push_back(EgressRecord::from(event));
+    }
+}
+
+// Serves the feed on its own threads until dropped. Give metrics() to
+// Simulation::set_metrics and sink() to Simulation::add_sink.
+pub struct LiveServer {
+    metrics: MetricsHandle,
+    sink: LiveSink,
+    local_addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl LiveServer {
+    pub fn start<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Self::start_with(addr, MetricsHandle::new())
+    }
+
+    // Reads the snapshots from a handle the simulation already publishes to, like the
+    // one of a MetricsServer
+    pub fn start_with<A: ToSocketAddrs>(addr: A, metrics: MetricsHandle) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let sink = LiveSink::default();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let clients = Arc::new(Mutex::new(Vec::new()));
+
+        let (accepted, stop) = (clients.clone(), shutdown.clone());
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let _ = accept(stream, &accepted);
+                    }
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(ACCEPT_POLL_INTERVAL);
+                    }
+                    Err(_) => return,
+                }
+            }
+        });
+
+        let (snapshots, events, stop) = (metrics.clone(), sink.clone(), shutdown.clone());
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                thread::sleep(FRAME_INTERVAL);
+                let mut clients = clients.lock().unwrap();
+                // Events stay in the sink for whoever connects first
+                if clients.is_empty() {
+                    continue;
+                }
+                events.watched.store(true, Ordering::Relaxed);
+                let frame = LiveFrame::new(&snapshots.latest(), events.take());
+                let json = serde_json::to_vec(&frame).expect("frames are json");
+                let frame = text_frame(&json);
+                clients.retain_mut(|client: &mut TcpStream| client.write_all(&frame).is_ok());
+                events.watched.store(!clients.is_empty(), Ordering::Relaxed);
+            }
+        });
+        Ok(Self {
+            metrics,
+            sink,
+            local_addr,
+            shutdown,
+        })
+    }
+
+    pub fn metrics(&self) -> MetricsHandle {
+        self.metrics.clone()
+    }
+
+    pub fn sink(&self) -> LiveSink {
+        self.sink.clone()
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for LiveServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        // Nobody takes the events anymore, and the sink can still be in a simulation
+        self.sink.watched.store(false, Ordering::Relaxed);
+    }
+}
+
+// The opening handshake of RFC 6455, the browser sends a key and gets back its hash
+// with a fixed GUID to prove the server speaks WebSocket
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+fn accept(stream: TcpStream, clients: &Mutex<Vec<TcpStream>>) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+    let mut reader = BufReader::new(stream);
+    let mut key = None;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line.trim_end() != "" {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value.trim().to_string());
+            }
+        }
+        line.clear();
+    }
+    let mut stream = reader.into_inner();
+    let Some(key) = key else {
+        let body = "this is a websocket endpoint";
+        return write!(
+            stream,
+            "HTTP/1.1 400 Bad Request\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+    };
+    // Registered before the answer goes out so the browser cant miss the next frame
+    let mut clients = clients.lock().unwrap();
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    )?;
+    clients.push(stream);
+    Ok(())
+}
+
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()))
+}
+
+// A whole unmasked text frame, servers dont mask what they send
+fn text_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x81];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// Only ever used for the handshake, not for anything that has to be secure
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp.wrapping_add(*word);
+        }
+        for (h, x) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(x);
+        }
+    }
+    let mut digest = [0; 20];
+    for (bytes, word) in digest.chunks_mut(4).zip(h) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use crate::time::message::{Message, Sign};
+    use std::io::Read;
+    use std::time::Instant;
+
+    fn read_frame(stream: &mut TcpStream) -> LiveFrame {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).unwrap();
+        assert_eq!(header[0], 0x81);
+        let len = match header[1] {
+            126 => {
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            }
+            127 => {
+                let mut len = [0; 8];
+                stream.read_exact(&mut len).unwrap();
+                u64::from_be_bytes(len) as usize
+            }
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).unwrap();
+        serde_json::from_slice(&payload).unwrap()
+    }
+
+    #[test]
+    fn test_browsers_get_the_committed_events() {
+        // The example from RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let server = LiveServer::start("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                  Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut response = String::new();
+        while !response.ends_with("\r\n\r\n") {
+            reader.read_line(&mut response).unwrap();
+        }
+        assert!(response.starts_with("HTTP/1.1 101"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        let mut simulation = ring(2);
+        simulation.set_metrics(server.metrics());
+        simulation.add_sink(Box::new(server.sink()));
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("2".to_string())));
+        simulation.run();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut events = Vec::new();
+        while events.len() < 3 {
+            assert!(Instant::now() < deadline, "the events never showed up");
+            let frame = read_frame(&mut stream);
+            events.extend(frame.events.iter().map(|event| (event.machine, event.at.ticks())));
+        }
+        assert_eq!(events, [(0, 1), (1, 4), (0, 7)]);
+        // The run is over by the next one
+        let frame = read_frame(&mut stream);
+        assert!(frame.events.is_empty());
+        assert_eq!(frame.gvt, None);
+        let lvts: Vec<_> = frame.machines.iter().map(|machine| machine.lvt.ticks()).collect();
+        assert_eq!(lvts, [7, 4]);
+    }
+
+    #[test]
+    fn test_nothing_is_dropped_while_watched() {
+        let mut sink = LiveSink::default();
+        let commit = |sink: &mut LiveSink, count: usize| {
+            for rec_time in 0..count {
+                let message = Arc::new(String::new());
+                let message = Message::new(0, rec_time, 0, 0, Sign::Message, message);
+                sink.on_commit(&CommittedEvent {
+                    machine_id: 0,
+                    message,
+                });
+            }
+        };
+        commit(&mut sink, RECENT_EVENTS + 10);
+        let events = sink.take();
+        assert_eq!(events.len(), RECENT_EVENTS);
+        assert_eq!(events[0].at.ticks(), 10);
+
+        sink.watched.store(true, Ordering::Relaxed);
+        commit(&mut sink, RECENT_EVENTS + 10);
+        assert_eq!(sink.take().len(), RECENT_EVENTS + 10);
+    }
+}
@@ -1,16 +1,31 @@
-use crate::time::input_queue::InputQueue;
-use crate::time::message::{MachineId, Message, MessagePayload, Sign, VirtualTime};
+use crate::bandwidth::LinkQueues;
+use crate::budget::{RollbackBudget, RollbackLimits};
+use crate::determinism::state_hash;
+use crate::effect::Compensations;
+use crate::latency::Latencies;
+use crate::process::{Context, TimeWarpProcess};
+use crate::saving::{SavePolicy, StateSaving};
+use crate::shared::Versioned;
+use crate::stats::MachineStats;
+use crate::storm::StormDetector;
+use crate::throttle::{PenaltyThrottle, ThrottleLimits};
+use crate::trace::{trace_debug, trace_span, trace_warn};
+use crate::time::in_flight::InFlightAntimessages;
+use crate::time::input_queue::{DuplicatePolicy, InputQueue};
+use crate::time::message::{
+    CancelHandle, Delay, MachineId, Message, MessageId, MessagePayload, Sign, VirtualTime,
+};
 use crate::time::output_queue::OutputQueue;
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::collections::BTreeSet;
-use std::ops::Bound::{Excluded, Included};
+use std::fmt;
 use std::sync::Arc;
 
 // This is the machine struct, it holds the machines state variables as 
-// well as the things needed for virtual time. For simplicity its all in
-// a single struct but ideally in a finished version there would be some
-// additional abstraction between the time and machine and it would be 
-// implemented as a trait to allow for more flexibility.
+// well as the things needed for virtual time. What the machine actually does
+// with the messages it receives is up to its process (see process.rs), the
+// machine only deals with the time side of things.
 
 // The main idea behind this system is that each machine has a local virtual
 // time. These can be different between machines since they are only local. 
@@ -20,16 +35,110 @@ use std::sync::Arc;
 // come in, when a message inevitably comes out of order the machine will rollback
 // to the last state it was in just before the out of order message should have
 // been received and continue execution. 
-pub struct Machine {
+#[derive(Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "P: Serialize, P::State: Serialize",
+    deserialize = "P: Deserialize<'de>, P::State: Deserialize<'de>"
+))]
+pub struct Machine<P: TimeWarpProcess = ExampleProcess> {
     machine_id: MachineId,
     local_virtual_time: VirtualTime,
-    pub state: MachineState,
+    process: P,
+    pub state: P::State,
     pub input_queue: InputQueue,
     pub output_queue: OutputQueue,
-    state_queue: BTreeSet<StampedMachineState>,
+    pub in_flight: InFlightAntimessages,
+    state_queue: BTreeSet<StampedMachineState<P::State>>,
+    stats: MachineStats,
+    // Everything processed below this has been counted as committed
+    commit_horizon: VirtualTime,
+    #[serde(default)]
+    policy: ExecutionPolicy,
+    #[serde(default)]
+    storm: StormDetector,
+    #[serde(default)]
+    budget: RollbackBudget,
+    #[serde(default)]
+    throttle: PenaltyThrottle,
+    #[serde(default)]
+    snapshot_limits: SnapshotLimits,
+    #[serde(default)]
+    saving: StateSaving,
+    // Set up through MachineBuilder, see there
+    #[serde(default)]
+    lookahead: Option<Delay>,
+    #[serde(default)]
+    window: Option<Delay>,
+    #[serde(default)]
+    seed: u64,
+    #[serde(default)]
+    ports: Option<BTreeSet<String>>,
+    #[serde(default)]
+    max_pending: Option<usize>,
+    #[serde(default)]
+    direct_cancellation: bool,
+    // What rollbacks took back directly that the runtime still has to take out of the
+    // receivers queues, see set_direct_cancellation
+    #[serde(default)]
+    cancelled: Vec<Message>,
+    // Stragglers that leave the state as it was dont roll back, see set_lazy_reevaluation
+    #[serde(default)]
+    lazy_reevaluation: bool,
+    // When the outgoing links with a bandwidth are free again, see bandwidth.rs
+    #[serde(default)]
+    links: LinkQueues,
+    // Not part of a checkpoint, set them again after restoring one
+    #[serde(skip)]
+    latencies: Option<Arc<Latencies>>,
+    // Undo actions for the side effects of uncommitted events, these cant be saved either
+    // so a restored machine cant undo what ran before the checkpoint
+    #[serde(skip)]
+    compensations: Compensations,
+    // Every shared variable the machine ever read or wrote, see shared.rs
+    #[serde(skip)]
+    shared: Vec<Arc<dyn Versioned>>,
 }
 
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+// How far ahead a machine is allowed to run. Optimistic machines process whatever they
+// have and roll back when they got it wrong, conservative ones only process messages the
+// runtime says are safe (see Simulation::safe_bound) so they never roll back. Useful for
+// machines whose state is expensive to save or whose effects cant be undone.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecutionPolicy {
+    #[default]
+    Optimistic,
+    Conservative,
+}
+
+// How many saved states a machine may keep. Everything below GVT except the newest state
+// before it is never needed again and goes first. If that isnt enough and coast_forward
+// is on the oldest states above GVT are dropped, a rollback to somewhere between two
+// remaining ones then restores the earlier one and runs the process forward over what
+// it had processed in between (throwing away what it sends, that was already sent).
+//
+// With coast_forward off nothing can be dropped and the limit only stalls the machine, it
+// isnt cancelback: a full machine stops taking events (see stalled_on_snapshots) until
+// GVT gets past its states, the same way running out of rollback budget works, and
+// doesnt hand anything back to free them. Simulation commits as soon as everything left
+// is waiting, and the machine holding GVT back always has states below it to drop, so a
+// run there keeps going. A TcpNode only commits after a GVT round someone starts though,
+// so its stalled machines wait for that, and if whatever would start the round waits on
+// them the run deadlocks. A limit below 2 is treated as 2, the state at GVT and the newest.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SnapshotLimits {
+    pub max_saved_states: Option<usize>,
+    pub coast_forward: bool,
+}
+
+impl SnapshotLimits {
+    fn max(&self) -> Option<usize> {
+        self.max_saved_states.map(|max| max.max(2))
+    }
+}
+
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct MachineState {
     local_var1: String,
     local_var2: i32,
@@ -40,14 +149,161 @@ impl MachineState {
     }
 }
 
-// Wrapper to allow sorted order of machine states
-#[derive(Debug, Clone, Eq, PartialEq, Default)]
-pub struct StampedMachineState {
-    machine_state: Option<MachineState>,
-    virtual_time_stamp: VirtualTime,
+// The process the examples use, every message just adds 5 to the state
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ExampleProcess;
+
+impl TimeWarpProcess for ExampleProcess {
+    type State = MachineState;
+
+    fn on_message(&self, state: &mut MachineState, _message: &Message, _ctx: &mut Context) {
+        state.local_var2 += 5;
+        trace_debug!(local_var2 = state.local_var2, "Adding 5 to current state");
+    }
+}
+
+fn dump_message(message: &Message) -> serde_json::Value {
+    let sign = match message.sign {
+        Sign::Message => "message",
+        Sign::Antimessage => "antimessage",
+    };
+    serde_json::json!({
+        "id": message.id,
+        "sender": message.sender,
+        "receiver": message.receiver,
+        "send_time": message.send_time.ticks(),
+        "rec_time": message.rec_time.ticks(),
+        "sign": sign,
+        "payload": message.message.as_str(),
+        "port": message.port.as_deref(),
+    })
+}
+
+// How much a machine is holding on to, see Machine::memory_report. Sizes are estimates:
+// a state counts whatever the process says through state_size, a message its own size
+// plus its payload. Payloads and parts of states are often shared so the real total is
+// usually lower, this is for finding the machines that keep the most around.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryReport {
+    pub saved_states: usize,
+    pub saved_state_bytes: usize,
+    pub queued_inputs: usize,
+    pub queued_input_bytes: usize,
+    pub logged_outputs: usize,
+    pub logged_output_bytes: usize,
+}
+
+impl MemoryReport {
+    pub fn total_bytes(&self) -> usize {
+        self.saved_state_bytes + self.queued_input_bytes + self.logged_output_bytes
+    }
+}
+
+// What a run of events did, see Machine::process_until and Machine::process_n
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    pub events: usize,
+    // Everything the events sent in the order they sent it, still to be delivered
+    pub sent: Vec<Message>,
+    pub stopped: BatchStop,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BatchStop {
+    // Got to the bound or ran as many events as asked
+    #[default]
+    Done,
+    // Nothing left in the input queue
+    Empty,
+    // An antimessage is at the front, waiting for its positive message
+    Antimessage,
+    // Over the rollback budget or out of saved states, see can_execute
+    Held,
+}
+
+// What a machine would do next, see Machine::next_event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NextEvent {
+    Ready(Message),
+    Blocked(BlockReason),
+}
+
+// Why a machine has nothing to process. Neither changes until a message is delivered to
+// it (or GVT passes a lone antimessage and it is thrown away), so runtimes leave blocked
+// machines out until then.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockReason {
+    // Nothing left in the input queue
+    Empty,
+    // The next message is an antimessage still waiting for its positive message. It would
+    // annihilate whatever is processed after it, so nothing is.
+    Antimessage {
+        message_id: MessageId,
+        rec_time: VirtualTime,
+    },
+}
+
+// Why send_outer refused a message. Either would break rolling back, the sends a rollback
+// cancels are the ones logged from the rollback target up to the local virtual time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    // The message would be received before it was sent
+    IntoThePast {
+        send_time: VirtualTime,
+        rec_time: VirtualTime,
+    },
+    // Messages are sent at the local virtual time of the sender, one sent at any other time
+    // wouldnt be cancelled by the rollbacks that should cancel it
+    NotNow {
+        send_time: VirtualTime,
+        lvt: VirtualTime,
+    },
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::IntoThePast { send_time, rec_time } => {
+                write!(f, "message sent at {send_time} would be received earlier, at {rec_time}")
+            }
+            SendError::NotNow { send_time, lvt } => {
+                write!(f, "message sent at {send_time} by a machine at local virtual time {lvt}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+fn message_size(message: &Message) -> usize {
+    std::mem::size_of::<Message>() + message.payload_bytes().len()
+}
+
+// Wrapper to allow sorted order of machine states, like the message wrappers only
+// the time stamp matters when comparing. The stamp is the time of the last message
+// processed before the state was saved, None for a state from before anything was
+// processed, which sorts before every time including 0.
+//
+// The state sits behind an Arc so cloning a snapshot (rolling back, looking at an old
+// state, checkpointing) doesnt copy it. Saving one still clones the live state once for
+// every time the machine has events at, for a large state that is only cheap if most of
+// it is shared, like keeping the big parts behind Arcs themselves and changing them
+// through Arc::make_mut (see the State docs in process.rs).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StampedMachineState<S = MachineState> {
+    machine_state: Option<Arc<S>>,
+    virtual_time_stamp: Option<VirtualTime>,
+}
+
+impl<S> PartialEq for StampedMachineState<S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.virtual_time_stamp == other.virtual_time_stamp
+    }
 }
 
-impl Ord for StampedMachineState {
+impl<S> Eq for StampedMachineState<S> {}
+
+impl<S> Ord for StampedMachineState<S> {
     fn cmp(&self, other: &Self) -> Ordering {
         other
             .virtual_time_stamp
@@ -56,7 +312,7 @@ impl Ord for StampedMachineState {
     }
 }
 
-impl PartialOrd for StampedMachineState {
+impl<S> PartialOrd for StampedMachineState<S> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
@@ -65,145 +321,1302 @@ impl PartialOrd for StampedMachineState {
 impl Machine {
     // In an actual imlementation virtual time could either be assigned by a global management system
     // or just initialized to 0 for all machines
-    pub fn new(machine_id: MachineId, local_virtual_time: VirtualTime) -> Self {
-        let mut self_var = Self {
-            machine_id,
-            local_virtual_time,
-            input_queue: InputQueue::new(local_virtual_time),
+    pub fn new(machine_id: MachineId, local_virtual_time: impl Into<VirtualTime>) -> Self {
+        Machine::builder(machine_id, ExampleProcess)
+            .start_at(local_virtual_time)
+            .build()
+    }
+}
+
+// Everything a machine can be set up with before it starts, from Machine::builder. What
+// isnt set is the same as a machine made with Machine::with_process.
+#[derive(Debug)]
+pub struct MachineBuilder<P: TimeWarpProcess> {
+    machine_id: MachineId,
+    process: P,
+    start: VirtualTime,
+    state: Option<P::State>,
+    policy: ExecutionPolicy,
+    snapshot_limits: SnapshotLimits,
+    saving: StateSaving,
+    rollback_limits: RollbackLimits,
+    throttle: Option<ThrottleLimits>,
+    lookahead: Option<Delay>,
+    window: Option<Delay>,
+    seed: u64,
+    ports: Option<BTreeSet<String>>,
+    max_pending: Option<usize>,
+    fifo: bool,
+    duplicates: DuplicatePolicy,
+    latencies: Option<Arc<Latencies>>,
+}
+
+impl<P: TimeWarpProcess> MachineBuilder<P> {
+    // Messages before the start time count as already processed
+    pub fn start_at(mut self, local_virtual_time: impl Into<VirtualTime>) -> Self {
+        self.start = local_virtual_time.into();
+        self
+    }
+
+    // Starts from this state instead of the default one, it is also the state a rollback
+    // all the way back to the start restores
+    pub fn state(mut self, state: P::State) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    pub fn policy(mut self, policy: ExecutionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // How many states are saved and what happens when there are too many
+    pub fn snapshots(mut self, limits: SnapshotLimits) -> Self {
+        self.snapshot_limits = limits;
+        self
+    }
+
+    // How often to save the state, before every event without one, see saving.rs
+    pub fn save_policy(mut self, policy: Arc<dyn SavePolicy>) -> Self {
+        self.saving = StateSaving::new(Some(policy));
+        self
+    }
+
+    // Narrows how far ahead the machine may run while it keeps getting rolled back, see
+    // throttle.rs
+    pub fn throttle(mut self, limits: ThrottleLimits) -> Self {
+        self.throttle = Some(limits);
+        self
+    }
+
+    pub fn rollback_limits(mut self, limits: RollbackLimits) -> Self {
+        self.rollback_limits = limits;
+        self
+    }
+
+    // Used instead of what the process says, for a process that doesnt know the delays it
+    // will be wired up with. Nothing checks it, a machine sending sooner than its
+    // lookahead can break the conservative runtimes.
+    pub fn lookahead(mut self, lookahead: impl Into<Delay>) -> Self {
+        self.lookahead = Some(lookahead.into());
+        self
+    }
+
+    // How far past its commit horizon (about GVT) the machine may run. An event further
+    // ahead waits until enough has committed, which keeps a fast machine from piling up
+    // work that is likely to be rolled back. None (the default) doesnt hold it back.
+    pub fn optimism_window(mut self, window: impl Into<Delay>) -> Self {
+        self.window = Some(window.into());
+        self
+    }
+
+    // Seeds Context::random
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    // Declares an input port. Once any are declared messages to a port that isnt are
+    // refused and counted in MachineStats::messages_refused, messages without a port
+    // are always taken.
+    pub fn port(mut self, port: &str) -> Self {
+        self.ports.get_or_insert_with(BTreeSet::new).insert(port.to_string());
+        self
+    }
+
+    // High-water mark for messages waiting to be processed. Past it the machine is
+    // flooded, and the runtime holds back the machines that sent what is waiting until
+    // it has caught up (see Simulation::ready), so a fast producer cant bury a slow
+    // consumer in input that will likely be rolled back anyway. None (the default)
+    // doesnt hold anyone back.
+    pub fn max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = Some(max_pending);
+        self
+    }
+
+    pub fn fifo(mut self, fifo: bool) -> Self {
+        self.fifo = fifo;
+        self
+    }
+
+    pub fn duplicates(mut self, duplicates: DuplicatePolicy) -> Self {
+        self.duplicates = duplicates;
+        self
+    }
+
+    pub fn latencies(mut self, latencies: Arc<Latencies>) -> Self {
+        self.latencies = Some(latencies);
+        self
+    }
+
+    pub fn build(self) -> Machine<P> {
+        let start = self.start;
+        let state = self.state.unwrap_or_default();
+        let mut input_queue = InputQueue::new(start.prev());
+        input_queue.set_fifo(self.fifo);
+        input_queue.set_duplicate_policy(self.duplicates);
+        let mut machine = Machine {
+            machine_id: self.machine_id,
+            local_virtual_time: start,
+            process: self.process,
+            input_queue,
             output_queue: OutputQueue::new(),
-            state: MachineState::new(),
+            in_flight: InFlightAntimessages::new(),
+            state: state.clone(),
             state_queue: BTreeSet::new(),
+            stats: MachineStats::new(),
+            commit_horizon: VirtualTime::ZERO,
+            policy: self.policy,
+            storm: StormDetector::default(),
+            budget: RollbackBudget::new(self.rollback_limits),
+            throttle: PenaltyThrottle::new(self.throttle),
+            snapshot_limits: self.snapshot_limits,
+            saving: self.saving,
+            lookahead: self.lookahead,
+            window: self.window,
+            seed: self.seed,
+            ports: self.ports,
+            max_pending: self.max_pending,
+            direct_cancellation: false,
+            cancelled: Vec::new(),
+            lazy_reevaluation: false,
+            links: LinkQueues::default(),
+            latencies: self.latencies,
+            compensations: Compensations::default(),
+            shared: Vec::new(),
         };
-        self_var.state_queue.insert(StampedMachineState {
-            virtual_time_stamp: 0,
-            machine_state: Some(MachineState::new()),
+        machine.state_queue.insert(StampedMachineState {
+            virtual_time_stamp: start.prev(),
+            machine_state: Some(Arc::new(state)),
         });
-        return self_var;
+        machine
+    }
+}
+
+impl<P: TimeWarpProcess> Machine<P> {
+    pub fn builder(machine_id: MachineId, process: P) -> MachineBuilder<P> {
+        MachineBuilder {
+            machine_id,
+            process,
+            start: VirtualTime::ZERO,
+            state: None,
+            policy: ExecutionPolicy::Optimistic,
+            snapshot_limits: SnapshotLimits::default(),
+            saving: StateSaving::default(),
+            rollback_limits: RollbackLimits::default(),
+            throttle: None,
+            lookahead: None,
+            window: None,
+            seed: 0,
+            ports: None,
+            max_pending: None,
+            fifo: false,
+            duplicates: DuplicatePolicy::default(),
+            latencies: None,
+        }
+    }
+
+    pub fn with_process(
+        machine_id: MachineId,
+        local_virtual_time: impl Into<VirtualTime>,
+        process: P,
+    ) -> Self {
+        Machine::builder(machine_id, process).start_at(local_virtual_time).build()
+    }
+
+    // Same as the builder with a start time and state
+    pub fn with_state(
+        machine_id: MachineId,
+        local_virtual_time: impl Into<VirtualTime>,
+        process: P,
+        state: P::State,
+    ) -> Self {
+        Machine::builder(machine_id, process)
+            .start_at(local_virtual_time)
+            .state(state)
+            .build()
+    }
+
+    pub fn machine_id(&self) -> MachineId {
+        self.machine_id
+    }
+
+    pub fn local_virtual_time(&self) -> VirtualTime {
+        self.local_virtual_time
+    }
+
+    // Nothing before this is rolled back anymore, messages for earlier are refused
+    pub fn commit_horizon(&self) -> VirtualTime {
+        self.commit_horizon
+    }
+
+    pub fn process(&self) -> &P {
+        &self.process
+    }
+
+    pub fn stats(&self) -> &MachineStats {
+        &self.stats
+    }
+
+    // Starts the counters over, see Simulation::set_warm_up
+    pub fn reset_stats(&mut self) {
+        self.stats = MachineStats::new();
+    }
+
+    pub fn lookahead(&self) -> Delay {
+        self.lookahead.unwrap_or_else(|| self.process.lookahead())
+    }
+
+    pub fn optimism_window(&self) -> Option<Delay> {
+        self.window
+    }
+
+    // The next event is further past the commit horizon than the optimism window allows,
+    // or the window of a penalty (see throttle.rs), the machine has to wait for commits
+    // to catch up
+    pub fn outside_window(&self) -> bool {
+        let window = match (self.window, self.throttle.window()) {
+            (Some(window), Some(penalty)) => window.min(penalty),
+            (Some(window), None) | (None, Some(window)) => window,
+            (None, None) => return false,
+        };
+        self.peek_next_message()
+            .is_some_and(|next| next.rec_time > self.commit_horizon.saturating_add(window))
+    }
+
+    pub fn max_pending(&self) -> Option<usize> {
+        self.max_pending
+    }
+
+    // With direct cancellation the machine keeps a handle to every message it sends (see
+    // Message::handle) and a rollback flags the messages it takes back as cancelled
+    // instead of making antimessages. Whatever delivers the messages then has to take the
+    // cancelled ones out of the receivers queues right away with Machine::cancel, or drop
+    // them if they werent delivered yet, there is no antimessage to wait for and nothing
+    // in flight to acknowledge. The handle is shared memory, so this only works when the
+    // sender and receiver are in the same process and something hands over the cancelled
+    // messages, Simulation::set_direct_cancellation does. Only what is sent from then on
+    // is cancelled directly.
+    pub fn set_direct_cancellation(&mut self, direct: bool) {
+        self.direct_cancellation = direct;
+    }
+
+    pub fn direct_cancellation(&self) -> bool {
+        self.direct_cancellation
+    }
+
+    // The messages rollbacks cancelled directly since the last call
+    pub fn take_cancelled(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.cancelled)
+    }
+
+    // With lazy re-evaluation a straggler that leaves the state as it was (compared by
+    // hash, see determinism.rs) doesnt roll anything back. Everything processed after it
+    // would run again on the same state and send the same messages, so the machine runs
+    // only the straggler, on the state from just before it, sends what that sends and
+    // jumps straight back to where it was, keeping its saved states and what it sent.
+    // recieve_outer returns the sends in place of the antimessages. A straggler that only
+    // reads the state (a query answered from it, say) then costs one event instead of a
+    // rollback. It is only tried for a positive straggler at a time nothing else was
+    // processed at, where it goes among the others would matter, and not for one that
+    // sends over a link with a bandwidth, sends something that would tie with what the
+    // machine sent after it or arrives in a burst through recieve_batch.
+    pub fn set_lazy_reevaluation(&mut self, lazy: bool) {
+        self.lazy_reevaluation = lazy;
+    }
+
+    pub fn lazy_reevaluation(&self) -> bool {
+        self.lazy_reevaluation
+    }
+
+    pub fn set_max_pending(&mut self, max_pending: Option<usize>) {
+        self.max_pending = max_pending;
+    }
+
+    // More messages are waiting than MachineBuilder::max_pending allows
+    pub fn flooded(&self) -> bool {
+        self.max_pending
+            .is_some_and(|max| self.input_queue.pending().take(max + 1).count() > max)
+    }
+
+    // The other machines that sent what a flooded machine has waiting, nobody when it
+    // isnt flooded
+    pub fn flooding_senders(&self) -> BTreeSet<MachineId> {
+        if !self.flooded() {
+            return BTreeSet::new();
+        }
+        self.input_queue
+            .pending()
+            .map(|message| message.sender)
+            .filter(|sender| *sender != self.machine_id)
+            .collect()
+    }
+
+    // Whether the machine takes messages on the port (see MachineBuilder::port)
+    pub fn accepts_port(&self, port: Option<&str>) -> bool {
+        match (&self.ports, port) {
+            (Some(ports), Some(port)) => ports.contains(port),
+            _ => true,
+        }
+    }
+
+    // The earliest time this machine could still send a message at, everything it has
+    // queued is handled at its receive time or later and sends at least lookahead after
+    pub fn earliest_send(&self) -> Option<VirtualTime> {
+        let next = self.peek_next_message()?;
+        Some(next.rec_time.saturating_add(self.lookahead()))
+    }
+
+    pub fn policy(&self) -> ExecutionPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: ExecutionPolicy) {
+        self.policy = policy;
+    }
+
+    // The policy the machine is actually running with right now, an optimistic machine
+    // caught in a rollback storm runs conservatively until it makes progress again
+    pub fn effective_policy(&self) -> ExecutionPolicy {
+        if self.storm.is_throttled() {
+            ExecutionPolicy::Conservative
+        } else {
+            self.policy
+        }
+    }
+
+    pub fn set_rollback_limits(&mut self, limits: RollbackLimits) {
+        self.budget = RollbackBudget::new(limits);
+    }
+
+    pub fn rollback_budget(&self) -> &RollbackBudget {
+        &self.budget
+    }
+
+    // None turns the throttle off
+    pub fn set_throttle_limits(&mut self, limits: Option<ThrottleLimits>) {
+        self.throttle = PenaltyThrottle::new(limits);
+    }
+
+    pub fn throttle(&self) -> &PenaltyThrottle {
+        &self.throttle
+    }
+
+    pub fn set_snapshot_limits(&mut self, limits: SnapshotLimits) {
+        self.snapshot_limits = limits;
+    }
+
+    pub fn snapshot_limits(&self) -> SnapshotLimits {
+        self.snapshot_limits
+    }
+
+    // None goes back to saving before every event
+    pub fn set_save_policy(&mut self, policy: Option<Arc<dyn SavePolicy>>) {
+        self.saving = StateSaving::new(policy);
+    }
+
+    pub fn saving(&self) -> &StateSaving {
+        &self.saving
+    }
+
+    // The machine cant save another state without going over its limit and cant drop any
+    // either, it stalls until some commit (see SnapshotLimits)
+    pub fn stalled_on_snapshots(&self) -> bool {
+        !self.snapshot_limits.coast_forward
+            && self
+                .snapshot_limits
+                .max()
+                .is_some_and(|max| self.state_queue.len() >= max)
+    }
+
+    // Events processed that havent been committed yet, what a rollback could still undo
+    pub fn uncommitted(&self) -> usize {
+        self.input_queue.count_processed(self.commit_horizon, None)
+    }
+
+    // The machine has as much uncommitted work as its rollback budget allows, it has to
+    // wait for some of it to commit before going on. Not when the next event is at the
+    // commit horizon though: of several events at that time the ones already processed
+    // only commit once the rest ran, so waiting would never end, and nothing anywhere is
+    // earlier than it anyway.
+    pub fn over_budget(&self) -> bool {
+        !self.budget.allows(self.uncommitted())
+            && self.peek_next_message().is_none_or(|next| next.rec_time > self.commit_horizon)
+    }
+
+    // Latency models the process can sample through Context::link_delay
+    pub fn set_latencies(&mut self, latencies: Arc<Latencies>) {
+        self.latencies = Some(latencies);
+    }
+
+    pub fn latencies(&self) -> Option<&Arc<Latencies>> {
+        self.latencies.as_ref()
+    }
+
+    // Gives up the machine, keeping only its process and current state
+    pub fn into_process(self) -> (P, P::State) {
+        (self.process, self.state)
+    }
+
+    pub fn storm_detector(&self) -> &StormDetector {
+        &self.storm
+    }
+
+    // How many rollbacks in a row without committing anything count as a storm, None
+    // turns detection off
+    pub fn set_storm_threshold(&mut self, threshold: Option<u64>) {
+        self.storm.set_threshold(threshold);
+    }
+
+    // The message the machine would process next, or why there isnt one. Being over its
+    // budget or out of saved states doesnt count as blocked, see can_execute for those.
+    pub fn next_event(&self) -> NextEvent {
+        match self.peek_next_message() {
+            None => NextEvent::Blocked(BlockReason::Empty),
+            Some(next) if next.sign == Sign::Antimessage => {
+                NextEvent::Blocked(BlockReason::Antimessage {
+                    message_id: next.id,
+                    rec_time: next.rec_time,
+                })
+            }
+            Some(next) => NextEvent::Ready(next),
+        }
+    }
+
+    // Nothing to do until more input arrives, the input queue is used up or starts with an
+    // antimessage waiting for its positive message. Unlike can_execute this only changes
+    // when a message is delivered, so a runtime can park the machine until then.
+    pub fn is_blocked(&self) -> bool {
+        matches!(self.next_event(), NextEvent::Blocked(_))
+    }
+
+    // Time of the earliest message still to be processed, not counting antimessages. An
+    // antimessage on its own never leads to anything, its positive message is still on the
+    // way and counted wherever it is, so GVT can be worked out from this instead of
+    // peek_next_message and move past an antimessage whose positive is never coming.
+    pub fn earliest_pending(&self) -> Option<VirtualTime> {
+        self.input_queue.peek_positive().map(|message| message.rec_time)
+    }
+
+    // Whether the machine has a message it is allowed to process, given that nothing
+    // earlier than safe_bound can still arrive (None meaning nothing can arrive at all)
+    // and that it hasnt used up its rollback budget
+    pub fn can_execute(&self, safe_bound: Option<VirtualTime>) -> bool {
+        let Some(next) = self.peek_next_message() else {
+            return false;
+        };
+        next.sign == Sign::Message
+            && match (self.effective_policy(), safe_bound) {
+                (ExecutionPolicy::Conservative, Some(bound)) => next.rec_time <= bound,
+                _ => true,
+            }
+            && !self.over_budget()
+            && !self.stalled_on_snapshots()
+            && !self.outside_window()
+    }
+
+    // Counts everything processed below GVT as committed, it can never be rolled back
+    // anymore. No GVT means there is nothing left anywhere that could cause a rollback.
+    // Returns the messages of the newly committed events in the order they were processed.
+    // Antimessages GVT got past without their positive message are thrown away.
+    pub fn commit(&mut self, gvt: Option<VirtualTime>) -> Vec<Message> {
+        let orphans = self.input_queue.discard_antimessages_below(gvt);
+        if orphans > 0 {
+            self.stats.antimessages_discarded += orphans as u64;
+            trace_warn!(
+                machine_id = self.machine_id,
+                orphans,
+                "Discarded antimessages whose positive message never arrived"
+            );
+        }
+        let newly_committed: Vec<_> = self
+            .input_queue
+            .processed_from(self.commit_horizon, gvt)
+            .cloned()
+            .collect();
+        self.stats.events_committed += newly_committed.len() as u64;
+        if !newly_committed.is_empty() {
+            self.storm.record_progress();
+            self.budget.record_progress();
+            self.throttle.record_progress(newly_committed.len());
+        }
+        self.commit_horizon = match gvt {
+            Some(gvt) => gvt.max(self.commit_horizon),
+            None => self.local_virtual_time.next(),
+        };
+        // Nothing sent before the horizon can be cancelled anymore
+        self.output_queue.truncate_below(self.commit_horizon);
+        self.links.commit(self.commit_horizon);
+        self.compensations.commit(self.commit_horizon);
+        for var in &self.shared {
+            var.commit(self.commit_horizon);
+        }
+        if self.snapshot_limits.max_saved_states.is_some() {
+            self.drop_committed_states();
+        }
+        self.collect_processed();
+        newly_committed
+    }
+
+    // The machine as one JSON document, for bug reports and vtw inspect --json: id, LVT,
+    // commit horizon, state, the times of the saved states (null for the one from
+    // before the first event) and every message in the input queue, the output queue
+    // and the antimessages still in flight. Messages are flattened to what a person
+    // reading it wants, the process itself and the settings are left out.
+    pub fn dump(&self) -> serde_json::Value
+    where
+        P::State: Serialize,
+    {
+        let snapshots: Vec<_> = self
+            .state_queue
+            .iter()
+            .map(|saved| saved.virtual_time_stamp.map(VirtualTime::ticks))
+            .collect();
+        let input_queue: Vec<_> = self
+            .input_queue
+            .iter()
+            .map(|message| {
+                let mut dumped = dump_message(message);
+                dumped["processed"] = self.input_queue.is_processed(message).into();
+                dumped
+            })
+            .collect();
+        serde_json::json!({
+            "id": self.machine_id,
+            "lvt": self.local_virtual_time.ticks(),
+            "commit_horizon": self.commit_horizon.ticks(),
+            "state": self.state,
+            "snapshots": snapshots,
+            "input_queue": input_queue,
+            "output_queue": self.output_queue.iter().map(dump_message).collect::<Vec<_>>(),
+            "in_flight": self.in_flight.pending().map(dump_message).collect::<Vec<_>>(),
+        })
+    }
+
+    // What the machine is keeping around, saved states and both queues
+    pub fn memory_report(&self) -> MemoryReport {
+        let saved_state_bytes = self
+            .state_queue
+            .iter()
+            .filter_map(|saved| saved.machine_state.as_deref())
+            .map(|state| self.process.state_size(state))
+            .sum();
+        MemoryReport {
+            saved_states: self.state_queue.len(),
+            saved_state_bytes,
+            queued_inputs: self.input_queue.len(),
+            queued_input_bytes: self.input_queue.iter().map(message_size).sum(),
+            logged_outputs: self.output_queue.len(),
+            logged_output_bytes: self.output_queue.iter().map(message_size).sum(),
+        }
+    }
+
+    // The saved state closest to (at or before) vt along with the time it was saved at
+    // (see StampedMachineState), that state already includes everything processed up to
+    // that time. None if vt is older than anything still saved.
+    pub fn snapshot_at(
+        &self,
+        vt: impl Into<VirtualTime>,
+    ) -> Option<(Option<VirtualTime>, Arc<P::State>)> {
+        let vt = vt.into();
+        let processed = self.input_queue.threshold();
+        if processed.is_none_or(|processed| vt >= processed) {
+            return Some((processed, Arc::new(self.state.clone())));
+        }
+        let snapshot = self
+            .state_queue
+            .range(
+                ..=StampedMachineState {
+                    machine_state: None,
+                    virtual_time_stamp: Some(vt),
+                },
+            )
+            .next_back()?;
+        Some((snapshot.virtual_time_stamp, snapshot.machine_state.clone()?))
+    }
+
+    // What the state was at vt, after every message up to and including vt was processed.
+    // Starts from the closest saved state and coasts forward by running the process again
+    // on a copy over the messages between the two. Whatever the process sends while
+    // coasting is thrown away, the live machine isnt touched. Times past the local virtual
+    // time give the current state since nothing after it has been processed yet.
+    pub fn state_at(&self, vt: impl Into<VirtualTime>) -> Option<P::State> {
+        let vt = vt.into();
+        let (saved_at, state) = self.snapshot_at(vt)?;
+        let mut state = Arc::unwrap_or_clone(state);
+        self.coast(&mut state, saved_at, vt);
+        Some(state)
+    }
+
+    // The state right after each message processed at vt, in the order they were
+    // processed. state_at only has the one after all of them, which is what every one of
+    // several events at the same time would see otherwise.
+    pub fn states_at(&self, vt: impl Into<VirtualTime>) -> Option<Vec<P::State>> {
+        let vt = vt.into();
+        let (saved_at, state) = match vt.prev() {
+            Some(before) => self.snapshot_at(before)?,
+            None => {
+                let start = self.state_queue.first()?;
+                if start.virtual_time_stamp.is_some() {
+                    return None;
+                }
+                (None, start.machine_state.clone()?)
+            }
+        };
+        let mut state = Arc::unwrap_or_clone(state);
+        if let Some(before) = vt.prev() {
+            self.coast(&mut state, saved_at, before);
+        }
+        let states = self
+            .input_queue
+            .processed_between(vt.prev(), vt)
+            .iter()
+            .map(|message| {
+                self.replay(&mut state, message);
+                state.clone()
+            })
+            .collect();
+        Some(states)
+    }
+
+    // A new machine starting at vt from the state this one had just before it, with the
+    // same process and settings and empty queues. None if that state is gone.
+    pub fn fork_at(&self, vt: impl Into<VirtualTime>) -> Option<Self>
+    where
+        P: Clone,
+    {
+        let vt = vt.into();
+        let state = match vt.prev() {
+            Some(before) => self.state_at(before)?,
+            None => {
+                let start = self.state_queue.first()?;
+                if start.virtual_time_stamp.is_some() {
+                    return None;
+                }
+                P::State::clone(start.machine_state.as_ref()?)
+            }
+        };
+        let mut fork = Self::with_state(self.machine_id, vt, self.process.clone(), state);
+        fork.policy = self.policy;
+        fork.input_queue.set_fifo(self.input_queue.is_fifo());
+        fork.input_queue.set_duplicate_policy(self.input_queue.duplicate_policy());
+        fork.budget = RollbackBudget::new(self.budget.limits());
+        fork.throttle = PenaltyThrottle::new(self.throttle.limits());
+        fork.storm = StormDetector::new(self.storm.threshold());
+        fork.snapshot_limits = self.snapshot_limits;
+        fork.saving = StateSaving::new(self.saving.policy().cloned());
+        fork.lookahead = self.lookahead;
+        fork.window = self.window;
+        fork.seed = self.seed;
+        fork.ports = self.ports.clone();
+        fork.max_pending = self.max_pending;
+        fork.direct_cancellation = self.direct_cancellation;
+        fork.lazy_reevaluation = self.lazy_reevaluation;
+        fork.latencies = self.latencies.clone();
+        // The links are as busy as what was sent before vt made them
+        fork.links = self.links.clone();
+        fork.links.roll_back(vt);
+        fork.commit_horizon = vt;
+        Some(fork)
+    }
+
+    // Runs the process over the processed messages after `after` and up to `up_to`,
+    // returns the time of the last one if there were any
+    fn coast(
+        &self,
+        state: &mut P::State,
+        after: Option<VirtualTime>,
+        up_to: VirtualTime,
+    ) -> Option<VirtualTime> {
+        let mut last = None;
+        for message in self.input_queue.processed_between(after, up_to) {
+            self.replay(state, &message);
+            last = Some(message.rec_time);
+        }
+        last
+    }
+
+    fn replay(&self, state: &mut P::State, message: &Message) {
+        let mut ctx = self.context(message).replaying();
+        self.process.on_message(state, message, &mut ctx);
+    }
+
+    fn context(&self, message: &Message) -> Context {
+        Context::new(self.machine_id, message.rec_time)
+            .with_latencies(self.latencies.clone())
+            .with_event(message)
+            .with_seed(self.seed)
+            .with_position(self.input_queue.position(message))
+    }
+
+    // Nothing can roll back to before the commit horizon, so of the states saved before
+    // it only the newest has to stay
+    fn drop_committed_states(&mut self) {
+        let horizon = StampedMachineState {
+            machine_state: None,
+            virtual_time_stamp: Some(self.commit_horizon),
+        };
+        if let Some(base) = self.state_queue.range(..&horizon).next_back().cloned() {
+            self.state_queue = self.state_queue.split_off(&base);
+        }
     }
+
+    // Drops the processed messages from before the commit horizon that even the oldest
+    // saved state is past, nothing rolls back or coasts over them anymore. Without
+    // SnapshotLimits the state from the start stays (state_at and fork_at can still go
+    // back that far) so every message does too.
+    fn collect_processed(&mut self) {
+        let Some(oldest) = self.state_queue.first().and_then(|saved| saved.virtual_time_stamp)
+        else {
+            return;
+        };
+        self.input_queue.truncate_below(oldest.next().min(self.commit_horizon));
+    }
+
+    // Keeps the saved states within SnapshotLimits, dropping the oldest ones above the
+    // commit horizon when coasting forward is allowed. The first state is the one at the
+    // horizon and the last the one just saved, those always stay.
+    fn evict_states(&mut self) {
+        let Some(max) = self.snapshot_limits.max() else {
+            return;
+        };
+        if self.state_queue.len() <= max {
+            return;
+        }
+        self.drop_committed_states();
+        if !self.snapshot_limits.coast_forward {
+            return;
+        }
+        while self.state_queue.len() > max {
+            let oldest_above = self.state_queue.iter().nth(1).cloned().unwrap();
+            self.state_queue.remove(&oldest_above);
+            self.stats.states_evicted += 1;
+        }
+    }
+
+    // The message that would be processed next, if it is an antimessage the machine
+    // wont process anything until its positive message shows up
+    pub fn peek_next_message(&self) -> Option<Message> {
+        self.input_queue.peek_smallest_greater()
+    }
+
     // This function receives the messages and puts them in the input queue so
     // that they are ready to be processed by the inner function. If a message is
     // received with a lower receive time than self.virtualtime then we have missed 
-    // the point in virtual time this message should have been received an rollback
-    pub fn recieve_outer(&mut self, message: Message) -> Option<Vec<Message>> {
-        if message.rec_time >= self.local_virtual_time {
-            self.input_queue.insert(message);
+    // the point in virtual time this message should have been received an rollback.
+    // The same goes for a message at the local virtual time that goes before one already
+    // processed at that time (see InputQueue for the order) and for an antimessage of a
+    // processed message, just cancelling that would keep its effects. Rolling back always
+    // undoes everything at the time of the message, all of it is processed again.
+    //
+    // Nothing can arrive before GVT, so a message before the commit horizon means whatever
+    // sent it is broken. The states from back then are gone, so it is dropped (and counted
+    // in MachineStats::messages_past_gvt) instead of rolling back into committed history.
+    pub fn recieve_outer(&mut self, mut message: Message) -> Option<Vec<Message>> {
+        if !self.admits(&message) {
             return None;
+        }
+        self.input_queue.fifo_stamp(&mut message);
+        if !self.input_queue.is_processed(&message) {
+            self.enqueue(message);
+            None
+        } else if let Some(sent) = self.jump_forward(&message) {
+            self.enqueue(message);
+            Some(sent)
         } else {
-            // Rollback:
-            // 1: find the most recent correct state and restore it
-            // 2: discard unnecessary states
-            // 3: "unsend" messages that have already been sent
-            // 4: update the local_virtual_time
-            // 5: insert the message
-
-            // 1
-            let rollback_target = message.rec_time;
-            let threshold = StampedMachineState {
-                machine_state: None,
-                virtual_time_stamp: rollback_target,
-            };
-            let most_recent_state = self
-                .state_queue
-                .range((
-                    Included(&StampedMachineState {
-                        machine_state: None,
-                        virtual_time_stamp: 0,
-                    }),
-                    Excluded(&threshold),
-                ))
-                .next()
-                .unwrap()
-                .clone();
-            self.state = most_recent_state.machine_state.unwrap();
-            // 2
-            let states_to_delete: Vec<_> = self
-                .state_queue
-                .range(
-                    StampedMachineState {
-                        virtual_time_stamp: rollback_target,
-                        machine_state: None,
-                    }..=StampedMachineState {
-                        virtual_time_stamp: self.local_virtual_time,
-                        machine_state: None,
-                    },
-                )
-                .cloned()
-                .collect();
-            for state in states_to_delete {
-                self.state_queue.remove(&state);
-            }
-            // 3
-            let sent_antimessages: Vec<_> = self
-                .output_queue
-                .range(rollback_target, self.local_virtual_time).iter()
-                .map(|message| {
-                    // Create a new message with the sign modified to Antimessage
-                    let mut modified_message = message.clone();
-                    modified_message.sign = Sign::Antimessage;
-
-                    // Send the modified message
-                    self.send_outer(modified_message)
-                })
-                .collect();
+            // Rollback to just before the message, then put it in the queue
+            let sent_antimessages = self.roll_back_for(&message);
+            self.enqueue(message);
 
-            // 4
-            self.local_virtual_time = rollback_target;
-            // offset by one to allow next call of get_next_message to get this message
-            self.input_queue.update_threshold(rollback_target - 1);
+            Some(sent_antimessages)
+        }
+    }
 
-            // 5
-            self.input_queue.insert(message);
+    // Same as calling recieve_outer for every message, but for a burst (everything a
+    // transport got in one read say) it rolls back at most once, to the earliest
+    // straggler, and the queue takes the whole lot in one go (see InputQueue::insert_batch).
+    // A FIFO machine restamps every message against the ones before it, so it still
+    // receives them one at a time.
+    pub fn recieve_batch(
+        &mut self,
+        messages: impl IntoIterator<Item = Message>,
+    ) -> Option<Vec<Message>> {
+        let mut batch: Vec<_> =
+            messages.into_iter().filter(|message| self.admits(message)).collect();
+        if self.input_queue.is_fifo() {
+            let antimessages: Vec<_> = batch
+                .into_iter()
+                .filter_map(|message| self.recieve_outer(message))
+                .flatten()
+                .collect();
+            return (!antimessages.is_empty()).then_some(antimessages);
+        }
+        InputQueue::sort_batch(&mut batch);
+        let sent_antimessages = match batch.first() {
+            Some(first) if self.input_queue.is_processed(first) => {
+                let first = first.clone();
+                Some(self.roll_back_for(&first))
+            }
+            _ => None,
+        };
+        for _error in self.input_queue.insert_batch(batch).refused {
+            self.stats.messages_refused += 1;
+            trace_warn!(
+                machine_id = self.machine_id,
+                error = %_error,
+                "Input queue refused a message"
+            );
+        }
+        sent_antimessages
+    }
 
-            return Some(sent_antimessages);
+    // Whether a message gets into the input queue at all, see recieve_outer. What doesnt
+    // is counted.
+    fn admits(&mut self, message: &Message) -> bool {
+        if !self.accepts_port(message.port()) {
+            self.stats.messages_refused += 1;
+            trace_warn!(
+                machine_id = self.machine_id,
+                message_id = message.id,
+                port = message.port(),
+                "Refused a message to an undeclared port"
+            );
+            return false;
         }
+        if message.rec_time < self.commit_horizon {
+            self.stats.messages_past_gvt += 1;
+            trace_warn!(
+                machine_id = self.machine_id,
+                message_id = message.id,
+                rec_time = %message.rec_time,
+                commit_horizon = %self.commit_horizon,
+                "Dropped a message from before the commit horizon"
+            );
+            return false;
+        }
+        true
     }
 
-    // Helper function to get a function from the input queue while updating the necessary variables
-    fn get_next_message(&mut self) -> Option<Message> {
-        let message = self.input_queue.peek_smallest_greater().unwrap();
+    // Rolls back to the time of a straggler and keeps the books on it
+    fn roll_back_for(&mut self, message: &Message) -> Vec<Message> {
+        let _span = trace_span!(
+            "rollback",
+            machine_id = self.machine_id,
+            lvt = %self.local_virtual_time,
+            message_id = message.id,
+            target = %message.rec_time
+        );
+        let ahead = self.local_virtual_time.saturating_since(self.commit_horizon);
+        let (depth, sent_antimessages) = self.roll_back(message.rec_time);
+        self.budget.record_rollback(depth);
+        self.penalize(ahead);
         if message.sign == Sign::Antimessage {
+            self.stats.cascading_rollbacks += 1;
+        }
+        if self.storm.record_rollback() {
+            self.stats.storms += 1;
+            trace_warn!(
+                machine_id = self.machine_id,
+                rollbacks = self.storm.rollbacks_since_progress(),
+                "Rollback storm, throttling until something commits"
+            );
+        }
+        if self.policy == ExecutionPolicy::Conservative && depth > 0 {
+            trace_warn!(
+                machine_id = self.machine_id,
+                depth,
+                "Conservative machine rolled back, a message arrived below the safe bound"
+            );
+        }
+        sent_antimessages
+    }
+
+    // Runs a straggler without rolling back if that is all it takes, see
+    // set_lazy_reevaluation. Returns what it sent, None if the machine has to roll back.
+    fn jump_forward(&mut self, message: &Message) -> Option<Vec<Message>> {
+        let rec_time = message.rec_time;
+        if !self.lazy_reevaluation
+            || message.sign == Sign::Antimessage
+            || self.input_queue.contains(message)
+            || self.input_queue.count_processed(rec_time, Some(rec_time.next())) > 0
+        {
             return None;
         }
-        self.state_queue.insert(StampedMachineState {
-            machine_state: Some(self.state.clone()),
-            virtual_time_stamp: self.local_virtual_time,
+        let before = self.state_at(rec_time.prev()?)?;
+        let mut after = before.clone();
+        let mut ctx = self.context(message).replaying();
+        self.process.on_message(&mut after, message, &mut ctx);
+        if state_hash(&after) != state_hash(&before) {
+            return None;
+        }
+        // When a link with a bandwidth gets a message across depends on what went first.
+        // And messages from one sender at the same time go by id (see InputQueue), what
+        // the events after the straggler sent is older than what it sends now, so none of
+        // it may tie with that.
+        let kept: Vec<_> =
+            self.output_queue.iter().filter(|kept| kept.send_time > rec_time).collect();
+        let blocked = ctx.into_outbox().iter().any(|sent| {
+            self.bandwidth_to(sent.receiver).is_some()
+                || kept.iter().any(|kept| {
+                    (kept.receiver, kept.rec_time, kept.generation)
+                        == (sent.receiver, sent.rec_time, sent.generation)
+                })
         });
+        if blocked {
+            return None;
+        }
+        // Again for real, the replay didnt have any side effects or shared reads
+        let mut after = before;
+        let mut ctx = self.context(message);
+        self.process.on_message(&mut after, message, &mut ctx);
+        let jumped = self.input_queue.count_processed(rec_time, None);
+        self.stats.events_processed += 1;
+        self.stats.jumps += 1;
+        self.stats.events_jumped += jumped as u64;
+        self.throttle.record_event();
+        trace_debug!(
+            machine_id = self.machine_id,
+            message_id = message.id,
+            rec_time = %rec_time,
+            jumped,
+            "Straggler left the state as it was, jumped forward instead of rolling back"
+        );
+        Some(self.keep_results(ctx))
+    }
+
+    fn penalize(&mut self, ahead: Delay) {
+        if self.throttle.record_rollback(ahead) {
+            self.stats.throttles += 1;
+            trace_warn!(
+                machine_id = self.machine_id,
+                window = ?self.throttle.window(),
+                "Rolled back too often, narrowing how far ahead the machine may run"
+            );
+        }
+    }
+
+    // Takes a message its sender cancelled directly (see set_direct_cancellation) out of
+    // the input queue, rolling back first if it was processed. Returns the antimessages
+    // like recieve_outer, None if there was no rollback.
+    pub fn cancel(&mut self, message: &Message) -> Option<Vec<Message>> {
+        // Looked up like its antimessage would be, a FIFO queue may have moved it
+        let mut cancelled = message.clone();
+        cancelled.sign = Sign::Antimessage;
+        self.input_queue.fifo_stamp(&mut cancelled);
+        if !self.input_queue.contains(&cancelled) {
+            return None;
+        }
+        let antimessages = self
+            .input_queue
+            .is_processed(&cancelled)
+            .then(|| self.roll_back_for(&cancelled));
+        self.input_queue.remove(&cancelled);
+        antimessages
+    }
+
+    // Messages the input queue refuses (see InsertError) are dropped and counted
+    fn enqueue(&mut self, message: Message) {
+        if let Err(_error) = self.input_queue.insert(message) {
+            self.stats.messages_refused += 1;
+            trace_warn!(
+                machine_id = self.machine_id,
+                error = %_error,
+                "Input queue refused a message"
+            );
+        }
+    }
+
+    // Rolls back to the earliest read of a shared variable that a write or a rollback on
+    // another machine made stale, returns the antimessages like recieve_outer. None if
+    // none of its reads are.
+    pub fn roll_back_stale_reads(&mut self) -> Option<Vec<Message>> {
+        let from = self.shared.iter().filter_map(|var| var.stale_since(self.machine_id)).min()?;
+        let _span = trace_span!("rollback", machine_id = self.machine_id, target = %from);
+        let ahead = self.local_virtual_time.saturating_since(self.commit_horizon);
+        let (depth, antimessages) = self.roll_back(from);
+        self.budget.record_rollback(depth);
+        self.penalize(ahead);
+        Some(antimessages)
+    }
+
+    // The machine fails and loses everything that wasnt committed yet, it goes back to
+    // its state at the commit horizon (the last committed snapshot). Returns the
+    // antimessages for whatever the lost work sent. The messages it received are kept,
+    // as if they were logged somewhere that survives the crash, and get processed again.
+    pub fn crash(&mut self) -> Vec<Message> {
+        self.stats.crashes += 1;
+        let target = self.commit_horizon;
+        if self.input_queue.threshold().is_none_or(|processed| processed < target) {
+            return Vec::new();
+        }
+        let _span = trace_span!("crash", machine_id = self.machine_id, target = %target);
+        let (_, antimessages) = self.roll_back(target);
+        antimessages
+    }
+
+    // Undoes everything processed at or after rollback_target, returns how many events
+    // were undone and the antimessages for what they sent
+    fn roll_back(&mut self, rollback_target: VirtualTime) -> (usize, Vec<Message>) {
+        // Rollback:
+        // 1: find the most recent correct state and restore it
+        // 2: discard unnecessary states
+        // 3: "unsend" messages that have already been sent
+        // 4: update the local_virtual_time to the time of the restored state
+
+        // 1
+        let threshold = StampedMachineState {
+            machine_state: None,
+            virtual_time_stamp: Some(rollback_target),
+        };
+        // Anything before the machines start time is the same as rolling back to the start
+        let most_recent_state = self
+            .state_queue
+            .range(..&threshold)
+            .next_back()
+            .or_else(|| self.state_queue.first())
+            .unwrap()
+            .clone();
+        // A state is saved just before processing a message and stamped with the time of
+        // the message before it, so this is the time of the last message still processed
+        let mut restored_time = most_recent_state.virtual_time_stamp;
+        self.state = P::State::clone(most_recent_state.machine_state.as_ref().unwrap());
+        // An evicted state in between means coasting forward to just before the target
+        if let Some(before_target) = rollback_target.prev() {
+            let mut state = std::mem::take(&mut self.state);
+            if let Some(coasted) = self.coast(&mut state, restored_time, before_target) {
+                restored_time = Some(coasted);
+            }
+            self.state = state;
+        }
+        self.stats.compensations += self.compensations.roll_back(rollback_target) as u64;
+        self.links.roll_back(rollback_target);
+        for var in &self.shared {
+            var.roll_back(self.machine_id, rollback_target);
+        }
+        // 2
+        self.state_queue.split_off(&threshold);
+        // Only missing when it was the start state, which has to stay
+        self.state_queue.insert(most_recent_state);
+        // 3
+        let mut sent_antimessages = Vec::new();
+        for message in self.output_queue.cancel_range(rollback_target, self.local_virtual_time) {
+            if let Some(handle) = &message.handle {
+                handle.cancel();
+                self.stats.messages_cancelled += 1;
+                self.cancelled.push(message);
+                continue;
+            }
+            // Create a new message with the sign modified to Antimessage
+            let mut antimessage = message;
+            antimessage.sign = Sign::Antimessage;
+
+            // Remember it is out there until the receiver acknowledges it
+            self.in_flight.record(antimessage.clone());
+            sent_antimessages.push(antimessage);
+        }
+
+        let depth = self
+            .input_queue
+            .count_processed(restored_time.map_or(VirtualTime::ZERO, VirtualTime::next), None);
+        self.stats.record_rollback(depth, sent_antimessages.len());
+        self.saving.rolled_back(depth);
+        trace_debug!(
+            restored_time = ?restored_time,
+            depth,
+            antimessages = sent_antimessages.len(),
+            "Rolled back"
+        );
+
+        // 4
+        self.local_virtual_time = restored_time.unwrap_or_default();
+        // everything after the restored time has to be processed again
+        self.input_queue.update_threshold(restored_time);
+
+        (depth, sent_antimessages)
+    }
+
+    // Helper function to get a function from the input queue while updating the necessary variables
+    fn get_next_message(&mut self) -> NextEvent {
+        let message = match self.next_event() {
+            NextEvent::Ready(message) => message,
+            blocked => return blocked,
+        };
+        // Rollbacks go back to the start of a time, so only the state before the first
+        // message at each time is needed, and with a SavePolicy only every so often
+        let processed = self.input_queue.threshold();
+        if self.saving.next_event() && processed != Some(message.rec_time) {
+            self.state_queue.insert(StampedMachineState {
+                machine_state: Some(Arc::new(self.state.clone())),
+                virtual_time_stamp: processed,
+            });
+            self.stats.states_saved += 1;
+            let state_bytes =
+                self.saving.wants_state_size().then(|| self.process.state_size(&self.state));
+            self.saving.saved(state_bytes);
+            self.evict_states();
+        }
         // sanity check
         if self.local_virtual_time > message.rec_time {
             panic!("Messages in input queue should always be valid");
         }
         self.local_virtual_time = message.rec_time;
-        self.input_queue.update_threshold(message.rec_time);
+        self.input_queue.advance_to(&message);
 
-        Some(message)
+        NextEvent::Ready(message)
     }
     // This is where the machine actually operates on the messages its receiving and
     // executes any logic that it wants to
 
     // Normally this could be private since the machine would just process
     // messages from its queues whenever, for demonstration its public for manual control
-    pub fn recieve_inner(&mut self) {
+    // Returns the messages the process sent while handling the message, they have
+    // already been logged in the output queue and just need to be delivered
+    pub fn recieve_inner(&mut self) -> Vec<Message> {
         let message = match self.get_next_message() {
-            Some(msg) => msg,
-            None => {
-                println!("Found an antimessage, skipping processing since it would guarentee a rollback");
-                return;
-            },
+            NextEvent::Ready(message) => message,
+            NextEvent::Blocked(BlockReason::Antimessage { .. }) => {
+                trace_debug!(
+                    machine_id = self.machine_id,
+                    lvt = %self.local_virtual_time,
+                    "Blocked by an antimessage, processing past it would guarentee a rollback"
+                );
+                return Vec::new();
+            }
+            NextEvent::Blocked(BlockReason::Empty) => return Vec::new(),
         };
-        
-        println!("Received message : {:?}", message);
 
-        self.state.local_var2 += 5;
-        println!(
-            "Adding 5 to current state, now at : {}",
-            self.state.local_var2
-        )
+        let _span = trace_span!(
+            "event",
+            machine_id = self.machine_id,
+            lvt = %self.local_virtual_time,
+            message_id = message.id
+        );
+        trace_debug!(sender = message.sender, send_time = %message.send_time, "Received message");
+        self.stats.events_processed += 1;
+        self.throttle.record_event();
+
+        let mut ctx = self.context(&message);
+        self.process.on_message(&mut self.state, &message, &mut ctx);
+        self.keep_results(ctx)
+    }
+
+    // Keeps what an event did besides changing the state and logs what it sent in the
+    // output queue
+    fn keep_results(&mut self, mut ctx: Context) -> Vec<Message> {
+        let lookahead = self.lookahead();
+        for var in ctx.take_shared() {
+            if !self.shared.iter().any(|touched| Arc::ptr_eq(touched, &var)) {
+                self.shared.push(var);
+            }
+        }
+        let now = ctx.now();
+        let (outbox, compensations) = ctx.into_parts();
+        self.compensations.add(now, compensations);
+        outbox
+            .into_iter()
+            .map(|sent| {
+                if sent.rec_time < sent.send_time + lookahead {
+                    self.stats.sends_under_lookahead += 1;
+                    trace_warn!(
+                        receiver = sent.receiver,
+                        rec_time = %sent.rec_time,
+                        %lookahead,
+                        "Message sent with less delay than the declared lookahead"
+                    );
+                }
+                let sent = self.queue_on_link(sent);
+                // Context::send only makes messages from the event into the future, which
+                // is before now for a straggler jumped over
+                self.log_send(sent)
+            })
+            .collect()
+    }
+
+    // A message over a link with a bandwidth arrives once the link got it across, see
+    // bandwidth.rs
+    fn queue_on_link(&mut self, mut sent: Message) -> Message {
+        if let Some(bytes_per_tick) = self.bandwidth_to(sent.receiver) {
+            let delay = sent.rec_time.saturating_since(sent.send_time);
+            let size = sent.payload_bytes().len();
+            sent.rec_time =
+                self.links.transmit(sent.receiver, sent.send_time, size, bytes_per_tick, delay);
+        }
+        sent
+    }
+
+    fn bandwidth_to(&self, receiver: MachineId) -> Option<u64> {
+        self.latencies.as_ref()?.bandwidth(self.machine_id, receiver)
+    }
+
+    // Processes every event up to and including end_time, or until the machine has to
+    // stop for one of the other reasons in BatchStop
+    pub fn process_until(&mut self, end_time: impl Into<VirtualTime>) -> Batch {
+        self.process_batch(usize::MAX, Some(end_time.into()))
+    }
+
+    // Processes at most n events, stopping early like process_until
+    pub fn process_n(&mut self, n: usize) -> Batch {
+        self.process_batch(n, None)
+    }
+
+    fn process_batch(&mut self, max: usize, end_time: Option<VirtualTime>) -> Batch {
+        let mut batch = Batch::default();
+        loop {
+            if batch.events >= max {
+                break;
+            }
+            let Some(next) = self.peek_next_message() else {
+                batch.stopped = BatchStop::Empty;
+                break;
+            };
+            if next.sign == Sign::Antimessage {
+                batch.stopped = BatchStop::Antimessage;
+                break;
+            }
+            if end_time.is_some_and(|end_time| next.rec_time > end_time) {
+                break;
+            }
+            if self.over_budget() || self.stalled_on_snapshots() || self.outside_window() {
+                batch.stopped = BatchStop::Held;
+                break;
+            }
+            batch.sent.extend(self.recieve_inner());
+            batch.events += 1;
+        }
+        batch
+    }
+
+    // The receiver of an antimessage (or whatever is delivering it) confirms it arrived
+    // so it no longer has to be accounted for
+    pub fn acknowledge_antimessage(&mut self, antimessage: &Message) -> bool {
+        self.in_flight.acknowledge(antimessage)
     }
 
     // Very simple helper similar to receive outer except sending a message cant
     // cause a rollback. Depending on implementation the message wrapper may be undesirable
-    // in which case the outer functions could handle that as well.
-    pub fn send_outer(&mut self, message: Message) -> Message {
+    // in which case the outer functions could handle that as well. The message has to be
+    // sent at the local virtual time and received no earlier than that, anything else is
+    // refused and not logged (see SendError).
+    pub fn send_outer(&mut self, message: Message) -> Result<Message, SendError> {
+        if message.rec_time < message.send_time {
+            return Err(SendError::IntoThePast {
+                send_time: message.send_time,
+                rec_time: message.rec_time,
+            });
+        }
+        if message.send_time != self.local_virtual_time {
+            return Err(SendError::NotNow {
+                send_time: message.send_time,
+                lvt: self.local_virtual_time,
+            });
+        }
+        Ok(self.log_send(message))
+    }
+
+    fn log_send(&mut self, mut message: Message) -> Message {
+        if self.direct_cancellation {
+            message.handle = Some(CancelHandle::default());
+        }
         self.output_queue.push(message.clone());
         message
     }
@@ -218,19 +1631,481 @@ impl Machine {
         let my_message = "example message".to_string();
 
         let wrapped_message = self.make_message(my_message, Sign::Message);
-        self.send_outer(wrapped_message)
+        self.send_outer(wrapped_message).expect("made at the local virtual time")
     }
 
     // Helper function to make messages that should be delivered in 5 virtual time units from now
     // to an arbitrary machine 0, this may be removed in a real implementation but helps for now
     fn make_message(&self, message: MessagePayload, sign: Sign) -> Message {
-        Message {
-            send_time: self.local_virtual_time,
-            rec_time: self.local_virtual_time + 5,
-            sender: self.machine_id,
-            receiver: 0,
+        Message::new(
+            self.local_virtual_time,
+            self.local_virtual_time + Delay::new(5),
+            self.machine_id,
+            0,
             sign,
-            message: Arc::new(message),
+            Arc::new(message),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message_at(rec_time: usize) -> Message {
+        Message::new(0, rec_time, 0, 1, Sign::Message, Arc::new("message".to_string()))
+    }
+
+    #[test]
+    fn test_dumps_have_the_state_snapshots_and_queues() {
+        let mut machine = Machine::new(1, 0);
+        machine.recieve_outer(message_at(3));
+        machine.recieve_outer(message_at(5));
+        machine.recieve_outer(message_at(7));
+        machine.recieve_inner();
+        machine.recieve_inner();
+        let sent = Message::new(5, 9, 1, 2, Sign::Message, Arc::new("out".to_string()));
+        machine.send_outer(sent).unwrap();
+
+        let dump = machine.dump();
+        assert_eq!((dump["id"].as_u64(), dump["lvt"].as_u64()), (Some(1), Some(5)));
+        assert_eq!(dump["state"]["local_var2"], 10);
+        // Saved before each event
+        assert_eq!(dump["snapshots"], serde_json::json!([null, 3]));
+        let inputs = dump["input_queue"].as_array().unwrap();
+        let processed: Vec<_> = inputs.iter().map(|input| input["processed"].clone()).collect();
+        assert_eq!(processed, [true, true, false]);
+        assert_eq!(inputs[2]["rec_time"], 7);
+        assert_eq!(dump["output_queue"][0]["payload"], "out");
+        assert_eq!(dump["in_flight"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn test_batches_stop_at_the_bound_or_the_queue() {
+        let mut machine = Machine::new(1, 0);
+        for rec_time in [2, 4, 6, 8] {
+            machine.recieve_outer(message_at(rec_time));
+        }
+        let batch = machine.process_until(5);
+        assert_eq!((batch.events, batch.stopped), (2, BatchStop::Done));
+        assert_eq!(machine.local_virtual_time(), 4);
+
+        let batch = machine.process_n(1);
+        assert_eq!((batch.events, batch.stopped), (1, BatchStop::Done));
+        let batch = machine.process_n(5);
+        assert_eq!((batch.events, batch.stopped), (1, BatchStop::Empty));
+
+        assert!(machine.is_blocked());
+
+        let mut antimessage = message_at(10);
+        antimessage.sign = Sign::Antimessage;
+        machine.recieve_outer(antimessage);
+        assert!(machine.is_blocked());
+        let batch = machine.process_until(20);
+        assert_eq!((batch.events, batch.stopped), (0, BatchStop::Antimessage));
+        assert!(batch.sent.is_empty());
+    }
+
+    #[test]
+    fn test_sends_have_to_be_from_now_into_the_future() {
+        let mut machine = Machine::new(1, 0);
+        machine.recieve_outer(message_at(3));
+        machine.recieve_inner();
+        let sent = |send_time: usize, rec_time: usize| {
+            Message::new(send_time, rec_time, 1, 2, Sign::Message, Arc::new(String::new()))
+        };
+        assert_eq!(
+            machine.send_outer(sent(3, 2)),
+            Err(SendError::IntoThePast {
+                send_time: VirtualTime::new(3),
+                rec_time: VirtualTime::new(2),
+            })
+        );
+        assert_eq!(
+            machine.send_outer(sent(1, 5)),
+            Err(SendError::NotNow {
+                send_time: VirtualTime::new(1),
+                lvt: VirtualTime::new(3),
+            })
+        );
+        assert!(machine.output_queue.is_empty());
+        machine.send_outer(sent(3, 3)).unwrap();
+        assert_eq!(machine.output_queue.len(), 1);
+    }
+
+    #[test]
+    fn test_messages_from_before_gvt_are_dropped() {
+        let mut machine = Machine::new(1, 0);
+        for rec_time in [2, 5] {
+            machine.recieve_outer(message_at(rec_time));
+            machine.recieve_inner();
+        }
+        machine.commit(Some(VirtualTime::new(4)));
+        assert_eq!(machine.recieve_outer(message_at(3)), None);
+        assert_eq!(machine.stats().messages_past_gvt, 1);
+        assert_eq!(machine.stats().rollbacks, 0);
+        assert_eq!(machine.local_virtual_time(), 5);
+
+        // At GVT itself it is still a straggler like any other
+        assert!(machine.recieve_outer(message_at(4)).is_some());
+        assert_eq!(machine.stats().rollbacks, 1);
+    }
+
+    #[test]
+    fn test_a_burst_of_stragglers_rolls_back_once() {
+        let mut machine = Machine::new(1, 0);
+        for rec_time in [2, 4, 6, 8] {
+            machine.recieve_outer(message_at(rec_time));
+            machine.recieve_inner();
         }
+        let burst = [7, 3, 9, 5].map(message_at);
+        assert!(machine.recieve_batch(burst).is_some());
+        assert_eq!(machine.stats().rollbacks, 1);
+        assert_eq!(machine.local_virtual_time(), 2);
+        assert_eq!(machine.input_queue.pending().count(), 7);
+        machine.process_until(9);
+        assert_eq!(machine.local_virtual_time(), 9);
+
+        // Nothing to roll back for
+        assert_eq!(machine.recieve_batch([12, 11].map(message_at)), None);
+        assert_eq!(machine.stats().rollbacks, 1);
+        assert_eq!(machine.input_queue.pending().count(), 2);
+    }
+
+    #[test]
+    fn test_builder_sets_up_window_ports_and_lookahead() {
+        let mut machine = Machine::builder(1, ExampleProcess)
+            .state(MachineState::new())
+            .lookahead(4)
+            .optimism_window(6)
+            .port("in")
+            .seed(7)
+            .build();
+        assert_eq!(machine.lookahead(), 4);
+        for rec_time in [2, 4, 12] {
+            machine.recieve_outer(message_at(rec_time));
+        }
+        assert_eq!(machine.recieve_outer(message_at(3).with_port("out")), None);
+        machine.recieve_outer(message_at(6).with_port("in"));
+        assert_eq!(machine.stats().messages_refused, 1);
+
+        // 12 is too far past the commit horizon until something commits
+        let batch = machine.process_until(20);
+        assert_eq!((batch.events, batch.stopped), (3, BatchStop::Held));
+        assert!(machine.outside_window() && !machine.can_execute(None));
+        machine.commit(Some(VirtualTime::new(8)));
+        assert_eq!(machine.process_until(20).events, 1);
+
+        let draws = |seed, position| {
+            let mut ctx = Context::new(1, 5).with_seed(seed).with_position(position);
+            [ctx.random(), ctx.random()]
+        };
+        assert_eq!(draws(7, 0), draws(7, 0));
+        assert_ne!(draws(7, 0)[0], draws(7, 0)[1]);
+        assert_ne!(draws(7, 0), draws(8, 0));
+        // Events at the same time get numbers of their own
+        assert_ne!(draws(7, 0), draws(7, 1));
+    }
+
+    #[test]
+    fn test_lone_antimessage_blocks_until_gvt_passes_it() {
+        let mut machine = Machine::new(1, 0);
+        let mut antimessage = message_at(3);
+        antimessage.sign = Sign::Antimessage;
+        let message_id = antimessage.id;
+        machine.recieve_outer(antimessage);
+        let message = message_at(6);
+        machine.recieve_outer(message.clone());
+        let blocked = BlockReason::Antimessage {
+            message_id,
+            rec_time: VirtualTime::new(3),
+        };
+        assert_eq!(machine.next_event(), NextEvent::Blocked(blocked));
+        assert!(machine.recieve_inner().is_empty());
+        assert_eq!(machine.earliest_pending(), Some(VirtualTime::new(6)));
+
+        machine.commit(Some(VirtualTime::new(6)));
+        assert_eq!(machine.stats().antimessages_discarded, 1);
+        assert_eq!(machine.next_event(), NextEvent::Ready(message));
+    }
+
+    #[test]
+    fn test_state_at_coasts_forward_without_touching_the_machine() {
+        let mut machine = Machine::new(1, 0);
+        for rec_time in [2, 4, 6] {
+            machine.recieve_outer(message_at(rec_time));
+            machine.recieve_inner();
+        }
+        assert_eq!(machine.local_virtual_time(), 6);
+
+        let value = |state: Option<MachineState>| state.map(|state| state.local_var2);
+        assert_eq!(value(machine.state_at(1)), Some(0));
+        assert_eq!(value(machine.state_at(2)), Some(5));
+        assert_eq!(value(machine.state_at(5)), Some(10));
+        assert_eq!(value(machine.state_at(6)), Some(15));
+        assert_eq!(value(machine.state_at(100)), Some(15));
+
+        // The snapshot for 5 is the one saved before processing 6
+        let (saved_at, state) = machine.snapshot_at(5).unwrap();
+        assert_eq!((saved_at, state.local_var2), (Some(VirtualTime::new(4)), 10));
+
+        assert_eq!(machine.state.local_var2, 15);
+        assert_eq!(machine.output_queue.len(), 0);
+        assert_eq!(machine.stats().events_processed, 3);
+    }
+
+    // Sends itself a message after each of the delays and declares a lookahead
+    struct Delays(Vec<u64>, u64);
+
+    impl TimeWarpProcess for Delays {
+        type State = ();
+
+        fn on_message(&self, _state: &mut (), _message: &Message, ctx: &mut Context) {
+            for delay in &self.0 {
+                ctx.send(1, Delay::new(*delay), "later".to_string());
+            }
+        }
+
+        fn lookahead(&self) -> Delay {
+            Delay::new(self.1)
+        }
+    }
+
+    #[test]
+    fn test_sends_are_checked_against_the_builder_lookahead() {
+        // The builder raises the lookahead of 1 to 3 and lowers the one of 6 to 2, every
+        // send is kept either way but only the ones under the builders are counted
+        for (declared, built, under) in [(1, 3, 1), (6, 2, 0)] {
+            let mut machine = Machine::builder(1, Delays(vec![2, 5], declared))
+                .lookahead(built)
+                .build();
+            machine.recieve_outer(message_at(1));
+            let sent: Vec<_> = machine.recieve_inner().iter().map(|m| m.rec_time).collect();
+            assert_eq!(sent, [3, 6]);
+            assert_eq!(machine.stats().sends_under_lookahead, under);
+        }
+    }
+
+    // Keeps every number it draws
+    struct Draws;
+
+    impl TimeWarpProcess for Draws {
+        type State = Vec<u64>;
+
+        fn on_message(&self, state: &mut Vec<u64>, _message: &Message, ctx: &mut Context) {
+            state.push(ctx.random());
+        }
+    }
+
+    #[test]
+    fn test_events_at_the_same_time_draw_their_own_numbers() {
+        let mut machine = Machine::builder(1, Draws).seed(7).build();
+        machine.recieve_outer(message_at(5));
+        machine.recieve_outer(message_at(5));
+        machine.process_until(10);
+        let draws = machine.state.clone();
+        assert_eq!(draws.len(), 2);
+        assert_ne!(draws[0], draws[1]);
+
+        // Run again after a straggler, they draw what they did the first time
+        machine.recieve_outer(message_at(3));
+        machine.process_until(10);
+        assert_eq!(machine.stats().events_rolled_back, 2);
+        assert_eq!(machine.state[1..], draws);
+    }
+
+    // Only the counter changes, the table is shared by every snapshot and survives a
+    // rollback without being copied
+    #[derive(Debug, Default, Clone)]
+    struct Counted {
+        table: Arc<Vec<u64>>,
+        count: u64,
+    }
+
+    struct Count;
+
+    impl TimeWarpProcess for Count {
+        type State = Counted;
+
+        fn on_message(&self, state: &mut Counted, _message: &Message, _ctx: &mut Context) {
+            state.count += 1;
+        }
+    }
+
+    #[test]
+    fn test_snapshots_share_what_the_state_shares() {
+        let table = Arc::new((0..1000).collect::<Vec<u64>>());
+        let start = Counted {
+            table: table.clone(),
+            count: 0,
+        };
+        let mut machine = Machine::with_state(1, 0, Count, start);
+        for rec_time in [2, 4, 6] {
+            machine.recieve_outer(message_at(rec_time));
+            machine.recieve_inner();
+        }
+        assert_eq!(machine.state_queue.len(), 3);
+        assert!(machine
+            .state_queue
+            .iter()
+            .all(|saved| Arc::ptr_eq(&saved.machine_state.as_ref().unwrap().table, &table)));
+
+        machine.recieve_outer(message_at(3));
+        assert_eq!(machine.state.count, 1);
+        assert!(Arc::ptr_eq(&machine.state.table, &table));
+        let (_, snapshot) = machine.snapshot_at(2).unwrap();
+        assert_eq!(snapshot.count, 1);
+    }
+
+    #[test]
+    fn test_rollback_coasts_forward_over_evicted_states() {
+        let limits = SnapshotLimits {
+            max_saved_states: Some(3),
+            coast_forward: true,
+        };
+        let mut limited = Machine::new(1, 0);
+        limited.set_snapshot_limits(limits);
+        let mut unlimited = Machine::new(1, 0);
+        for machine in [&mut limited, &mut unlimited] {
+            for rec_time in [2, 4, 6, 8, 10, 12] {
+                machine.recieve_outer(message_at(rec_time));
+                machine.recieve_inner();
+            }
+        }
+        assert_eq!(limited.state_queue.len(), 3);
+        assert!(limited.stats().states_evicted > 0);
+        assert_eq!(limited.memory_report().saved_states, 3);
+
+        // The states saved at 4 and 6 are gone, rolling back to 7 coasts from further back
+        let from_limited = limited.recieve_outer(message_at(7)).unwrap();
+        let from_unlimited = unlimited.recieve_outer(message_at(7)).unwrap();
+        assert_eq!(limited.state, unlimited.state);
+        assert_eq!(limited.local_virtual_time(), 6);
+        assert_eq!(from_limited.len(), from_unlimited.len());
+        assert_eq!(limited.stats().events_rolled_back, 3);
+        for machine in [&mut limited, &mut unlimited] {
+            while machine.peek_next_message().is_some() {
+                machine.recieve_inner();
+            }
+        }
+        assert_eq!(limited.state, unlimited.state);
+
+        // Committing drops the states and then the messages no state needs anymore
+        limited.commit(Some(VirtualTime::new(9)));
+        unlimited.commit(Some(VirtualTime::new(9)));
+        assert_eq!(limited.input_queue.len(), 2);
+        assert_eq!(unlimited.input_queue.len(), 7);
+        assert_eq!(limited.state_at(8), unlimited.state_at(8));
+    }
+
+    #[test]
+    fn test_full_machine_stalls_until_commits_without_coast_forward() {
+        let mut machine = Machine::new(1, 0);
+        machine.set_snapshot_limits(SnapshotLimits {
+            max_saved_states: Some(3),
+            coast_forward: false,
+        });
+        for rec_time in [2, 4, 6, 8] {
+            machine.recieve_outer(message_at(rec_time));
+        }
+        for _ in 0..3 {
+            machine.recieve_inner();
+        }
+        assert!(machine.stalled_on_snapshots());
+        assert!(!machine.can_execute(None));
+
+        // With 4 committed only the state saved before processing 6 is still needed
+        machine.commit(Some(VirtualTime::new(6)));
+        assert!(!machine.stalled_on_snapshots());
+        assert!(machine.can_execute(None));
+        assert_eq!(machine.state_queue.len(), 1);
+    }
+
+    #[test]
+    fn test_memory_report_counts_what_the_machine_keeps() {
+        let mut machine = Machine::new(1, 0);
+        assert_eq!(machine.memory_report().saved_states, 1);
+        for rec_time in [2, 4] {
+            machine.recieve_outer(message_at(rec_time));
+            machine.recieve_inner();
+        }
+        machine.recieve_outer(message_at(9));
+
+        let report = machine.memory_report();
+        assert_eq!(report.saved_states, 2);
+        assert_eq!(report.saved_state_bytes, 2 * std::mem::size_of::<MachineState>());
+        assert_eq!(report.queued_inputs, 3);
+        assert_eq!(report.queued_input_bytes, 3 * (std::mem::size_of::<Message>() + 7));
+        assert_eq!(report.logged_outputs, 0);
+        assert_eq!(report.total_bytes(), report.saved_state_bytes + report.queued_input_bytes);
+    }
+
+    #[test]
+    fn test_straggler_at_time_zero_rolls_back_to_the_start() {
+        let mut machine = Machine::new(1, 0);
+        machine.recieve_outer(message_at(3));
+        machine.recieve_inner();
+        assert_eq!(machine.state.local_var2, 5);
+
+        let antimessages = machine.recieve_outer(message_at(0));
+        assert_eq!(antimessages.map(|sent| sent.len()), Some(0));
+        assert_eq!(machine.state.local_var2, 0);
+        assert_eq!(machine.local_virtual_time(), 0);
+
+        // Time 0 is processed like any other time, then 3 again
+        machine.recieve_inner();
+        assert_eq!(machine.local_virtual_time(), 0);
+        machine.recieve_inner();
+        assert_eq!(machine.state.local_var2, 10);
+        assert_eq!(machine.local_virtual_time(), 3);
+        assert_eq!(machine.stats().rollbacks, 1);
+        assert_eq!(machine.stats().events_rolled_back, 1);
+    }
+
+    #[test]
+    fn test_repeated_rollbacks_throttle_until_commit() {
+        let mut machine = Machine::new(1, 0);
+        machine.set_storm_threshold(Some(2));
+        machine.recieve_outer(message_at(10));
+        machine.recieve_inner();
+
+        machine.recieve_outer(message_at(8));
+        assert_eq!(machine.effective_policy(), ExecutionPolicy::Optimistic);
+        machine.recieve_inner();
+        machine.recieve_inner();
+        machine.recieve_outer(message_at(6));
+        assert_eq!(machine.effective_policy(), ExecutionPolicy::Conservative);
+        assert_eq!(machine.stats().storms, 1);
+
+        // Throttled it only runs what is safe
+        assert!(!machine.can_execute(Some(VirtualTime::new(5))));
+        assert!(machine.can_execute(Some(VirtualTime::new(6))));
+        machine.recieve_inner();
+
+        machine.commit(Some(VirtualTime::new(7)));
+        assert_eq!(machine.effective_policy(), ExecutionPolicy::Optimistic);
+        assert!(machine.can_execute(Some(VirtualTime::new(0))));
+    }
+
+    #[test]
+    fn test_out_of_budget_still_runs_the_rest_of_the_commit_horizon() {
+        let mut machine = Machine::new(1, 0);
+        machine.set_rollback_limits(RollbackLimits {
+            max_depth: Some(1),
+            ..RollbackLimits::default()
+        });
+        machine.recieve_outer(message_at(5));
+        machine.recieve_outer(message_at(5));
+        machine.recieve_inner();
+        assert!(machine.over_budget());
+
+        // GVT cant get past 5 before the other event at 5 ran, and the first one cant
+        // commit before GVT is past it, so waiting on the budget would wait forever
+        machine.commit(Some(VirtualTime::new(5)));
+        assert_eq!(machine.stats().events_committed, 0);
+        assert!(!machine.over_budget());
+        machine.recieve_inner();
+        machine.commit(Some(VirtualTime::new(6)));
+        assert_eq!(machine.stats().events_committed, 2);
     }
 }
@@ -1,256 +1,561 @@
-use std::sync::Arc;
-
-use machine::Machine;
-use time::message::{Message, Sign};
-
-mod machine;
-mod time;
-
-
-fn main() {
-    send_antimessage_first();
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use virtual_time::config::{SimulationConfig, TopologyProcess};
+use virtual_time::critical_path::{CriticalPath, CriticalPathReport};
+use virtual_time::determinism::{check_determinism, Reference};
+use virtual_time::export::chrome::{self, TimeAxis};
+use virtual_time::export::svg::{self, SvgOptions};
+use virtual_time::export::pcap::{PcapOptions, PcapSink};
+use virtual_time::dashboard::{Dashboard, DEFAULT_REFRESH};
+use virtual_time::live::LiveServer;
+use virtual_time::machine::Machine;
+use virtual_time::metrics::MetricsServer;
+use virtual_time::recorder::{Trace, TraceEvent};
+use virtual_time::repl::Repl;
+use virtual_time::runtime::async_executor::AsyncSimulation;
+use virtual_time::runtime::checkpoint::Checkpoint;
+use virtual_time::runtime::Simulation;
+use virtual_time::stats::SimulationStats;
+use virtual_time::time::message::{Delay, VirtualTime};
+
+// vtw, runs simulations described by a config file (see config.rs) and looks at what
+// they leave behind. Examples of using the library directly live in examples/.
+#[derive(Parser)]
+#[command(name = "vtw", version, about = "Time Warp simulator")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
 }
 
-// Example where a single machine receives 2 messages, they need not be in order. When the
-// message is received it is first put into the input queue by the outer function and then 
-// the machine can process them at whatever pace it wants using the inner function. This function
-// assumes that all the messages happen to come in the correct order, the idea is that this assumption
-// should hold most of the time although the system is capable of handling it when it isnt the case
-fn simple_message() {
-    let mut machine1 = Machine::new(1, 0);
-
-    let message1 = Message::new(0, 3, 0, 1, Sign::Message, Arc::new("message".to_string()));
-    let message2 = Message::new(0, 5, 0, 1, Sign::Message, Arc::new("message".to_string()));
-
-    machine1.recieve_outer(message1);
-    machine1.recieve_outer(message2);
-    
-    println!("{:?}", machine1.input_queue);
-
-    machine1.recieve_inner();
-
-    println!("{:?}", machine1.input_queue);
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Run a simulation from a config file")]
+    Run(RunArgs),
+    #[command(about = "Print a recorded trace event by event, optionally exporting it")]
+    Replay {
+        trace: PathBuf,
+        #[arg(long, help = "Export the trace in Chrome/Perfetto format")]
+        chrome: Option<PathBuf>,
+        #[arg(long, help = "Render the trace as a space-time diagram")]
+        svg: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = Axis::Virtual)]
+        axis: Axis,
+    },
+    #[command(about = "Summarize a checkpoint written by run")]
+    Inspect {
+        checkpoint: PathBuf,
+        #[arg(long, help = "Dump every machine with its queues as JSON instead")]
+        json: bool,
+    },
+    #[command(about = "Run a config twice and report where the committed states diverge")]
+    Check {
+        config: PathBuf,
+        #[arg(long, value_enum, default_value_t = Protocol::Optimistic)]
+        against: Protocol,
+        #[arg(long, default_value_t = Delay::new(1), help = "Virtual time covered by each digest")]
+        epoch: Delay,
+        #[arg(long)]
+        end_time: Option<VirtualTime>,
+    },
+    #[command(about = "Step through a config interactively, with breakpoints")]
+    Debug { config: PathBuf },
+    #[command(about = "Split the machines of a config over nodes by the traffic between them")]
+    Partition {
+        config: PathBuf,
+        #[arg(long, default_value_t = 2)]
+        parts: usize,
+    },
 }
 
-// This demonstrates a machine rewinding its own state after it realizes that it has done processed 
-// messages that are supposed to come after the one it just received. In this case message2 is processed
-// first even though it should have been processed second. Then when message1 comes in the machine rollback
-// to the state it was in just before it processed the wrong message.
-fn simple_rollback() {
-    let mut machine1 = Machine::new(1, 0);
-
-    let message1 = Message::new(0, 3, 0, 1, Sign::Message, Arc::new("message".to_string()));
-    let message2 = Message::new(0, 5, 0, 1, Sign::Message, Arc::new("message".to_string()));
-
-    machine1.recieve_outer(message2);
-    
-    println!("{:?}", machine1.input_queue);
-    machine1.recieve_inner();
-    
-    println!("{:?}", machine1.input_queue);
-    machine1.recieve_outer(message1);
-    println!("\nPost Rollback:\n\n");
-    println!("{:?}", machine1.input_queue);
-    
-    machine1.recieve_inner();
-    machine1.recieve_inner();
+#[derive(Args)]
+struct RunArgs {
+    config: PathBuf,
+    #[arg(long, help = "Leave every message after this time unprocessed")]
+    end_time: Option<VirtualTime>,
+    #[arg(long, help = "Seed to use instead of the one in the config")]
+    seed: Option<u64>,
+    #[arg(long, default_value_t = 1, help = "Worker threads, more than 1 uses the tokio executor")]
+    threads: usize,
+    #[arg(long, value_enum, default_value_t = Protocol::Optimistic)]
+    protocol: Protocol,
+    #[arg(long, help = "Record every execution and rollback to this json file")]
+    trace: Option<PathBuf>,
+    #[arg(long, help = "Write a checkpoint here once the run stops")]
+    checkpoint: Option<PathBuf>,
+    #[arg(long, help = "Serve Prometheus metrics on this address while running")]
+    metrics: Option<String>,
+    #[arg(long, help = "Stream the run to a browser over a WebSocket on this address")]
+    live: Option<String>,
+    #[arg(long, help = "Show a dashboard in the terminal while running")]
+    dashboard: bool,
+    #[arg(long, help = "Print a digest of every committed state, for regression tests")]
+    digest: bool,
+    #[arg(long, help = "Write the committed messages between machines to this pcap file")]
+    pcap: Option<PathBuf>,
+    #[arg(long, help = "Report the critical path of the committed events and the speedup ceiling")]
+    critical_path: bool,
 }
 
-// This example is very similar to the last example however it rollsback multiple messages in a single pass
-// and once it processes them it happens to rollback a second time.
-fn extended_rollback() {
-    let mut machine1 = Machine::new(1, 0);
-
-    let message1 = Message::new(0, 3, 0, 1, Sign::Message, Arc::new("message".to_string()));
-    let message2 = Message::new(0, 4, 0, 1, Sign::Message, Arc::new("message".to_string()));
-    let message3 = Message::new(0, 5, 0, 1, Sign::Message, Arc::new("message".to_string()));
-    let message4 = Message::new(0, 6, 0, 1, Sign::Message, Arc::new("message".to_string()));
-    let message5 = Message::new(0, 7, 0, 1, Sign::Message, Arc::new("message".to_string()));
-
-    // Receive and process messages 3-5
-    machine1.recieve_outer(message3);
-    machine1.recieve_outer(message4);
-    machine1.recieve_outer(message5);
-
-    machine1.recieve_inner();
-    machine1.recieve_inner();
-    machine1.recieve_inner();
-    
-    
-    println!("Pre-rollback:\n{:?}", machine1.input_queue);
-
-    machine1.recieve_outer(message2);
-
-    println!("Rollback 1:\n{:?}", machine1.input_queue);
-
-    machine1.recieve_inner();
-    machine1.recieve_inner();
-    machine1.recieve_inner();
-    machine1.recieve_inner();
-
-    println!("Pre-second-rollback:\n{:?}", machine1.input_queue);
-    
-    machine1.recieve_outer(message1);
-
-    println!("Rollback 2:\n{:?}", machine1.input_queue);
-
-    machine1.recieve_inner();
-    machine1.recieve_inner();
-    machine1.recieve_inner();
-    machine1.recieve_inner();
-    machine1.recieve_inner();
+// Conservative runs use the links as channels with the link delay as lookahead and put
+// held back messages in right away, sequential runs do too and run one event at a time
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Protocol {
+    Optimistic,
+    Conservative,
+    Sequential,
 }
 
-// This is an example of sending a message, this example doesnt implement channels or any kind of message
-// passing between machines so send just returns the message which is then passed in manually. The send is fairly
-// straighforward, the machine does the work it wants to do in send and wraps the payload in the message struct.
-// Then the machine logs that it sent the message for later.
-fn send_message() {
-    let mut machine1 = Machine::new(1, 0);
-    let mut machine2 = Machine::new(2, 0);
-
-    let message1 = Message::new(0, 3, 1, 2, Sign::Message, Arc::new("message".to_string()));
-    let message2 = Message::new(2, 5, 1, 2, Sign::Message, Arc::new("message".to_string()));
-
-    let sent_message1 = machine1.send_outer(message1);
-    machine2.recieve_outer(sent_message1);
-    
-    let sent_message2 = machine1.send_outer(message2);
-    machine2.recieve_outer(sent_message2);
-    
-    println!("Machine 1 output queue: {:?}", machine1.output_queue);
-    println!("Machine 2 input queue: {:?}", machine2.input_queue);
-
-    machine2.recieve_inner();
-    machine2.recieve_inner();
-
-    println!("{:?}", machine1.input_queue);
+#[derive(Clone, Copy, ValueEnum)]
+enum Axis {
+    Virtual,
+    Wall,
 }
 
-// During some period of time a machine not only alters its own state but also sends messages to other machines. Then
-// when rolling back you need to revert not only the state but also chase down the messages that you sent to other machines
-// to revert them. This is done by sending "antimessage" that cancel out the original message that was sent. 
-// There are 3 cases in which this can happen which are shown below.
-
-// When M1 sends to M2 the messages arrive in M2's input queue but it may or may not have processed them yet. In this case
-// it has not been processed so when M1 rollsback it needs to cancel out the messages it sent to M2. This is done by sending
-// antimessages, and since M2 hasnt processed the reqular messages yet they are just cancel out and M2 never even realizes
-// that it received messages in the wrong order.
-fn send_message_simple_rollback() {
-    let mut machine1 = Machine::new(1, 0);
-    let mut machine2 = Machine::new(2, 0);
-
-    let message0 = Message::new(1, 3, 2, 1, Sign::Message, Arc::new("message1".to_string()));
-    let message1 = Message::new(1, 3, 1, 2, Sign::Message, Arc::new("message2".to_string()));
-    let message2 = Message::new(2, 5, 1, 2, Sign::Message, Arc::new("message3".to_string()));
-    let message3 = Message::new(0, 1, 1, 2, Sign::Message, Arc::new("message4".to_string()));
-
-    machine1.recieve_outer(message0);
-    machine1.recieve_inner();
-
-    let sent_message1 = machine1.send_outer(message1);
-    machine2.recieve_outer(sent_message1);
-    
-    let sent_message2 = machine1.send_outer(message2);
-    machine2.recieve_outer(sent_message2);
-    
-    println!("Machine 1 output queue: {:?}\n", machine1.output_queue);
-    println!("Machine 1 input queue: {:?}\n", machine1.input_queue);
-    println!("Machine 2 input queue: {:?}\n", machine2.input_queue);
-
-    // ROLLBACK HAPPENING
-    let messages_to_unsend = machine1.recieve_outer(message3).unwrap();
-    for message in messages_to_unsend {
-        let _ = machine2.recieve_outer(message.clone());
+fn main() -> ExitCode {
+    #[cfg(feature = "tracing")]
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "warn".into()),
+        )
+        .init();
+
+    let result = match Cli::parse().command {
+        Command::Run(args) => run(&args),
+        Command::Replay {
+            trace,
+            chrome,
+            svg,
+            axis,
+        } => replay(&trace, chrome.as_deref(), svg.as_deref(), axis),
+        Command::Inspect { checkpoint, json } => inspect(&checkpoint, json),
+        Command::Check {
+            config,
+            against,
+            epoch,
+            end_time,
+        } => check(&config, against, epoch, end_time),
+        Command::Partition { config, parts } => partition(&config, parts),
+        Command::Debug { config } => debug(&config),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
+        }
     }
-
-    println!("\n\n\nPost rollback!\n\n");
-    println!("Machine 1 output queue: {:?}\n", machine1.output_queue);
-    println!("Machine 1 input queue: {:?}\n", machine1.input_queue);
-    println!("Machine 2 input queue: {:?}\n", machine2.input_queue);
-
 }
 
-// This is a more complex example where M1 sends to M2 and M2 ends up processing the messages before M1 rollsback. In this case
-// everything happens the same as the last case up until the antimessages are received by M2. Because M2 has already processed the
-// regular messages we know that its local virtual time is already greater than that message, so when the antimessage comes M2
-// know it is receiving something out of order and itself rollsback to just before it received the out of order method.
-fn send_message_double_rollback() {
-    let mut machine1 = Machine::new(1, 0);
-    let mut machine2 = Machine::new(2, 0);
-
-    let message0 = Message::new(1, 3, 2, 1, Sign::Message, Arc::new("message1".to_string()));
-    let message1 = Message::new(1, 3, 1, 2, Sign::Message, Arc::new("message2".to_string()));
-    let message2 = Message::new(2, 5, 1, 2, Sign::Message, Arc::new("message3".to_string()));
-    let message3 = Message::new(0, 1, 1, 2, Sign::Message, Arc::new("message4".to_string()));
+fn run(args: &RunArgs) -> Result<(), Box<dyn Error>> {
+    let mut config = SimulationConfig::load(&args.config)?;
+    if let Some(seed) = args.seed {
+        config.seed = seed;
+    }
+    let end_time = args.end_time.or(config.end_time);
+
+    if args.protocol == Protocol::Conservative {
+        if args.trace.is_some()
+            || args.checkpoint.is_some()
+            || args.metrics.is_some()
+            || args.live.is_some()
+            || args.dashboard
+            || args.pcap.is_some()
+            || args.critical_path
+            || args.threads > 1
+        {
+            let unsupported = "--trace, --checkpoint, --metrics, --live, --dashboard, --pcap, \
+                 --critical-path or --threads";
+            return Err(format!("conservative runs dont support {}", unsupported).into());
+        }
+        let mut simulation = config.build_conservative()?;
+        if args.digest {
+            simulation.record_digests(1);
+        }
+        match end_time {
+            Some(end_time) => simulation.run_until(end_time),
+            None => simulation.run(),
+        }
+        print_stats(&simulation.stats());
+        println!("null messages {}", simulation.null_messages());
+        if let Some(digest) = simulation.run_digest() {
+            println!("digest {}", digest);
+        }
+        return Ok(());
+    }
 
-    machine1.recieve_outer(message0);
-    machine1.recieve_inner();
+    if args.protocol == Protocol::Sequential {
+        if args.trace.is_some()
+            || args.checkpoint.is_some()
+            || args.metrics.is_some()
+            || args.live.is_some()
+            || args.dashboard
+            || args.pcap.is_some()
+            || args.critical_path
+            || args.threads > 1
+        {
+            let unsupported = "--trace, --checkpoint, --metrics, --live, --dashboard, --pcap, \
+                 --critical-path or --threads";
+            return Err(format!("sequential runs dont support {}", unsupported).into());
+        }
+        let mut simulation = config.build_sequential()?;
+        if args.digest {
+            simulation.record_digests(1);
+        }
+        match end_time {
+            Some(end_time) => simulation.run_until(end_time),
+            None => simulation.run(),
+        }
+        println!("events {}", simulation.events_processed());
+        for (machine_id, state) in simulation.states() {
+            println!("machine {} state {:?}", machine_id, state);
+        }
+        if let Some(digest) = simulation.run_digest() {
+            println!("digest {}", digest);
+        }
+        return Ok(());
+    }
 
-    let sent_message1 = machine1.send_outer(message1);
-    machine2.recieve_outer(sent_message1);
-    
-    let sent_message2 = machine1.send_outer(message2);
-    machine2.recieve_outer(sent_message2);
-    
-    machine2.recieve_inner();
-    machine2.recieve_inner();
+    let (simulation, critical_path) = if args.threads <= 1 {
+        let mut simulation = config.build()?;
+        if args.trace.is_some() {
+            simulation.start_recording();
+        }
+        if args.digest {
+            simulation.record_digests(1);
+        }
+        // Kept around until the run is over, the endpoints go away with them. Whatever
+        // else wants the metrics snapshots reads the ones published to the first.
+        let server = args.metrics.as_deref().map(MetricsServer::start).transpose()?;
+        let mut snapshots = server.as_ref().map(MetricsServer::handle);
+        let live = match (args.live.as_deref(), &snapshots) {
+            (Some(addr), Some(snapshots)) => Some(LiveServer::start_with(addr, snapshots.clone())?),
+            (Some(addr), None) => Some(LiveServer::start(addr)?),
+            (None, _) => None,
+        };
+        if let Some(live) = &live {
+            snapshots.get_or_insert_with(|| live.metrics());
+            simulation.add_sink(Box::new(live.sink()));
+        }
+        let dashboard = args.dashboard.then(|| match &snapshots {
+            Some(snapshots) => Dashboard::with_metrics(snapshots.clone(), end_time),
+            None => Dashboard::new(end_time),
+        });
+        if let Some(dashboard) = &dashboard {
+            snapshots.get_or_insert_with(|| dashboard.metrics());
+            simulation.add_sink(Box::new(dashboard.sink()));
+        }
+        if let Some(snapshots) = snapshots {
+            simulation.set_metrics(snapshots);
+        }
+        if let Some(path) = &args.pcap {
+            simulation.add_sink(Box::new(PcapSink::create(path, PcapOptions::default())?));
+        }
+        let critical_path = args.critical_path.then(CriticalPath::new);
+        if let Some(critical_path) = &critical_path {
+            simulation.add_sink(Box::new(critical_path.clone()));
+        }
+        let redraw = dashboard.map(|dashboard| dashboard.spawn(DEFAULT_REFRESH, io::stdout()));
+        config.run(&mut simulation, end_time);
+        if let Some(redraw) = redraw {
+            redraw.stop()?;
+        }
+        simulation.flush_sinks()?;
+        if let (Some(path), Some(recorded)) = (&args.trace, simulation.take_trace()) {
+            serde_json::to_writer(BufWriter::new(File::create(path)?), &recorded)?;
+        }
+        (simulation, critical_path)
+    } else {
+        if args.trace.is_some()
+            || args.metrics.is_some()
+            || args.live.is_some()
+            || args.dashboard
+            || args.digest
+            || args.pcap.is_some()
+            || args.critical_path
+        {
+            let options =
+                "--trace, --metrics, --live, --dashboard, --digest, --pcap and --critical-path";
+            return Err(format!("{} need --threads 1", options).into());
+        }
+        (run_async(&config, end_time, args.threads)?, None)
+    };
+
+    print_stats(&simulation.stats());
+    if let Some(critical_path) = critical_path {
+        print_critical_path(&critical_path.report());
+    }
+    if let Some(digest) = simulation.run_digest() {
+        println!("digest {}", digest);
+    }
+    if let Some(path) = &args.checkpoint {
+        simulation.checkpoint(path)?;
+    }
+    Ok(())
+}
 
-    println!("Machine 1 output queue: {:?}\n", machine1.output_queue);
-    println!("Machine 1 input queue: {:?}\n", machine1.input_queue);
-    println!("Machine 2 input queue: {:?}\n", machine2.input_queue);
-    println!("Machine 2 state: {:?}\n", machine2.state);
+// Runs the config on a multi threaded tokio runtime and puts the machines back into a
+// Simulation afterwards so the rest of run doesnt care which executor was used
+fn run_async(
+    config: &SimulationConfig,
+    end_time: Option<VirtualTime>,
+    threads: usize,
+) -> Result<Simulation<TopologyProcess>, Box<dyn Error>> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads)
+        .enable_all()
+        .build()?;
+    let machines = config.build_machines()?;
+    let machines = runtime.block_on(async {
+        let mut simulation = AsyncSimulation::new();
+        for machine in machines {
+            simulation.add_machine(machine);
+        }
+        // Same as SimulationConfig::run, held back messages go in once enough events ran
+        let mut steps = 0;
+        for (after, message) in config.initial_messages() {
+            while steps < after {
+                simulation.settle().await;
+                match (simulation.next_machine(), end_time) {
+                    (Some((_, rec_time)), Some(end_time)) if rec_time > end_time => break,
+                    (Some(_), _) => simulation.step().await,
+                    (None, _) => break,
+                };
+                steps += 1;
+            }
+            simulation.inject(message);
+        }
+        match end_time {
+            Some(end_time) => simulation.run_until(end_time).await,
+            None => simulation.run().await,
+        }
+        simulation.shutdown().await
+    });
+    let mut simulation = Simulation::new();
+    for machine in machines.into_values() {
+        simulation.add_machine(machine);
+    }
+    simulation.commit();
+    Ok(simulation)
+}
 
-    // ROLLBACK HAPPENING
-    let messages_to_unsend = machine1.recieve_outer(message3).unwrap();
-    for message in messages_to_unsend {
-        let _ = machine2.recieve_outer(message.clone());
+fn check(
+    config: &Path,
+    against: Protocol,
+    epoch: Delay,
+    end_time: Option<VirtualTime>,
+) -> Result<(), Box<dyn Error>> {
+    let config = SimulationConfig::load(config)?;
+    let reference = match against {
+        Protocol::Optimistic => Reference::Optimistic,
+        Protocol::Conservative => Reference::Conservative,
+        Protocol::Sequential => Reference::Sequential,
+    };
+    match check_determinism(&config, end_time, reference, epoch)? {
+        None => {
+            println!("runs agree");
+            Ok(())
+        }
+        Some(divergence) => Err(format!(
+            "runs diverge in the epoch starting at {} (digests {:?} and {:?})",
+            divergence.epoch_start, divergence.first, divergence.second
+        )
+        .into()),
     }
+}
 
-    println!("\n\n\nPost rollback!\n\n");
-    println!("Machine 1 output queue: {:?}\n", machine1.output_queue);
-    println!("Machine 1 input queue: {:?}\n", machine1.input_queue);
-    println!("Machine 2 input queue: {:?}\n", machine2.input_queue);
-    println!("Machine 2 state: {:?}\n", machine2.state);
+fn print_stats(stats: &SimulationStats) {
+    println!(
+        "{:>8} {:>10} {:>10} {:>10} {:>10} {:>12}",
+        "machine", "processed", "committed", "undone", "rollbacks", "antimessages"
+    );
+    let rows = stats
+        .machines
+        .iter()
+        .map(|(machine_id, machine)| (machine_id.to_string(), machine))
+        .chain(std::iter::once(("total".to_string(), &stats.total)));
+    for (name, machine) in rows {
+        println!(
+            "{:>8} {:>10} {:>10} {:>10} {:>10} {:>12}",
+            name,
+            machine.events_processed,
+            machine.events_committed,
+            machine.events_rolled_back,
+            machine.rollbacks,
+            machine.antimessages_sent
+        );
+    }
+    println!("efficiency {:.3}", stats.total.efficiency());
+    let horizon = &stats.horizon;
+    if horizon.cycles > 0 {
+        println!(
+            "event horizon: {} cycles, {:.2} events per cycle (widest {})",
+            horizon.cycles,
+            horizon.parallelism(),
+            horizon.widest
+        );
+    }
+}
 
+fn print_critical_path(report: &CriticalPathReport) {
+    println!(
+        "critical path: {} of {} events, speedup ceiling {:.2}",
+        report.length,
+        report.events,
+        report.speedup_ceiling()
+    );
+    for (machine_id, events) in &report.machines {
+        println!(
+            "{:>8} {:>10} events on the path ({:.1}%)",
+            machine_id,
+            events,
+            report.share(*machine_id) * 100.0
+        );
+    }
 }
 
-// The last case may be rare (or not existant depending on implementation) but still works under this system. In an asynchronous
-// setting if M1 sends a message then rollsback and sends an antimessage it is possible in some cases that the antimessage actually
-// arrives before the regular message. In this case if the antimessage is processed as normal and increments the local virtual time
-// the arrival of the regular message later will cause the rollback. There is an optimization here because if an antimessage is ever
-// at the front of the queue you can do a no-op and wait until it cancels out because processing it guarentees a rollback.
-fn send_antimessage_first() {
-    let mut machine1 = Machine::new(1, 0);
-    let mut machine2 = Machine::new(2, 0);
+fn replay(
+    path: &Path,
+    chrome_path: Option<&Path>,
+    svg_path: Option<&Path>,
+    axis: Axis,
+) -> Result<(), Box<dyn Error>> {
+    let trace: Trace = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    for event in &trace.events {
+        match event {
+            TraceEvent::Execute {
+                machine,
+                message_id,
+                sender,
+                rec_time,
+                sent,
+                ..
+            } => {
+                print!(
+                    "{:>6} machine {} executes message {} from {}",
+                    rec_time, machine, message_id, sender
+                );
+                for message in sent {
+                    print!(", sends {} to {} at {}", message.id, message.receiver, message.rec_time);
+                }
+                println!();
+            }
+            TraceEvent::Rollback {
+                machine,
+                from,
+                to,
+                cause_id,
+                cause_sender,
+                antimessage,
+                parent,
+                ..
+            } => {
+                let kind = if *antimessage { "antimessage" } else { "message" };
+                print!(
+                    "{:>6} machine {} rolls back to {} because of {} {} from {}",
+                    from, machine, to, kind, cause_id, cause_sender
+                );
+                if let Some(TraceEvent::Rollback { machine, from, .. }) =
+                    parent.and_then(|parent| trace.events.get(parent))
+                {
+                    print!(", set off by the rollback of machine {} at {}", machine, from);
+                }
+                println!();
+            }
+        }
+    }
+    for cascade in trace.cascades().iter().filter(|cascade| cascade.rollbacks > 1) {
+        println!(
+            "message {} from {} caused {} rollbacks {} deep on machines {:?}",
+            cascade.cause_id,
+            cascade.cause_sender,
+            cascade.rollbacks,
+            cascade.depth,
+            cascade.machines
+        );
+    }
 
-    let message0 = Message::new(1, 3, 2, 1, Sign::Message, Arc::new("message1".to_string()));
-    let message1 = Message::new(1, 3, 1, 2, Sign::Message, Arc::new("message2".to_string()));
-    let message2 = Message::new(2, 5, 1, 2, Sign::Message, Arc::new("message3".to_string()));
-    let message3 = Message::new(0, 1, 1, 2, Sign::Message, Arc::new("message4".to_string()));
+    if let Some(path) = chrome_path {
+        let axis = match axis {
+            Axis::Virtual => TimeAxis::Virtual,
+            Axis::Wall => TimeAxis::Wall,
+        };
+        chrome::write_chrome_trace(&trace, axis, BufWriter::new(File::create(path)?))?;
+    }
+    if let Some(path) = svg_path {
+        svg::write_svg(&trace, &SvgOptions::default(), BufWriter::new(File::create(path)?))?;
+    }
+    Ok(())
+}
 
-    machine1.recieve_outer(message0);
-    machine1.recieve_inner();
+fn partition(path: &Path, parts: usize) -> Result<(), Box<dyn Error>> {
+    let config = SimulationConfig::load(path)?;
+    let graph = config.graph();
+    let placement = graph.partition(parts);
+    let naive = graph.round_robin(parts);
+    println!("{:>8} {:>6}", "machine", "part");
+    for (machine_id, part) in &placement {
+        println!("{:>8} {:>6}", machine_id, part);
+    }
+    println!(
+        "{} links cross parts, {} with round robin",
+        graph.cut(&placement),
+        graph.cut(&naive)
+    );
+    Ok(())
+}
 
-    let sent_message1 = machine1.send_outer(message1);
-    
-    println!("Machine 1 output queue: {:?}\n", machine1.output_queue);
-    println!("Machine 1 input queue: {:?}\n", machine1.input_queue);
-    println!("Machine 2 input queue: {:?}\n", machine2.input_queue);
-    
-    // ROLLBACK HAPPENING
-    let messages_to_unsend = machine1.recieve_outer(message3).unwrap();
-    for message in messages_to_unsend {
-        let _ = machine2.recieve_outer(message.clone());
+// Held back messages go in right away, like in conservative runs, so stepping starts
+// from the same messages the run would have in the end
+fn debug(path: &Path) -> Result<(), Box<dyn Error>> {
+    let config = SimulationConfig::load(path)?;
+    let mut simulation = config.build()?;
+    for (_, message) in config.initial_messages() {
+        simulation.inject(message);
     }
-    // Antimessage first in queue so it wont poll
-    machine2.recieve_inner();
-    // Receive actual message second
-    machine2.recieve_outer(sent_message1);
-    
-    println!("\n\n\nPost rollback!\n\n");
-    println!("Machine 1 output queue: {:?}\n", machine1.output_queue);
-    println!("Machine 1 input queue: {:?}\n", machine1.input_queue);
-    println!("Machine 2 input queue: {:?}\n", machine2.input_queue);
+    println!("{} machines loaded, type help for the commands", simulation.machines().count());
+    let mut repl = Repl::new(simulation);
+    repl.run(io::stdin().lock(), io::stdout())?;
+    Ok(())
+}
 
+fn inspect(path: &Path, json: bool) -> Result<(), Box<dyn Error>> {
+    let checkpoint: Checkpoint<TopologyProcess> = Checkpoint::read(path)?;
+    if json {
+        let machines: Vec<_> = checkpoint.machines.values().map(Machine::dump).collect();
+        let dump = serde_json::json!({
+            "gvt": checkpoint.gvt.map(VirtualTime::ticks),
+            "in_transit": checkpoint.in_transit,
+            "machines": machines,
+        });
+        println!("{}", serde_json::to_string_pretty(&dump)?);
+        return Ok(());
+    }
+    match checkpoint.gvt {
+        Some(gvt) => println!("gvt {}", gvt),
+        None => println!("gvt none, nothing left to process"),
+    }
+    println!("{} messages in transit", checkpoint.in_transit.len());
+    println!(
+        "{:>8} {:>6} {:>8} {:>8} {:>8} {:>10}",
+        "machine", "lvt", "state", "inputs", "outputs", "in flight"
+    );
+    for (machine_id, machine) in &checkpoint.machines {
+        println!(
+            "{:>8} {:>6} {:>8} {:>8} {:>8} {:>10}",
+            machine_id,
+            machine.local_virtual_time(),
+            machine.state,
+            machine.input_queue.len(),
+            machine.output_queue.len(),
+            machine.in_flight.len()
+        );
+    }
+    Ok(())
 }
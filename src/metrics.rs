@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::machine::Machine;
+use crate::process::TimeWarpProcess;
+use crate::stats::MachineStats;
+use crate::time::message::{MachineId, VirtualTime};
+
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Numbers for watching a long run from a dashboard. The runtime publishes a snapshot
+// every so often (see Simulation::set_metrics and TcpNode::set_metrics) and whatever
+// is scraping the endpoint gets the latest one in the Prometheus text format. Counters
+// are totals since the start, rates like events or rollbacks per second are for the
+// dashboard to work out with rate().
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub gvt: Option<VirtualTime>,
+    pub machines: BTreeMap<MachineId, MachineMetrics>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MachineMetrics {
+    pub stats: MachineStats,
+    pub local_virtual_time: VirtualTime,
+    pub input_queue: usize,
+    pub output_queue: usize,
+    pub in_flight: usize,
+}
+
+impl MetricsSnapshot {
+    pub fn new<'a, P: TimeWarpProcess + 'a>(
+        gvt: Option<VirtualTime>,
+        machines: impl IntoIterator<Item = &'a Machine<P>>,
+    ) -> Self {
+        let machines = machines
+            .into_iter()
+            .map(|machine| {
+                let metrics = MachineMetrics {
+                    stats: machine.stats().clone(),
+                    local_virtual_time: machine.local_virtual_time(),
+                    input_queue: machine.input_queue.len(),
+                    output_queue: machine.output_queue.len(),
+                    in_flight: machine.in_flight.len(),
+                };
+                (machine.machine_id(), metrics)
+            })
+            .collect();
+        Self { gvt, machines }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        // There is no GVT once there is nothing left to do, leaving it out is how the
+        // text format says a value is missing
+        metric_header(&mut out, "vtw_gvt", "gauge", "Global virtual time");
+        if let Some(gvt) = self.gvt {
+            let _ = writeln!(out, "vtw_gvt {}", gvt);
+        }
+        let per_machine: [PerMachine; 9] = [
+            ("vtw_events_processed_total", "counter", "Events handed to the process", |m| {
+                m.stats.events_processed
+            }),
+            ("vtw_events_committed_total", "counter", "Events below GVT", |m| {
+                m.stats.events_committed
+            }),
+            ("vtw_events_rolled_back_total", "counter", "Events undone by rollbacks", |m| {
+                m.stats.events_rolled_back
+            }),
+            ("vtw_rollbacks_total", "counter", "Rollbacks", |m| m.stats.rollbacks),
+            ("vtw_antimessages_sent_total", "counter", "Antimessages sent", |m| {
+                m.stats.antimessages_sent
+            }),
+            ("vtw_local_virtual_time", "gauge", "Local virtual time", |m| {
+                m.local_virtual_time.ticks()
+            }),
+            ("vtw_input_queue_messages", "gauge", "Messages in the input queue", |m| {
+                m.input_queue as u64
+            }),
+            ("vtw_output_queue_messages", "gauge", "Messages in the output queue", |m| {
+                m.output_queue as u64
+            }),
+            ("vtw_in_flight_antimessages", "gauge", "Unacknowledged antimessages", |m| {
+                m.in_flight as u64
+            }),
+        ];
+        for (name, kind, help, value) in per_machine {
+            metric_header(&mut out, name, kind, help);
+            for (machine_id, metrics) in &self.machines {
+                let _ = writeln!(out, "{}{{machine=\"{}\"}} {}", name, machine_id, value(metrics));
+            }
+        }
+        out
+    }
+}
+
+// Name, type, help and how to get the value
+type PerMachine = (&'static str, &'static str, &'static str, fn(&MachineMetrics) -> u64);
+
+fn metric_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// Where the runtime puts its snapshots, cloning it gives another handle to the same one
+#[derive(Debug, Default, Clone)]
+pub struct MetricsHandle {
+    latest: Arc<Mutex<MetricsSnapshot>>,
+}
+
+impl MetricsHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn publish(&self, snapshot: MetricsSnapshot) {
+        *self.latest.lock().unwrap() = snapshot;
+    }
+
+    pub fn latest(&self) -> MetricsSnapshot {
+        self.latest.lock().unwrap().clone()
+    }
+}
+
+// A tiny HTTP server on its own thread that answers every request with the latest
+// snapshot, Prometheus only ever asks for one path so it doesnt look at which. It stops
+// when dropped.
+pub struct MetricsServer {
+    handle: MetricsHandle,
+    local_addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl MetricsServer {
+    pub fn start<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let handle = MetricsHandle::new();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let (serving, stop) = (handle.clone(), shutdown.clone());
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let _ = respond(stream, &serving);
+                    }
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(ACCEPT_POLL_INTERVAL);
+                    }
+                    Err(_) => return,
+                }
+            }
+        });
+        Ok(Self {
+            handle,
+            local_addr,
+            shutdown,
+        })
+    }
+
+    // Give this to the runtime
+    pub fn handle(&self) -> MetricsHandle {
+        self.handle.clone()
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+    }
+}
+
+fn respond(stream: TcpStream, handle: &MetricsHandle) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut reader = BufReader::new(stream);
+    // Read up to the blank line that ends the request headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line.trim_end() != "" {
+        line.clear();
+    }
+    let body = handle.latest().render();
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use crate::time::message::{Message, Sign};
+    use std::io::Read;
+
+    #[test]
+    fn test_endpoint_serves_the_latest_snapshot() {
+        let server = MetricsServer::start("127.0.0.1:0").unwrap();
+        let mut simulation = ring(2);
+        simulation.set_metrics(server.handle());
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("3".to_string())));
+        simulation.run();
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("vtw_events_committed_total{machine=\"1\"} 2"));
+        assert!(response.contains("# TYPE vtw_rollbacks_total counter"));
+        // Nothing left to do, so no GVT
+        assert!(!response.contains("\nvtw_gvt "));
+    }
+}
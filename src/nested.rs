@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+
+use crate::process::{Context, TimeWarpProcess};
+use crate::time::message::{Delay, MachineId, Message, MessageId, Sign, VirtualTime};
+use crate::trace::trace_warn;
+
+// Port the sub model sends itself on to get woken up for its next inner event
+pub const WAKE_PORT: &str = "nested:wake";
+
+// A whole model run as the process of one machine, so big models can be put together
+// from smaller ones that were written (and tested) on their own. Messages from outside
+// go to the entry machine of the inner model, or to the inner machine their port is
+// routed to, at the time they arrive. The inner machines send to each other as usual and
+// anything they send to an id that isnt one of them leaves the sub model, to the outer
+// machine with that id.
+//
+// The inner model runs in timestamp order one event at a time, like SequentialSimulation,
+// and the World (all inner states and the inner events still to come) is the state of
+// the outer machine. Rolling the outer machine back so rolls back the whole inner world
+// with it, and a straggler from outside is just another event for the inner model once
+// the world is back to where it was. To get to inner events that come after the last
+// message from outside the sub model sends itself a message on WAKE_PORT for the time of
+// the next one.
+//
+// Inner events run with a fresh Context, their side effects and shared variables wouldnt
+// be undone or kept consistent with the outer machine, so inner processes shouldnt use
+// them. Inner events at the same time run in the same order as in SequentialSimulation.
+#[derive(Debug)]
+pub struct SubModel<P: TimeWarpProcess> {
+    processes: BTreeMap<MachineId, P>,
+    initial: BTreeMap<MachineId, P::State>,
+    entry: MachineId,
+    ports: BTreeMap<String, MachineId>,
+}
+
+// Everything inside a SubModel, the state of the machine running it
+#[derive(Debug, Clone, Default)]
+pub struct World<S> {
+    states: BTreeMap<MachineId, S>,
+    pending: BTreeMap<(VirtualTime, u32, MachineId, MachineId, MessageId), Message>,
+    events: u64,
+    // Time of the latest wake up sent, so another one isnt sent for the same time
+    wake: Option<VirtualTime>,
+}
+
+impl<S> World<S> {
+    // The state of an inner machine, None until its first event
+    pub fn state(&self, machine_id: MachineId) -> Option<&S> {
+        self.states.get(&machine_id)
+    }
+
+    // Inner events run so far
+    pub fn events(&self) -> u64 {
+        self.events
+    }
+
+    pub fn next_event_time(&self) -> Option<VirtualTime> {
+        self.pending.keys().next().map(|(rec_time, ..)| *rec_time)
+    }
+}
+
+impl<S> World<S> {
+    fn schedule(&mut self, event: Message) {
+        let key = (event.rec_time, event.generation, event.receiver, event.sender, event.id);
+        self.pending.insert(key, event);
+    }
+}
+
+impl<P: TimeWarpProcess> SubModel<P> {
+    // Messages from outside go to entry unless their port is routed somewhere else
+    pub fn new(entry: MachineId) -> Self {
+        Self {
+            processes: BTreeMap::new(),
+            initial: BTreeMap::new(),
+            entry,
+            ports: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_machine(&mut self, machine_id: MachineId, process: P) {
+        self.processes.insert(machine_id, process);
+    }
+
+    pub fn add_machine_with_state(&mut self, machine_id: MachineId, process: P, state: P::State) {
+        self.initial.insert(machine_id, state);
+        self.add_machine(machine_id, process);
+    }
+
+    // Messages from outside on the port go to the inner machine
+    pub fn route_port(&mut self, port: &str, machine_id: MachineId) {
+        self.ports.insert(port.to_string(), machine_id);
+    }
+
+    fn run_event(&self, world: &mut World<P::State>, event: Message, ctx: &mut Context) {
+        let Some(process) = self.processes.get(&event.receiver) else {
+            trace_warn!(
+                receiver = event.receiver,
+                message_id = event.id,
+                "Dropping message for unknown inner machine"
+            );
+            return;
+        };
+        let state = world.states.entry(event.receiver).or_insert_with(|| {
+            self.initial.get(&event.receiver).cloned().unwrap_or_default()
+        });
+        let mut inner = Context::new(event.receiver, event.rec_time).with_event(&event);
+        process.on_message(state, &event, &mut inner);
+        world.events += 1;
+        for sent in inner.into_outbox() {
+            if self.processes.contains_key(&sent.receiver) {
+                world.schedule(sent);
+                continue;
+            }
+            let delay = sent.rec_time.saturating_since(ctx.now());
+            let (receiver, payload) = (sent.receiver, sent.message.as_ref().clone());
+            match (sent.port(), sent.data.clone()) {
+                (Some(port), Some(data)) => ctx.send_bytes_to_port(receiver, port, delay, data),
+                (None, Some(data)) => ctx.send_bytes(receiver, delay, data),
+                (Some(port), None) => ctx.send_to_port(receiver, port, delay, payload),
+                (None, None) => ctx.send(receiver, delay, payload),
+            }
+        }
+    }
+}
+
+impl<P: TimeWarpProcess> TimeWarpProcess for SubModel<P> {
+    type State = World<P::State>;
+
+    fn on_message(&self, world: &mut World<P::State>, message: &Message, ctx: &mut Context) {
+        let now = ctx.now();
+        if message.port() != Some(WAKE_PORT) {
+            let receiver = message
+                .port()
+                .and_then(|port| self.ports.get(port))
+                .copied()
+                .unwrap_or(self.entry);
+            let mut inner = Message::new(
+                message.send_time,
+                now,
+                message.sender,
+                receiver,
+                Sign::Message,
+                message.message.clone(),
+            );
+            inner.parent = Some(message.id);
+            inner.data = message.data.clone();
+            world.schedule(inner);
+        }
+        if world.wake == Some(now) {
+            world.wake = None;
+        }
+        while let Some(next) = world.pending.first_entry() {
+            if next.key().0 > now {
+                break;
+            }
+            let event = next.remove();
+            self.run_event(world, event, ctx);
+        }
+        let Some(next) = world.next_event_time() else {
+            return;
+        };
+        if world.wake.is_none_or(|wake| next < wake) {
+            let delay = next.saturating_since(now);
+            ctx.send_to_port(ctx.machine_id(), WAKE_PORT, delay, String::new());
+            world.wake = Some(next);
+        }
+    }
+
+    // Inner events run at the time of the outer event so whatever leaves has at least the
+    // delay it was sent with, and the next inner event is at least that far away too
+    fn lookahead(&self) -> Delay {
+        self.processes.values().map(P::lookahead).min().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::runtime::Simulation;
+    use std::sync::Arc;
+
+    // Counts its messages and passes each on if it has somewhere to pass it to
+    struct Pass {
+        next: Option<MachineId>,
+    }
+
+    impl TimeWarpProcess for Pass {
+        type State = usize;
+
+        fn on_message(&self, state: &mut usize, message: &Message, ctx: &mut Context) {
+            *state += 1;
+            if let Some(next) = self.next {
+                ctx.send(next, 2, message.message.as_ref().clone());
+            }
+        }
+
+        fn lookahead(&self) -> Delay {
+            Delay::new(2)
+        }
+    }
+
+    // Outer machine 5 is a chain 10 -> 11 that hands on to outer machine 6, which is a
+    // single machine 20
+    fn world() -> Simulation<SubModel<Pass>> {
+        let mut chain = SubModel::new(10);
+        chain.add_machine(10, Pass { next: Some(11) });
+        chain.add_machine(11, Pass { next: Some(6) });
+        let mut end = SubModel::new(20);
+        end.add_machine(20, Pass { next: None });
+        let mut simulation = Simulation::new();
+        simulation.add_machine(Machine::with_process(5, 0, chain));
+        simulation.add_machine(Machine::with_process(6, 0, end));
+        simulation
+    }
+
+    fn input(rec_time: usize) -> Message {
+        Message::new(0, rec_time, 5, 5, Sign::Message, Arc::new(String::new()))
+    }
+
+    #[test]
+    fn test_straggler_rolls_back_the_inner_world() {
+        let mut expected = world();
+        expected.inject(input(4));
+        expected.inject(input(10));
+        expected.run();
+
+        let mut simulation = world();
+        simulation.inject(input(10));
+        // Stepping doesnt commit anything, 4 is still after GVT
+        while simulation.step_until(12) {}
+        assert_eq!(simulation.machine(5).unwrap().state.state(11), Some(&1));
+        simulation.inject(input(4));
+        simulation.run();
+        assert!(simulation.machine(5).unwrap().stats().rollbacks > 0);
+
+        for (machine_id, inner) in [(5, 10), (5, 11), (6, 20)] {
+            let world = &simulation.machine(machine_id).unwrap().state;
+            let reference = &expected.machine(machine_id).unwrap().state;
+            assert_eq!(world.state(inner), Some(&2));
+            assert_eq!(world.state(inner), reference.state(inner));
+        }
+        assert_eq!(simulation.machine(6).unwrap().state.events(), 2);
+        assert_eq!(simulation.machine(5).unwrap().state.next_event_time(), None);
+    }
+}
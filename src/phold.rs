@@ -0,0 +1,197 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::latency::mix;
+use crate::machine::Machine;
+use crate::process::{Context, TimeWarpProcess};
+use crate::runtime::sequential::SequentialSimulation;
+use crate::runtime::Simulation;
+use crate::stats::SimulationStats;
+use crate::time::message::{Delay, MachineId, Message, Sign, VirtualTime};
+
+// PHOLD, the usual synthetic workload for benchmarking optimistic simulators. Every
+// machine starts with a few events and handling one just schedules another, so the
+// number of events in the system stays the same for the whole run. The new event goes
+// to a random other machine with probability remote_fraction and back to the same one
+// otherwise, lookahead plus an exponentially distributed delay later. Little work per
+// event and random communication make it mostly a measure of the queues, the scheduler
+// and the rollback machinery.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PholdConfig {
+    pub machines: usize,
+    // Events per machine at the start
+    pub population: usize,
+    pub remote_fraction: f64,
+    pub lookahead: Delay,
+    pub mean_delay: f64,
+    pub end_time: VirtualTime,
+    pub seed: u64,
+}
+
+impl Default for PholdConfig {
+    fn default() -> Self {
+        Self {
+            machines: 16,
+            population: 4,
+            remote_fraction: 0.5,
+            lookahead: Delay::new(1),
+            mean_delay: 100.0,
+            end_time: VirtualTime::new(100_000),
+            seed: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PholdProcess {
+    pub machines: usize,
+    pub remote_fraction: f64,
+    pub lookahead: Delay,
+    pub mean_delay: f64,
+    pub seed: u64,
+}
+
+impl TimeWarpProcess for PholdProcess {
+    // Events handled
+    type State = u64;
+
+    fn on_message(&self, state: &mut u64, message: &Message, ctx: &mut Context) {
+        *state += 1;
+        // Only what stays the same when the event runs again after a rollback goes into
+        // the draws, message ids dont
+        let draw = [ctx.machine_id() as u64, ctx.now().ticks(), message.sender as u64]
+            .into_iter()
+            .chain([message.send_time.ticks()])
+            .fold(self.seed, |hash, value| mix(hash ^ value));
+        let remote = unit(draw) < self.remote_fraction && self.machines > 1;
+        let to = if remote {
+            let other = (mix(draw) % (self.machines as u64 - 1)) as MachineId;
+            if other >= ctx.machine_id() { other + 1 } else { other }
+        } else {
+            ctx.machine_id()
+        };
+        let exponential = -(1.0 - unit(mix(draw ^ 1))).ln() * self.mean_delay;
+        ctx.send(to, self.lookahead() + Delay::new(exponential as u64), String::new());
+    }
+
+    fn lookahead(&self) -> Delay {
+        self.lookahead.max(Delay::new(1))
+    }
+}
+
+// Uniform in [0, 1)
+fn unit(draw: u64) -> f64 {
+    (draw >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl PholdConfig {
+    pub fn process(&self) -> PholdProcess {
+        PholdProcess {
+            machines: self.machines,
+            remote_fraction: self.remote_fraction,
+            lookahead: self.lookahead,
+            mean_delay: self.mean_delay,
+            seed: self.seed,
+        }
+    }
+
+    // The starting population, spread over the first mean_delay units of time
+    pub fn initial_messages(&self) -> Vec<Message> {
+        let spread = self.mean_delay.max(1.0) as u64;
+        (0..self.machines)
+            .flat_map(|machine_id| {
+                (0..self.population).map(move |nth| {
+                    let draw = mix(self.seed ^ mix((machine_id * self.population + nth) as u64));
+                    let rec_time = VirtualTime::new(1 + draw % spread);
+                    let payload = Arc::new(String::new());
+                    Message::new(0, rec_time, machine_id, machine_id, Sign::Message, payload)
+                })
+            })
+            .collect()
+    }
+
+    pub fn build(&self) -> Simulation<PholdProcess> {
+        let mut simulation = Simulation::new();
+        for machine_id in 0..self.machines {
+            simulation.add_machine(Machine::with_process(machine_id, 0, self.process()));
+        }
+        for message in self.initial_messages() {
+            simulation.inject(message);
+        }
+        simulation
+    }
+
+    pub fn build_sequential(&self) -> SequentialSimulation<PholdProcess> {
+        let mut simulation = SequentialSimulation::new();
+        for machine_id in 0..self.machines {
+            simulation.add_process(machine_id, self.process(), 0);
+        }
+        for message in self.initial_messages() {
+            simulation.inject(message);
+        }
+        simulation
+    }
+
+    // Builds and runs the model up to end_time on the single threaded executor
+    pub fn run(&self) -> PholdReport {
+        let mut simulation = self.build();
+        let start = Instant::now();
+        simulation.run_until(self.end_time);
+        PholdReport {
+            wall: start.elapsed(),
+            stats: simulation.stats(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PholdReport {
+    pub wall: Duration,
+    pub stats: SimulationStats,
+}
+
+impl PholdReport {
+    // Committed events per wall clock second
+    pub fn throughput(&self) -> f64 {
+        self.stats.total.events_committed as f64 / self.wall.as_secs_f64().max(f64::EPSILON)
+    }
+
+    // Rollbacks per committed event
+    pub fn rollback_rate(&self) -> f64 {
+        match self.stats.total.events_committed {
+            0 => 0.0,
+            committed => self.stats.total.rollbacks as f64 / committed as f64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phold_matches_the_sequential_run() {
+        let config = PholdConfig {
+            machines: 4,
+            population: 2,
+            end_time: VirtualTime::new(2_000),
+            seed: 9,
+            ..PholdConfig::default()
+        };
+        let report = config.run();
+        assert!(report.stats.total.events_committed > 0);
+        assert!(report.throughput() > 0.0);
+
+        let mut optimistic = config.build();
+        optimistic.run_until(config.end_time);
+        let mut sequential = config.build_sequential();
+        sequential.run_until(config.end_time);
+        for (machine_id, state) in sequential.states() {
+            assert_eq!(*state, optimistic.machine(machine_id).unwrap().state);
+        }
+        assert_eq!(sequential.events_processed(), report.stats.total.events_committed);
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::config::SimulationConfig;
+use crate::time::message::MachineId;
+
+// Which part (worker thread, node, rank) every machine goes to
+pub type Placement = BTreeMap<MachineId, usize>;
+
+// Splitting the machines of a run over workers so as little traffic as possible crosses
+// between them, messages within a worker are cheap and ones between workers go over a
+// channel or the wire. The input is the communication graph, a weight per pair of
+// machines for how much they talk (the links of a config, see SimulationConfig::placement).
+//
+// Parts are grown greedily first: each one starts from the unplaced machine with the most
+// traffic and keeps taking the unplaced machine that talks to it the most until it has
+// its share. Then Kernighan-Lin style passes swap pairs of machines between parts as long
+// as some swap lowers the traffic across, swapping keeps every part the size it was.
+// Ties go to the lowest machine id so the same graph always gives the same placement.
+#[derive(Debug, Clone, Default)]
+pub struct Graph {
+    machines: BTreeSet<MachineId>,
+    // Both directions of a pair added together, keyed by (lower, higher)
+    weights: BTreeMap<(MachineId, MachineId), u64>,
+    neighbours: BTreeMap<MachineId, BTreeMap<MachineId, u64>>,
+}
+
+impl Graph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_machine(&mut self, machine_id: MachineId) {
+        self.machines.insert(machine_id);
+    }
+
+    // Adds to how much two machines talk, the direction doesnt matter
+    pub fn add_traffic(&mut self, from: MachineId, to: MachineId, weight: u64) {
+        self.add_machine(from);
+        self.add_machine(to);
+        if from == to {
+            return;
+        }
+        *self.weights.entry((from.min(to), from.max(to))).or_insert(0) += weight;
+        *self.neighbours.entry(from).or_default().entry(to).or_insert(0) += weight;
+        *self.neighbours.entry(to).or_default().entry(from).or_insert(0) += weight;
+    }
+
+    fn weight(&self, a: MachineId, b: MachineId) -> u64 {
+        self.weights.get(&(a.min(b), a.max(b))).copied().unwrap_or(0)
+    }
+
+    fn neighbours(&self, machine_id: MachineId) -> impl Iterator<Item = (MachineId, u64)> + '_ {
+        self.neighbours
+            .get(&machine_id)
+            .into_iter()
+            .flat_map(|neighbours| neighbours.iter().map(|(id, weight)| (*id, *weight)))
+    }
+
+    // The traffic between machines in different parts
+    pub fn cut(&self, placement: &Placement) -> u64 {
+        self.weights
+            .iter()
+            .filter(|((a, b), _)| placement.get(a) != placement.get(b))
+            .map(|(_, weight)| weight)
+            .sum()
+    }
+
+    // Machine i of the sorted ids goes to part i % parts, what you get without a graph
+    pub fn round_robin(&self, parts: usize) -> Placement {
+        let parts = parts.max(1);
+        self.machines
+            .iter()
+            .enumerate()
+            .map(|(index, machine_id)| (*machine_id, index % parts))
+            .collect()
+    }
+
+    pub fn partition(&self, parts: usize) -> Placement {
+        let mut placement = self.grow(parts.max(1));
+        while self.best_swap(&mut placement) {}
+        placement
+    }
+
+    fn grow(&self, parts: usize) -> Placement {
+        let mut placement = Placement::new();
+        let mut unplaced = self.machines.clone();
+        for part in 0..parts {
+            // Spread what doesnt divide evenly over the first parts
+            let share = unplaced.len().div_ceil(parts - part);
+            // Traffic from each unplaced machine into the part so far
+            let mut pull: BTreeMap<MachineId, u64> = BTreeMap::new();
+            for _ in 0..share {
+                let next = unplaced
+                    .iter()
+                    .max_by_key(|id| {
+                        let total: u64 = self.neighbours(**id).map(|(_, weight)| weight).sum();
+                        (pull.get(*id).copied().unwrap_or(0), total, std::cmp::Reverse(**id))
+                    })
+                    .copied();
+                let Some(next) = next else {
+                    break;
+                };
+                unplaced.remove(&next);
+                placement.insert(next, part);
+                for (neighbour, weight) in self.neighbours(next) {
+                    *pull.entry(neighbour).or_insert(0) += weight;
+                }
+            }
+        }
+        placement
+    }
+
+    // What moving a machine out of its part would save, traffic to the other part minus
+    // traffic within its own
+    fn gain(&self, placement: &Placement, machine_id: MachineId, to: usize) -> i64 {
+        let from = placement[&machine_id];
+        self.neighbours(machine_id)
+            .map(|(neighbour, weight)| match placement[&neighbour] {
+                part if part == to => weight as i64,
+                part if part == from => -(weight as i64),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    // Applies the swap that lowers the cut the most, false if none does
+    fn best_swap(&self, placement: &mut Placement) -> bool {
+        let mut best: Option<(i64, MachineId, MachineId)> = None;
+        for (a, part_a) in placement.iter() {
+            for (b, part_b) in placement.range(a + 1..) {
+                if part_a == part_b {
+                    continue;
+                }
+                let gain = self.gain(placement, *a, *part_b) + self.gain(placement, *b, *part_a)
+                    - 2 * self.weight(*a, *b) as i64;
+                if gain > best.map_or(0, |(best_gain, _, _)| best_gain) {
+                    best = Some((gain, *a, *b));
+                }
+            }
+        }
+        let Some((_, a, b)) = best else {
+            return false;
+        };
+        let part_a = placement[&a];
+        let part_b = placement.insert(b, part_a).unwrap();
+        placement.insert(a, part_b);
+        true
+    }
+}
+
+impl SimulationConfig {
+    // The communication graph of the config, every link counting once
+    pub fn graph(&self) -> Graph {
+        let mut graph = Graph::new();
+        for machine in &self.machines {
+            graph.add_machine(machine.id);
+        }
+        for link in &self.links {
+            graph.add_traffic(link.from, link.to, 1);
+        }
+        graph
+    }
+
+    // Splits the machines into parts with little traffic between them, see placement.rs
+    pub fn placement(&self, parts: usize) -> Placement {
+        self.graph().partition(parts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partition_keeps_clusters_together() {
+        // Two triangles joined by one link, round robin splits both of them
+        let mut graph = Graph::new();
+        for (from, to) in [(0, 1), (1, 2), (2, 0), (3, 4), (4, 5), (5, 3), (2, 3)] {
+            graph.add_traffic(from, to, 1);
+        }
+        let naive = graph.round_robin(2);
+        assert_eq!(graph.cut(&naive), 5);
+
+        let placement = graph.partition(2);
+        assert_eq!(graph.cut(&placement), 1);
+        assert_eq!(placement[&0], placement[&1]);
+        assert_eq!(placement[&1], placement[&2]);
+        assert_ne!(placement[&2], placement[&3]);
+
+        // A bad start gets fixed by the swaps
+        let mut placement: Placement = [(0, 0), (1, 0), (3, 0), (2, 1), (4, 1), (5, 1)].into();
+        while graph.best_swap(&mut placement) {}
+        assert_eq!(graph.cut(&placement), 1);
+    }
+
+    #[test]
+    fn test_parts_stay_balanced() {
+        let config = SimulationConfig::parse(
+            include_str!("../scenarios/ring.yaml"),
+            crate::config::Format::Yaml,
+        )
+        .unwrap();
+        let placement = config.placement(3);
+        assert_eq!(placement.len(), config.machines.len());
+        let mut sizes = [0; 3];
+        for part in placement.values() {
+            sizes[*part] += 1;
+        }
+        assert!(sizes.iter().max().unwrap() - sizes.iter().min().unwrap() <= 1);
+    }
+}
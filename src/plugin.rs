@@ -0,0 +1,402 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::elvis::{from_hex, to_hex};
+use crate::process::{Context, TimeWarpProcess};
+use crate::time::message::{Delay, MachineId, Message, MessagePayload, VirtualTime};
+use crate::trace::trace_warn;
+
+// Machine logic from a plugin, meant for WebAssembly modules so models can be written in
+// whatever compiles to wasm. The ABI is kept small, a module exports
+//
+//   set_state(bytes)          replace the models state, empty means start fresh
+//   get_state() -> bytes      the models state after an event
+//   on_message(bytes) -> bytes  run one event, json in and out (see below)
+//
+// and the machine state is nothing but the bytes get_state hands back, so saving a
+// state is copying them out and a rollback is handing the old ones back with set_state.
+// For a module that keeps its state in linear memory get_state can be a copy of the
+// memory itself, the engine never looks inside.
+//
+// PluginModule is the part a host binding implements on top of the exports of an
+// instance, moving the bytes in and out of linear memory with the modules own allocator.
+// The wasm feature has one on wasmi, WasmModule in wasm.rs. Anything else that can speak
+// the ABI works too, the tests here use plain Rust.
+pub trait PluginModule: Send + 'static {
+    fn set_state(&mut self, state: &[u8]);
+
+    fn get_state(&mut self) -> Vec<u8>;
+
+    // Takes a json PluginEvent and returns a json list of PluginSend. Anything else, like
+    // an empty reply from a module that failed, sends nothing and counts as an invalid
+    // reply.
+    fn on_message(&mut self, event: &[u8]) -> Vec<u8>;
+}
+
+// What a module is told about the event it runs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PluginEvent {
+    pub now: VirtualTime,
+    pub machine_id: MachineId,
+    pub sender: MachineId,
+    pub port: Option<String>,
+    pub payload: MessagePayload,
+}
+
+// A message a module wants sent
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PluginSend {
+    pub receiver: MachineId,
+    pub delay: Delay,
+    #[serde(default)]
+    pub port: Option<String>,
+    pub payload: MessagePayload,
+}
+
+// Runs a PluginModule as a machines process. Calling into a module needs it mutable so it
+// sits behind a lock, and since the module keeps nothing between events that the machine
+// doesnt save every event loads the state into it first.
+#[derive(Debug)]
+pub struct PluginProcess<M> {
+    module: Mutex<M>,
+    lookahead: Delay,
+    invalid_replies: AtomicU64,
+}
+
+impl<M: PluginModule> PluginProcess<M> {
+    pub fn new(module: M) -> Self {
+        Self {
+            module: Mutex::new(module),
+            lookahead: Delay::ZERO,
+            invalid_replies: AtomicU64::new(0),
+        }
+    }
+
+    // The lookahead the module promises, see TimeWarpProcess::lookahead
+    pub fn with_lookahead(mut self, lookahead: impl Into<Delay>) -> Self {
+        self.lookahead = lookahead.into();
+        self
+    }
+
+    // Replies that werent a list of sends, over every execution so rolled back events
+    // count again when they run again
+    pub fn invalid_replies(&self) -> u64 {
+        self.invalid_replies.load(Ordering::Relaxed)
+    }
+}
+
+impl<M: PluginModule> TimeWarpProcess for PluginProcess<M> {
+    type State = Arc<Vec<u8>>;
+
+    fn on_message(&self, state: &mut Arc<Vec<u8>>, message: &Message, ctx: &mut Context) {
+        let event = PluginEvent {
+            now: ctx.now(),
+            machine_id: ctx.machine_id(),
+            sender: message.sender,
+            port: message.port().map(str::to_string),
+            payload: message.message.to_string(),
+        };
+        let event = serde_json::to_vec(&event).expect("plugin event cant be written as json");
+        let mut module = self.module.lock().unwrap();
+        module.set_state(state);
+        let reply = module.on_message(&event);
+        *state = Arc::new(module.get_state());
+        drop(module);
+
+        let sends: Vec<PluginSend> = match serde_json::from_slice(&reply) {
+            Ok(sends) => sends,
+            Err(_error) => {
+                self.invalid_replies.fetch_add(1, Ordering::Relaxed);
+                trace_warn!(
+                    machine_id = ctx.machine_id(),
+                    error = %_error,
+                    "Plugin sent back something that isnt a list of sends"
+                );
+                return;
+            }
+        };
+        for send in sends {
+            match send.port {
+                Some(port) => ctx.send_to_port(send.receiver, &port, send.delay, send.payload),
+                None => ctx.send(send.receiver, send.delay, send.payload),
+            }
+        }
+    }
+
+    fn lookahead(&self) -> Delay {
+        self.lookahead
+    }
+
+    fn state_size(&self, state: &Arc<Vec<u8>>) -> usize {
+        std::mem::size_of_val(state) + state.len()
+    }
+}
+
+// Speaks the ABI with a child process over its stdin and stdout, one json line per event
+// each way, for models written in a scripting language. python/vtw_plugin.py is the
+// Python side of it which keeps the state as any picklable object, so a model there is
+// just a function (the python feature runs such models in process instead, see
+// python.rs). Every event goes to the child together with the state to run it on
+// and the child answers with the new state and the sends:
+//
+//   {"state": "<hex>", "event": PluginEvent}
+//   {"state": "<hex>", "sends": [PluginSend, ..]}
+//
+// A child that dies or writes garbage makes on_message return an empty reply, so the
+// event sends nothing, keeps the state it ran on and counts as an invalid reply. A dead
+// child stays dead, every later event is one too.
+#[derive(Debug)]
+pub struct PipeModule {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    state: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct PipeRequest {
+    state: String,
+    event: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct PipeReply {
+    state: String,
+    sends: serde_json::Value,
+}
+
+impl PipeModule {
+    // Starts the command with its stdin and stdout piped to the module
+    pub fn spawn(command: &mut Command) -> io::Result<Self> {
+        let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Ok(Self {
+            child,
+            stdin,
+            stdout,
+            state: Vec::new(),
+        })
+    }
+
+    fn call(&mut self, event: &[u8]) -> io::Result<PipeReply> {
+        let request = PipeRequest {
+            state: to_hex(&self.state),
+            event: serde_json::from_slice(event)?,
+        };
+        serde_json::to_writer(&mut self.stdin, &request)?;
+        self.stdin.write_all(b"\n")?;
+        self.stdin.flush()?;
+        let mut line = String::new();
+        if self.stdout.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "plugin exited"));
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+impl PluginModule for PipeModule {
+    fn set_state(&mut self, state: &[u8]) {
+        self.state = state.to_vec();
+    }
+
+    fn get_state(&mut self) -> Vec<u8> {
+        self.state.clone()
+    }
+
+    fn on_message(&mut self, event: &[u8]) -> Vec<u8> {
+        let reply = match self.call(event) {
+            Ok(reply) => reply,
+            Err(_error) => {
+                trace_warn!(pid = self.child.id(), error = %_error, "Plugin process failed");
+                return Vec::new();
+            }
+        };
+        let Some(state) = from_hex(&reply.state) else {
+            trace_warn!(pid = self.child.id(), "Plugin state isnt hex encoded");
+            return Vec::new();
+        };
+        self.state = state;
+        reply.sends.to_string().into_bytes()
+    }
+}
+
+impl Drop for PipeModule {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::runtime::Simulation;
+    use crate::sink::VecSink;
+    use crate::time::message::Sign;
+
+    // Stands in for a wasm module, a counter kept as 8 little endian bytes that forwards
+    // every event to the next machine until it has seen 3
+    #[derive(Default)]
+    struct Counter {
+        count: u64,
+    }
+
+    impl PluginModule for Counter {
+        fn set_state(&mut self, state: &[u8]) {
+            self.count = match state.try_into() {
+                Ok(bytes) => u64::from_le_bytes(bytes),
+                Err(_) => 0,
+            };
+        }
+
+        fn get_state(&mut self) -> Vec<u8> {
+            self.count.to_le_bytes().to_vec()
+        }
+
+        fn on_message(&mut self, event: &[u8]) -> Vec<u8> {
+            let event: PluginEvent = serde_json::from_slice(event).unwrap();
+            self.count += 1;
+            let sends = if self.count < 3 {
+                vec![PluginSend {
+                    receiver: (event.machine_id + 1) % 2,
+                    delay: Delay::new(2),
+                    port: Some("count".to_string()),
+                    payload: self.count.to_string(),
+                }]
+            } else {
+                Vec::new()
+            };
+            serde_json::to_vec(&sends).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_plugin_state_lives_in_the_machine() {
+        let mut simulation = Simulation::new();
+        for machine_id in 0..2 {
+            let process = PluginProcess::new(Counter::default()).with_lookahead(2);
+            simulation.add_machine(Machine::with_process(machine_id, 0, process));
+        }
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("go".to_string())));
+        simulation.run();
+
+        // The machines take turns, 0 sees events at 1, 5 and 9 and 1 at 3 and 7
+        let count = |machine_id| {
+            let state = &simulation.machine(machine_id).unwrap().state;
+            u64::from_le_bytes(state.as_slice().try_into().unwrap())
+        };
+        assert_eq!(count(0), 3);
+        assert_eq!(count(1), 2);
+        assert_eq!(simulation.machine(1).unwrap().lookahead(), 2);
+    }
+
+    // Both machines remember every payload and answer with how many they have seen
+    const PING: &str = "
+import sys
+sys.path.insert(0, sys.argv[1])
+from vtw_plugin import run, send
+
+def on_message(seen, event):
+    seen = (seen or []) + [event['payload']]
+    if len(seen) == 2 and event['machine_id'] == 1:
+        return seen, []
+    return seen, [send(1 - event['machine_id'], 2, str(len(seen)), port='ping')]
+
+run(on_message)
+";
+
+    #[test]
+    fn test_pipe_modules_run_python_models() {
+        let python = concat!(env!("CARGO_MANIFEST_DIR"), "/python");
+        let mut simulation = Simulation::new();
+        for machine_id in 0..2 {
+            let mut command = Command::new("python3");
+            // Nothing to test against without a Python around
+            let Ok(module) = PipeModule::spawn(command.args(["-c", PING, python])) else {
+                return;
+            };
+            let process = PluginProcess::new(module);
+            simulation.add_machine(Machine::with_process(machine_id, 0, process));
+        }
+        let sink = VecSink::new();
+        simulation.add_sink(Box::new(sink.clone()));
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("go".to_string())));
+        simulation.run();
+        simulation.flush_sinks().unwrap();
+
+        // The second answer of machine 0 only says 2 if its state made it across events
+        let received: Vec<_> = sink
+            .events()
+            .into_iter()
+            .map(|event| (event.machine_id, event.message.message.to_string()))
+            .collect();
+        let expected = [(0, "go"), (1, "1"), (0, "1"), (1, "2")];
+        let expected: Vec<_> = expected.iter().map(|(id, m)| (*id, m.to_string())).collect();
+        assert_eq!(received, expected);
+        assert!(!simulation.machine(1).unwrap().state.is_empty());
+    }
+
+    // Counts what it is asked to run but answers with anything but a list of sends
+    #[derive(Default)]
+    struct Garbage {
+        count: u8,
+    }
+
+    impl PluginModule for Garbage {
+        fn set_state(&mut self, state: &[u8]) {
+            self.count = state.first().copied().unwrap_or(0);
+        }
+
+        fn get_state(&mut self) -> Vec<u8> {
+            vec![self.count]
+        }
+
+        fn on_message(&mut self, _event: &[u8]) -> Vec<u8> {
+            self.count += 1;
+            b"{\"receiver\": 0".to_vec()
+        }
+    }
+
+    #[test]
+    fn test_invalid_replies_send_nothing() {
+        let mut simulation = Simulation::new();
+        simulation.add_machine(Machine::with_process(0, 0, PluginProcess::new(Garbage::default())));
+        for rec_time in [1, 2] {
+            let message = Arc::new("go".to_string());
+            simulation.inject(Message::new(0, rec_time, 0, 0, Sign::Message, message));
+        }
+        simulation.run();
+
+        let machine = simulation.machine(0).unwrap();
+        assert_eq!(machine.process().invalid_replies(), 2);
+        assert_eq!(machine.stats().events_processed, 2);
+        assert_eq!(*machine.state, vec![2]);
+    }
+
+    #[test]
+    fn test_pipe_modules_that_write_garbage_send_nothing() {
+        let mut command = Command::new("sh");
+        let script = "read event; echo nope; read event";
+        let Ok(module) = PipeModule::spawn(command.args(["-c", script])) else {
+            return;
+        };
+        let mut simulation = Simulation::new();
+        simulation.add_machine(Machine::with_process(0, 0, PluginProcess::new(module)));
+        // Garbage for the first, the second finds it gone
+        for rec_time in [1, 2] {
+            let message = Arc::new("go".to_string());
+            simulation.inject(Message::new(0, rec_time, 0, 0, Sign::Message, message));
+        }
+        simulation.run();
+
+        let machine = simulation.machine(0).unwrap();
+        assert_eq!(machine.process().invalid_replies(), 2);
+        assert!(machine.state.is_empty());
+    }
+}
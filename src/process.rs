@@ -0,0 +1,263 @@
+use bytes::Bytes;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::effect::{Compensation, SideEffect};
+use crate::latency::{mix, Latencies};
+use crate::shared::{SharedVar, Versioned};
+use crate::time::message::{
+    Delay, MachineId, Message, MessageId, MessagePayload, Sign, TimeOverflow, VirtualTime,
+};
+use crate::time::pool;
+use crate::trace::trace_warn;
+
+// This is the abstraction between the time and the machine mentioned in machine.rs. A
+// process is the logic of a machine, the machine itself takes care of everything to do
+// with virtual time (queues, saving states, rolling back) and hands each message to
+// the process when it is time to execute it.
+//
+// Everything the process wants to be rolled back has to live in its State since that
+// is what gets saved and restored, which is why on_message only gets &self.
+pub trait TimeWarpProcess: Send + 'static {
+    // The machine clones the state before every event it processes. For a big state
+    // that only stays cheap with persistent data, keep the large parts behind an Arc and
+    // change them with Arc::make_mut so a snapshot shares whatever the event didnt touch.
+    // Saved states are shared between threads, hence Sync.
+    type State: Clone + Debug + Default + Send + Sync + 'static;
+
+    fn on_message(&self, state: &mut Self::State, message: &Message, ctx: &mut Context);
+
+    // The smallest delay this process will ever put on a message it sends. The runtimes
+    // use it to work out how far ahead conservative machines can safely go, so it must
+    // never be more than the real minimum, 0 (the default) is always correct.
+    fn lookahead(&self) -> Delay {
+        Delay::ZERO
+    }
+
+    // Roughly how many bytes a saved state takes, for Machine::memory_report. The default
+    // only sees the state itself and not whatever it keeps on the heap, a process with
+    // big vectors or maps in its state should count them here.
+    fn state_size(&self, state: &Self::State) -> usize {
+        std::mem::size_of_val(state)
+    }
+}
+
+// Handed to a process while it is executing a message so it can send new ones. The
+// messages are collected here and go through the machines send_outer once the process
+// is done so they get logged in the output queue.
+#[derive(Debug)]
+pub struct Context {
+    machine_id: MachineId,
+    now: VirtualTime,
+    outbox: Vec<Message>,
+    latencies: Option<Arc<Latencies>>,
+    samples: usize,
+    seed: u64,
+    draws: u64,
+    position: usize,
+    event: Option<MessageId>,
+    generation: u32,
+    compensations: Vec<Compensation>,
+    // The event already ran once and is only run again to rebuild a state
+    replaying: bool,
+    shared: Vec<Arc<dyn Versioned>>,
+}
+
+impl Context {
+    pub fn new(machine_id: MachineId, now: impl Into<VirtualTime>) -> Self {
+        Self {
+            machine_id,
+            now: now.into(),
+            outbox: Vec::new(),
+            latencies: None,
+            samples: 0,
+            seed: 0,
+            draws: 0,
+            position: 0,
+            event: None,
+            generation: 0,
+            compensations: Vec::new(),
+            replaying: false,
+            shared: Vec::new(),
+        }
+    }
+
+    pub fn replaying(mut self) -> Self {
+        self.replaying = true;
+        self
+    }
+
+    // The message being processed, what everything sent gets as its parent. Whatever is
+    // sent with no delay is a generation after it (see Message::generation).
+    pub fn with_event(mut self, event: &Message) -> Self {
+        self.event = Some(event.id);
+        self.generation = event.generation;
+        self
+    }
+
+    pub fn event_id(&self) -> Option<MessageId> {
+        self.event
+    }
+
+    pub fn with_latencies(mut self, latencies: Option<Arc<Latencies>>) -> Self {
+        self.latencies = latencies;
+        self
+    }
+
+    // Seed for random, see MachineBuilder::seed
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    // Where the event is among the ones at its time, see random
+    pub fn with_position(mut self, position: usize) -> Self {
+        self.position = position;
+        self
+    }
+
+    pub fn machine_id(&self) -> MachineId {
+        self.machine_id
+    }
+
+    // The local virtual time of the event being executed
+    pub fn now(&self) -> VirtualTime {
+        self.now
+    }
+
+    // Sends a payload to another machine to be received delay time units from now. A delay
+    // that would go past the end of time (VirtualTime::MAX) gets the message there, use
+    // try_send to be told instead.
+    pub fn send(&mut self, receiver: MachineId, delay: impl Into<Delay>, payload: MessagePayload) {
+        let delay = delay.into();
+        if self.now.checked_add(delay).is_none() {
+            trace_warn!(receiver, now = %self.now, %delay, "Message sent past the end of time");
+        }
+        let mut message = Message::new(
+            self.now,
+            self.now + delay,
+            self.machine_id,
+            receiver,
+            Sign::Message,
+            pool::payload(payload),
+        );
+        message.parent = self.event;
+        if delay == Delay::ZERO {
+            message.generation = self.generation.saturating_add(1);
+        }
+        self.outbox.push(message);
+    }
+
+    // Like send but sends nothing when the message would be received after the end of time
+    pub fn try_send(
+        &mut self,
+        receiver: MachineId,
+        delay: impl Into<Delay>,
+        payload: MessagePayload,
+    ) -> Result<(), TimeOverflow> {
+        let delay = delay.into();
+        self.now.try_add(delay)?;
+        self.send(receiver, delay, payload);
+        Ok(())
+    }
+
+    // Sends to a named input port of the receiver, see Message::port
+    pub fn send_to_port(
+        &mut self,
+        receiver: MachineId,
+        port: &str,
+        delay: impl Into<Delay>,
+        payload: MessagePayload,
+    ) {
+        self.send(receiver, delay, payload);
+        let sent = self.outbox.pop().unwrap();
+        self.outbox.push(sent.with_port(port));
+    }
+
+    // Sends binary data instead of a text payload, see Message::data. The bytes go
+    // wherever the message goes without being copied or converted.
+    pub fn send_bytes(
+        &mut self,
+        receiver: MachineId,
+        delay: impl Into<Delay>,
+        data: impl Into<Bytes>,
+    ) {
+        self.send(receiver, delay, MessagePayload::new());
+        let sent = self.outbox.pop().unwrap();
+        self.outbox.push(sent.with_data(data));
+    }
+
+    pub fn send_bytes_to_port(
+        &mut self,
+        receiver: MachineId,
+        port: &str,
+        delay: impl Into<Delay>,
+        data: impl Into<Bytes>,
+    ) {
+        self.send_bytes(receiver, delay, data);
+        let sent = self.outbox.pop().unwrap();
+        self.outbox.push(sent.with_port(port));
+    }
+
+    // A delay for a message to the receiver from the latency model of the link, None if
+    // the link doesnt have one and the process has to pick the delay itself
+    pub fn link_delay(&mut self, receiver: MachineId) -> Option<Delay> {
+        let delay = self
+            .latencies
+            .as_ref()?
+            .delay(self.machine_id, receiver, self.now, self.samples)?;
+        self.samples += 1;
+        Some(delay)
+    }
+
+    // A random number that only depends on the machines seed, the machine, the time of the
+    // event, its place among the events at that time (see InputQueue::position) and how
+    // many were drawn before in it. An event that is rolled back and run again draws the
+    // same numbers, which a thread_rng in the process wouldnt, and events at the same time
+    // draw different ones. The message id would tell them apart too, but a message sent
+    // again after a rollback gets a new one.
+    pub fn random(&mut self) -> u64 {
+        let draw = [self.machine_id as u64, self.now.ticks(), self.position as u64, self.draws]
+            .into_iter()
+            .fold(self.seed, |hash, value| mix(hash ^ value));
+        self.draws += 1;
+        draw
+    }
+
+    // Runs the action right away, the machine undoes it if the event is rolled back, see
+    // effect.rs. When the event is only being run again to rebuild a state the action
+    // already happened and nothing is done.
+    pub fn side_effect(&mut self, effect: SideEffect) {
+        if !self.replaying {
+            self.compensations.push(effect.perform());
+        }
+    }
+
+    // The value of a shared variable as of now, see shared.rs
+    pub fn read<T: Clone + Send + 'static>(&mut self, var: &SharedVar<T>) -> T {
+        if !self.replaying {
+            self.shared.push(var.handle());
+        }
+        var.read(self.machine_id, self.now, !self.replaying)
+    }
+
+    pub fn write<T: Clone + Send + 'static>(&mut self, var: &SharedVar<T>, value: T) {
+        if !self.replaying {
+            self.shared.push(var.handle());
+            var.write(self.machine_id, self.now, value);
+        }
+    }
+
+    // The shared variables the event touched
+    pub(crate) fn take_shared(&mut self) -> Vec<Arc<dyn Versioned>> {
+        std::mem::take(&mut self.shared)
+    }
+
+    pub fn into_outbox(self) -> Vec<Message> {
+        self.outbox
+    }
+
+    pub(crate) fn into_parts(self) -> (Vec<Message>, Vec<Compensation>) {
+        (self.outbox, self.compensations)
+    }
+}
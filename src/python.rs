@@ -0,0 +1,335 @@
+// Python bindings with pyo3, behind the python feature, so models can be written as
+// Python functions and run in the engine in the same process. Built as the cdylib with
+// the feature on (maturin does it, or rename libvirtual_time.so to virtual_time.so):
+//
+//     from virtual_time import Simulation, send
+//
+//     def on_message(count, event):
+//         count += 1
+//         return count, [send(1 - event["machine_id"], 1, str(count))] if count < 3 else []
+//
+//     simulation = Simulation()
+//     for machine_id in (0, 1):
+//         simulation.add_machine(machine_id, on_message, 0)
+//     simulation.inject(0, 1, "go")
+//     simulation.run()
+//     simulation.state(0)  # 3
+//
+// A handler gets the machines state and the event (now, machine_id, sender, port and
+// payload, like a PluginEvent) and gives back the new state and a list of sends. The
+// state can be any object pickle can handle, the machine keeps it pickled so saving it is
+// keeping the bytes and a rollback unpickles the old ones. The handler shouldnt keep
+// anything of its own between calls, it wouldnt be rolled back.
+//
+// An exception in a handler leaves the state as it was and sends nothing, run raises the
+// first one once it is done (the event that raised it may since have been rolled back).
+use pyo3::exceptions::{PyKeyError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+use std::sync::{Arc, Mutex};
+
+use crate::machine::Machine;
+use crate::plugin::PluginSend;
+use crate::process::{Context, TimeWarpProcess};
+use crate::runtime::Simulation;
+use crate::stats::MachineStats;
+use crate::time::message::{Delay, MachineId, Message, Sign, VirtualTime};
+
+#[derive(Debug)]
+pub struct PythonProcess {
+    handler: Py<PyAny>,
+    // Shared with the simulation that raises it
+    error: Arc<Mutex<Option<PyErr>>>,
+}
+
+impl PythonProcess {
+    fn call(
+        &self,
+        py: Python<'_>,
+        state: &[u8],
+        message: &Message,
+        ctx: &Context,
+    ) -> PyResult<(Vec<u8>, Vec<PluginSend>)> {
+        let pickle = py.import("pickle")?;
+        let state = pickle.call_method1("loads", (PyBytes::new(py, state),))?;
+        let event = PyDict::new(py);
+        event.set_item("now", ctx.now().ticks())?;
+        event.set_item("machine_id", ctx.machine_id())?;
+        event.set_item("sender", message.sender)?;
+        event.set_item("port", message.port())?;
+        event.set_item("payload", message.message.as_str())?;
+        let reply = self.handler.call1(py, (state, event))?;
+        let (state, sends): (Bound<'_, PyAny>, Vec<Bound<'_, PyDict>>) = reply.extract(py)?;
+        let state = pickle.call_method1("dumps", (state,))?.extract()?;
+        let sends = sends.iter().map(plugin_send).collect::<PyResult<_>>()?;
+        Ok((state, sends))
+    }
+}
+
+impl TimeWarpProcess for PythonProcess {
+    type State = Arc<Vec<u8>>;
+
+    fn on_message(&self, state: &mut Arc<Vec<u8>>, message: &Message, ctx: &mut Context) {
+        let reply = Python::with_gil(|py| self.call(py, state, message, ctx));
+        let (new_state, sends) = match reply {
+            Ok(reply) => reply,
+            Err(error) => {
+                self.error.lock().unwrap().get_or_insert(error);
+                return;
+            }
+        };
+        *state = Arc::new(new_state);
+        for send in sends {
+            match send.port {
+                Some(port) => ctx.send_to_port(send.receiver, &port, send.delay, send.payload),
+                None => ctx.send(send.receiver, send.delay, send.payload),
+            }
+        }
+    }
+
+    fn state_size(&self, state: &Arc<Vec<u8>>) -> usize {
+        std::mem::size_of_val(state) + state.len()
+    }
+}
+
+// A send as made by send() below, a dict with the fields of PluginSend
+fn plugin_send(send: &Bound<'_, PyDict>) -> PyResult<PluginSend> {
+    let field = |name: &str| {
+        send.get_item(name)?
+            .ok_or_else(|| PyKeyError::new_err(format!("a send needs a {}", name)))
+    };
+    let port = match send.get_item("port")? {
+        Some(port) if !port.is_none() => Some(port.extract()?),
+        _ => None,
+    };
+    Ok(PluginSend {
+        receiver: field("receiver")?.extract()?,
+        delay: Delay::new(field("delay")?.extract()?),
+        port,
+        payload: field("payload")?.extract()?,
+    })
+}
+
+#[pyfunction]
+#[pyo3(signature = (receiver, delay, payload, port=None))]
+fn send(
+    py: Python<'_>,
+    receiver: MachineId,
+    delay: u64,
+    payload: String,
+    port: Option<String>,
+) -> PyResult<Bound<'_, PyDict>> {
+    let send = PyDict::new(py);
+    send.set_item("receiver", receiver)?;
+    send.set_item("delay", delay)?;
+    send.set_item("payload", payload)?;
+    send.set_item("port", port)?;
+    Ok(send)
+}
+
+fn stats<'py>(py: Python<'py>, stats: &MachineStats) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new(py);
+    dict.set_item("events_processed", stats.events_processed)?;
+    dict.set_item("events_committed", stats.events_committed)?;
+    dict.set_item("events_rolled_back", stats.events_rolled_back)?;
+    dict.set_item("rollbacks", stats.rollbacks)?;
+    dict.set_item("antimessages_sent", stats.antimessages_sent)?;
+    Ok(dict)
+}
+
+// Runs on the thread that calls it with the GIL held, handlers take it again which is fine
+#[pyclass(name = "Simulation", unsendable)]
+pub struct PySimulation {
+    simulation: Simulation<PythonProcess>,
+    error: Arc<Mutex<Option<PyErr>>>,
+}
+
+impl PySimulation {
+    fn raise(&self) -> PyResult<()> {
+        match self.error.lock().unwrap().take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+#[pymethods]
+impl PySimulation {
+    #[new]
+    fn new() -> Self {
+        Self {
+            simulation: Simulation::new(),
+            error: Arc::default(),
+        }
+    }
+
+    #[pyo3(signature = (machine_id, handler, state=None))]
+    fn add_machine(
+        &mut self,
+        py: Python<'_>,
+        machine_id: MachineId,
+        handler: Py<PyAny>,
+        state: Option<Py<PyAny>>,
+    ) -> PyResult<()> {
+        if self.simulation.machine(machine_id).is_some() {
+            return Err(PyValueError::new_err(format!("machine {} exists", machine_id)));
+        }
+        let state: Vec<u8> = py.import("pickle")?.call_method1("dumps", (state,))?.extract()?;
+        let process = PythonProcess {
+            handler,
+            error: self.error.clone(),
+        };
+        let machine = Machine::with_state(machine_id, 0, process, Arc::new(state));
+        self.simulation.add_machine(machine);
+        Ok(())
+    }
+
+    #[pyo3(signature = (receiver, rec_time, payload, port=None))]
+    fn inject(
+        &mut self,
+        receiver: MachineId,
+        rec_time: u64,
+        payload: String,
+        port: Option<String>,
+    ) -> PyResult<()> {
+        let Some(machine) = self.simulation.machine(receiver) else {
+            return Err(PyKeyError::new_err(receiver));
+        };
+        let rec_time = VirtualTime::new(rec_time);
+        // The machine would drop it, see vtw_inject
+        if rec_time < machine.commit_horizon() {
+            return Err(PyValueError::new_err(format!(
+                "{} is before what machine {} already committed",
+                rec_time, receiver
+            )));
+        }
+        let payload = Arc::new(payload);
+        let mut message = Message::new(0, rec_time, receiver, receiver, Sign::Message, payload);
+        if let Some(port) = port {
+            message = message.with_port(port);
+        }
+        self.simulation.inject(message);
+        Ok(())
+    }
+
+    fn run(&mut self) -> PyResult<()> {
+        self.simulation.run();
+        self.raise()
+    }
+
+    fn run_until(&mut self, end_time: u64) -> PyResult<()> {
+        self.simulation.run_until(VirtualTime::new(end_time));
+        self.raise()
+    }
+
+    fn gvt(&self) -> Option<u64> {
+        self.simulation.gvt().map(VirtualTime::ticks)
+    }
+
+    // The current state of a machine, unpickled
+    fn state<'py>(&self, py: Python<'py>, machine_id: MachineId) -> PyResult<Bound<'py, PyAny>> {
+        let Some(machine) = self.simulation.machine(machine_id) else {
+            return Err(PyKeyError::new_err(machine_id));
+        };
+        let state = PyBytes::new(py, &machine.state);
+        py.import("pickle")?.call_method1("loads", (state,))
+    }
+
+    // The stats of one machine, or the totals without one
+    #[pyo3(signature = (machine_id=None))]
+    fn stats<'py>(
+        &self,
+        py: Python<'py>,
+        machine_id: Option<MachineId>,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        match machine_id {
+            None => stats(py, &self.simulation.stats().total),
+            Some(machine_id) => match self.simulation.machine(machine_id) {
+                Some(machine) => stats(py, machine.stats()),
+                None => Err(PyKeyError::new_err(machine_id)),
+            },
+        }
+    }
+}
+
+#[pymodule]
+fn virtual_time(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PySimulation>()?;
+    module.add_function(wrap_pyfunction!(send, module)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::ffi::c_str;
+
+    fn run_python(code: &std::ffi::CStr) -> PyResult<()> {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let module = PyModule::new(py, "virtual_time")?;
+            virtual_time(&module)?;
+            let globals = PyDict::new(py);
+            globals.set_item("virtual_time", module)?;
+            py.run(code, Some(&globals), None)
+        })
+    }
+
+    #[test]
+    fn test_python_handlers_keep_their_state_in_the_machine() {
+        // The second answer of machine 0 only says 2 if its state made it across events
+        run_python(c_str!(
+            "
+Simulation, send = virtual_time.Simulation, virtual_time.send
+
+def on_message(seen, event):
+    seen = seen + [event['payload']]
+    if len(seen) == 2 and event['machine_id'] == 1:
+        return seen, []
+    return seen, [send(1 - event['machine_id'], 2, str(len(seen)), port='ping')]
+
+simulation = Simulation()
+for machine_id in (0, 1):
+    simulation.add_machine(machine_id, on_message, [])
+simulation.inject(0, 1, 'go')
+simulation.run()
+assert simulation.state(0) == ['go', '1'], simulation.state(0)
+assert simulation.state(1) == ['1', '2'], simulation.state(1)
+assert simulation.stats()['events_committed'] == 4
+assert simulation.gvt() is None
+try:
+    simulation.inject(0, 2, 'late')
+    raise AssertionError('injected before the commit horizon')
+except ValueError:
+    pass
+"
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_exceptions_in_handlers_are_raised_by_run() {
+        run_python(c_str!(
+            "
+def on_message(count, event):
+    if event['payload'] == 'bad':
+        raise RuntimeError('bad event')
+    return count + 1, []
+
+simulation = virtual_time.Simulation()
+simulation.add_machine(0, on_message, 0)
+simulation.inject(0, 1, 'good')
+simulation.inject(0, 2, 'bad')
+simulation.inject(0, 3, 'good')
+try:
+    simulation.run()
+    raise AssertionError('run didnt raise')
+except RuntimeError as error:
+    assert str(error) == 'bad event'
+assert simulation.state(0) == 2
+simulation.run()
+"
+        ))
+        .unwrap();
+    }
+}
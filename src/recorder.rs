@@ -0,0 +1,313 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::Instant;
+
+use crate::time::message::{MachineId, Message, MessageId, Sign, VirtualTime};
+
+// A record of everything that happened in a run, executions and rollbacks in the order
+// they happened. Unlike the committed history this includes all the speculative work
+// that ended up being thrown away, which is the interesting part when looking at why a
+// model rolls back so much. Wall times are nanoseconds since recording started.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trace {
+    pub events: Vec<TraceEvent>,
+    // Names of the machines that have one, see Trace::label
+    #[serde(default)]
+    pub names: BTreeMap<MachineId, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TraceEvent {
+    Execute {
+        machine: MachineId,
+        message_id: MessageId,
+        sender: MachineId,
+        send_time: VirtualTime,
+        rec_time: VirtualTime,
+        wall_start: u64,
+        wall_duration: u64,
+        sent: Vec<SentMessage>,
+    },
+    // The machine went back from its local virtual time `from` to `to` because of the
+    // message (or antimessage) `cause_id`. An antimessage comes from a rollback on its
+    // sender, parent is where that one is in events so the rollbacks a straggler sets off
+    // form a tree, see Trace::cascades.
+    Rollback {
+        machine: MachineId,
+        from: VirtualTime,
+        to: VirtualTime,
+        cause_id: MessageId,
+        cause_sender: MachineId,
+        wall_time: u64,
+        #[serde(default)]
+        antimessage: bool,
+        #[serde(default)]
+        parent: Option<usize>,
+        // Antimessages this rollback sent
+        #[serde(default)]
+        cancelled: Vec<MessageId>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SentMessage {
+    pub id: MessageId,
+    pub receiver: MachineId,
+    pub rec_time: VirtualTime,
+}
+
+impl Trace {
+    // What the exports call the machine, its name or "machine <id>"
+    pub fn label(&self, machine: MachineId) -> String {
+        match self.names.get(&machine) {
+            Some(name) => name.clone(),
+            None => format!("machine {machine}"),
+        }
+    }
+
+    pub fn machines(&self) -> Vec<MachineId> {
+        let mut machines: Vec<_> = self
+            .events
+            .iter()
+            .flat_map(|event| match event {
+                TraceEvent::Execute { machine, sent, .. } => {
+                    let mut ids = vec![*machine];
+                    ids.extend(sent.iter().map(|message| message.receiver));
+                    ids
+                }
+                TraceEvent::Rollback { machine, .. } => vec![*machine],
+            })
+            .collect();
+        machines.sort_unstable();
+        machines.dedup();
+        machines
+    }
+
+    // The rollback whose antimessages the one at index came from, None for the first in a
+    // cascade (and for events that arent rollbacks)
+    pub fn rollback_parent(&self, index: usize) -> Option<usize> {
+        match self.events.get(index)? {
+            TraceEvent::Rollback { parent, .. } => *parent,
+            TraceEvent::Execute { .. } => None,
+        }
+    }
+
+    // The rollback that started the cascade the one at index is part of
+    pub fn rollback_root(&self, mut index: usize) -> usize {
+        while let Some(parent) = self.rollback_parent(index) {
+            index = parent;
+        }
+        index
+    }
+
+    // The rollback at index and everything it went on to cause, in the order it happened
+    pub fn rollback_descendants(&self, index: usize) -> Vec<usize> {
+        let mut found = BTreeSet::from([index]);
+        for (later, _) in self.events.iter().enumerate().skip(index + 1) {
+            if self.rollback_parent(later).is_some_and(|parent| found.contains(&parent)) {
+                found.insert(later);
+            }
+        }
+        found.into_iter().collect()
+    }
+
+    // Every cascade of rollbacks that started with a straggler, the biggest first. The
+    // cause of the root is the message that came in too late, usually the model behaviour
+    // to look at when a run keeps rolling back.
+    pub fn cascades(&self) -> Vec<Cascade> {
+        let mut cascades: BTreeMap<usize, Cascade> = BTreeMap::new();
+        let mut depths: HashMap<usize, usize> = HashMap::new();
+        for (index, event) in self.events.iter().enumerate() {
+            let TraceEvent::Rollback {
+                machine,
+                cause_id,
+                cause_sender,
+                parent,
+                ..
+            } = event
+            else {
+                continue;
+            };
+            let depth = parent.map_or(1, |parent| depths.get(&parent).copied().unwrap_or(0) + 1);
+            depths.insert(index, depth);
+            let root = self.rollback_root(index);
+            let cascade = cascades.entry(root).or_insert_with(|| Cascade {
+                root,
+                cause_id: *cause_id,
+                cause_sender: *cause_sender,
+                rollbacks: 0,
+                depth: 0,
+                machines: Vec::new(),
+            });
+            cascade.rollbacks += 1;
+            cascade.depth = cascade.depth.max(depth);
+            if !cascade.machines.contains(machine) {
+                cascade.machines.push(*machine);
+            }
+        }
+        let mut cascades: Vec<_> = cascades.into_values().collect();
+        cascades.sort_by_key(|cascade| (std::cmp::Reverse(cascade.rollbacks), cascade.root));
+        cascades
+    }
+}
+
+// A straggler and the rollbacks it set off, see Trace::cascades
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cascade {
+    // Index of the first rollback in events
+    pub root: usize,
+    pub cause_id: MessageId,
+    pub cause_sender: MachineId,
+    pub rollbacks: usize,
+    // Longest chain of rollbacks causing each other, 1 when nothing followed the first
+    pub depth: usize,
+    // Machines that rolled back, in the order they first did
+    pub machines: Vec<MachineId>,
+}
+
+// Collects the trace while a runtime is executing
+#[derive(Debug)]
+pub struct Recorder {
+    start: Instant,
+    trace: Trace,
+    // Which rollback sent each antimessage, for the parent of the rollbacks they cause
+    cancelled_by: HashMap<MessageId, usize>,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            trace: Trace::default(),
+            cancelled_by: HashMap::new(),
+        }
+    }
+
+    // Nanoseconds since the recording started, used to stamp events
+    pub fn now(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+
+    pub fn record_execution(
+        &mut self,
+        machine: MachineId,
+        message: &Message,
+        wall_start: u64,
+        sent: &[Message],
+    ) {
+        let wall_duration = self.now().saturating_sub(wall_start);
+        self.trace.events.push(TraceEvent::Execute {
+            machine,
+            message_id: message.id,
+            sender: message.sender,
+            send_time: message.send_time,
+            rec_time: message.rec_time,
+            wall_start,
+            wall_duration,
+            sent: sent
+                .iter()
+                .map(|message| SentMessage {
+                    id: message.id,
+                    receiver: message.receiver,
+                    rec_time: message.rec_time,
+                })
+                .collect(),
+        });
+    }
+
+    pub fn record_rollback(
+        &mut self,
+        machine: MachineId,
+        from: VirtualTime,
+        to: VirtualTime,
+        cause: &Message,
+        antimessages: &[Message],
+    ) {
+        let wall_time = self.now();
+        let antimessage = cause.sign == Sign::Antimessage;
+        let parent = match antimessage {
+            true => self.cancelled_by.get(&cause.id).copied(),
+            false => None,
+        };
+        let index = self.trace.events.len();
+        for cancelled in antimessages {
+            self.cancelled_by.insert(cancelled.id, index);
+        }
+        self.trace.events.push(TraceEvent::Rollback {
+            machine,
+            from,
+            to,
+            cause_id: cause.id,
+            cause_sender: cause.sender,
+            wall_time,
+            antimessage,
+            parent,
+            cancelled: antimessages.iter().map(|message| message.id).collect(),
+        });
+    }
+
+    pub fn set_name(&mut self, machine: MachineId, name: &str) {
+        self.trace.names.insert(machine, name.to_string());
+    }
+
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+
+    pub fn into_trace(self) -> Trace {
+        self.trace
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_rollbacks_link_to_the_one_that_caused_them() {
+        let message = |rec_time, hops: &str| {
+            Message::new(0, rec_time, 0, 0, Sign::Message, Arc::new(hops.to_string()))
+        };
+        let mut simulation = ring(2);
+        simulation.start_recording();
+        simulation.inject(message(3, "1"));
+        simulation.inject(message(9, "1"));
+        // Machine 0 runs 3 and 9, machine 1 the messages they send for 6 and 12
+        for _ in 0..4 {
+            simulation.step();
+        }
+        // Undoes 9 on machine 0, and 12 on machine 1 once the antimessage gets there
+        simulation.inject(message(5, "0"));
+        simulation.run();
+
+        let trace = simulation.take_trace().unwrap();
+        let rollbacks: Vec<_> = trace
+            .events
+            .iter()
+            .enumerate()
+            .filter(|(_, event)| matches!(event, TraceEvent::Rollback { .. }))
+            .map(|(index, _)| index)
+            .collect();
+        let [first, second] = rollbacks[..] else {
+            panic!("expected two rollbacks, got {:?}", rollbacks);
+        };
+        assert_eq!(trace.rollback_parent(first), None);
+        assert_eq!(trace.rollback_parent(second), Some(first));
+        assert_eq!(trace.rollback_root(second), first);
+        assert_eq!(trace.rollback_descendants(first), [first, second]);
+
+        let cascades = trace.cascades();
+        assert_eq!(cascades.len(), 1);
+        assert_eq!((cascades[0].rollbacks, cascades[0].depth), (2, 2));
+        assert_eq!(cascades[0].machines, [0, 1]);
+        assert_eq!(cascades[0].cause_sender, 0);
+    }
+}
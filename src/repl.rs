@@ -0,0 +1,380 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write as _};
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+
+use crate::machine::Machine;
+use crate::process::TimeWarpProcess;
+use crate::runtime::breakpoints::{Breakpoint, BreakpointHit, BreakpointId};
+use crate::runtime::Simulation;
+use crate::time::message::{MachineId, Message, Sign, VirtualTime};
+
+pub const PROMPT: &str = "(vtw) ";
+
+const HELP: &str = "\
+commands
+  status                        GVT and every machines LVT and queues
+  step [n]                      process the next n events, 1 by default
+  continue [t]                  run until a breakpoint, the end or time t
+  inject <to> <at> <payload>    a message from outside for machine to at time at
+  state <machine> [at <t>]      the state now, or what it was at time t
+  break at <t>                  stop before any event at t or later
+  break on <machine|*> <text>   stop before the machine processes a payload with text in it
+  break gvt <t>                 stop once GVT gets to t
+  break rollback <depth>        stop after a rollback undoing more than depth events
+  breaks                        list the breakpoints
+  delete <n>                    remove breakpoint n
+  quit";
+
+// A command console for poking at a simulation, to debug a model by stepping through it
+// event by event. The breakpoints are the simulations own (see runtime/breakpoints.rs),
+// continue runs until one of them pauses the simulation. A breakpoint on an event stops
+// right before it, so the state shown is the one the event is about to see, and the
+// next step or continue runs the event.
+pub struct Repl<P: TimeWarpProcess> {
+    simulation: Simulation<P>,
+    // What every breakpoint was set with, to list them
+    breakpoints: BTreeMap<BreakpointId, String>,
+    done: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplError {
+    UnknownCommand(String),
+    // How the command is used
+    Usage(&'static str),
+    UnknownMachine(MachineId),
+    UnknownBreakpoint(usize),
+    StateGone { machine: MachineId, at: VirtualTime },
+}
+
+impl fmt::Display for ReplError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplError::UnknownCommand(command) => {
+                write!(f, "unknown command {:?}, try help", command)
+            }
+            ReplError::Usage(usage) => write!(f, "usage: {}", usage),
+            ReplError::UnknownMachine(id) => write!(f, "there is no machine {}", id),
+            ReplError::UnknownBreakpoint(n) => write!(f, "there is no breakpoint {}", n),
+            ReplError::StateGone { machine, at } => {
+                write!(f, "the state of machine {} at {} isnt kept anymore", machine, at)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplError {}
+
+impl<P: TimeWarpProcess> Repl<P> {
+    pub fn new(simulation: Simulation<P>) -> Self {
+        Self {
+            simulation,
+            breakpoints: BTreeMap::new(),
+            done: false,
+        }
+    }
+
+    pub fn simulation(&self) -> &Simulation<P> {
+        &self.simulation
+    }
+
+    pub fn into_simulation(self) -> Simulation<P> {
+        self.simulation
+    }
+
+    // True once quit was given
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    // Reads commands until quit or the end of the input, errors are written out like
+    // any other answer
+    pub fn run(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        write!(output, "{}", PROMPT)?;
+        output.flush()?;
+        for line in input.lines() {
+            match self.execute(&line?) {
+                Ok(answer) if answer.is_empty() => {}
+                Ok(answer) => writeln!(output, "{}", answer)?,
+                Err(error) => writeln!(output, "error: {}", error)?,
+            }
+            if self.done {
+                return Ok(());
+            }
+            write!(output, "{}", PROMPT)?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn execute(&mut self, line: &str) -> Result<String, ReplError> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => Ok(String::new()),
+            ["help"] => Ok(HELP.to_string()),
+            ["quit"] | ["exit"] => {
+                self.done = true;
+                Ok(String::new())
+            }
+            ["status"] => Ok(self.status()),
+            ["step"] => Ok(self.step(1)),
+            ["step", n] => Ok(self.step(parse(n, "step [n]")?)),
+            ["continue"] => Ok(self.resume(None)),
+            ["continue", until] => Ok(self.resume(Some(parse(until, "continue [t]")?))),
+            ["inject", to, at, payload @ ..] if !payload.is_empty() => {
+                let usage = "inject <to> <at> <payload>";
+                let (to, at): (MachineId, VirtualTime) = (parse(to, usage)?, parse(at, usage)?);
+                self.machine(to)?;
+                let payload = Arc::new(payload.join(" "));
+                self.simulation.inject(Message::new(at, at, to, to, Sign::Message, payload));
+                Ok(format!("injected for machine {} at {}", to, at))
+            }
+            ["inject", ..] => Err(ReplError::Usage("inject <to> <at> <payload>")),
+            ["state", machine] => {
+                let machine = self.machine(parse(machine, "state <machine> [at <t>]")?)?;
+                Ok(format!("{:?}", machine.state))
+            }
+            ["state", machine, "at", at] => {
+                let usage = "state <machine> [at <t>]";
+                let (machine_id, at) = (parse(machine, usage)?, parse(at, usage)?);
+                let state = self.machine(machine_id)?.state_at(at);
+                let state = state.ok_or(ReplError::StateGone {
+                    machine: machine_id,
+                    at,
+                })?;
+                Ok(format!("{:?}", state))
+            }
+            ["state", ..] => Err(ReplError::Usage("state <machine> [at <t>]")),
+            ["break", "at", at] => {
+                let at: VirtualTime = parse(at, "break at <t>")?;
+                let breakpoint = Breakpoint::any_event(move |message| message.rec_time >= at);
+                Ok(self.add_breakpoint(breakpoint, format!("at {}", at)))
+            }
+            ["break", "on", machine, text @ ..] if !text.is_empty() => {
+                let contains = text.join(" ");
+                let description = format!("processing {:?}", contains);
+                let matches = move |message: &Message| message.message.contains(&contains);
+                match *machine {
+                    "*" => Ok(self.add_breakpoint(
+                        Breakpoint::any_event(matches),
+                        format!("on any machine {}", description),
+                    )),
+                    machine => {
+                        let machine = parse(machine, "break on <machine|*> <text>")?;
+                        Ok(self.add_breakpoint(
+                            Breakpoint::event(machine, matches),
+                            format!("on machine {} {}", machine, description),
+                        ))
+                    }
+                }
+            }
+            ["break", "gvt", at] => {
+                let at: VirtualTime = parse(at, "break gvt <t>")?;
+                Ok(self.add_breakpoint(Breakpoint::Gvt(at), format!("when GVT gets to {}", at)))
+            }
+            ["break", "rollback", depth] => {
+                let depth = parse(depth, "break rollback <depth>")?;
+                let description = format!("on rollbacks deeper than {}", depth);
+                Ok(self.add_breakpoint(Breakpoint::RollbackDeeper(depth), description))
+            }
+            ["break", ..] => Err(ReplError::Usage(
+                "break at <t> | on <machine|*> <text> | gvt <t> | rollback <depth>",
+            )),
+            ["breaks"] => Ok(self.list_breakpoints()),
+            ["delete", id] => {
+                let id = parse(id, "delete <n>")?;
+                if !self.simulation.remove_breakpoint(id) {
+                    return Err(ReplError::UnknownBreakpoint(id));
+                }
+                let removed = self.breakpoints.remove(&id).unwrap_or_default();
+                Ok(format!("deleted breakpoint {} {}", id, removed))
+            }
+            [command, ..] => Err(ReplError::UnknownCommand(command.to_string())),
+        }
+    }
+
+    fn machine(&self, machine_id: MachineId) -> Result<&Machine<P>, ReplError> {
+        self.simulation.machine(machine_id).ok_or(ReplError::UnknownMachine(machine_id))
+    }
+
+    fn add_breakpoint(&mut self, breakpoint: Breakpoint, description: String) -> String {
+        let id = self.simulation.add_breakpoint(breakpoint);
+        let answer = format!("breakpoint {} {}", id, description);
+        self.breakpoints.insert(id, description);
+        answer
+    }
+
+    fn list_breakpoints(&mut self) -> String {
+        // GVT breakpoints go away once they hit
+        let set: BTreeSet<_> = self.simulation.breakpoints().map(|(id, _)| id).collect();
+        self.breakpoints.retain(|id, _| set.contains(id));
+        if self.breakpoints.is_empty() {
+            return "no breakpoints".to_string();
+        }
+        let mut out = String::new();
+        for (id, description) in &self.breakpoints {
+            let _ = writeln!(out, "{} {}", id, description);
+        }
+        out.trim_end().to_string()
+    }
+
+    fn status(&mut self) -> String {
+        // Injected messages only show up in the queues once delivered
+        self.simulation.deliver_pending();
+        let mut out = String::new();
+        match self.simulation.gvt() {
+            Some(gvt) => {
+                let _ = writeln!(out, "GVT {}", gvt);
+            }
+            None => {
+                let _ = writeln!(out, "GVT none, nothing left to do");
+            }
+        }
+        for machine in self.simulation.machines() {
+            let next = machine.peek_next_message();
+            let next = next.map_or("nothing".to_string(), |next| next.rec_time.to_string());
+            let _ = writeln!(
+                out,
+                "machine {} lvt {} next {} input {} output {}",
+                machine.machine_id(),
+                machine.local_virtual_time(),
+                next,
+                machine.input_queue.len(),
+                machine.output_queue.len()
+            );
+        }
+        out.trim_end().to_string()
+    }
+
+    // The machine that runs next and the message it processes
+    fn next_event(&mut self) -> Option<(MachineId, Message)> {
+        self.simulation.deliver_pending();
+        let (machine_id, _) = self.simulation.next_machine()?;
+        let message = self.simulation.machine(machine_id)?.peek_next_message()?;
+        Some((machine_id, message))
+    }
+
+    fn events_processed(&self) -> u64 {
+        self.simulation.machines().map(|machine| machine.stats().events_processed).sum()
+    }
+
+    // Steps run the next event whatever breakpoints it hits, the others are still told
+    fn step(&mut self, n: usize) -> String {
+        let mut out = String::new();
+        for _ in 0..n {
+            let next = self.next_event();
+            if !self.simulation.step() {
+                out.push_str("nothing left to do");
+                break;
+            }
+            match self.simulation.take_breakpoint_hit() {
+                // Stopped right before it, the next step runs it
+                Some(hit) if hit.message_id.is_some() => {
+                    self.simulation.step();
+                }
+                Some(hit) => {
+                    let _ = writeln!(out, "{}", self.describe(&hit));
+                }
+                None => {}
+            }
+            if let Some((machine_id, message)) = next {
+                let _ = writeln!(
+                    out,
+                    "machine {} processed {:?} at {}",
+                    machine_id, message.message, message.rec_time
+                );
+            }
+        }
+        out.trim_end().to_string()
+    }
+
+    fn resume(&mut self, until: Option<VirtualTime>) -> String {
+        let before = self.events_processed();
+        self.simulation.resume();
+        match until {
+            Some(until) => self.simulation.run_until(until),
+            None => self.simulation.run(),
+        }
+        let events = self.events_processed() - before;
+        match (self.simulation.take_breakpoint_hit(), until) {
+            (Some(hit), _) => format!("ran {} events, {}", events, self.describe(&hit)),
+            (None, Some(until)) => format!("ran {} events, nothing left up to {}", events, until),
+            (None, None) => format!("ran {} events, nothing left to do", events),
+        }
+    }
+
+    fn describe(&self, hit: &BreakpointHit) -> String {
+        let what = match (hit.machine_id, hit.depth) {
+            (Some(machine_id), Some(depth)) => {
+                format!("machine {} rolled back {} events to {}", machine_id, depth, hit.at)
+            }
+            (Some(machine_id), None) => {
+                let next = self.simulation.machine(machine_id).and_then(Machine::peek_next_message);
+                let payload = next.map(|next| next.message).unwrap_or_default();
+                format!(
+                    "machine {} is about to process {:?} at {}",
+                    machine_id, payload, hit.at
+                )
+            }
+            (None, _) => format!("GVT is at {}", hit.at),
+        };
+        format!("breakpoint {} hit: {}", hit.id, what)
+    }
+}
+
+fn parse<T: std::str::FromStr>(word: &str, usage: &'static str) -> Result<T, ReplError> {
+    word.parse().map_err(|_| ReplError::Usage(usage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+
+    #[test]
+    fn test_stepping_through_a_ring() {
+        let mut repl = Repl::new(ring(2));
+        let script = "\
+            inject 0 1 5\n\
+            break on 1 2\n\
+            break at 12\n\
+            continue\n\
+            state 1\n\
+            step\n\
+            continue\n\
+            state 0 at 7\n\
+            delete 1\n\
+            breaks\n\
+            frobnicate\n\
+            continue\n\
+            continue\n\
+            quit\n\
+            status\n";
+        let mut output = Vec::new();
+        repl.run(script.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let answers: Vec<_> = output.split(PROMPT).map(str::trim).collect();
+        assert_eq!(
+            answers[1..],
+            [
+                "injected for machine 0 at 1",
+                "breakpoint 1 on machine 1 processing \"2\"",
+                "breakpoint 2 at 12",
+                "ran 3 events, breakpoint 1 hit: machine 1 is about to process \"2\" at 10",
+                "1",
+                "machine 1 processed \"2\" at 10",
+                "ran 0 events, breakpoint 2 hit: machine 0 is about to process \"1\" at 13",
+                "2",
+                "deleted breakpoint 1 on machine 1 processing \"2\"",
+                "2 at 12",
+                "error: unknown command \"frobnicate\", try help",
+                "ran 1 events, breakpoint 2 hit: machine 1 is about to process \"0\" at 16",
+                "ran 1 events, nothing left to do",
+                "",
+            ]
+        );
+        assert!(repl.is_done());
+        assert_eq!(repl.simulation().machine(1).unwrap().state, 3);
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+// Runs the same model a number of times with different seeds and summarizes what came
+// out. A replication is any function from a seed to named outputs (mean queue length,
+// events committed, whatever the model measures), replications dont share anything so
+// they can run on as many threads as asked for. Replication i gets base_seed + i, so the
+// same base seed always gives the same replications no matter how many threads ran them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Replications {
+    pub count: usize,
+    pub base_seed: u64,
+    pub threads: usize,
+}
+
+impl Default for Replications {
+    fn default() -> Self {
+        Self {
+            count: 10,
+            base_seed: 0,
+            threads: 1,
+        }
+    }
+}
+
+pub type Outputs = BTreeMap<String, f64>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplicationReport {
+    // Seed and outputs of every replication, in seed order
+    pub runs: Vec<(u64, Outputs)>,
+    pub summaries: BTreeMap<String, Summary>,
+}
+
+// Mean of an output over the replications with a 95% confidence interval, using the t
+// distribution since there are usually only a handful of replications
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub samples: usize,
+    pub mean: f64,
+    pub std_dev: f64,
+    // The interval is mean +- half_width, infinite with a single sample
+    pub half_width: f64,
+}
+
+impl Summary {
+    pub fn from_samples(samples: &[f64]) -> Self {
+        let n = samples.len();
+        let mean = samples.iter().sum::<f64>() / n.max(1) as f64;
+        if n < 2 {
+            return Self {
+                samples: n,
+                mean,
+                std_dev: 0.0,
+                half_width: f64::INFINITY,
+            };
+        }
+        let variance = samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+        let std_dev = variance.sqrt();
+        Self {
+            samples: n,
+            mean,
+            std_dev,
+            half_width: t_95(n - 1) * std_dev / (n as f64).sqrt(),
+        }
+    }
+
+    pub fn interval(&self) -> (f64, f64) {
+        (self.mean - self.half_width, self.mean + self.half_width)
+    }
+}
+
+// Two sided 95% critical values of the t distribution, the normal one past 30 degrees
+// of freedom is close enough
+fn t_95(degrees_of_freedom: usize) -> f64 {
+    const TABLE: [f64; 30] = [
+        12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179,
+        2.160, 2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064,
+        2.060, 2.056, 2.052, 2.048, 2.045, 2.042,
+    ];
+    TABLE.get(degrees_of_freedom.wrapping_sub(1)).copied().unwrap_or(1.960)
+}
+
+impl Replications {
+    pub fn run<F>(&self, replicate: F) -> ReplicationReport
+    where
+        F: Fn(u64) -> Outputs + Sync,
+    {
+        let next = AtomicUsize::new(0);
+        let runs = Mutex::new(Vec::with_capacity(self.count));
+        thread::scope(|scope| {
+            for _ in 0..self.threads.clamp(1, self.count.max(1)) {
+                scope.spawn(|| loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    if index >= self.count {
+                        return;
+                    }
+                    let seed = self.base_seed.wrapping_add(index as u64);
+                    let outputs = replicate(seed);
+                    runs.lock().unwrap().push((seed, outputs));
+                });
+            }
+        });
+        let mut runs = runs.into_inner().unwrap();
+        runs.sort_by_key(|(seed, _)| *seed);
+        ReplicationReport::from_runs(runs)
+    }
+}
+
+impl ReplicationReport {
+    // Outputs missing from some replications are summarized over the ones that have them
+    pub fn from_runs(runs: Vec<(u64, Outputs)>) -> Self {
+        let mut samples: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+        for (_, outputs) in &runs {
+            for (name, value) in outputs {
+                samples.entry(name.clone()).or_default().push(*value);
+            }
+        }
+        let summaries = samples
+            .into_iter()
+            .map(|(name, values)| (name, Summary::from_samples(&values)))
+            .collect();
+        Self { runs, summaries }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phold::PholdConfig;
+    use crate::time::message::VirtualTime;
+
+    #[test]
+    fn test_replications_are_reproducible_across_threads() {
+        let replicate = |seed| {
+            let report = PholdConfig {
+                machines: 3,
+                population: 2,
+                end_time: VirtualTime::new(500),
+                seed,
+                ..PholdConfig::default()
+            }
+            .run();
+            Outputs::from([(
+                "committed".to_string(),
+                report.stats.total.events_committed as f64,
+            )])
+        };
+        let serial = Replications {
+            count: 6,
+            base_seed: 40,
+            threads: 1,
+        }
+        .run(replicate);
+        let parallel = Replications {
+            count: 6,
+            base_seed: 40,
+            threads: 4,
+        }
+        .run(replicate);
+        assert_eq!(serial, parallel);
+        assert_eq!(serial.runs.first().unwrap().0, 40);
+
+        let summary = Summary::from_samples(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]);
+        assert!((summary.mean - 5.0).abs() < 1e-9);
+        assert!((summary.std_dev - 2.138).abs() < 1e-3);
+        let (low, high) = summary.interval();
+        assert!(low < 5.0 && high > 5.0);
+        assert!((summary.half_width - 2.365 * summary.std_dev / 8f64.sqrt()).abs() < 1e-9);
+    }
+}
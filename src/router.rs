@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::time::message::MachineId;
+
+// Where every machine can be reached and what it is called. The endpoint is whatever the
+// runtime delivers to, the node hosting the machine for a TcpNode or nothing at all for
+// a Simulation that has every machine itself. Names are for people, so topology files
+// and traces can say "bank" instead of 3 and a process can be handed a name to look up
+// instead of an id someone had to count out. A name belongs to one machine at a time and
+// a machine has at most one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Router<E> {
+    endpoints: BTreeMap<MachineId, E>,
+    names: BTreeMap<String, MachineId>,
+    labels: BTreeMap<MachineId, String>,
+}
+
+impl<E> Default for Router<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouterError {
+    NameTaken { name: String, machine_id: MachineId },
+}
+
+impl fmt::Display for RouterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouterError::NameTaken { name, machine_id } => {
+                write!(f, "the name {name} is already taken by machine {machine_id}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RouterError {}
+
+impl<E> Router<E> {
+    pub fn new() -> Self {
+        Self {
+            endpoints: BTreeMap::new(),
+            names: BTreeMap::new(),
+            labels: BTreeMap::new(),
+        }
+    }
+
+    // Returns the endpoint the machine had before, if it had one
+    pub fn insert(&mut self, machine_id: MachineId, endpoint: E) -> Option<E> {
+        self.endpoints.insert(machine_id, endpoint)
+    }
+
+    // Forgets where the machine is, its name stays in case it shows up again
+    pub fn remove(&mut self, machine_id: MachineId) -> Option<E> {
+        self.endpoints.remove(&machine_id)
+    }
+
+    pub fn lookup(&self, machine_id: MachineId) -> Option<&E> {
+        self.endpoints.get(&machine_id)
+    }
+
+    pub fn contains(&self, machine_id: MachineId) -> bool {
+        self.endpoints.contains_key(&machine_id)
+    }
+
+    // Every machine with an endpoint in id order
+    pub fn iter(&self) -> impl Iterator<Item = (MachineId, &E)> {
+        self.endpoints.iter().map(|(machine_id, endpoint)| (*machine_id, endpoint))
+    }
+
+    // Gives the machine a name, replacing the one it had. Naming a machine what it is
+    // already called is fine, taking another machines name isnt.
+    pub fn set_name(&mut self, machine_id: MachineId, name: &str) -> Result<(), RouterError> {
+        match self.names.get(name) {
+            Some(owner) if *owner != machine_id => {
+                return Err(RouterError::NameTaken {
+                    name: name.to_string(),
+                    machine_id: *owner,
+                });
+            }
+            _ => {}
+        }
+        if let Some(old) = self.labels.insert(machine_id, name.to_string()) {
+            self.names.remove(&old);
+        }
+        self.names.insert(name.to_string(), machine_id);
+        Ok(())
+    }
+
+    pub fn lookup_by_name(&self, name: &str) -> Option<MachineId> {
+        self.names.get(name).copied()
+    }
+
+    pub fn name(&self, machine_id: MachineId) -> Option<&str> {
+        self.labels.get(&machine_id).map(String::as_str)
+    }
+
+    // Every machine that has a name, by id
+    pub fn names(&self) -> &BTreeMap<MachineId, String> {
+        &self.labels
+    }
+
+    // The name if there is one and the id otherwise, what traces and logs show
+    pub fn label(&self, machine_id: MachineId) -> String {
+        match self.name(machine_id) {
+            Some(name) => name.to_string(),
+            None => format!("machine {machine_id}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names_belong_to_one_machine() {
+        let mut router = Router::new();
+        router.insert(1, "node a");
+        router.insert(2, "node b");
+        router.set_name(1, "bank").unwrap();
+        router.set_name(2, "teller").unwrap();
+        assert_eq!(router.lookup_by_name("bank"), Some(1));
+        assert_eq!(router.lookup(2), Some(&"node b"));
+        assert_eq!(
+            router.set_name(2, "bank"),
+            Err(RouterError::NameTaken {
+                name: "bank".to_string(),
+                machine_id: 1,
+            })
+        );
+
+        // Renaming frees the old name up
+        router.set_name(1, "vault").unwrap();
+        assert_eq!(router.lookup_by_name("bank"), None);
+        router.set_name(2, "bank").unwrap();
+        assert_eq!(router.label(2), "bank");
+        assert_eq!(router.label(3), "machine 3");
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::BTreeMap;
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+use crate::machine::{ExampleProcess, ExecutionPolicy, Machine};
+use crate::process::TimeWarpProcess;
+use crate::time::message::{MachineId, Message, Sign, VirtualTime};
+use crate::trace::trace_warn;
+
+// Async variant of Simulation for embedding the simulator inside an existing tokio service.
+// Every machine runs as its own task and owns its machine, the only way to reach it is
+// the mpsc channel feeding recieve_outer (and the step/ack commands). The scheduler
+// routes whatever the machines send and, once every delivery has been handled, tells
+// the machine with the earliest unprocessed message to run.
+//
+// Has to be created inside a tokio runtime since adding a machine spawns its task.
+pub struct AsyncSimulation<P: TimeWarpProcess = ExampleProcess> {
+    machines: BTreeMap<MachineId, MachineHandle<P>>,
+    reports: UnboundedReceiver<Report>,
+    report_sender: UnboundedSender<Report>,
+    injected: UnboundedReceiver<Message>,
+    injector: UnboundedSender<Message>,
+    // Commands sent to machine tasks that havent reported back yet
+    outstanding: usize,
+}
+
+struct MachineHandle<P: TimeWarpProcess> {
+    commands: UnboundedSender<Command>,
+    task: JoinHandle<Machine<P>>,
+    // What the machine last reported about itself
+    status: MachineStatus,
+}
+
+enum Command {
+    Deliver(Message),
+    Step,
+    Acknowledge(Message),
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct MachineStatus {
+    // Earliest positive message waiting to be processed
+    next_ready: Option<VirtualTime>,
+    // Earliest message of any sign waiting, includes antimessages at the head
+    next_queued: Option<VirtualTime>,
+    in_flight: Option<VirtualTime>,
+    earliest_send: Option<VirtualTime>,
+    policy: ExecutionPolicy,
+}
+
+struct Report {
+    machine_id: MachineId,
+    status: MachineStatus,
+    outgoing: Vec<Message>,
+}
+
+impl<P: TimeWarpProcess> Default for AsyncSimulation<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: TimeWarpProcess> AsyncSimulation<P> {
+    pub fn new() -> Self {
+        let (report_sender, reports) = mpsc::unbounded_channel();
+        let (injector, injected) = mpsc::unbounded_channel();
+        Self {
+            machines: BTreeMap::new(),
+            reports,
+            report_sender,
+            injected,
+            injector,
+            outstanding: 0,
+        }
+    }
+
+    pub fn add_machine(&mut self, machine: Machine<P>) {
+        let machine_id = machine.machine_id();
+        let status = status_of(&machine);
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let task = tokio::spawn(machine_task(machine, receiver, self.report_sender.clone()));
+        self.machines.insert(
+            machine_id,
+            MachineHandle {
+                commands,
+                task,
+                status,
+            },
+        );
+    }
+
+    // A handle other tasks can use to push messages into the running simulation, they are
+    // routed the next time the scheduler settles
+    pub fn injector(&self) -> UnboundedSender<Message> {
+        self.injector.clone()
+    }
+
+    pub fn inject(&mut self, message: Message) {
+        self.route(message);
+    }
+
+    // Waits until every delivery (and every delivery those cause) has been handled
+    pub async fn settle(&mut self) {
+        loop {
+            while let Ok(message) = self.injected.try_recv() {
+                self.route(message);
+            }
+            if self.outstanding == 0 {
+                return;
+            }
+            let Some(report) = self.reports.recv().await else {
+                return;
+            };
+            self.outstanding -= 1;
+            if let Some(handle) = self.machines.get_mut(&report.machine_id) {
+                handle.status = report.status;
+            }
+            for message in report.outgoing {
+                self.route(message);
+            }
+        }
+    }
+
+    fn route(&mut self, message: Message) {
+        let antimessage = (message.sign == Sign::Antimessage).then(|| message.clone());
+        let sender = message.sender;
+        let Some(receiver) = self.machines.get(&message.receiver) else {
+            trace_warn!(
+                receiver = message.receiver,
+                message_id = message.id,
+                "Dropping message for unknown machine"
+            );
+            // Nobody else will, and GVT cant pass it while its in flight
+            self.acknowledge(sender, antimessage);
+            return;
+        };
+        if receiver.commands.send(Command::Deliver(message)).is_ok() {
+            self.outstanding += 1;
+        }
+        // The receivers channel is in order so the antimessage is as good as delivered
+        self.acknowledge(sender, antimessage);
+    }
+
+    fn acknowledge(&mut self, sender: MachineId, antimessage: Option<Message>) {
+        if let (Some(antimessage), Some(sender)) = (antimessage, self.machines.get(&sender)) {
+            if sender.commands.send(Command::Acknowledge(antimessage)).is_ok() {
+                self.outstanding += 1;
+            }
+        }
+    }
+
+    // Conservative machines are only picked once their next message is safe, see
+    // Simulation::next_machine
+    pub fn next_machine(&self) -> Option<(MachineId, VirtualTime)> {
+        let safe_bound = self.safe_bound();
+        self.machines
+            .iter()
+            .filter_map(|(machine_id, handle)| Some((*machine_id, handle.status.next_ready?)))
+            .filter(|(machine_id, rec_time)| {
+                self.machines[machine_id].status.policy == ExecutionPolicy::Optimistic
+                    || safe_bound.is_none_or(|bound| *rec_time <= bound)
+            })
+            .min_by_key(|(machine_id, rec_time)| (*rec_time, *machine_id))
+    }
+
+    // Processes a single message on the machine with the earliest one, returns false when
+    // there was nothing left to do
+    pub async fn step(&mut self) -> bool {
+        self.settle().await;
+        let Some((machine_id, _)) = self.next_machine() else {
+            return false;
+        };
+        if self.machines[&machine_id].commands.send(Command::Step).is_ok() {
+            self.outstanding += 1;
+        }
+        self.settle().await;
+        true
+    }
+
+    pub async fn run(&mut self) {
+        while self.step().await {}
+    }
+
+    pub async fn run_until(&mut self, end_time: impl Into<VirtualTime>) {
+        let end_time = end_time.into();
+        loop {
+            self.settle().await;
+            match self.next_machine() {
+                Some((_, rec_time)) if rec_time <= end_time => {
+                    self.step().await;
+                }
+                _ => return,
+            }
+        }
+    }
+
+    // Same definition as Simulation::gvt, only valid once the simulation has settled
+    pub async fn gvt(&mut self) -> Option<VirtualTime> {
+        self.settle().await;
+        self.local_gvt()
+    }
+
+    // Same as Simulation::safe_bound, from what the machines last reported. Only valid
+    // once the simulation has settled since nothing is in transit then.
+    fn safe_bound(&self) -> Option<VirtualTime> {
+        self.machines
+            .values()
+            .flat_map(|handle| [handle.status.earliest_send, handle.status.in_flight])
+            .flatten()
+            .min()
+    }
+
+    // GVT from what the machines last reported
+    fn local_gvt(&self) -> Option<VirtualTime> {
+        self.machines
+            .values()
+            .flat_map(|handle| [handle.status.next_queued, handle.status.in_flight])
+            .flatten()
+            .min()
+    }
+
+    // Stops every machine task and hands the machines back
+    pub async fn shutdown(mut self) -> BTreeMap<MachineId, Machine<P>> {
+        self.settle().await;
+        let mut machines = BTreeMap::new();
+        for (machine_id, handle) in std::mem::take(&mut self.machines) {
+            drop(handle.commands);
+            let machine = handle.task.await.expect("machine task panicked");
+            machines.insert(machine_id, machine);
+        }
+        machines
+    }
+}
+
+fn status_of<P: TimeWarpProcess>(machine: &Machine<P>) -> MachineStatus {
+    let next = machine.peek_next_message();
+    MachineStatus {
+        next_ready: next
+            .as_ref()
+            .filter(|message| message.sign == Sign::Message)
+            .map(|message| message.rec_time),
+        next_queued: next.map(|message| message.rec_time),
+        in_flight: machine.in_flight.min_rec_time(),
+        earliest_send: machine.earliest_send(),
+        policy: machine.effective_policy(),
+    }
+}
+
+async fn machine_task<P: TimeWarpProcess>(
+    mut machine: Machine<P>,
+    mut commands: UnboundedReceiver<Command>,
+    reports: UnboundedSender<Report>,
+) -> Machine<P> {
+    while let Some(command) = commands.recv().await {
+        let outgoing = match command {
+            Command::Deliver(message) => machine.recieve_outer(message).unwrap_or_default(),
+            Command::Step => machine.recieve_inner(),
+            Command::Acknowledge(antimessage) => {
+                machine.acknowledge_antimessage(&antimessage);
+                Vec::new()
+            }
+        };
+        let report = Report {
+            machine_id: machine.machine_id(),
+            status: status_of(&machine),
+            outgoing,
+        };
+        if reports.send(report).is_err() {
+            break;
+        }
+    }
+    machine
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::{ring, Ring};
+    use std::sync::Arc;
+
+    fn async_ring(machines: usize) -> AsyncSimulation<Ring> {
+        let mut simulation = AsyncSimulation::new();
+        for machine in ring(machines).into_machines().into_values() {
+            simulation.add_machine(machine);
+        }
+        simulation
+    }
+
+    #[tokio::test]
+    async fn test_matches_sync_simulation() {
+        let start = || Message::new(0, 1, 0, 0, Sign::Message, Arc::new("7".to_string()));
+
+        let mut expected = ring(3);
+        expected.inject(start());
+        expected.run();
+
+        let mut simulation = async_ring(3);
+        simulation.inject(start());
+        simulation.run().await;
+        assert_eq!(simulation.gvt().await, None);
+
+        let machines = simulation.shutdown().await;
+        for (machine_id, machine) in machines {
+            let reference = expected.machine(machine_id).unwrap();
+            assert_eq!(machine.state, reference.state);
+            assert_eq!(machine.local_virtual_time(), reference.local_virtual_time());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_injector_and_rollback() {
+        let mut simulation = async_ring(2);
+        let injector = simulation.injector();
+        injector
+            .send(Message::new(0, 3, 0, 0, Sign::Message, Arc::new("1".to_string())))
+            .unwrap();
+        injector
+            .send(Message::new(0, 9, 0, 0, Sign::Message, Arc::new("1".to_string())))
+            .unwrap();
+        simulation.run_until(8).await;
+        assert_eq!(simulation.gvt().await, Some(VirtualTime::new(9)));
+
+        simulation.run().await;
+        // Straggler undoes the event at 9 and the message it sent machine 1
+        injector
+            .send(Message::new(0, 5, 0, 0, Sign::Message, Arc::new("0".to_string())))
+            .unwrap();
+        simulation.run().await;
+
+        let machines = simulation.shutdown().await;
+        assert_eq!(machines[&0].state, 3);
+        assert_eq!(machines[&1].state, 2);
+        assert!(machines.values().all(|machine| machine.in_flight.is_empty()));
+    }
+}
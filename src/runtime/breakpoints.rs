@@ -0,0 +1,248 @@
+use std::fmt;
+use std::sync::Arc;
+
+use super::Simulation;
+use crate::process::TimeWarpProcess;
+use crate::time::message::{MachineId, Message, MessageId, VirtualTime};
+use crate::trace::trace_debug;
+
+pub type BreakpointId = usize;
+
+// Whether an event should stop the run, given the message it processes
+pub type EventPredicate = Arc<dyn Fn(&Message) -> bool + Send + Sync>;
+
+// Breakpoints pause the simulation (see PauseHandle) when they hit, so run and run_until
+// return and whoever embeds the simulation, like the debug console in repl.rs, gets
+// control back with take_breakpoint_hit saying why. Resuming and running again carries
+// on from where it stopped.
+//
+// A GVT breakpoint hits once GVT got to its time and is removed then, GVT never goes
+// back so it would only hit again right away. An event breakpoint hits before the
+// event runs, what the machine looks like is what the event is about to see, and the
+// next step runs that event whatever breakpoints it matches. A rollback breakpoint hits
+// right after a rollback that undid more events than its depth.
+#[derive(Clone)]
+pub enum Breakpoint {
+    Gvt(VirtualTime),
+    Event {
+        // None is any machine
+        machine: Option<MachineId>,
+        matches: EventPredicate,
+    },
+    RollbackDeeper(usize),
+}
+
+impl Breakpoint {
+    // Before the machine processes a message the predicate matches
+    pub fn event(
+        machine: MachineId,
+        matches: impl Fn(&Message) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Breakpoint::Event {
+            machine: Some(machine),
+            matches: Arc::new(matches),
+        }
+    }
+
+    // Before any machine does
+    pub fn any_event(matches: impl Fn(&Message) -> bool + Send + Sync + 'static) -> Self {
+        Breakpoint::Event {
+            machine: None,
+            matches: Arc::new(matches),
+        }
+    }
+}
+
+impl fmt::Debug for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Breakpoint::Gvt(at) => f.debug_tuple("Gvt").field(at).finish(),
+            Breakpoint::Event { machine, .. } => {
+                f.debug_struct("Event").field("machine", machine).finish_non_exhaustive()
+            }
+            Breakpoint::RollbackDeeper(depth) => {
+                f.debug_tuple("RollbackDeeper").field(depth).finish()
+            }
+        }
+    }
+}
+
+// Why the simulation paused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BreakpointHit {
+    pub id: BreakpointId,
+    // The machine about to run the event or the one that rolled back, None for GVT
+    pub machine_id: Option<MachineId>,
+    // GVT, the time of the event or the time rolled back to
+    pub at: VirtualTime,
+    // The event about to run
+    pub message_id: Option<MessageId>,
+    // Events undone by the rollback
+    pub depth: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Breakpoints {
+    set: Vec<(BreakpointId, Breakpoint)>,
+    next_id: BreakpointId,
+    hit: Option<BreakpointHit>,
+    // The event the last event breakpoint stopped before, it runs on the next step
+    stopped_before: Option<MessageId>,
+}
+
+impl Breakpoints {
+    fn hit(&mut self, hit: BreakpointHit) {
+        trace_debug!(id = hit.id, machine_id = ?hit.machine_id, at = %hit.at, "Breakpoint hit");
+        self.hit = Some(hit);
+    }
+
+    fn watches_gvt(&self) -> bool {
+        self.set.iter().any(|(_, breakpoint)| matches!(breakpoint, Breakpoint::Gvt(_)))
+    }
+}
+
+impl<P: TimeWarpProcess> Simulation<P> {
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> BreakpointId {
+        self.breakpoints.next_id += 1;
+        let id = self.breakpoints.next_id;
+        self.breakpoints.set.push((id, breakpoint));
+        id
+    }
+
+    // False if there is no such breakpoint (anymore)
+    pub fn remove_breakpoint(&mut self, id: BreakpointId) -> bool {
+        let before = self.breakpoints.set.len();
+        self.breakpoints.set.retain(|(set, _)| *set != id);
+        self.breakpoints.set.len() < before
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (BreakpointId, &Breakpoint)> {
+        self.breakpoints.set.iter().map(|(id, breakpoint)| (*id, breakpoint))
+    }
+
+    // The breakpoint that paused the simulation last, if nobody took it yet
+    pub fn take_breakpoint_hit(&mut self) -> Option<BreakpointHit> {
+        self.breakpoints.hit.take()
+    }
+
+    // True if the event shouldnt run yet, the simulation is paused then
+    pub(super) fn breaks_before(&mut self, machine_id: MachineId, message: &Message) -> bool {
+        let breakpoints = &mut self.breakpoints;
+        if breakpoints.stopped_before.take() == Some(message.id) {
+            return false;
+        }
+        let hit = breakpoints.set.iter().find(|(_, breakpoint)| match breakpoint {
+            Breakpoint::Event { machine, matches } => {
+                machine.is_none_or(|machine| machine == machine_id) && matches(message)
+            }
+            _ => false,
+        });
+        let Some((id, _)) = hit else {
+            return false;
+        };
+        let hit = BreakpointHit {
+            id: *id,
+            machine_id: Some(machine_id),
+            at: message.rec_time,
+            message_id: Some(message.id),
+            depth: None,
+        };
+        breakpoints.hit(hit);
+        breakpoints.stopped_before = Some(message.id);
+        self.pause();
+        true
+    }
+
+    pub(super) fn check_rollback(&mut self, machine_id: MachineId, at: VirtualTime, depth: usize) {
+        let hit = self.breakpoints.set.iter().find(|(_, breakpoint)| {
+            matches!(breakpoint, Breakpoint::RollbackDeeper(deeper) if depth > *deeper)
+        });
+        let Some((id, _)) = hit else {
+            return;
+        };
+        let hit = BreakpointHit {
+            id: *id,
+            machine_id: Some(machine_id),
+            at,
+            message_id: None,
+            depth: Some(depth),
+        };
+        self.breakpoints.hit(hit);
+        self.pause();
+    }
+
+    pub(super) fn check_gvt(&mut self) {
+        if !self.breakpoints.watches_gvt() {
+            return;
+        }
+        let Some(gvt) = self.gvt() else {
+            return;
+        };
+        let reached = self.breakpoints.set.iter().position(|(_, breakpoint)| {
+            matches!(breakpoint, Breakpoint::Gvt(at) if gvt >= *at)
+        });
+        let Some(reached) = reached else {
+            return;
+        };
+        let (id, _) = self.breakpoints.set.remove(reached);
+        let hit = BreakpointHit {
+            id,
+            machine_id: None,
+            at: gvt,
+            message_id: None,
+            depth: None,
+        };
+        self.breakpoints.hit(hit);
+        self.pause();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use crate::time::message::Sign;
+
+    fn at(rec_time: usize, machine_id: usize, hops: &str) -> Message {
+        Message::new(0, rec_time, machine_id, machine_id, Sign::Message, Arc::new(hops.into()))
+    }
+
+    #[test]
+    fn test_runs_stop_at_breakpoints_and_carry_on() {
+        let mut simulation = ring(2);
+        simulation.inject(at(1, 0, "6"));
+        let payload = simulation.add_breakpoint(Breakpoint::event(1, |message| {
+            message.message.as_str() == "3"
+        }));
+        let gvt = simulation.add_breakpoint(Breakpoint::Gvt(VirtualTime::new(14)));
+        simulation.run();
+        let hit = simulation.take_breakpoint_hit().unwrap();
+        assert_eq!((hit.id, hit.machine_id, hit.at), (payload, Some(1), VirtualTime::new(10)));
+        // Stopped right before the event
+        assert_eq!(simulation.machine(1).unwrap().state, 1);
+        assert!(simulation.is_paused());
+
+        simulation.resume();
+        simulation.run();
+        let hit = simulation.take_breakpoint_hit().unwrap();
+        assert_eq!((hit.id, hit.machine_id), (gvt, None));
+        assert!(hit.at >= 14);
+        assert!(!simulation.breakpoints().any(|(id, _)| id == gvt));
+
+        assert!(simulation.remove_breakpoint(payload));
+        simulation.resume();
+        simulation.run();
+        assert_eq!(simulation.take_breakpoint_hit(), None);
+        assert_eq!(simulation.machine(1).unwrap().state, 3);
+
+        // Stepping doesnt commit, so a straggler can still undo machine 0 at 7, 13 and 19
+        let mut simulation = ring(2);
+        simulation.add_breakpoint(Breakpoint::RollbackDeeper(2));
+        simulation.inject(at(1, 0, "6"));
+        while simulation.step() {}
+        simulation.inject(at(2, 0, "0"));
+        simulation.run();
+        let hit = simulation.take_breakpoint_hit().unwrap();
+        assert_eq!((hit.machine_id, hit.at, hit.depth), (Some(0), VirtualTime::new(2), Some(3)));
+    }
+}
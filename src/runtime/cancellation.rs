@@ -0,0 +1,83 @@
+use super::Simulation;
+use crate::machine::Machine;
+use crate::process::TimeWarpProcess;
+use crate::time::message::Sign;
+
+// Direct cancellation, see Machine::set_direct_cancellation. Everything here is in one
+// process, so a sender rolling back flags what it takes back and the copies still in
+// transit are dropped when they come up, and the simulation takes the ones already
+// delivered out of their receivers queues right after the rollback (rolling those back
+// if they processed them). The antimessages a rollback would have sent are never made,
+// delivered or acknowledged, and nothing waits on them for GVT.
+impl<P: TimeWarpProcess> Simulation<P> {
+    // For the machines already added and the ones added later. Turning it off only
+    // changes what is sent from then on, messages sent before are still cancelled
+    // directly.
+    pub fn set_direct_cancellation(&mut self, direct: bool) {
+        self.direct_cancellation = direct;
+        for machine in self.machines.values_mut() {
+            machine.set_direct_cancellation(direct);
+        }
+    }
+
+    pub fn direct_cancellation(&self) -> bool {
+        self.direct_cancellation
+    }
+
+    // True if anything was cancelled
+    pub(super) fn cancel_directly(&mut self) -> bool {
+        let cancelled: Vec<_> =
+            self.machines.values_mut().flat_map(Machine::take_cancelled).collect();
+        for message in &cancelled {
+            let Some(receiver) = self.machines.get_mut(&message.receiver) else {
+                continue;
+            };
+            let lvt_before = receiver.local_virtual_time();
+            let rolled_back_before = receiver.stats().events_rolled_back;
+            if let Some(antimessages) = receiver.cancel(message) {
+                // Traces show it the way the antimessage would have been
+                let mut cause = message.clone();
+                cause.sign = Sign::Antimessage;
+                self.rolled_back(&cause, lvt_before, rolled_back_before, antimessages);
+            }
+        }
+        !cancelled.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::tests::ring;
+    use crate::time::message::{Message, Sign};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_direct_cancellation_needs_no_antimessages() {
+        let run = |direct: bool| {
+            let mut simulation = ring(2);
+            simulation.set_direct_cancellation(direct);
+            simulation.inject(Message::new(0, 3, 0, 0, Sign::Message, Arc::new("1".into())));
+            simulation.inject(Message::new(0, 9, 0, 0, Sign::Message, Arc::new("4".into())));
+            while simulation.step() {}
+            assert_eq!(simulation.machine(1).unwrap().local_virtual_time(), 18);
+
+            // Undoes 9, 15 and 21 on machine 0, which takes back the messages to 1 at 12
+            // and 18. Machine 1 processed both and takes back the ones to 0 at 15 and 21.
+            simulation.inject(Message::new(0, 5, 0, 0, Sign::Message, Arc::new("0".into())));
+            simulation.deliver_pending();
+            assert_eq!(simulation.machine(0).unwrap().local_virtual_time(), 3);
+            assert_eq!(simulation.machine(1).unwrap().local_virtual_time(), 6);
+            simulation.run();
+            let states: Vec<_> = simulation.machines().map(|machine| machine.state).collect();
+            (states, simulation.stats().total)
+        };
+        let (states, antimessages) = run(false);
+        let (direct_states, direct) = run(true);
+        assert_eq!(direct_states, states);
+        assert_eq!(direct_states, vec![5, 3]);
+        assert_eq!(antimessages.antimessages_sent, 4);
+        assert_eq!((direct.antimessages_sent, direct.messages_cancelled), (0, 4));
+        assert_eq!(direct.events_rolled_back, antimessages.events_rolled_back);
+        assert_eq!(direct.cascading_rollbacks, antimessages.cascading_rollbacks);
+    }
+}
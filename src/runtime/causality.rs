@@ -0,0 +1,227 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::Simulation;
+use crate::process::TimeWarpProcess;
+use crate::sink::CommittedEvent;
+use crate::time::message::{MachineId, MessageId, VirtualTime};
+
+// Vector clocks over the committed history, for auditing the engine and not used to run
+// anything. Every committed event gets the clock of the event before it on the same
+// machine merged with the clock of the event that sent its message (Message::parent),
+// and then ticks its own machine. Timestamp order has to agree with that: an event can
+// only commit after the one that sent its message and never at an earlier virtual time.
+// Either going wrong means a bug in a runtime, a valid run can never trip it.
+//
+// Clocks are worked out as events commit instead of being carried around on messages
+// while they are speculative, so rollbacks dont have to undo them. Every committed event
+// keeps its clock since a message can show up committed long after its sender was, only
+// turn this on where that memory is fine.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VectorClock {
+    ticks: BTreeMap<MachineId, u64>,
+}
+
+impl VectorClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, machine_id: MachineId) -> u64 {
+        self.ticks.get(&machine_id).copied().unwrap_or(0)
+    }
+
+    pub fn tick(&mut self, machine_id: MachineId) {
+        *self.ticks.entry(machine_id).or_insert(0) += 1;
+    }
+
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (machine_id, ticks) in &other.ticks {
+            let entry = self.ticks.entry(*machine_id).or_insert(0);
+            *entry = (*entry).max(*ticks);
+        }
+    }
+
+    // Everything this clock saw the other saw too, and the other saw more
+    pub fn happened_before(&self, other: &VectorClock) -> bool {
+        self != other
+            && self
+                .ticks
+                .iter()
+                .all(|(machine_id, ticks)| *ticks <= other.get(*machine_id))
+    }
+
+    pub fn concurrent_with(&self, other: &VectorClock) -> bool {
+        self != other && !self.happened_before(other) && !other.happened_before(self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CausalityViolation {
+    // The event committed but the one that sent its message never did before it
+    CauseNotCommitted {
+        machine_id: MachineId,
+        message_id: MessageId,
+        parent: MessageId,
+    },
+    // The event that sent the message is later in virtual time than the one it caused
+    CauseIsLater {
+        machine_id: MachineId,
+        message_id: MessageId,
+        rec_time: VirtualTime,
+        parent_rec_time: VirtualTime,
+    },
+}
+
+#[derive(Debug, Default)]
+pub(super) struct CausalityAudit {
+    // Clock and time of every committed event, by the id of the message it processed
+    events: HashMap<MessageId, (VirtualTime, VectorClock)>,
+    machines: HashMap<MachineId, VectorClock>,
+    violations: Vec<CausalityViolation>,
+}
+
+impl CausalityAudit {
+    // Takes a batch of commits in timestamp order. A zero delay send can land at the
+    // same time as its cause and sort before it, so events wait for a cause that is
+    // still coming in the same batch.
+    fn observe(&mut self, committed: &[CommittedEvent]) {
+        let in_batch: HashSet<_> = committed.iter().map(|event| event.message.id).collect();
+        let mut waiting: Vec<&CommittedEvent> = committed.iter().collect();
+        loop {
+            let before = waiting.len();
+            waiting.retain(|event| {
+                let waits = event.message.parent.is_some_and(|parent| {
+                    !self.events.contains_key(&parent) && in_batch.contains(&parent)
+                });
+                if !waits {
+                    self.commit(event);
+                }
+                waits
+            });
+            if waiting.is_empty() || waiting.len() == before {
+                break;
+            }
+        }
+        // A cycle in the batch, whatever is left can only be a bug
+        for event in waiting {
+            self.commit(event);
+        }
+    }
+
+    fn commit(&mut self, event: &CommittedEvent) {
+        let message = &event.message;
+        let mut clock = self.machines.get(&event.machine_id).cloned().unwrap_or_default();
+        if let Some(parent) = message.parent {
+            match self.events.get(&parent) {
+                Some((parent_rec_time, parent_clock)) => {
+                    if *parent_rec_time > message.rec_time {
+                        self.violations.push(CausalityViolation::CauseIsLater {
+                            machine_id: event.machine_id,
+                            message_id: message.id,
+                            rec_time: message.rec_time,
+                            parent_rec_time: *parent_rec_time,
+                        });
+                    }
+                    clock.merge(parent_clock);
+                }
+                None => self.violations.push(CausalityViolation::CauseNotCommitted {
+                    machine_id: event.machine_id,
+                    message_id: message.id,
+                    parent,
+                }),
+            }
+        }
+        clock.tick(event.machine_id);
+        self.machines.insert(event.machine_id, clock.clone());
+        self.events.insert(message.id, (message.rec_time, clock));
+    }
+}
+
+impl<P: TimeWarpProcess> Simulation<P> {
+    // Starts giving every event committed from now on a vector clock and checking them
+    pub fn audit_causality(&mut self) {
+        self.causality = Some(CausalityAudit::default());
+    }
+
+    // The clock of the committed event that processed the message
+    pub fn event_clock(&self, message_id: MessageId) -> Option<&VectorClock> {
+        let (_, clock) = self.causality.as_ref()?.events.get(&message_id)?;
+        Some(clock)
+    }
+
+    pub fn causality_violations(&self) -> &[CausalityViolation] {
+        self.causality.as_ref().map_or(&[], |audit| &audit.violations)
+    }
+
+    pub(super) fn audit_committed(&mut self, committed: &[CommittedEvent]) {
+        if let Some(audit) = &mut self.causality {
+            audit.observe(committed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use crate::sink::VecSink;
+    use crate::time::message::{Message, Sign};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_committed_events_follow_their_causes() {
+        let mut simulation = ring(3);
+        simulation.audit_causality();
+        let events = VecSink::new();
+        simulation.add_sink(Box::new(events.clone()));
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("4".to_string())));
+        simulation.inject(Message::new(0, 2, 2, 2, Sign::Message, Arc::new("1".to_string())));
+        simulation.run();
+        assert!(simulation.causality_violations().is_empty());
+
+        let clock = |rec_time| {
+            let event = events.events().into_iter().find(|e| e.message.rec_time == rec_time);
+            simulation.event_clock(event.unwrap().message.id).unwrap().clone()
+        };
+        // 1 -> 4 -> 7 is a chain, 2 on machine 2 has nothing to do with the first hops
+        assert!(clock(1).happened_before(&clock(4)));
+        assert!(clock(4).happened_before(&clock(7)));
+        assert!(clock(1).concurrent_with(&clock(2)));
+        assert!(clock(2).happened_before(&clock(7)));
+    }
+
+    #[test]
+    fn test_audit_catches_events_committed_before_their_cause() {
+        let mut audit = CausalityAudit::default();
+        let mut first = Message::new(0, 5, 0, 1, Sign::Message, Arc::new(String::new()));
+        let mut second = Message::new(5, 3, 1, 0, Sign::Message, Arc::new(String::new()));
+        second.parent = Some(first.id);
+        first.parent = Some(12345);
+        audit.observe(&[
+            CommittedEvent {
+                machine_id: 1,
+                message: first.clone(),
+            },
+            CommittedEvent {
+                machine_id: 0,
+                message: second.clone(),
+            },
+        ]);
+        assert_eq!(
+            audit.violations,
+            [
+                CausalityViolation::CauseNotCommitted {
+                    machine_id: 1,
+                    message_id: first.id,
+                    parent: 12345,
+                },
+                CausalityViolation::CauseIsLater {
+                    machine_id: 0,
+                    message_id: second.id,
+                    rec_time: VirtualTime::new(3),
+                    parent_rec_time: VirtualTime::new(5),
+                },
+            ]
+        );
+    }
+}
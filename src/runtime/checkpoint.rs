@@ -0,0 +1,335 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use super::crash::Crashes;
+use super::external::ExternalEvent;
+use super::Simulation;
+use crate::codec::{self, CodecError, Compression};
+use crate::machine::Machine;
+use crate::process::TimeWarpProcess;
+use crate::time::message::{reserve_message_ids, MachineId, Message, VirtualTime};
+
+// Everything needed to pick a simulation back up after the process restarts. Each machine
+// is written whole (state, saved states, both queues and its in flight antimessages) so
+// the restored simulation can still roll back past the point it was checkpointed at,
+// and so are the crashes (the machines that are down with the messages waiting for them
+// and the planned ones) since the messages held for a down machine still count for GVT.
+// The machine names go along, and the external events sent but not picked up yet, they
+// are stamped once the restored simulation picks them up like they would have been. The
+// injectors themselves belong to the old simulation, whoever sends external events has
+// to get new ones from the restored one.
+// The file is written with codec::write_file so it carries the codec version like the
+// wire does, but isnt held to the size of a frame. It goes to a temporary file next to
+// the path first and replaces the old checkpoint only once it is all written, a
+// checkpoint that fails halfway leaves the last good one as it was.
+#[derive(Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "P: Serialize, P::State: Serialize",
+    deserialize = "P: Deserialize<'de>, P::State: Deserialize<'de>"
+))]
+pub struct Checkpoint<P: TimeWarpProcess> {
+    pub gvt: Option<VirtualTime>,
+    pub machines: BTreeMap<MachineId, Machine<P>>,
+    pub in_transit: VecDeque<Message>,
+    pub(super) crashes: Crashes,
+    pub names: BTreeMap<MachineId, String>,
+    pub external: Vec<ExternalEvent>,
+}
+
+impl<P> Checkpoint<P>
+where
+    P: TimeWarpProcess + DeserializeOwned,
+    P::State: DeserializeOwned,
+{
+    // Reads a checkpoint without turning it back into a simulation, for inspecting one
+    pub fn read<T: AsRef<Path>>(path: T) -> Result<Self, CodecError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        codec::read_file(&mut reader)
+    }
+}
+
+// Same layout as Checkpoint but borrowing so writing one doesnt clone every machine
+#[derive(Serialize)]
+#[serde(bound(serialize = "P: Serialize, P::State: Serialize"))]
+struct CheckpointRef<'a, P: TimeWarpProcess> {
+    gvt: Option<VirtualTime>,
+    machines: &'a BTreeMap<MachineId, Machine<P>>,
+    in_transit: &'a VecDeque<Message>,
+    crashes: &'a Crashes,
+    names: &'a BTreeMap<MachineId, String>,
+    external: Vec<ExternalEvent>,
+}
+
+impl<P> Simulation<P>
+where
+    P: TimeWarpProcess + Serialize,
+    P::State: Serialize,
+{
+    pub fn checkpoint<T: AsRef<Path>>(&self, path: T) -> Result<(), CodecError> {
+        self.checkpoint_with(path, None)
+    }
+
+    // A checkpoint is mostly machine states, which tend to compress well. Restoring
+    // works the same either way.
+    pub fn checkpoint_with<T: AsRef<Path>>(
+        &self,
+        path: T,
+        compression: Option<Compression>,
+    ) -> Result<(), CodecError> {
+        let checkpoint = CheckpointRef {
+            gvt: self.gvt(),
+            machines: &self.machines,
+            in_transit: &self.in_transit,
+            crashes: &self.crashes,
+            names: self.names.names(),
+            external: self.external.pending(),
+        };
+        write(path.as_ref(), &checkpoint, compression)
+    }
+}
+
+// Also used by the nodes of a distributed run, which have machines but no Simulation (and
+// no crashes or external input)
+pub(crate) fn write_checkpoint<P, T>(
+    path: T,
+    gvt: Option<VirtualTime>,
+    machines: &BTreeMap<MachineId, Machine<P>>,
+    in_transit: &VecDeque<Message>,
+    names: &BTreeMap<MachineId, String>,
+    compression: Option<Compression>,
+) -> Result<(), CodecError>
+where
+    P: TimeWarpProcess + Serialize,
+    P::State: Serialize,
+    T: AsRef<Path>,
+{
+    let crashes = Crashes::default();
+    let checkpoint = CheckpointRef {
+        gvt,
+        machines,
+        in_transit,
+        crashes: &crashes,
+        names,
+        external: Vec::new(),
+    };
+    write(path.as_ref(), &checkpoint, compression)
+}
+
+fn write<P>(
+    path: &Path,
+    checkpoint: &CheckpointRef<P>,
+    compression: Option<Compression>,
+) -> Result<(), CodecError>
+where
+    P: TimeWarpProcess + Serialize,
+    P::State: Serialize,
+{
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let temporary = path.with_file_name(name);
+    let written = (|| {
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        codec::write_file(&mut writer, checkpoint, compression)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        Ok::<_, CodecError>(())
+    })();
+    if let Err(error) = written {
+        let _ = fs::remove_file(&temporary);
+        return Err(error);
+    }
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+impl<P> Simulation<P>
+where
+    P: TimeWarpProcess + DeserializeOwned,
+    P::State: DeserializeOwned,
+{
+    pub fn restore<T: AsRef<Path>>(path: T) -> Result<Self, CodecError> {
+        Ok(Checkpoint::read(path)?.into())
+    }
+}
+
+impl<P: TimeWarpProcess> From<Checkpoint<P>> for Simulation<P> {
+    fn from(checkpoint: Checkpoint<P>) -> Self {
+        // New messages must not reuse the ids of the ones being restored
+        let max_id = checkpoint
+            .machines
+            .values()
+            .flat_map(|machine| {
+                [
+                    machine.input_queue.max_message_id(),
+                    machine.output_queue.max_message_id(),
+                    machine.in_flight.pending().map(|message| message.id).max(),
+                ]
+            })
+            .chain(checkpoint.in_transit.iter().map(|message| Some(message.id)))
+            .chain(checkpoint.crashes.held().map(|message| Some(message.id)))
+            .flatten()
+            .max();
+        if let Some(max_id) = max_id {
+            reserve_message_ids(max_id);
+        }
+        let mut simulation = Simulation::new();
+        simulation.machines = checkpoint.machines;
+        simulation.in_transit = checkpoint.in_transit;
+        simulation.crashes = checkpoint.crashes;
+        for (machine_id, name) in &checkpoint.names {
+            // Cant be taken, they all came out of one router
+            let _ = simulation.names.set_name(*machine_id, name);
+        }
+        simulation.external.requeue(checkpoint.external);
+        simulation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::Context;
+    use crate::runtime::crash::CrashPlan;
+    use crate::runtime::tests::{ring, Ring};
+    use crate::time::message::Sign;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_checkpoint_and_restore() {
+        let path = std::env::temp_dir().join(format!("vtw-checkpoint-{}.bin", std::process::id()));
+
+        let mut simulation = ring(3);
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("9".to_string())));
+        simulation.run_until(10);
+        simulation.checkpoint(&path).unwrap();
+
+        let checkpoint: Checkpoint<Ring> = Checkpoint::read(&path).unwrap();
+        assert_eq!(checkpoint.gvt, simulation.gvt());
+        assert_eq!(checkpoint.machines.len(), 3);
+
+        // A compressed one restores the same way
+        let compressed = path.with_extension("deflated");
+        let compression = Compression {
+            threshold: 0,
+            level: 6,
+        };
+        simulation.checkpoint_with(&compressed, Some(compression)).unwrap();
+        let size = |path: &Path| std::fs::metadata(path).unwrap().len();
+        assert!(size(&compressed) < size(&path));
+        std::fs::remove_file(&path).unwrap();
+
+        let mut restored: Simulation<Ring> = Simulation::restore(&compressed).unwrap();
+        std::fs::remove_file(&compressed).unwrap();
+
+        // A straggler before the checkpoint can still be rolled back to after restoring
+        let straggler = || Message::new(0, 5, 0, 0, Sign::Message, Arc::new("0".to_string()));
+        simulation.inject(straggler());
+        restored.inject(straggler());
+        simulation.run();
+        restored.run();
+
+        for machine in simulation.machines() {
+            let other = restored.machine(machine.machine_id()).unwrap();
+            assert_eq!(other.state, machine.state);
+            assert_eq!(other.local_virtual_time(), machine.local_virtual_time());
+        }
+    }
+
+    // Bigger than a frame can be, with a state the size of a blob
+    #[derive(Serialize, Deserialize)]
+    struct Blob;
+
+    impl TimeWarpProcess for Blob {
+        type State = Vec<u8>;
+
+        fn on_message(&self, state: &mut Vec<u8>, _message: &Message, _ctx: &mut Context) {
+            state.resize(codec::MAX_FRAME_LEN + 1, 7);
+        }
+    }
+
+    #[test]
+    fn test_checkpoints_keep_the_machines_that_are_down() {
+        let path = std::env::temp_dir().join(format!("vtw-crashed-{}.bin", std::process::id()));
+        let mut expected = ring(2);
+        expected.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("3".to_string())));
+        expected.run();
+
+        // Machine 0 sends to 1 at 4 while 1 is down, that waits for it to restart
+        let mut simulation = ring(2);
+        simulation.crash(1);
+        simulation.schedule_crash(
+            0,
+            CrashPlan {
+                at: VirtualTime::new(100),
+                restart_after: None,
+            },
+        );
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("3".to_string())));
+        simulation.run();
+        assert_eq!(simulation.gvt(), Some(VirtualTime::new(4)));
+        simulation.checkpoint(&path).unwrap();
+
+        let mut restored: Simulation<Ring> = Simulation::restore(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(restored.is_down(1));
+        assert_eq!(restored.gvt(), Some(VirtualTime::new(4)));
+        // The plan for machine 0 too
+        assert_eq!(format!("{:?}", restored.crashes), format!("{:?}", simulation.crashes));
+        assert!(restored.restart(1));
+        restored.run();
+        for machine in restored.machines() {
+            assert_eq!(machine.state, expected.machine(machine.machine_id()).unwrap().state);
+        }
+    }
+
+    #[test]
+    fn test_checkpoints_keep_names_and_external_input() {
+        let path = std::env::temp_dir().join(format!("vtw-external-{}.bin", std::process::id()));
+        let mut simulation = ring(2);
+        simulation.name_machine(1, "second").unwrap();
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("2".to_string())));
+        simulation.run();
+        assert!(simulation.external_injector().inject(1, "3".to_string()));
+        simulation.checkpoint(&path).unwrap();
+
+        let mut restored: Simulation<Ring> = Simulation::restore(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored.lookup_by_name("second"), Some(1));
+        // The event is still picked up by the checkpointed simulation as well
+        simulation.run();
+        restored.run();
+        assert!(simulation.machine(1).unwrap().stats().events_committed > 1);
+        for machine in simulation.machines() {
+            let other = restored.machine(machine.machine_id()).unwrap();
+            assert_eq!(other.state, machine.state);
+            assert_eq!(other.stats().events_committed, machine.stats().events_committed);
+        }
+    }
+
+    #[test]
+    fn test_checkpoints_bigger_than_a_frame_replace_the_old_one_whole() {
+        let path = std::env::temp_dir().join(format!("vtw-blob-{}.bin", std::process::id()));
+        let mut simulation = Simulation::new();
+        simulation.add_machine(Machine::with_process(0, 0, Blob));
+        simulation.checkpoint(&path).unwrap();
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("0".to_string())));
+        simulation.run();
+        simulation.checkpoint(&path).unwrap();
+        let restored: Simulation<Blob> = Simulation::restore(&path).unwrap();
+        assert_eq!(restored.machine(0).unwrap().state.len(), codec::MAX_FRAME_LEN + 1);
+
+        // A checkpoint that cant be written leaves the last one as it was
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+        std::fs::create_dir(&temporary).unwrap();
+        assert!(ring(1).checkpoint(&path).is_err());
+        std::fs::remove_dir(&temporary).unwrap();
+        let restored: Simulation<Blob> = Simulation::restore(&path).unwrap();
+        assert_eq!(restored.machine(0).unwrap().state.len(), codec::MAX_FRAME_LEN + 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
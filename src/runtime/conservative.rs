@@ -0,0 +1,340 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::determinism::{RunDigest, StateDigests};
+use crate::machine::{ExampleProcess, Machine};
+use crate::process::TimeWarpProcess;
+use crate::stats::SimulationStats;
+use crate::time::message::{Delay, MachineId, Message, VirtualTime};
+use crate::trace::{trace_debug, trace_warn};
+
+// Conservative (Chandy-Misra-Bryant) counterpart to Simulation, running the same
+// machines and processes but never executing a message until nothing earlier can
+// arrive, so nothing is ever rolled back. Useful for checking a Time Warp run against
+// and for seeing which of the two protocols suits a model better.
+//
+// The channels between machines have to be declared up front with the lookahead of the
+// link, the smallest delay the sender will ever put on a message to that receiver. Every
+// channel keeps a clock, the time of the last message or null message sent on it. A
+// machine can safely process a message once its receive time is no later than the clock
+// of every channel coming into it. When a machine cant go on it tells its neighbours how
+// far ahead it is safe for them with null messages: nothing it sends from now on can be
+// earlier than the earliest time it could still process plus the lookahead.
+//
+// Null messages never reach the machines, they only move channel clocks forward. Messages
+// put in with inject skip the channels, so anything injected after the run started must
+// not be earlier than what the receiver already processed.
+pub struct ConservativeSimulation<P: TimeWarpProcess = ExampleProcess> {
+    machines: BTreeMap<MachineId, Machine<P>>,
+    channels: BTreeMap<(MachineId, MachineId), Channel>,
+    in_transit: VecDeque<Message>,
+    null_messages: u64,
+    digests: Option<StateDigests>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Channel {
+    lookahead: Delay,
+    clock: VirtualTime,
+}
+
+impl<P: TimeWarpProcess> Default for ConservativeSimulation<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: TimeWarpProcess> ConservativeSimulation<P> {
+    pub fn new() -> Self {
+        Self {
+            machines: BTreeMap::new(),
+            channels: BTreeMap::new(),
+            in_transit: VecDeque::new(),
+            null_messages: 0,
+            digests: None,
+        }
+    }
+
+    pub fn add_machine(&mut self, machine: Machine<P>) {
+        self.machines.insert(machine.machine_id(), machine);
+    }
+
+    // Declares that `from` may send to `to`, never with a delay smaller than lookahead.
+    // If the senders process declares a bigger lookahead of its own that one is used.
+    pub fn connect(&mut self, from: MachineId, to: MachineId, lookahead: impl Into<Delay>) {
+        let declared = self.machines.get(&from).map_or(Delay::ZERO, Machine::lookahead);
+        self.channels.insert(
+            (from, to),
+            Channel {
+                lookahead: lookahead.into().max(declared),
+                clock: VirtualTime::ZERO,
+            },
+        );
+    }
+
+    pub fn machine(&self, machine_id: MachineId) -> Option<&Machine<P>> {
+        self.machines.get(&machine_id)
+    }
+
+    pub fn machines(&self) -> impl Iterator<Item = &Machine<P>> {
+        self.machines.values()
+    }
+
+    pub fn into_machines(self) -> BTreeMap<MachineId, Machine<P>> {
+        self.machines
+    }
+
+    pub fn inject(&mut self, message: Message) {
+        self.in_transit.push_back(message);
+    }
+
+    // Same as Simulation::record_digests
+    pub fn record_digests(&mut self, epoch_length: impl Into<Delay>) {
+        self.digests = Some(StateDigests::new(epoch_length));
+    }
+
+    pub fn take_digests(&mut self) -> Option<StateDigests> {
+        self.digests.take()
+    }
+
+    pub fn run_digest(&self) -> Option<RunDigest> {
+        self.digests.as_ref().map(StateDigests::run_digest)
+    }
+
+    // How many null messages have been sent so far, the overhead the protocol adds
+    pub fn null_messages(&self) -> u64 {
+        self.null_messages
+    }
+
+    fn deliver_pending(&mut self) {
+        while let Some(message) = self.in_transit.pop_front() {
+            let Some(receiver) = self.machines.get_mut(&message.receiver) else {
+                trace_warn!(
+                    receiver = message.receiver,
+                    message_id = message.id,
+                    "Dropping message for unknown machine"
+                );
+                // Nothing rolls back here, so it cant be an antimessage someone waits on
+                continue;
+            };
+            // Every message is safe here so this can never roll back
+            let _ = receiver.recieve_outer(message);
+        }
+    }
+
+    // Nothing earlier than this can still show up on any channel into the machine
+    fn safe_time(&self, machine_id: MachineId) -> VirtualTime {
+        self.channels
+            .iter()
+            .filter(|((_, to), _)| *to == machine_id)
+            .map(|(_, channel)| channel.clock)
+            .min()
+            .unwrap_or(VirtualTime::MAX)
+    }
+
+    // The machine with the earliest message that is safe to process
+    pub fn next_machine(&self) -> Option<(MachineId, VirtualTime)> {
+        self.machines
+            .iter()
+            .filter_map(|(machine_id, machine)| {
+                let next = machine.peek_next_message()?;
+                (next.rec_time <= self.safe_time(*machine_id)).then_some((*machine_id, next.rec_time))
+            })
+            .min_by_key(|(machine_id, rec_time)| (*rec_time, *machine_id))
+    }
+
+    // Sends a null message on every channel out of the machine, returns whether any
+    // channel clock moved
+    fn send_null_messages(&mut self, machine_id: MachineId) -> bool {
+        let next = self.machines[&machine_id]
+            .peek_next_message()
+            .map_or(VirtualTime::MAX, |message| message.rec_time);
+        let earliest = self.safe_time(machine_id).min(next);
+        let mut advanced = false;
+        for ((from, _), channel) in self.channels.iter_mut() {
+            if *from != machine_id {
+                continue;
+            }
+            let clock = earliest.saturating_add(channel.lookahead);
+            if clock > channel.clock {
+                channel.clock = clock;
+                self.null_messages += 1;
+                advanced = true;
+            }
+        }
+        advanced
+    }
+
+    fn route(&mut self, sent: Vec<Message>) {
+        for message in sent {
+            if let Some(channel) = self.channels.get_mut(&(message.sender, message.receiver)) {
+                if message.rec_time < channel.clock {
+                    trace_warn!(
+                        sender = message.sender,
+                        receiver = message.receiver,
+                        rec_time = %message.rec_time,
+                        clock = %channel.clock,
+                        "Message is earlier than the lookahead promised"
+                    );
+                }
+                channel.clock = channel.clock.max(message.rec_time);
+            } else {
+                trace_warn!(
+                    sender = message.sender,
+                    receiver = message.receiver,
+                    "Message sent on a channel that was never connected"
+                );
+            }
+            self.in_transit.push_back(message);
+        }
+    }
+
+    // Processes the earliest safe message, sending null messages until one becomes safe.
+    // Returns false once there is nothing left, or when the null messages stop making
+    // progress, which means a cycle of channels with no lookahead is deadlocked.
+    pub fn step(&mut self) -> bool {
+        self.deliver_pending();
+        loop {
+            if let Some((machine_id, _)) = self.next_machine() {
+                let machine = self.machines.get_mut(&machine_id).unwrap();
+                let sent = machine.recieve_inner();
+                self.route(sent);
+                self.send_null_messages(machine_id);
+                self.deliver_pending();
+                return true;
+            }
+            if self.machines.values().all(|machine| machine.peek_next_message().is_none()) {
+                return false;
+            }
+            let ids: Vec<_> = self.machines.keys().copied().collect();
+            let mut advanced = false;
+            for machine_id in ids {
+                advanced |= self.send_null_messages(machine_id);
+            }
+            if !advanced {
+                trace_warn!("Deadlocked, no channel clock can move forward");
+                return false;
+            }
+            trace_debug!(null_messages = self.null_messages, "Sent a round of null messages");
+        }
+    }
+
+    pub fn run(&mut self) {
+        while self.step() {}
+        self.commit();
+    }
+
+    pub fn run_until(&mut self, end_time: impl Into<VirtualTime>) {
+        let end_time = end_time.into();
+        loop {
+            self.deliver_pending();
+            let next = self
+                .machines
+                .values()
+                .filter_map(|machine| machine.peek_next_message().map(|message| message.rec_time))
+                .min();
+            match next {
+                Some(rec_time) if rec_time <= end_time => {
+                    if !self.step() {
+                        break;
+                    }
+                }
+                _ => break,
+            }
+        }
+        self.commit();
+    }
+
+    // Everything processed is final, this just lets the stats say so
+    pub fn commit(&mut self) {
+        let next = self
+            .machines
+            .values()
+            .filter_map(|machine| machine.peek_next_message().map(|message| message.rec_time))
+            .chain(self.in_transit.iter().map(|message| message.rec_time))
+            .min();
+        for machine in self.machines.values_mut() {
+            let committed = machine.commit(next);
+            let Some(digests) = self.digests.as_mut() else {
+                continue;
+            };
+            for message in committed {
+                if let Some(state) = machine.state_at(message.rec_time) {
+                    digests.record(machine.machine_id(), message.rec_time, &state);
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> SimulationStats {
+        SimulationStats::from_machines(
+            self.machines
+                .iter()
+                .map(|(machine_id, machine)| (*machine_id, machine.stats().clone()))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::{ring, Ring};
+    use crate::time::message::Sign;
+    use std::sync::Arc;
+
+    fn conservative_ring(machines: usize) -> ConservativeSimulation<Ring> {
+        let mut simulation = ConservativeSimulation::new();
+        for machine in ring(machines).into_machines().into_values() {
+            simulation.add_machine(machine);
+        }
+        for id in 0..machines {
+            simulation.connect(id, (id + 1) % machines, 3);
+        }
+        simulation
+    }
+
+    #[test]
+    fn test_matches_optimistic_run_without_rollbacks() {
+        let messages = || {
+            vec![
+                Message::new(0, 9, 0, 0, Sign::Message, Arc::new("4".to_string())),
+                Message::new(0, 2, 1, 1, Sign::Message, Arc::new("6".to_string())),
+                Message::new(0, 5, 2, 2, Sign::Message, Arc::new("3".to_string())),
+            ]
+        };
+
+        let mut optimistic = ring(3);
+        let mut conservative = conservative_ring(3);
+        for message in messages() {
+            optimistic.inject(message.clone());
+            conservative.inject(message);
+        }
+        optimistic.run();
+        conservative.run();
+
+        for machine in conservative.machines() {
+            let reference = optimistic.machine(machine.machine_id()).unwrap();
+            assert_eq!(machine.state, reference.state);
+            assert_eq!(machine.local_virtual_time(), reference.local_virtual_time());
+        }
+        let stats = conservative.stats();
+        assert_eq!(stats.total.rollbacks, 0);
+        assert_eq!(stats.total.events_committed, stats.total.events_processed);
+        assert!(conservative.null_messages() > 0);
+    }
+
+    #[test]
+    fn test_zero_lookahead_cycle_deadlocks() {
+        let mut simulation = ConservativeSimulation::new();
+        let machines: Vec<Machine<ExampleProcess>> = vec![Machine::new(0, 0), Machine::new(1, 0)];
+        for machine in machines {
+            simulation.add_machine(machine);
+        }
+        simulation.connect(0, 1, 0);
+        simulation.connect(1, 0, 0);
+        simulation.inject(Message::new(0, 4, 0, 0, Sign::Message, Arc::new("1".to_string())));
+
+        assert!(!simulation.step());
+        assert_eq!(simulation.machine(0).unwrap().stats().events_processed, 0);
+    }
+}
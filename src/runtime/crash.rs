@@ -0,0 +1,229 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use super::Simulation;
+use crate::process::TimeWarpProcess;
+use crate::time::message::{MachineId, Message, VirtualTime};
+use crate::trace::trace_debug;
+
+// Machine failures. A crashed machine drops everything it hadnt committed (see
+// Machine::crash) and the antimessages for what that work sent go out right away, so
+// the rest of the simulation undoes whatever the lost work caused. While it is down
+// nothing is delivered to it, messages for it wait here and go in once it restarts, and
+// it doesnt run. It still counts for GVT, so a machine that never comes back holds GVT
+// at the point it crashed. Checkpoints keep all of it, see checkpoint.rs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(super) struct Crashes {
+    planned: BTreeMap<MachineId, CrashPlan>,
+    down: BTreeMap<MachineId, Down>,
+}
+
+// Crash the machine when it gets to a message at or after `at`, and if restart_after is
+// set bring it back once that many other events have run (or sooner if nothing else
+// can run)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CrashPlan {
+    pub at: VirtualTime,
+    pub restart_after: Option<usize>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Down {
+    restart_in: Option<usize>,
+    backlog: Vec<Message>,
+}
+
+impl Crashes {
+    pub(super) fn is_down(&self, machine_id: MachineId) -> bool {
+        self.down.contains_key(&machine_id)
+    }
+
+    // Keeps the message if its receiver is down, otherwise hands it back
+    pub(super) fn hold(&mut self, message: Message) -> Option<Message> {
+        match self.down.get_mut(&message.receiver) {
+            Some(down) => {
+                down.backlog.push(message);
+                None
+            }
+            None => Some(message),
+        }
+    }
+
+    pub(super) fn held(&self) -> impl Iterator<Item = &Message> {
+        self.down.values().flat_map(|down| down.backlog.iter())
+    }
+
+    fn due(&self, machine_id: MachineId, rec_time: VirtualTime) -> Option<CrashPlan> {
+        self.planned
+            .get(&machine_id)
+            .filter(|plan| plan.at <= rec_time)
+            .copied()
+    }
+}
+
+impl<P: TimeWarpProcess> Simulation<P> {
+    pub fn schedule_crash(&mut self, machine_id: MachineId, plan: CrashPlan) {
+        self.crashes.planned.insert(machine_id, plan);
+    }
+
+    // Crashes the machine right now, returns false if there is no such machine or it is
+    // already down. It stays down until restart is called.
+    pub fn crash(&mut self, machine_id: MachineId) -> bool {
+        self.crash_with(machine_id, None)
+    }
+
+    fn crash_with(&mut self, machine_id: MachineId, restart_in: Option<usize>) -> bool {
+        if self.crashes.is_down(machine_id) {
+            return false;
+        }
+        let Some(machine) = self.machines.get_mut(&machine_id) else {
+            return false;
+        };
+        let antimessages = machine.crash();
+        trace_debug!(
+            machine_id,
+            lvt = %machine.local_virtual_time(),
+            antimessages = antimessages.len(),
+            "Machine crashed"
+        );
+        self.crashes.planned.remove(&machine_id);
+        self.crashes.down.insert(
+            machine_id,
+            Down {
+                restart_in,
+                backlog: Vec::new(),
+            },
+        );
+        self.in_transit.extend(antimessages);
+        true
+    }
+
+    // Brings a crashed machine back, whatever was sent to it while it was down is
+    // delivered now. Returns false if it wasnt down.
+    pub fn restart(&mut self, machine_id: MachineId) -> bool {
+        let Some(down) = self.crashes.down.remove(&machine_id) else {
+            return false;
+        };
+        trace_debug!(machine_id, backlog = down.backlog.len(), "Machine restarted");
+        self.in_transit.extend(down.backlog);
+        true
+    }
+
+    pub fn is_down(&self, machine_id: MachineId) -> bool {
+        self.crashes.is_down(machine_id)
+    }
+
+    // Called before a machine executes a message, crashes it if that is past its planned
+    // crash. Returns true if it crashed.
+    pub(super) fn crash_if_due(&mut self, machine_id: MachineId, rec_time: VirtualTime) -> bool {
+        match self.crashes.due(machine_id, rec_time) {
+            Some(plan) => self.crash_with(machine_id, plan.restart_after),
+            None => false,
+        }
+    }
+
+    // Restarts the down machine closest to its planned restart, if any has one
+    pub(super) fn restart_next(&mut self) -> bool {
+        let next = self
+            .crashes
+            .down
+            .iter()
+            .filter_map(|(machine_id, down)| Some((down.restart_in?, *machine_id)))
+            .min();
+        match next {
+            Some((_, machine_id)) => self.restart(machine_id),
+            None => false,
+        }
+    }
+
+    // One more event ran, restarts the machines whose time is up
+    pub(super) fn count_down_restarts(&mut self) {
+        let mut restart = Vec::new();
+        for (machine_id, down) in self.crashes.down.iter_mut() {
+            if let Some(left) = down.restart_in.as_mut() {
+                *left = left.saturating_sub(1);
+                if *left == 0 {
+                    restart.push(*machine_id);
+                }
+            }
+        }
+        for machine_id in restart {
+            self.restart(machine_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use crate::time::message::Sign;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_crash_undoes_uncommitted_work_and_restart_redoes_it() {
+        let mut expected = ring(2);
+        expected.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("5".to_string())));
+        expected.run();
+
+        let mut simulation = ring(2);
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("5".to_string())));
+        // Machine 0 handles 1, 7 and 13, it goes down before 13 after having done 7
+        simulation.schedule_crash(
+            0,
+            CrashPlan {
+                at: VirtualTime::new(13),
+                restart_after: None,
+            },
+        );
+        simulation.run();
+        assert!(simulation.is_down(0));
+        // Nothing was committed so all of machine 0s work is gone, machine 1 undid what
+        // that caused and is waiting on it again
+        assert_eq!(simulation.machine(0).unwrap().state, 0);
+        assert_eq!(simulation.machine(1).unwrap().state, 0);
+        assert_eq!(simulation.gvt(), Some(VirtualTime::new(1)));
+
+        assert!(simulation.restart(0));
+        simulation.run();
+        for machine in simulation.machines() {
+            let reference = expected.machine(machine.machine_id()).unwrap();
+            assert_eq!(machine.state, reference.state);
+        }
+        assert_eq!(simulation.stats().total.crashes, 1);
+    }
+
+    #[test]
+    fn test_messages_wait_while_down_and_planned_restart() {
+        let mut simulation = ring(2);
+        simulation.crash(1);
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("1".to_string())));
+        simulation.run();
+        // Machine 0 ran and sent to machine 1, which never got it
+        assert_eq!(simulation.machine(0).unwrap().state, 1);
+        assert_eq!(simulation.machine(1).unwrap().state, 0);
+        assert_eq!(simulation.gvt(), Some(VirtualTime::new(4)));
+
+        // Everything from here on is after GVT, anything before it would be dropped
+        simulation.restart(1);
+        for rec_time in [5, 6] {
+            let message = Message::new(0, rec_time, 1, 1, Sign::Message, Arc::new("0".into()));
+            simulation.inject(message);
+        }
+        simulation.schedule_crash(
+            1,
+            CrashPlan {
+                at: VirtualTime::new(6),
+                restart_after: Some(1),
+            },
+        );
+        simulation.inject(Message::new(0, 7, 0, 0, Sign::Message, Arc::new("0".to_string())));
+        simulation.run();
+        // Machine 1 lost 4 and 5 when it crashed before 6, came back once machine 0 ran
+        // and did all three
+        assert!(!simulation.is_down(1));
+        assert_eq!(simulation.machine(1).unwrap().state, 3);
+        assert_eq!(simulation.stats().total.messages_past_gvt, 0);
+        assert_eq!(simulation.gvt(), None);
+    }
+}
@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::Simulation;
+use crate::process::TimeWarpProcess;
+use crate::time::message::{MachineId, Message, MessagePayload, Sign, VirtualTime};
+use crate::trace::trace_debug;
+
+// Events coming from outside the model (stdin, a socket, a sensor) dont have a virtual
+// time of their own. Whoever produces them gets an ExternalInjector and only says who
+// the event is for, the simulation stamps it when it picks it up: never earlier than GVT
+// and after the receivers local virtual time so it cant cause a rollback, and when the
+// run is paced to the wall clock, at the virtual time that corresponds to now. From
+// there it is a normal message going through the receivers input queue.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExternalEvent {
+    pub receiver: MachineId,
+    pub payload: MessagePayload,
+}
+
+// Cheap to clone and can be moved to other threads
+#[derive(Debug, Clone)]
+pub struct ExternalInjector {
+    sender: Sender<ExternalEvent>,
+}
+
+impl ExternalInjector {
+    // Returns false if the simulation is gone
+    pub fn inject(&self, receiver: MachineId, payload: MessagePayload) -> bool {
+        self.sender.send(ExternalEvent { receiver, payload }).is_ok()
+    }
+}
+
+pub(super) struct ExternalInput {
+    sender: Sender<ExternalEvent>,
+    receiver: Receiver<ExternalEvent>,
+    // Taken off the channel by a checkpoint but not stamped yet, see pending
+    received: RefCell<VecDeque<ExternalEvent>>,
+    // Wall clock start and how long one unit of virtual time takes while running paced
+    pace: Option<(Instant, Duration)>,
+}
+
+impl ExternalInput {
+    pub(super) fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            sender,
+            receiver,
+            received: RefCell::default(),
+            pace: None,
+        }
+    }
+
+    // The events sent but not picked up yet, in the order they came in. A checkpoint
+    // cant stamp them (that would change the simulation) so they are kept aside until
+    // the next receive_external.
+    pub(super) fn pending(&self) -> Vec<ExternalEvent> {
+        let mut received = self.received.borrow_mut();
+        received.extend(self.receiver.try_iter());
+        received.iter().cloned().collect()
+    }
+
+    // Queues events as if an injector had just sent them, for restoring a checkpoint
+    pub(super) fn requeue(&self, events: impl IntoIterator<Item = ExternalEvent>) {
+        self.received.borrow_mut().extend(events);
+    }
+
+    // Virtual time the wall clock is at, only while running paced
+    fn paced_now(&self) -> Option<VirtualTime> {
+        let (start, unit) = self.pace?;
+        let units = start.elapsed().as_nanos() / unit.as_nanos().max(1);
+        Some(VirtualTime::new(units as u64))
+    }
+}
+
+impl<P: TimeWarpProcess> Simulation<P> {
+    pub fn external_injector(&self) -> ExternalInjector {
+        ExternalInjector {
+            sender: self.external.sender.clone(),
+        }
+    }
+
+    // The receive time an external event for the machine would get right now
+    pub fn external_time(&self, receiver: MachineId) -> VirtualTime {
+        let lvt = self
+            .machines
+            .get(&receiver)
+            .map_or(VirtualTime::ZERO, |machine| machine.local_virtual_time());
+        let floor = self.gvt().unwrap_or_default().max(lvt.next());
+        self.external.paced_now().map_or(floor, |now| now.max(floor))
+    }
+
+    fn stamp(&self, event: ExternalEvent) -> Message {
+        let rec_time = self.external_time(event.receiver);
+        trace_debug!(receiver = event.receiver, %rec_time, "Stamped external event");
+        Message::new(
+            rec_time,
+            rec_time,
+            event.receiver,
+            event.receiver,
+            Sign::Message,
+            Arc::new(event.payload),
+        )
+    }
+
+    // Moves whatever the injectors sent since the last call into transit
+    pub(super) fn receive_external(&mut self) {
+        let received = std::mem::take(self.external.received.get_mut());
+        for event in received.into_iter().chain(self.external.receiver.try_iter()) {
+            let message = self.stamp(event);
+            self.in_transit.push_back(message);
+        }
+    }
+
+    // Runs in step with the wall clock, one unit of virtual time taking time_unit, until
+    // the virtual time reaches end_time. Events are not processed before their time comes
+    // up and while there is nothing to do the simulation waits for external events. Like
+    // the other run methods it returns early when paused, but only notices once it wakes.
+    pub fn run_paced(&mut self, time_unit: Duration, end_time: impl Into<VirtualTime>) {
+        let end_time = end_time.into();
+        self.external.pace = Some((Instant::now(), time_unit));
+        while !self.is_paused() {
+            self.deliver_pending();
+            let now = self.external.paced_now().unwrap_or_default();
+            let due = match self.earliest_ready() {
+                Some(rec_time) if rec_time <= end_time => rec_time,
+                _ if now >= end_time => break,
+                _ => end_time,
+            };
+            if due <= now {
+                self.step_until(now);
+                continue;
+            }
+            // Sleep until the next event is due, waking up early for external ones
+            let (start, unit) = self.external.pace.unwrap();
+            let wake = start + unit.saturating_mul(due.ticks().min(u32::MAX as u64) as u32);
+            let timeout = wake.saturating_duration_since(Instant::now());
+            match self.external.receiver.recv_timeout(timeout) {
+                Ok(event) => {
+                    let message = self.stamp(event);
+                    self.in_transit.push_back(message);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                // Cant happen, the simulation holds a sender itself
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        self.external.pace = None;
+        self.commit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use std::thread;
+
+    #[test]
+    fn test_external_events_never_roll_back() {
+        let mut simulation = ring(2);
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("4".to_string())));
+        simulation.run();
+        assert_eq!(simulation.machine(0).unwrap().local_virtual_time(), 13);
+
+        let injector = simulation.external_injector();
+        assert!(injector.inject(0, "1".to_string()));
+        simulation.run();
+
+        // Stamped just after machine 0s last event and forwarded on to machine 1
+        let machine = simulation.machine(0).unwrap();
+        assert_eq!(machine.state, 4);
+        assert_eq!(machine.local_virtual_time(), 14);
+        assert_eq!(simulation.machine(1).unwrap().local_virtual_time(), 17);
+        assert_eq!(simulation.stats().total.rollbacks, 0);
+    }
+
+    #[test]
+    fn test_paced_run_stamps_with_wall_clock() {
+        let mut simulation = ring(2);
+        let injector = simulation.external_injector();
+        let producer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(30));
+            injector.inject(1, "0".to_string());
+        });
+        simulation.run_paced(Duration::from_millis(1), 60);
+        producer.join().unwrap();
+
+        let machine = simulation.machine(1).unwrap();
+        assert_eq!(machine.state, 1);
+        assert!(machine.local_virtual_time() >= 30);
+        assert!(machine.local_virtual_time() <= 60);
+    }
+}
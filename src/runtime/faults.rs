@@ -0,0 +1,209 @@
+use std::collections::VecDeque;
+
+use super::Simulation;
+use crate::latency::mix;
+use crate::process::TimeWarpProcess;
+use crate::time::message::{Message, Sign};
+use crate::trace::trace_debug;
+
+// Fault injection for the simulations router, for stress testing models and the
+// annihilation logic. Every message (and, unless turned off, every antimessage) going
+// through deliver_pending can be dropped, delivered twice or held back while up to
+// max_delay later messages overtake it. The fate of each message comes from the seed
+// and how many messages came before it, so the same run with the same seed breaks the
+// same way.
+//
+// Time Warp already copes with messages arriving in any order, so delays should never
+// change the result. Duplicates only do if the input queues DuplicatePolicy keeps or
+// rejects the copy, by default it is dropped. Drops do, and a dropped antimessage is
+// never acknowledged so GVT cant get past it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultPolicy {
+    // Chance of each fault, checked in this order
+    pub drop: f64,
+    pub duplicate: f64,
+    pub delay: f64,
+    pub max_delay: usize,
+    pub antimessages: bool,
+    pub seed: u64,
+}
+
+impl Default for FaultPolicy {
+    fn default() -> Self {
+        Self {
+            drop: 0.0,
+            duplicate: 0.0,
+            delay: 0.0,
+            max_delay: 4,
+            antimessages: true,
+            seed: 0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FaultStats {
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub delayed: u64,
+}
+
+#[derive(Debug)]
+pub(super) struct FaultInjector {
+    policy: FaultPolicy,
+    draws: u64,
+    // Messages that made it through and are next to be delivered
+    ready: VecDeque<Message>,
+    // Held back messages with how many more messages get to overtake them
+    held: VecDeque<(usize, Message)>,
+    stats: FaultStats,
+}
+
+impl FaultInjector {
+    pub(super) fn new(policy: FaultPolicy) -> Self {
+        Self {
+            policy,
+            draws: 0,
+            ready: VecDeque::new(),
+            held: VecDeque::new(),
+            stats: FaultStats::default(),
+        }
+    }
+
+    // Uniform in [0, 1)
+    fn draw(&mut self) -> f64 {
+        self.draws += 1;
+        (mix(self.policy.seed ^ self.draws) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn admit(&mut self, message: Message) {
+        for (overtakes_left, _) in self.held.iter_mut() {
+            *overtakes_left = overtakes_left.saturating_sub(1);
+        }
+        while self.held.front().is_some_and(|(overtakes_left, _)| *overtakes_left == 0) {
+            let (_, released) = self.held.pop_front().unwrap();
+            self.ready.push_back(released);
+        }
+
+        if message.sign == Sign::Antimessage && !self.policy.antimessages {
+            self.ready.push_back(message);
+            return;
+        }
+        if self.draw() < self.policy.drop {
+            trace_debug!(message_id = message.id, "Fault injection dropped a message");
+            self.stats.dropped += 1;
+        } else if self.draw() < self.policy.duplicate {
+            trace_debug!(message_id = message.id, "Fault injection duplicated a message");
+            self.stats.duplicated += 1;
+            self.ready.push_back(message.clone());
+            self.ready.push_back(message);
+        } else if self.draw() < self.policy.delay && self.policy.max_delay > 0 {
+            let overtakes = mix(self.policy.seed ^ self.draws) % self.policy.max_delay as u64;
+            self.held.push_back((overtakes as usize + 1, message));
+            self.stats.delayed += 1;
+        } else {
+            self.ready.push_back(message);
+        }
+    }
+}
+
+impl<P: TimeWarpProcess> Simulation<P> {
+    // Every message delivered from now on goes through the fault policy
+    pub fn set_faults(&mut self, policy: FaultPolicy) {
+        self.faults = Some(FaultInjector::new(policy));
+    }
+
+    pub fn clear_faults(&mut self) {
+        self.faults = None;
+    }
+
+    pub fn fault_stats(&self) -> Option<FaultStats> {
+        self.faults.as_ref().map(|faults| faults.stats)
+    }
+
+    // The next message to deliver. Held back messages are let go once nothing else is
+    // in transit, so when deliver_pending returns nothing is left waiting in here.
+    pub(super) fn next_in_transit(&mut self) -> Option<Message> {
+        let Some(faults) = self.faults.as_mut() else {
+            return self.in_transit.pop_front();
+        };
+        loop {
+            if let Some(message) = faults.ready.pop_front() {
+                return Some(message);
+            }
+            match self.in_transit.pop_front() {
+                Some(message) => faults.admit(message),
+                None => return faults.held.pop_front().map(|(_, message)| message),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use std::sync::Arc;
+
+    fn start() -> Vec<Message> {
+        vec![
+            Message::new(0, 1, 0, 0, Sign::Message, Arc::new("6".to_string())),
+            Message::new(0, 2, 1, 1, Sign::Message, Arc::new("5".to_string())),
+            Message::new(0, 4, 2, 2, Sign::Message, Arc::new("7".to_string())),
+        ]
+    }
+
+    #[test]
+    fn test_delays_dont_change_the_result() {
+        let mut expected = ring(3);
+        for message in start() {
+            expected.inject(message);
+        }
+        expected.run();
+
+        let mut simulation = ring(3);
+        simulation.set_faults(FaultPolicy {
+            delay: 0.5,
+            seed: 11,
+            ..FaultPolicy::default()
+        });
+        for message in start() {
+            simulation.inject(message);
+        }
+        simulation.run();
+
+        assert!(simulation.fault_stats().unwrap().delayed > 0);
+        for machine in simulation.machines() {
+            let reference = expected.machine(machine.machine_id()).unwrap();
+            assert_eq!(machine.state, reference.state);
+            assert_eq!(machine.local_virtual_time(), reference.local_virtual_time());
+        }
+    }
+
+    #[test]
+    fn test_duplicate_is_dropped_and_drop_loses() {
+        let mut expected = ring(2);
+        expected.inject(start().remove(0));
+        expected.run();
+
+        let mut simulation = ring(2);
+        simulation.set_faults(FaultPolicy {
+            duplicate: 1.0,
+            ..FaultPolicy::default()
+        });
+        simulation.inject(start().remove(0));
+        simulation.run();
+        assert!(simulation.fault_stats().unwrap().duplicated > 0);
+        assert_eq!(simulation.stats().total, expected.stats().total);
+
+        let mut simulation = ring(2);
+        simulation.set_faults(FaultPolicy {
+            drop: 1.0,
+            ..FaultPolicy::default()
+        });
+        simulation.inject(start().remove(0));
+        simulation.run();
+        assert_eq!(simulation.fault_stats().unwrap().dropped, 1);
+        assert_eq!(simulation.stats().total.events_processed, 0);
+    }
+}
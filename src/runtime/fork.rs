@@ -0,0 +1,110 @@
+use std::fmt;
+
+use super::Simulation;
+use crate::process::TimeWarpProcess;
+use crate::time::message::{MachineId, Sign, VirtualTime};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForkError {
+    // Only the committed past is the same in every future, vt has to be at most GVT
+    NotCommitted { vt: VirtualTime, gvt: VirtualTime },
+    // The machine doesnt have a state from before vt anymore, see SnapshotLimits
+    StateGone { machine_id: MachineId },
+}
+
+impl fmt::Display for ForkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForkError::NotCommitted { vt, gvt } => {
+                write!(f, "cant fork at {vt}, only up to GVT ({gvt}) is committed")
+            }
+            ForkError::StateGone { machine_id } => {
+                write!(f, "machine {machine_id} has no saved state from before the fork")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ForkError {}
+
+impl<P: TimeWarpProcess + Clone> Simulation<P> {
+    // A new simulation that shares the committed past of this one up to vt and goes its
+    // own way from there, for trying out what happens with different events injected.
+    // Every machine starts at vt with the state it had just before it, and everything
+    // sent before vt to be received at vt or later is waiting for it, so left alone a
+    // fork ends up where this simulation does. Nothing here is touched.
+    //
+    // Only the machines, their names and the messages are copied. The fork starts with the
+    // default scheduler and without sinks, recording, faults or crashes, set those up
+    // again. The processes are cloned, so whatever they share (a SharedVar say) they share
+    // with the fork as well.
+    pub fn fork_at(&self, vt: impl Into<VirtualTime>) -> Result<Simulation<P>, ForkError> {
+        let vt = vt.into();
+        if let Some(gvt) = self.gvt().filter(|gvt| vt > *gvt) {
+            return Err(ForkError::NotCommitted { vt, gvt });
+        }
+        let mut fork = Simulation::new();
+        fork.names = self.names.clone();
+        for machine in self.machines.values() {
+            let forked = machine.fork_at(vt).ok_or(ForkError::StateGone {
+                machine_id: machine.machine_id(),
+            })?;
+            fork.add_machine(forked);
+        }
+        // Messages that are still in an input queue were never cancelled, and everything
+        // sent before GVT is committed so nothing on the way can cancel them either
+        let queued = self.machines.values().flat_map(|machine| machine.input_queue.iter());
+        let in_transit = self.in_transit.iter().chain(self.crashes.held());
+        for message in queued.chain(in_transit) {
+            if message.sign == Sign::Message && message.send_time < vt && message.rec_time >= vt {
+                fork.inject(message.clone());
+            }
+        }
+        Ok(fork)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use crate::time::message::Message;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_forks_share_the_past_and_not_the_future() {
+        let mut simulation = ring(3);
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("6".to_string())));
+        simulation.run_until(8);
+
+        // Left alone the fork gets to the same place
+        let mut same = simulation.fork_at(5).unwrap();
+        let mut other = simulation.fork_at(5).unwrap();
+        simulation.run();
+        same.run();
+        for machine in simulation.machines() {
+            let forked = same.machine(machine.machine_id()).unwrap();
+            assert_eq!(forked.state, machine.state);
+            assert_eq!(forked.local_virtual_time(), machine.local_virtual_time());
+        }
+
+        // Another message changes only the fork it went into
+        other.inject(Message::new(5, 6, 2, 2, Sign::Message, Arc::new("0".to_string())));
+        other.run();
+        assert_eq!(other.machine(2).unwrap().state, simulation.machine(2).unwrap().state + 1);
+        assert_eq!(other.machine(0).unwrap().state, simulation.machine(0).unwrap().state);
+
+        // Everything is committed once the run is over
+        assert!(simulation.fork_at(30).is_ok());
+        let mut running = ring(2);
+        running.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("9".to_string())));
+        running.run_until(4);
+        assert_eq!(
+            running.fork_at(9).err(),
+            Some(ForkError::NotCommitted {
+                vt: VirtualTime::new(9),
+                gvt: VirtualTime::new(7),
+            })
+        );
+    }
+}
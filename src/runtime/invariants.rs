@@ -0,0 +1,178 @@
+use super::Simulation;
+use crate::determinism::{RunDigest, StateDigests};
+use crate::process::TimeWarpProcess;
+use crate::sink::CommittedEvent;
+use crate::time::message::{Delay, MachineId, Message, VirtualTime};
+use crate::trace::trace_warn;
+
+// Assertions about machine state that are only checked on committed state. Checking
+// inside on_message would trip on speculative states that are about to be rolled back
+// anyway, so instead every invariant is checked against the state right after each
+// event once GVT has passed it, when that state is final. The state is rebuilt with
+// Machine::state_at, so this costs one more run of every committed event while any
+// invariant is registered. The same committed states also go into the digests the
+// determinism checker compares, see determinism.rs.
+type Predicate<S> = Box<dyn Fn(MachineId, &S) -> bool>;
+
+pub(super) struct Invariants<S> {
+    predicates: Vec<(String, Predicate<S>)>,
+    violations: Vec<InvariantViolation>,
+    digests: Option<StateDigests>,
+}
+
+impl<S> Default for Invariants<S> {
+    fn default() -> Self {
+        Self {
+            predicates: Vec::new(),
+            violations: Vec::new(),
+            digests: None,
+        }
+    }
+}
+
+// The invariant that didnt hold, on which machine and the event that led to it
+#[derive(Debug, Clone)]
+pub struct InvariantViolation {
+    pub invariant: String,
+    pub machine_id: MachineId,
+    pub rec_time: VirtualTime,
+    pub event: Message,
+}
+
+impl<P: TimeWarpProcess> Simulation<P> {
+    // The predicate gets the machine id and its state and returns whether it holds
+    pub fn add_invariant(
+        &mut self,
+        name: impl Into<String>,
+        predicate: impl Fn(MachineId, &P::State) -> bool + 'static,
+    ) {
+        self.invariants
+            .predicates
+            .push((name.into(), Box::new(predicate)));
+    }
+
+    // Every violation found so far, in the order of the events that caused them
+    pub fn violations(&self) -> &[InvariantViolation] {
+        &self.invariants.violations
+    }
+
+    // Hashes every state committed from now on, epoch_length units of virtual time to a
+    // digest
+    pub fn record_digests(&mut self, epoch_length: impl Into<Delay>) {
+        self.invariants.digests = Some(StateDigests::new(epoch_length));
+    }
+
+    pub fn digests(&self) -> Option<&StateDigests> {
+        self.invariants.digests.as_ref()
+    }
+
+    pub fn take_digests(&mut self) -> Option<StateDigests> {
+        self.invariants.digests.take()
+    }
+
+    // The digest of everything committed since record_digests, see RunDigest
+    pub fn run_digest(&self) -> Option<RunDigest> {
+        self.invariants.digests.as_ref().map(StateDigests::run_digest)
+    }
+
+    // Called on every batch of newly committed events, in timestamp order
+    pub(super) fn check_committed(&mut self, committed: &[CommittedEvent]) {
+        if self.invariants.predicates.is_empty() && self.invariants.digests.is_none() {
+            return;
+        }
+        // The events of a machine at one time are next to each other, each gets the state
+        // it left behind
+        let mut states = Vec::new().into_iter();
+        for (index, event) in committed.iter().enumerate() {
+            let rec_time = event.message.rec_time;
+            let same_time = |other: &CommittedEvent| {
+                (other.machine_id, other.message.rec_time) == (event.machine_id, rec_time)
+            };
+            if index == 0 || !same_time(&committed[index - 1]) {
+                states = self
+                    .machines
+                    .get(&event.machine_id)
+                    .and_then(|machine| machine.states_at(rec_time))
+                    .unwrap_or_default()
+                    .into_iter();
+            }
+            let Some(state) = states.next() else {
+                continue;
+            };
+            if let Some(digests) = self.invariants.digests.as_mut() {
+                digests.record(event.machine_id, rec_time, &state);
+            }
+            for (name, predicate) in &self.invariants.predicates {
+                if predicate(event.machine_id, &state) {
+                    continue;
+                }
+                trace_warn!(
+                    invariant = name.as_str(),
+                    machine_id = event.machine_id,
+                    %rec_time,
+                    message_id = event.message.id,
+                    "Invariant violated"
+                );
+                self.invariants.violations.push(InvariantViolation {
+                    invariant: name.clone(),
+                    machine_id: event.machine_id,
+                    rec_time,
+                    event: event.message.clone(),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::tests::ring;
+    use crate::time::message::{Message, Sign};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_only_committed_states_are_checked() {
+        let mut simulation = ring(2);
+        simulation.add_invariant("fewer than 3", |_, handled: &usize| *handled < 3);
+        simulation.add_invariant("fewer than 2", |_, handled: &usize| *handled < 2);
+        let message =
+            |rec_time| Message::new(0, rec_time, 0, 0, Sign::Message, Arc::new("0".to_string()));
+        let cancelled = message(9);
+        let last = message(12);
+        simulation.inject(message(3));
+        simulation.inject(cancelled.clone());
+        simulation.inject(last.clone());
+        while simulation.step() {}
+        // Machine 0 speculatively got to 3 handled at 12, then the one at 9 is cancelled
+        let mut antimessage = cancelled;
+        antimessage.sign = Sign::Antimessage;
+        simulation.inject(antimessage);
+        simulation.run();
+
+        // What committed is 3 and 12, so only the second invariant ever broke
+        let violations = simulation.violations();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].invariant, "fewer than 2");
+        assert_eq!(violations[0].machine_id, 0);
+        assert_eq!(violations[0].rec_time, 12);
+        assert_eq!(violations[0].event, last);
+    }
+
+    #[test]
+    fn test_events_at_the_same_time_are_checked_with_their_own_state() {
+        let mut simulation = ring(1);
+        simulation.add_invariant("fewer than 2", |_, handled: &usize| *handled < 2);
+        let message = || Message::new(0, 1, 0, 0, Sign::Message, Arc::new("0".to_string()));
+        let first = message();
+        let second = message();
+        simulation.inject(first);
+        simulation.inject(second.clone());
+        simulation.run();
+
+        // Both ran at 1 but only the second one left 2 handled
+        let violations = simulation.violations();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].rec_time, 1);
+        assert_eq!(violations[0].event, second);
+    }
+}
@@ -0,0 +1,925 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::latency::Latencies;
+use crate::machine::{BlockReason, ExampleProcess, Machine, NextEvent};
+use crate::metrics::{MetricsHandle, MetricsSnapshot};
+use crate::process::TimeWarpProcess;
+use crate::recorder::{Recorder, Trace};
+use crate::router::{Router, RouterError};
+use crate::sink::{CommittedEvent, EventSink};
+use crate::stats::{EventHorizon, SimulationStats};
+use crate::time::message::{MachineId, Message, Sign, VirtualTime};
+use crate::trace::trace_warn;
+
+pub mod async_executor;
+pub mod breakpoints;
+pub mod cancellation;
+#[cfg(feature = "vector-clocks")]
+pub mod causality;
+pub mod checkpoint;
+pub mod conservative;
+pub mod crash;
+pub mod external;
+pub mod faults;
+pub mod fork;
+pub mod invariants;
+pub mod pool;
+pub mod reevaluation;
+pub mod scheduler;
+pub mod sequential;
+pub mod warm_up;
+pub mod wolf;
+
+use breakpoints::Breakpoints;
+use crash::Crashes;
+use external::ExternalInput;
+use faults::FaultInjector;
+use invariants::Invariants;
+use scheduler::{LowestTimestamp, Ready, Scheduler};
+use warm_up::WarmUp;
+use wolf::WolfCalls;
+
+// The simulation owns every machine in a run and plays the part the examples in main.rs
+// do by hand: messages sent by one machine are delivered to the receivers input queue,
+// antimessages produced by rollbacks are delivered and acknowledged, and the machine
+// with the earliest unprocessed message is the one picked to run next.
+//
+// Everything here runs on one thread so it doubles as the reference for how the other
+// executors are supposed to behave.
+pub struct Simulation<P: TimeWarpProcess = ExampleProcess> {
+    machines: BTreeMap<MachineId, Machine<P>>,
+    in_transit: VecDeque<Message>,
+    recorder: Option<Recorder>,
+    external: ExternalInput,
+    paused: PauseHandle,
+    faults: Option<FaultInjector>,
+    crashes: Crashes,
+    sinks: Vec<Box<dyn EventSink>>,
+    metrics: Option<MetricsHandle>,
+    events_since_metrics: usize,
+    invariants: Invariants<P::State>,
+    warm_up: WarmUp,
+    wolf_calls: WolfCalls,
+    breakpoints: Breakpoints,
+    event_horizon: EventHorizon,
+    // Set on every machine added, see cancellation.rs
+    direct_cancellation: bool,
+    // Set on every machine added, see reevaluation.rs
+    lazy_reevaluation: bool,
+    scheduler: Box<dyn Scheduler>,
+    // Events after this arent considered while step_until runs
+    horizon: Option<VirtualTime>,
+    // Every machine is right here, the router only keeps their names
+    names: Router<()>,
+    #[cfg(feature = "vector-clocks")]
+    causality: Option<causality::CausalityAudit>,
+}
+
+// How many events run between metrics snapshots, on top of the one after every commit
+const METRICS_INTERVAL: usize = 256;
+
+// Pauses a simulation from somewhere else, like another thread or a ui. The run methods
+// check it between events and return once it is set, step and step_machine still work
+// while paused so a paused simulation can be single stepped.
+#[derive(Debug, Default, Clone)]
+pub struct PauseHandle {
+    paused: Arc<AtomicBool>,
+}
+
+impl PauseHandle {
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+}
+
+impl<P: TimeWarpProcess> Default for Simulation<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: TimeWarpProcess> Simulation<P> {
+    pub fn new() -> Self {
+        Self {
+            machines: BTreeMap::new(),
+            in_transit: VecDeque::new(),
+            recorder: None,
+            external: ExternalInput::new(),
+            paused: PauseHandle::default(),
+            faults: None,
+            crashes: Crashes::default(),
+            sinks: Vec::new(),
+            metrics: None,
+            events_since_metrics: 0,
+            invariants: Invariants::default(),
+            warm_up: WarmUp::default(),
+            wolf_calls: WolfCalls::default(),
+            breakpoints: Breakpoints::default(),
+            event_horizon: EventHorizon::default(),
+            direct_cancellation: false,
+            lazy_reevaluation: false,
+            scheduler: Box::new(LowestTimestamp),
+            horizon: None,
+            names: Router::new(),
+            #[cfg(feature = "vector-clocks")]
+            causality: None,
+        }
+    }
+
+    pub fn pause(&self) {
+        self.paused.pause();
+    }
+
+    // Only clears the pause, call one of the run methods again to carry on
+    pub fn resume(&self) {
+        self.paused.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_paused()
+    }
+
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.paused.clone()
+    }
+
+    // Starts recording every execution and rollback from here on, see recorder.rs
+    pub fn start_recording(&mut self) {
+        let mut recorder = Recorder::new();
+        for (machine_id, name) in self.names.names() {
+            recorder.set_name(*machine_id, name);
+        }
+        self.recorder = Some(recorder);
+    }
+
+    pub fn trace(&self) -> Option<&Trace> {
+        self.recorder.as_ref().map(Recorder::trace)
+    }
+
+    // Stops recording and hands back what was recorded
+    pub fn take_trace(&mut self) -> Option<Trace> {
+        self.recorder.take().map(Recorder::into_trace)
+    }
+
+    // Every event committed from now on is handed to the sink, see sink.rs
+    pub fn add_sink(&mut self, sink: Box<dyn EventSink>) {
+        self.sinks.push(sink);
+    }
+
+    pub fn flush_sinks(&mut self) -> io::Result<()> {
+        self.sinks.iter_mut().try_for_each(|sink| sink.flush())
+    }
+
+    // Publishes a metrics snapshot to the handle every so often, see metrics.rs
+    pub fn set_metrics(&mut self, metrics: MetricsHandle) {
+        self.metrics = Some(metrics);
+        self.publish_metrics();
+    }
+
+    pub fn metrics(&self) -> MetricsSnapshot {
+        MetricsSnapshot::new(self.gvt(), self.machines.values())
+    }
+
+    fn publish_metrics(&mut self) {
+        self.events_since_metrics = 0;
+        if let Some(metrics) = &self.metrics {
+            metrics.publish(self.metrics());
+        }
+    }
+
+    pub fn add_machine(&mut self, mut machine: Machine<P>) {
+        if self.direct_cancellation {
+            machine.set_direct_cancellation(true);
+        }
+        if self.lazy_reevaluation {
+            machine.set_lazy_reevaluation(true);
+        }
+        self.machines.insert(machine.machine_id(), machine);
+    }
+
+    // Names the machine for lookup_by_name and in traces, see router.rs
+    pub fn name_machine(&mut self, machine_id: MachineId, name: &str) -> Result<(), RouterError> {
+        self.names.set_name(machine_id, name)?;
+        if let Some(recorder) = &mut self.recorder {
+            recorder.set_name(machine_id, name);
+        }
+        Ok(())
+    }
+
+    pub fn lookup_by_name(&self, name: &str) -> Option<MachineId> {
+        self.names.lookup_by_name(name)
+    }
+
+    pub fn router(&self) -> &Router<()> {
+        &self.names
+    }
+
+    // Gives every machine added so far the same latency models
+    pub fn set_latencies(&mut self, latencies: Latencies) {
+        let latencies = Arc::new(latencies);
+        for machine in self.machines.values_mut() {
+            machine.set_latencies(latencies.clone());
+        }
+    }
+
+    pub fn machine(&self, machine_id: MachineId) -> Option<&Machine<P>> {
+        self.machines.get(&machine_id)
+    }
+
+    pub fn machine_mut(&mut self, machine_id: MachineId) -> Option<&mut Machine<P>> {
+        self.machines.get_mut(&machine_id)
+    }
+
+    pub fn machines(&self) -> impl Iterator<Item = &Machine<P>> {
+        self.machines.values()
+    }
+
+    pub fn into_machines(self) -> BTreeMap<MachineId, Machine<P>> {
+        self.machines
+    }
+
+    // Puts a message into the simulation from outside, it is delivered on the next step
+    pub fn inject(&mut self, message: Message) {
+        self.in_transit.push_back(message);
+    }
+
+    // Delivers everything in transit, including any antimessages the deliveries cause
+    pub fn deliver_pending(&mut self) {
+        self.receive_external();
+        loop {
+            while let Some(message) = self.next_in_transit() {
+                // Its sender took it back before it got there, see cancellation.rs
+                if message.is_cancelled() {
+                    continue;
+                }
+                let Some(message) = self.crashes.hold(message) else {
+                    continue;
+                };
+                let Some(receiver) = self.machines.get_mut(&message.receiver) else {
+                    trace_warn!(
+                        receiver = message.receiver,
+                        message_id = message.id,
+                        "Dropping message for unknown machine"
+                    );
+                    // Nobody else will, and GVT cant pass it while its in flight
+                    self.acknowledge(&message);
+                    continue;
+                };
+                let lvt_before = receiver.local_virtual_time();
+                let rolled_back_before = receiver.stats().events_rolled_back;
+                let jumps_before = receiver.stats().jumps;
+                let wall_start = self.recorder.as_ref().map(Recorder::now);
+                if let Some(sent) = receiver.recieve_outer(message.clone()) {
+                    if receiver.stats().jumps > jumps_before {
+                        self.jumped(&message, wall_start, sent);
+                    } else {
+                        self.rolled_back(&message, lvt_before, rolled_back_before, sent);
+                    }
+                }
+                self.acknowledge(&message);
+            }
+            // Rollbacks that cancelled directly and rolling back a machine that read a
+            // shared variable too early can roll back more machines
+            if !self.cancel_directly() && !self.roll_back_stale_reads() {
+                break;
+            }
+        }
+    }
+
+    // An antimessage got where it was going, its sender can stop counting it in GVT
+    fn acknowledge(&mut self, message: &Message) {
+        if message.sign == Sign::Antimessage {
+            if let Some(sender) = self.machines.get_mut(&message.sender) {
+                sender.acknowledge_antimessage(message);
+            }
+        }
+    }
+
+    // The receiver of the message rolled back for it, this keeps the books and sends the
+    // antimessages on
+    fn rolled_back(
+        &mut self,
+        message: &Message,
+        lvt_before: VirtualTime,
+        rolled_back_before: u64,
+        antimessages: Vec<Message>,
+    ) {
+        let receiver = &self.machines[&message.receiver];
+        let depth = (receiver.stats().events_rolled_back - rolled_back_before) as usize;
+        self.wolf_calls.record_rollback(message.receiver, message.rec_time, depth);
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record_rollback(
+                message.receiver,
+                lvt_before,
+                receiver.local_virtual_time(),
+                message,
+                &antimessages,
+            );
+        }
+        self.check_rollback(message.receiver, message.rec_time, depth);
+        self.in_transit.extend(antimessages);
+    }
+
+    // See shared.rs, true if some machine had to roll back
+    fn roll_back_stale_reads(&mut self) -> bool {
+        let mut rolled_back = false;
+        for machine in self.machines.values_mut() {
+            if let Some(antimessages) = machine.roll_back_stale_reads() {
+                self.in_transit.extend(antimessages);
+                rolled_back = true;
+            }
+        }
+        rolled_back
+    }
+
+    // The machine that should run next, by default the one with the earliest message
+    // waiting (see scheduler.rs for others). Machines with an antimessage at the front
+    // are skipped since processing would only guarantee a rollback once the positive
+    // message arrives, and so are conservative machines whose next message isnt safe yet.
+    pub fn next_machine(&self) -> Option<(MachineId, VirtualTime)> {
+        let ready = self.ready();
+        if ready.is_empty() {
+            return None;
+        }
+        let machine_id = self.scheduler.pick(&ready);
+        let picked = ready.iter().find(|ready| ready.machine_id == machine_id);
+        Some((machine_id, picked.expect("scheduler picked a machine that isnt ready").rec_time))
+    }
+
+    // Every machine that can run, the choice the scheduler gets. Machines that fed a
+    // flooded machine (see MachineBuilder::max_pending) are left out while it catches
+    // up, unless that would leave nothing to run at all.
+    fn ready(&self) -> Vec<Ready> {
+        let ready = self.runnable();
+        let held = self.held_back();
+        if held.is_empty() {
+            return ready;
+        }
+        let unheld: Vec<_> =
+            ready.iter().filter(|ready| !held.contains(&ready.machine_id)).copied().collect();
+        if unheld.is_empty() {
+            return ready;
+        }
+        unheld
+    }
+
+    // The machines holding back for a flooded one, a flooded machine itself never is
+    pub fn held_back(&self) -> BTreeSet<MachineId> {
+        let flooded: Vec<_> = self.machines.values().filter(|machine| machine.flooded()).collect();
+        flooded
+            .iter()
+            .flat_map(|machine| machine.flooding_senders())
+            .filter(|sender| !self.machines.get(sender).is_some_and(Machine::flooded))
+            .collect()
+    }
+
+    fn runnable(&self) -> Vec<Ready> {
+        let safe_bound = self.safe_bound();
+        self.machines
+            .values()
+            .filter(|machine| machine.can_execute(safe_bound))
+            .filter(|machine| !self.crashes.is_down(machine.machine_id()))
+            .filter_map(|machine| {
+                let rec_time = machine.peek_next_message()?.rec_time;
+                Some(Ready {
+                    machine_id: machine.machine_id(),
+                    rec_time,
+                    lvt: machine.local_virtual_time(),
+                    events_processed: machine.stats().events_processed,
+                    events_rolled_back: machine.stats().events_rolled_back,
+                })
+            })
+            .filter(|ready| self.horizon.is_none_or(|horizon| ready.rec_time <= horizon))
+            .filter(|ready| self.wolf_calls.allows(ready.machine_id, ready.rec_time))
+            .collect()
+    }
+
+    // The machines left out of ready() because there is nothing they could run, whatever
+    // the safe bound or their budget. They stay that way until something is delivered.
+    pub fn blocked(&self) -> Vec<(MachineId, BlockReason)> {
+        self.machines
+            .values()
+            .filter_map(|machine| match machine.next_event() {
+                NextEvent::Blocked(reason) => Some((machine.machine_id(), reason)),
+                NextEvent::Ready(_) => None,
+            })
+            .collect()
+    }
+
+    // Time of the earliest event some machine can run, whatever the scheduler would pick
+    pub(crate) fn earliest_ready(&self) -> Option<VirtualTime> {
+        self.ready().iter().map(|ready| ready.rec_time).min()
+    }
+
+    // Conservative machines can process anything up to this, no message earlier than it
+    // can show up anymore. Without lookahead this is just GVT, with it every machine can
+    // only send lookahead after the earliest message it still has to process, which lets
+    // the bound run ahead of GVT. Messages that have been sent but not yet received count
+    // at the time they arrive.
+    pub fn safe_bound(&self) -> Option<VirtualTime> {
+        let sends = self.machines.values().filter_map(|machine| machine.earliest_send());
+        let in_flight = self
+            .machines
+            .values()
+            .filter_map(|machine| machine.in_flight.min_rec_time());
+        let in_transit = self
+            .in_transit
+            .iter()
+            .chain(self.crashes.held())
+            .map(|message| message.rec_time);
+        sends.chain(in_flight).chain(in_transit).min()
+    }
+
+    // Processes the next message on one machine, even if another machine has an earlier
+    // one, as long as the machines policy allows it. Returns false if it didnt run.
+    pub fn step_machine(&mut self, machine_id: MachineId) -> bool {
+        self.deliver_pending();
+        self.release_wolf_calls();
+        let safe_bound = self.safe_bound();
+        match self.machines.get(&machine_id) {
+            Some(machine) if machine.can_execute(safe_bound) && !self.is_down(machine_id) => {}
+            _ => return false,
+        }
+        let next = self.machines[&machine_id].peek_next_message();
+        if next.is_some_and(|next| !self.wolf_calls.allows(machine_id, next.rec_time)) {
+            return false;
+        }
+        self.execute(machine_id);
+        true
+    }
+
+    // Processes a single message on the machine with the earliest one, returns false when
+    // there was nothing left to do
+    pub fn step(&mut self) -> bool {
+        self.deliver_pending();
+        self.release_wolf_calls();
+        // Machines that used up their rollback budget, their saved states or their optimism
+        // window wait for their work to commit, ones blocked by an antimessage for GVT to
+        // pass it
+        let next = self.next_machine().or_else(|| {
+            let waiting = |machine: &Machine<P>| {
+                machine.over_budget()
+                    || machine.stalled_on_snapshots()
+                    || machine.outside_window()
+                    || matches!(
+                        machine.next_event(),
+                        NextEvent::Blocked(BlockReason::Antimessage { .. })
+                    )
+            };
+            if !self.machines.values().any(waiting) {
+                return None;
+            }
+            self.commit();
+            self.next_machine()
+        });
+        let Some((machine_id, _)) = next else {
+            // Crashed machines waiting to restart dont have to wait for events that wont
+            // happen
+            return self.restart_next();
+        };
+        self.execute(machine_id);
+        true
+    }
+
+    fn execute(&mut self, machine_id: MachineId) {
+        let next = self.machines[&machine_id].peek_next_message();
+        if next.as_ref().is_some_and(|message| self.breaks_before(machine_id, message)) {
+            return;
+        }
+        self.end_warm_up_if_due();
+        if next.is_some_and(|message| self.crash_if_due(machine_id, message.rec_time)) {
+            self.deliver_pending();
+            return;
+        }
+        self.count_down_restarts();
+        let machine = self.machines.get_mut(&machine_id).unwrap();
+        let wall_start = self.recorder.as_ref().map(Recorder::now);
+        let message = machine.peek_next_message();
+        let sent = machine.recieve_inner();
+        if let (Some(recorder), Some(wall_start), Some(message)) =
+            (&mut self.recorder, wall_start, message)
+        {
+            recorder.record_execution(machine_id, &message, wall_start, &sent);
+        }
+        self.scheduler.executed(machine_id);
+        self.in_transit.extend(sent);
+        self.deliver_pending();
+        self.events_since_metrics += 1;
+        if self.metrics.is_some() && self.events_since_metrics >= METRICS_INTERVAL {
+            self.publish_metrics();
+        }
+        // A throttled machine only gets back to running optimistically once something
+        // it processed commits, so keep commits up to date instead of waiting for the
+        // end of the run. Everyone commits together so the sinks still see the events
+        // in order.
+        if self.machines[&machine_id].storm_detector().is_throttled() {
+            self.commit();
+        }
+        self.check_gvt();
+    }
+
+    // Runs until there is nothing left or the simulation is paused
+    pub fn run(&mut self) {
+        while !self.is_paused() && self.step() {}
+        self.commit();
+    }
+
+    // Like run but leaves any messages after end_time unprocessed
+    pub fn run_until(&mut self, end_time: impl Into<VirtualTime>) {
+        let end_time = end_time.into();
+        while !self.is_paused() && self.step_until(end_time) {}
+        self.commit();
+    }
+
+    // Like step but only picks from the events up to end_time, false if there are none
+    pub fn step_until(&mut self, end_time: impl Into<VirtualTime>) -> bool {
+        self.deliver_pending();
+        let end_time = end_time.into();
+        self.horizon = Some(end_time);
+        let stepped = self.next_machine().is_some() && self.step();
+        self.horizon = None;
+        stepped
+    }
+
+    // Lets every machine count what is below GVT as committed and hands the newly
+    // committed events to the sinks. GVT only moves forward so each commit only has
+    // events later than the ones before it.
+    pub fn commit(&mut self) {
+        let gvt = self.gvt();
+        let mut committed = Vec::new();
+        for (machine_id, machine) in self.machines.iter_mut() {
+            let messages = machine.commit(gvt);
+            committed.extend(messages.into_iter().map(|message| CommittedEvent {
+                machine_id: *machine_id,
+                message,
+            }));
+        }
+        committed.sort_by_key(|event| (event.message.rec_time, event.machine_id));
+        for event in &committed {
+            self.event_horizon.record(event.machine_id, &event.message);
+        }
+        self.check_committed(&committed);
+        #[cfg(feature = "vector-clocks")]
+        self.audit_committed(&committed);
+        for event in &committed {
+            for sink in self.sinks.iter_mut() {
+                sink.on_commit(event);
+            }
+        }
+        for sink in self.sinks.iter_mut() {
+            sink.on_gvt(gvt);
+        }
+        self.publish_metrics();
+        self.check_gvt();
+    }
+
+    pub fn stats(&self) -> SimulationStats {
+        let mut stats = SimulationStats::from_machines(
+            self.machines
+                .iter()
+                .map(|(machine_id, machine)| (*machine_id, machine.stats().clone()))
+                .collect(),
+        );
+        stats.horizon = self.event_horizon.stats();
+        stats
+    }
+
+    // Global virtual time, nothing earlier than this can ever be rolled back. It is the
+    // smallest time any machine could still be asked to process, either a message waiting
+    // in an input queue, one that hasnt been delivered yet, or an unacknowledged antimessage.
+    // Antimessages in an input queue dont count, see Machine::earliest_pending.
+    pub fn gvt(&self) -> Option<VirtualTime> {
+        let queued = self
+            .machines
+            .values()
+            .filter_map(|machine| machine.earliest_pending());
+        let in_flight = self
+            .machines
+            .values()
+            .filter_map(|machine| machine.in_flight.min_rec_time());
+        let in_transit = self
+            .in_transit
+            .iter()
+            .chain(self.crashes.held())
+            .map(|message| message.rec_time);
+        queued.chain(in_flight).chain(in_transit).min()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::budget::RollbackLimits;
+    use crate::machine::{ExecutionPolicy, SnapshotLimits};
+    use crate::process::Context;
+    use crate::time::message::Delay;
+    use serde::{Deserialize, Serialize};
+    use std::sync::Arc;
+
+    // Forwards every message to the next machine in a ring until the hop count runs out,
+    // the state is how many messages the machine has handled
+    #[derive(Clone, Serialize, Deserialize)]
+    pub(crate) struct Ring {
+        pub(crate) machines: usize,
+    }
+
+    impl TimeWarpProcess for Ring {
+        type State = usize;
+
+        fn on_message(&self, state: &mut usize, message: &Message, ctx: &mut Context) {
+            *state += 1;
+            let hops_left: usize = message.message.parse().unwrap();
+            if hops_left > 0 {
+                let next = (ctx.machine_id() + 1) % self.machines;
+                ctx.send(next, 3, (hops_left - 1).to_string());
+            }
+        }
+
+        fn lookahead(&self) -> Delay {
+            Delay::new(3)
+        }
+    }
+
+    pub(crate) fn ring(machines: usize) -> Simulation<Ring> {
+        let mut simulation = Simulation::new();
+        for id in 0..machines {
+            simulation.add_machine(Machine::with_process(id, 0, Ring { machines }));
+        }
+        simulation
+    }
+
+    // Machine 0 ticks every time unit and sends machine 1 a message far in the future
+    // every tick, until the ticks run out
+    struct Flood;
+
+    impl TimeWarpProcess for Flood {
+        type State = usize;
+
+        fn on_message(&self, state: &mut usize, message: &Message, ctx: &mut Context) {
+            *state += 1;
+            let ticks_left: usize = message.message.parse().unwrap();
+            if ctx.machine_id() == 0 && ticks_left > 0 {
+                ctx.send(0, 1, (ticks_left - 1).to_string());
+                ctx.send(1, 100, "0".to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn test_flooded_machines_hold_back_their_senders() {
+        let run = |max_pending: Option<usize>| {
+            let mut simulation = Simulation::new();
+            for id in 0..2 {
+                let mut machine = Machine::with_process(id, 0, Flood);
+                machine.set_max_pending(max_pending);
+                simulation.add_machine(machine);
+            }
+            simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("30".to_string())));
+            let mut most_pending = 0;
+            while simulation.step() {
+                let consumer = simulation.machine(1).unwrap();
+                most_pending = most_pending.max(consumer.input_queue.pending().count());
+            }
+            simulation.commit();
+            (simulation.machine(1).unwrap().state, most_pending)
+        };
+        assert_eq!(run(None), (30, 30));
+        // Never more than one past the mark, the send that flooded it
+        assert_eq!(run(Some(5)), (30, 6));
+    }
+
+    #[test]
+    fn test_rollback_budget_bounds_uncommitted_work() {
+        let start = || {
+            vec![
+                Message::new(0, 1, 0, 0, Sign::Message, Arc::new("6".to_string())),
+                Message::new(0, 2, 1, 1, Sign::Message, Arc::new("5".to_string())),
+            ]
+        };
+        let mut expected = ring(2);
+        for message in start() {
+            expected.inject(message);
+        }
+        expected.run();
+
+        let mut simulation = ring(2);
+        for machine in simulation.machines.values_mut() {
+            machine.set_rollback_limits(RollbackLimits {
+                max_depth: Some(1),
+                ..RollbackLimits::default()
+            });
+        }
+        for message in start() {
+            simulation.inject(message);
+        }
+        while simulation.step() {
+            assert!(simulation.machines().all(|machine| machine.uncommitted() <= 1));
+        }
+        for machine in simulation.machines() {
+            assert_eq!(machine.state, expected.machine(machine.machine_id()).unwrap().state);
+        }
+    }
+
+    #[test]
+    fn test_full_machines_wait_for_commits() {
+        let mut simulation = ring(3);
+        for machine in simulation.machines.values_mut() {
+            machine.set_snapshot_limits(SnapshotLimits {
+                max_saved_states: Some(2),
+                coast_forward: false,
+            });
+        }
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("8".to_string())));
+        while simulation.step() {
+            assert!(simulation.machines().all(|machine| machine.memory_report().saved_states <= 2));
+        }
+        let handled: Vec<_> = simulation.machines().map(|machine| machine.state).collect();
+        assert_eq!(handled, vec![3, 3, 3]);
+    }
+
+    #[test]
+    fn test_ring_runs_to_completion() {
+        let mut simulation = ring(3);
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("5".to_string())));
+        simulation.run();
+
+        let handled: Vec<_> = simulation.machines().map(|machine| machine.state).collect();
+        assert_eq!(handled, vec![2, 2, 2]);
+        assert_eq!(simulation.machine(2).unwrap().local_virtual_time(), 16);
+        assert_eq!(simulation.gvt(), None);
+    }
+
+    #[test]
+    fn test_antimessages_for_unknown_machines_dont_hold_up_gvt() {
+        // Machine 1 sends to machine 2, which isnt there
+        let mut simulation = Simulation::new();
+        for id in 0..2 {
+            simulation.add_machine(Machine::with_process(id, 0, Ring { machines: 3 }));
+        }
+        simulation.inject(Message::new(0, 10, 1, 1, Sign::Message, Arc::new("1".to_string())));
+        while simulation.step() {}
+        // The straggler rolls it back, the antimessage to machine 2 is dropped like the send
+        simulation.inject(Message::new(0, 5, 1, 1, Sign::Message, Arc::new("0".to_string())));
+        simulation.run();
+
+        assert_eq!(simulation.gvt(), None);
+        let machine = simulation.machine(1).unwrap();
+        assert!(machine.in_flight.is_empty());
+        assert_eq!(machine.stats().events_committed, 2);
+    }
+
+    #[test]
+    fn test_orphaned_antimessage_doesnt_hold_up_the_run() {
+        // The positive message of this one is never coming, machine 0 is blocked behind it
+        let mut simulation = ring(2);
+        let orphan = Message::new(0, 2, 1, 0, Sign::Antimessage, Arc::new("0".to_string()));
+        simulation.inject(orphan);
+        simulation.inject(Message::new(0, 5, 1, 0, Sign::Message, Arc::new("1".to_string())));
+        simulation.deliver_pending();
+        assert!(matches!(simulation.blocked()[..], [(0, BlockReason::Antimessage { .. }), _]));
+        assert_eq!(simulation.gvt(), Some(VirtualTime::new(5)));
+
+        simulation.run();
+        assert_eq!(simulation.machine(0).unwrap().state, 1);
+        assert_eq!(simulation.stats().total.antimessages_discarded, 1);
+        assert!(simulation.blocked().iter().all(|(_, reason)| *reason == BlockReason::Empty));
+    }
+
+    #[test]
+    fn test_run_until_and_gvt() {
+        let mut simulation = ring(2);
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("10".to_string())));
+        simulation.run_until(7);
+
+        // Events at 1, 4 and 7 ran, the next one at 10 is waiting
+        assert_eq!(simulation.gvt(), Some(VirtualTime::new(10)));
+        assert_eq!(simulation.machine(0).unwrap().state, 2);
+        assert_eq!(simulation.machine(1).unwrap().state, 1);
+    }
+
+    #[test]
+    fn test_conservative_machine_waits_for_safe_bound() {
+        let mut simulation = ring(2);
+        simulation
+            .machine_mut(1)
+            .unwrap()
+            .set_policy(ExecutionPolicy::Conservative);
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("2".to_string())));
+        simulation.inject(Message::new(0, 20, 1, 1, Sign::Message, Arc::new("0".to_string())));
+        simulation.inject(Message::new(0, 30, 0, 0, Sign::Message, Arc::new("0".to_string())));
+
+        // Machine 0 could still send machine 1 something before 20
+        assert!(!simulation.step_machine(1));
+        assert!(simulation.step_machine(0));
+        // Its message at 4 is the earliest anywhere so it is safe
+        assert!(simulation.step_machine(1));
+        assert!(!simulation.step_machine(1));
+
+        // The optimistic machine is free to run ahead
+        assert!(simulation.step_machine(0));
+        assert!(simulation.step_machine(0));
+        assert_eq!(simulation.machine(0).unwrap().local_virtual_time(), 30);
+        // Machine 1 has 20 waiting and can send again no earlier than 23
+        assert_eq!(simulation.safe_bound(), Some(VirtualTime::new(23)));
+        assert!(simulation.step_machine(1));
+
+        simulation.run();
+        assert_eq!(simulation.stats().machines[&1].rollbacks, 0);
+        assert_eq!(simulation.machine(1).unwrap().state, 2);
+    }
+
+    #[test]
+    fn test_lookahead_lets_conservative_machine_run_ahead_of_gvt() {
+        let mut simulation = ring(2);
+        simulation
+            .machine_mut(1)
+            .unwrap()
+            .set_policy(ExecutionPolicy::Conservative);
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("1".to_string())));
+        simulation.inject(Message::new(0, 3, 1, 1, Sign::Message, Arc::new("0".to_string())));
+        simulation.deliver_pending();
+
+        // Machine 0 is at 1 so nothing it sends can arrive before 4
+        assert_eq!(simulation.gvt(), Some(VirtualTime::new(1)));
+        assert_eq!(simulation.safe_bound(), Some(VirtualTime::new(4)));
+        assert!(simulation.step_machine(1));
+        assert!(simulation.step_machine(0));
+        simulation.run();
+        assert_eq!(simulation.stats().machines[&1].rollbacks, 0);
+        assert_eq!(simulation.machine(1).unwrap().state, 2);
+    }
+
+    #[test]
+    fn test_pause_stops_run_but_not_step() {
+        let mut simulation = ring(2);
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("3".to_string())));
+        let handle = simulation.pause_handle();
+        handle.pause();
+
+        simulation.run();
+        assert!(simulation.is_paused());
+        assert_eq!(simulation.stats().total.events_processed, 0);
+
+        assert!(simulation.step());
+        assert!(simulation.step_machine(1));
+        assert_eq!(simulation.machine(1).unwrap().local_virtual_time(), 4);
+
+        simulation.resume();
+        simulation.run();
+        assert_eq!(simulation.stats().total.events_processed, 4);
+        assert_eq!(simulation.gvt(), None);
+    }
+
+    #[test]
+    fn test_straggler_rolls_back_and_cancels_sends() {
+        let mut simulation = ring(2);
+        simulation.inject(Message::new(0, 3, 0, 0, Sign::Message, Arc::new("1".to_string())));
+        simulation.inject(Message::new(0, 9, 0, 0, Sign::Message, Arc::new("1".to_string())));
+        // Stepping instead of run since run commits everything once there is nothing left
+        while simulation.step() {}
+        assert_eq!(simulation.machine(0).unwrap().state, 2);
+        assert_eq!(simulation.machine(1).unwrap().state, 2);
+        assert_eq!(simulation.machine(1).unwrap().local_virtual_time(), 12);
+
+        // Machine 0 handled 3 then 9, a straggler at 5 has to undo the event at 9 but
+        // keep 3, that also unsends the message machine 1 already processed at 12
+        simulation.inject(Message::new(0, 5, 0, 0, Sign::Message, Arc::new("0".to_string())));
+        simulation.deliver_pending();
+        assert_eq!(simulation.machine(0).unwrap().state, 1);
+        assert_eq!(simulation.machine(0).unwrap().local_virtual_time(), 3);
+        assert_eq!(simulation.machine(1).unwrap().state, 1);
+        assert_eq!(simulation.machine(1).unwrap().local_virtual_time(), 6);
+
+        simulation.run();
+        assert_eq!(simulation.machine(0).unwrap().state, 3);
+        assert_eq!(simulation.machine(1).unwrap().state, 2);
+        assert_eq!(simulation.machine(1).unwrap().local_virtual_time(), 12);
+        assert!(simulation.machines().all(|machine| machine.in_flight.is_empty()));
+
+        let stats = simulation.stats();
+        let machine0 = &stats.machines[&0];
+        assert_eq!(machine0.events_processed, 4);
+        assert_eq!(machine0.events_rolled_back, 1);
+        assert_eq!(machine0.events_committed, 3);
+        assert_eq!(machine0.antimessages_sent, 1);
+        assert_eq!(machine0.rollback_depths, BTreeMap::from([(1, 1)]));
+        let machine1 = &stats.machines[&1];
+        assert_eq!(machine1.events_processed, 3);
+        assert_eq!(machine1.events_rolled_back, 1);
+        assert_eq!(machine1.events_committed, 2);
+        assert_eq!(stats.total.events_processed, 7);
+        assert_eq!(stats.total.rollbacks, 2);
+        assert!((stats.total.efficiency() - 5.0 / 7.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,474 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+use crate::machine::{ExampleProcess, Machine};
+use crate::placement::Placement;
+use crate::process::TimeWarpProcess;
+use crate::stats::SimulationStats;
+use crate::time::message::{MachineId, Message, Sign, VirtualTime};
+use crate::trace::trace_warn;
+
+// Runs the machines on a pool of worker threads that really process events at the same
+// time, unlike AsyncSimulation which only ever steps one machine. Every machine belongs
+// to a worker (round robin, or a Placement from placement.rs) and a worker keeps running
+// the earliest event of its own machines. A worker with nothing left of its own steals the
+// machine with the lowest unprocessed time from the workers that have more than one
+// machine with work, so when the load is skewed the events holding GVT back dont wait
+// behind others on a busy worker. A stolen machine stays with its new worker.
+//
+// Workers run in rounds of at most batch events each, between rounds every thread is
+// joined and GVT is computed and committed with nothing in transit like in Simulation.
+// A worker that finds nothing to run or steal parks until a delivery wakes it, machines
+// that are blocked (see Machine::is_blocked) arent looked at until one arrives for them.
+// The round ends once every worker is parked or through with its batch.
+// Conservative machines only run up to the safe bound from the start of the round, the
+// messages created during the round cant go below it.
+pub struct PoolSimulation<P: TimeWarpProcess = ExampleProcess> {
+    machines: BTreeMap<MachineId, Machine<P>>,
+    owners: BTreeMap<MachineId, usize>,
+    pending: Vec<Message>,
+    workers: usize,
+    batch: usize,
+    steals: u64,
+    gvt_rounds: u64,
+}
+
+// What a worker can see of a machine without taking its lock
+struct Slot<P: TimeWarpProcess> {
+    machine: Mutex<Machine<P>>,
+    // Ticks of the next event the machine can run, NOTHING if there is none
+    next: AtomicU64,
+    owner: AtomicUsize,
+}
+
+const NOTHING: u64 = u64::MAX;
+
+struct Round<P: TimeWarpProcess> {
+    slots: BTreeMap<MachineId, Slot<P>>,
+    safe_bound: Option<VirtualTime>,
+    // Only one worker steals at a time so two cant take the same machine
+    stealing: Mutex<()>,
+    steals: AtomicU64,
+    workers: usize,
+    // Goes up with every delivery so a worker about to park can tell it missed one
+    deliveries: AtomicU64,
+    idle: Mutex<Idle>,
+    woken: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Idle {
+    // Workers waiting for a delivery, a delivery wakes all of them
+    parked: usize,
+    // Workers through with their batch
+    finished: usize,
+    over: bool,
+}
+
+impl Idle {
+    // Nobody left who could deliver anything
+    fn end_if_all_idle(&mut self, workers: usize) -> bool {
+        self.over |= self.parked + self.finished == workers;
+        self.over
+    }
+}
+
+impl<P: TimeWarpProcess> PoolSimulation<P> {
+    pub fn new(workers: usize) -> Self {
+        Self {
+            machines: BTreeMap::new(),
+            owners: BTreeMap::new(),
+            pending: Vec::new(),
+            workers: workers.max(1),
+            batch: 64,
+            steals: 0,
+            gvt_rounds: 0,
+        }
+    }
+
+    // Events every worker runs between GVT rounds
+    pub fn set_batch(&mut self, batch: usize) {
+        self.batch = batch.max(1);
+    }
+
+    pub fn add_machine(&mut self, machine: Machine<P>) {
+        let worker = self.machines.len() % self.workers;
+        self.owners.insert(machine.machine_id(), worker);
+        self.machines.insert(machine.machine_id(), machine);
+    }
+
+    // Hands the machines to the workers the placement says, parts past the number of
+    // workers wrap around
+    pub fn set_placement(&mut self, placement: &Placement) {
+        for (machine_id, part) in placement {
+            if let Some(owner) = self.owners.get_mut(machine_id) {
+                *owner = part % self.workers;
+            }
+        }
+    }
+
+    pub fn owner(&self, machine_id: MachineId) -> Option<usize> {
+        self.owners.get(&machine_id).copied()
+    }
+
+    pub fn machine(&self, machine_id: MachineId) -> Option<&Machine<P>> {
+        self.machines.get(&machine_id)
+    }
+
+    pub fn machines(&self) -> impl Iterator<Item = &Machine<P>> {
+        self.machines.values()
+    }
+
+    pub fn into_machines(self) -> BTreeMap<MachineId, Machine<P>> {
+        self.machines
+    }
+
+    // Delivered when the next round starts
+    pub fn inject(&mut self, message: Message) {
+        self.pending.push(message);
+    }
+
+    // Machines that moved to another worker so far
+    pub fn steals(&self) -> u64 {
+        self.steals
+    }
+
+    pub fn gvt_rounds(&self) -> u64 {
+        self.gvt_rounds
+    }
+
+    pub fn stats(&self) -> SimulationStats {
+        SimulationStats::from_machines(
+            self.machines
+                .iter()
+                .map(|(machine_id, machine)| (*machine_id, machine.stats().clone()))
+                .collect(),
+        )
+    }
+
+    // Same as Simulation::gvt, between rounds nothing is in transit
+    pub fn gvt(&self) -> Option<VirtualTime> {
+        let queued = self
+            .machines
+            .values()
+            .filter_map(|machine| machine.earliest_pending());
+        let in_flight =
+            self.machines.values().filter_map(|machine| machine.in_flight.min_rec_time());
+        queued.chain(in_flight).min()
+    }
+
+    // Same as Simulation::safe_bound
+    fn safe_bound(&self) -> Option<VirtualTime> {
+        let sends = self.machines.values().filter_map(|machine| machine.earliest_send());
+        let in_flight =
+            self.machines.values().filter_map(|machine| machine.in_flight.min_rec_time());
+        sends.chain(in_flight).min()
+    }
+
+    // Runs rounds until no machine has anything left it can run
+    pub fn run(&mut self) {
+        while self.round() > 0 {}
+    }
+
+    // Runs one round on all workers and commits, returns how many events it ran
+    pub fn round(&mut self) -> usize {
+        let safe_bound = self.safe_bound();
+        let machines = std::mem::take(&mut self.machines);
+        let round = Round::new(machines, &self.owners, safe_bound, self.workers);
+        for message in std::mem::take(&mut self.pending) {
+            round.route(message);
+        }
+
+        let batch = self.batch;
+        let events: usize = thread::scope(|scope| {
+            let handles: Vec<_> = (0..self.workers)
+                .map(|worker| {
+                    let round = &round;
+                    scope.spawn(move || round.work(worker, batch))
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).sum()
+        });
+
+        self.steals += round.steals.into_inner();
+        for (machine_id, slot) in round.slots {
+            self.owners.insert(machine_id, slot.owner.into_inner());
+            self.machines.insert(machine_id, slot.machine.into_inner().unwrap());
+        }
+        self.commit();
+        events
+    }
+
+    fn commit(&mut self) {
+        let gvt = self.gvt();
+        self.gvt_rounds += 1;
+        for machine in self.machines.values_mut() {
+            machine.commit(gvt);
+        }
+    }
+}
+
+impl<P: TimeWarpProcess> Round<P> {
+    fn new(
+        machines: BTreeMap<MachineId, Machine<P>>,
+        owners: &BTreeMap<MachineId, usize>,
+        safe_bound: Option<VirtualTime>,
+        workers: usize,
+    ) -> Self {
+        let round = Round {
+            slots: machines
+                .into_iter()
+                .map(|(machine_id, machine)| {
+                    let slot = Slot {
+                        machine: Mutex::new(machine),
+                        next: AtomicU64::new(NOTHING),
+                        owner: AtomicUsize::new(owners[&machine_id]),
+                    };
+                    (machine_id, slot)
+                })
+                .collect(),
+            safe_bound,
+            stealing: Mutex::new(()),
+            steals: AtomicU64::new(0),
+            workers,
+            deliveries: AtomicU64::new(0),
+            idle: Mutex::new(Idle::default()),
+            woken: Condvar::new(),
+        };
+        for slot in round.slots.values() {
+            round.update(slot, &slot.machine.lock().unwrap());
+        }
+        round
+    }
+
+    fn work(&self, worker: usize, batch: usize) -> usize {
+        let mut events = 0;
+        while events < batch {
+            let deliveries = self.deliveries.load(Ordering::SeqCst);
+            match self.next_own(worker).or_else(|| self.steal(worker)) {
+                Some(machine_id) => {
+                    if self.execute(worker, machine_id) {
+                        events += 1;
+                    }
+                }
+                None if self.park(deliveries) => {}
+                None => return events,
+            }
+        }
+        let mut idle = self.idle.lock().unwrap();
+        idle.finished += 1;
+        if idle.end_if_all_idle(self.workers) {
+            self.woken.notify_all();
+        }
+        events
+    }
+
+    // Waits for a delivery after the one counted in deliveries, false if the round ended
+    // instead. The last worker to go idle ends it.
+    fn park(&self, deliveries: u64) -> bool {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.over {
+            return false;
+        }
+        if self.deliveries.load(Ordering::SeqCst) != deliveries {
+            return true;
+        }
+        idle.parked += 1;
+        if idle.end_if_all_idle(self.workers) {
+            self.woken.notify_all();
+            return false;
+        }
+        loop {
+            idle = self.woken.wait(idle).unwrap();
+            if idle.over {
+                return false;
+            }
+            // wake took us off the parked count
+            if self.deliveries.load(Ordering::SeqCst) != deliveries {
+                return true;
+            }
+        }
+    }
+
+    fn wake(&self) {
+        self.deliveries.fetch_add(1, Ordering::SeqCst);
+        let mut idle = self.idle.lock().unwrap();
+        if idle.parked > 0 {
+            idle.parked = 0;
+            self.woken.notify_all();
+        }
+    }
+
+    fn next_own(&self, worker: usize) -> Option<MachineId> {
+        self.slots
+            .iter()
+            .filter(|(_, slot)| slot.owner.load(Ordering::Acquire) == worker)
+            .map(|(machine_id, slot)| (slot.next.load(Ordering::Acquire), *machine_id))
+            .filter(|(next, _)| *next != NOTHING)
+            .min()
+            .map(|(_, machine_id)| machine_id)
+    }
+
+    // Takes the machine with the earliest event from the workers that have at least two
+    // machines with work, taking the only one a worker has would just move the idling
+    fn steal(&self, worker: usize) -> Option<MachineId> {
+        let _stealing = self.stealing.lock().unwrap();
+        let candidates: Vec<_> = self
+            .slots
+            .iter()
+            .map(|(machine_id, slot)| {
+                let owner = slot.owner.load(Ordering::Acquire);
+                (slot.next.load(Ordering::Acquire), *machine_id, owner)
+            })
+            .filter(|(next, _, owner)| *next != NOTHING && *owner != worker)
+            .collect();
+        let mut ready: BTreeMap<usize, usize> = BTreeMap::new();
+        for (_, _, owner) in &candidates {
+            *ready.entry(*owner).or_insert(0) += 1;
+        }
+        let (_, machine_id, _) =
+            candidates.into_iter().filter(|(_, _, owner)| ready[owner] >= 2).min()?;
+        self.slots[&machine_id].owner.store(worker, Ordering::Release);
+        self.steals.fetch_add(1, Ordering::Relaxed);
+        Some(machine_id)
+    }
+
+    // Runs the next event of a machine the worker owns, false if someone else got it
+    fn execute(&self, worker: usize, machine_id: MachineId) -> bool {
+        let slot = &self.slots[&machine_id];
+        let mut machine = slot.machine.lock().unwrap();
+        if slot.owner.load(Ordering::Acquire) != worker || !self.can_execute(&machine) {
+            return false;
+        }
+        let sent = machine.recieve_inner();
+        self.update(slot, &machine);
+        drop(machine);
+        for message in sent {
+            self.route(message);
+        }
+        true
+    }
+
+    // Delivers a message and everything the rollbacks it causes send, one lock at a time
+    fn route(&self, message: Message) {
+        let mut pending = VecDeque::from([message]);
+        while let Some(message) = pending.pop_front() {
+            let Some(slot) = self.slots.get(&message.receiver) else {
+                trace_warn!(
+                    receiver = message.receiver,
+                    message_id = message.id,
+                    "Dropping message for unknown machine"
+                );
+                // Nobody else will, and GVT cant pass it while its in flight
+                if let (Sign::Antimessage, Some(sender)) =
+                    (&message.sign, self.slots.get(&message.sender))
+                {
+                    sender.machine.lock().unwrap().acknowledge_antimessage(&message);
+                }
+                continue;
+            };
+            let antimessage = (message.sign == Sign::Antimessage).then(|| message.clone());
+            let sender = message.sender;
+            let mut machine = slot.machine.lock().unwrap();
+            pending.extend(machine.recieve_outer(message).unwrap_or_default());
+            self.update(slot, &machine);
+            drop(machine);
+            self.wake();
+            if let (Some(antimessage), Some(sender)) = (antimessage, self.slots.get(&sender)) {
+                sender.machine.lock().unwrap().acknowledge_antimessage(&antimessage);
+            }
+        }
+    }
+
+    fn can_execute(&self, machine: &Machine<P>) -> bool {
+        machine.can_execute(self.safe_bound)
+    }
+
+    // A blocked machine stays parked until route delivers something to it
+    fn update(&self, slot: &Slot<P>, machine: &Machine<P>) {
+        let next = match !machine.is_blocked() && self.can_execute(machine) {
+            true => machine.peek_next_message().map_or(NOTHING, |message| message.rec_time.ticks()),
+            false => NOTHING,
+        };
+        slot.next.store(next, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use std::sync::Arc;
+
+    fn start() -> [Message; 2] {
+        [
+            Message::new(0, 1, 0, 0, Sign::Message, Arc::new("12".to_string())),
+            Message::new(0, 2, 2, 2, Sign::Message, Arc::new("9".to_string())),
+        ]
+    }
+
+    #[test]
+    fn test_pool_matches_single_thread_run() {
+        let mut expected = ring(4);
+        for message in start() {
+            expected.inject(message);
+        }
+        expected.run();
+
+        let mut simulation = PoolSimulation::new(3);
+        simulation.set_batch(2);
+        for machine in ring(4).into_machines().into_values() {
+            simulation.add_machine(machine);
+        }
+        // Everything on worker 0 so the other two only get work by stealing
+        simulation.set_placement(&(0..4).map(|machine_id| (machine_id, 0)).collect());
+        for message in start() {
+            simulation.inject(message);
+        }
+        simulation.run();
+
+        assert_eq!(simulation.gvt(), None);
+        assert!(simulation.gvt_rounds() > 1);
+        for machine in simulation.machines() {
+            let reference = expected.machine(machine.machine_id()).unwrap();
+            assert_eq!(machine.state, reference.state);
+            assert_eq!(machine.stats().events_committed, reference.stats().events_committed);
+        }
+    }
+
+    #[test]
+    fn test_idle_workers_park_until_work_arrives() {
+        // The token goes back and forth between the workers, the one without it has to
+        // wait for it instead of giving up on the round
+        let mut simulation = PoolSimulation::new(2);
+        for machine in ring(2).into_machines().into_values() {
+            simulation.add_machine(machine);
+        }
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("5".to_string())));
+        assert_eq!(simulation.round(), 6);
+        assert_eq!(simulation.round(), 0);
+        assert_eq!(simulation.machine(1).unwrap().state, 3);
+    }
+
+    #[test]
+    fn test_idle_workers_steal_the_earliest_machine() {
+        // Two workers with every machine on the first
+        let owners = (0..3).map(|machine_id| (machine_id, 0)).collect();
+        let round = Round::new(ring(3).into_machines(), &owners, None, 2);
+
+        // Worker 0 has a single machine with work, nothing to take from it
+        round.route(Message::new(0, 4, 2, 2, Sign::Message, Arc::new("0".to_string())));
+        assert_eq!(round.steal(1), None);
+
+        round.route(Message::new(0, 6, 0, 0, Sign::Message, Arc::new("0".to_string())));
+        round.route(Message::new(0, 5, 1, 1, Sign::Message, Arc::new("0".to_string())));
+        assert_eq!(round.steal(1), Some(2));
+        assert_eq!(round.next_own(1), Some(2));
+        assert_eq!(round.next_own(0), Some(1));
+        assert!(!round.execute(0, 2));
+        assert!(round.execute(1, 2));
+        assert_eq!(round.steals.load(Ordering::Relaxed), 1);
+    }
+}
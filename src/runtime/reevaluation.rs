@@ -0,0 +1,99 @@
+use super::Simulation;
+use crate::process::TimeWarpProcess;
+use crate::time::message::Message;
+
+// Lazy re-evaluation, see Machine::set_lazy_reevaluation. A machine that jumped over a
+// straggler hands back what the straggler sent where the antimessages of a rollback
+// would be, here that is told apart by the jumps in its stats. It didnt roll back, so
+// it goes to the recorder as an execution and not a rollback.
+impl<P: TimeWarpProcess> Simulation<P> {
+    // For the machines already added and the ones added later
+    pub fn set_lazy_reevaluation(&mut self, lazy: bool) {
+        self.lazy_reevaluation = lazy;
+        for machine in self.machines.values_mut() {
+            machine.set_lazy_reevaluation(lazy);
+        }
+    }
+
+    pub fn lazy_reevaluation(&self) -> bool {
+        self.lazy_reevaluation
+    }
+
+    pub(super) fn jumped(
+        &mut self,
+        message: &Message,
+        wall_start: Option<u64>,
+        sent: Vec<Message>,
+    ) {
+        if let (Some(recorder), Some(wall_start)) = (&mut self.recorder, wall_start) {
+            recorder.record_execution(message.receiver, message, wall_start, &sent);
+        }
+        self.in_transit.extend(sent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::machine::Machine;
+    use crate::process::{Context, TimeWarpProcess};
+    use crate::runtime::Simulation;
+    use crate::time::message::{Message, Sign};
+    use std::sync::Arc;
+
+    // Machine 0 counts its ticks and answers everything else with the count so far,
+    // machine 1 writes down the answers
+    struct Ledger;
+
+    impl TimeWarpProcess for Ledger {
+        type State = Vec<String>;
+
+        fn on_message(&self, state: &mut Vec<String>, message: &Message, ctx: &mut Context) {
+            match (ctx.machine_id(), message.message.as_str()) {
+                (0, "tick") => state.push("tick".to_string()),
+                (0, _) => ctx.send(message.sender, 1, state.len().to_string()),
+                _ => state.push(message.message.to_string()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_stragglers_that_only_read_jump_forward() {
+        let at = |rec_time: usize, payload: &str| {
+            Message::new(0, rec_time, 1, 0, Sign::Message, Arc::new(payload.into()))
+        };
+        let run = |lazy: bool| {
+            let mut simulation = Simulation::new();
+            for id in 0..2 {
+                simulation.add_machine(Machine::with_process(id, 0, Ledger));
+            }
+            simulation.set_lazy_reevaluation(lazy);
+            for rec_time in (2..=20).step_by(2) {
+                simulation.inject(at(rec_time, "tick"));
+            }
+            while simulation.step() {}
+            assert_eq!(simulation.machine(0).unwrap().local_virtual_time(), 20);
+
+            // Only reads the ticks at 2 and 4, the 8 after it would run the same again
+            simulation.inject(at(5, "ask"));
+            simulation.deliver_pending();
+            let lvt = simulation.machine(0).unwrap().local_virtual_time();
+            while simulation.step() {}
+            // A tick changes the count, that has to roll back even when lazy
+            simulation.inject(at(9, "tick"));
+            simulation.run();
+            let states: Vec<_> =
+                simulation.machines().map(|machine| machine.state.clone()).collect();
+            (lvt, states, simulation.stats().total)
+        };
+        let (lvt, states, rolled_back) = run(false);
+        let (lazy_lvt, lazy_states, lazy) = run(true);
+        assert_eq!(lazy_states, states);
+        assert_eq!(lazy_states[1], vec!["2".to_string()]);
+        assert_eq!((lvt.ticks(), lazy_lvt.ticks()), (4, 20));
+        assert_eq!((rolled_back.rollbacks, rolled_back.events_rolled_back), (2, 14));
+        assert_eq!((lazy.rollbacks, lazy.events_rolled_back), (1, 6));
+        assert_eq!((lazy.jumps, lazy.events_jumped), (1, 8));
+        assert_eq!(rolled_back.events_processed - lazy.events_processed, 8);
+        assert_eq!(lazy.events_committed, rolled_back.events_committed);
+    }
+}
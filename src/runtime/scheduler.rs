@@ -0,0 +1,165 @@
+use super::Simulation;
+use crate::process::TimeWarpProcess;
+use crate::time::message::{Delay, MachineId, VirtualTime};
+
+// Which of the machines that can run goes next. Simulation hands the scheduler every
+// machine whose next event it is allowed to process (policy, crashes and wolf calls are
+// already taken care of) and runs the one it picks. Any order gives the same committed
+// results, rollbacks fix up whatever ran too early, but how much has to be rolled back
+// and how fast GVT moves depends a lot on it.
+pub trait Scheduler: Send {
+    // ready is never empty, the pick has to be one of them
+    fn pick(&self, ready: &[Ready]) -> MachineId;
+
+    // Called after a machine ran an event, for schedulers that keep track
+    fn executed(&mut self, _machine_id: MachineId) {}
+}
+
+// What a scheduler gets to know about a machine that can run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ready {
+    pub machine_id: MachineId,
+    // Time of the event it would run
+    pub rec_time: VirtualTime,
+    pub lvt: VirtualTime,
+    pub events_processed: u64,
+    pub events_rolled_back: u64,
+}
+
+// The earliest event anywhere first, what a sequential simulator would do and what keeps
+// rollbacks the rarest. The default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LowestTimestamp;
+
+impl Scheduler for LowestTimestamp {
+    fn pick(&self, ready: &[Ready]) -> MachineId {
+        ready.iter().min_by_key(|ready| (ready.rec_time, ready.machine_id)).unwrap().machine_id
+    }
+}
+
+// The machine whose clock is furthest behind first, so no machine gets much ahead of the
+// others even if its next event is early
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LowestLvt;
+
+impl Scheduler for LowestLvt {
+    fn pick(&self, ready: &[Ready]) -> MachineId {
+        let key = |ready: &&Ready| (ready.lvt, ready.rec_time, ready.machine_id);
+        ready.iter().min_by_key(key).unwrap().machine_id
+    }
+}
+
+// Every machine in turn by id whatever its times, the worst case for rollbacks and handy
+// for shaking out bugs that only show with a different order
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RoundRobin {
+    last: Option<MachineId>,
+}
+
+impl Scheduler for RoundRobin {
+    fn pick(&self, ready: &[Ready]) -> MachineId {
+        let after_last = ready
+            .iter()
+            .filter(|ready| self.last.is_none_or(|last| ready.machine_id > last))
+            .min_by_key(|ready| ready.machine_id);
+        after_last.or_else(|| ready.iter().min_by_key(|ready| ready.machine_id)).unwrap().machine_id
+    }
+
+    fn executed(&mut self, machine_id: MachineId) {
+        self.last = Some(machine_id);
+    }
+}
+
+// Lowest timestamp, but machines that threw away a lot of their work count as later than
+// they are, by penalty times the part of their events that got rolled back. A machine
+// that keeps running ahead into rollbacks waits a bit for the others that way.
+#[derive(Debug, Clone, Copy)]
+pub struct RollbackPenalized {
+    pub penalty: Delay,
+}
+
+impl Default for RollbackPenalized {
+    fn default() -> Self {
+        Self {
+            penalty: Delay::new(10),
+        }
+    }
+}
+
+impl Scheduler for RollbackPenalized {
+    fn pick(&self, ready: &[Ready]) -> MachineId {
+        let key = |ready: &&Ready| {
+            let wasted = ready.events_rolled_back as f64 / ready.events_processed.max(1) as f64;
+            let delay = Delay::new((self.penalty.ticks() as f64 * wasted) as u64);
+            (ready.rec_time.saturating_add(delay), ready.machine_id)
+        };
+        ready.iter().min_by_key(key).unwrap().machine_id
+    }
+}
+
+impl<P: TimeWarpProcess> Simulation<P> {
+    pub fn set_scheduler(&mut self, scheduler: impl Scheduler + 'static) {
+        self.scheduler = Box::new(scheduler);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use crate::time::message::{Message, Sign};
+    use std::sync::Arc;
+
+    fn ready(machine_id: MachineId, rec_time: usize, lvt: usize) -> Ready {
+        Ready {
+            machine_id,
+            rec_time: rec_time.into(),
+            lvt: lvt.into(),
+            events_processed: 0,
+            events_rolled_back: 0,
+        }
+    }
+
+    #[test]
+    fn test_schedulers_pick_by_their_rule() {
+        let mut machines = [ready(0, 9, 1), ready(1, 4, 3), ready(2, 6, 2)];
+        assert_eq!(LowestTimestamp.pick(&machines), 1);
+        assert_eq!(LowestLvt.pick(&machines), 0);
+
+        let mut round_robin = RoundRobin::default();
+        let mut order = Vec::new();
+        for _ in 0..4 {
+            let machine_id = round_robin.pick(&machines);
+            round_robin.executed(machine_id);
+            order.push(machine_id);
+        }
+        assert_eq!(order, [0, 1, 2, 0]);
+
+        // Half of what machine 1 did got undone, that puts it 5 later
+        machines[1].events_processed = 10;
+        machines[1].events_rolled_back = 5;
+        assert_eq!(RollbackPenalized::default().pick(&machines), 2);
+    }
+
+    #[test]
+    fn test_any_scheduler_commits_the_same_results() {
+        let run = |mut simulation: Simulation<_>| {
+            simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("8".to_string())));
+            simulation.inject(Message::new(0, 2, 1, 1, Sign::Message, Arc::new("5".to_string())));
+            simulation.run();
+            simulation
+        };
+        let expected = run(ring(3));
+        let mut others = [ring(3), ring(3), ring(3)];
+        others[0].set_scheduler(LowestLvt);
+        others[1].set_scheduler(RoundRobin::default());
+        others[2].set_scheduler(RollbackPenalized::default());
+        for simulation in others.map(run) {
+            for machine in simulation.machines() {
+                let reference = expected.machine(machine.machine_id()).unwrap();
+                assert_eq!(machine.state, reference.state);
+                assert_eq!(machine.stats().events_committed, reference.stats().events_committed);
+            }
+        }
+    }
+}
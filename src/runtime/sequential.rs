@@ -0,0 +1,261 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::determinism::{RunDigest, StateDigests};
+use crate::latency::Latencies;
+use crate::machine::{ExampleProcess, Machine};
+use crate::process::{Context, TimeWarpProcess};
+use crate::time::message::{Delay, MachineId, Message, MessageId, Sign, VirtualTime};
+use crate::trace::trace_warn;
+
+// Receive time, generation, receiver, sender and id
+type EventKey = (VirtualTime, u32, MachineId, MachineId, MessageId);
+
+// The plain discrete event simulation every other executor has to agree with. One event
+// list for the whole model, always running the globally earliest event, so there is
+// nothing to roll back and no queues, saved states or antimessages involved at all, just
+// the processes and their states. Slow, but what it comes up with is the ground truth a
+// Time Warp run of the same processes can be checked against.
+//
+// Events at the same time run by generation (see Message::generation), then by receiver
+// and among the ones for one machine in the order its input queue would have them,
+// which is the order a Time Warp machine processes them in as well.
+// Antimessages are ignored since nothing is ever sent speculatively, and so are fifo
+// input queues.
+pub struct SequentialSimulation<P: TimeWarpProcess = ExampleProcess> {
+    machines: BTreeMap<MachineId, Sequential<P>>,
+    events: BTreeMap<EventKey, Message>,
+    now: VirtualTime,
+    events_processed: u64,
+    digests: Option<StateDigests>,
+}
+
+struct Sequential<P: TimeWarpProcess> {
+    process: P,
+    state: P::State,
+    latencies: Option<Arc<Latencies>>,
+}
+
+impl<P: TimeWarpProcess> Default for SequentialSimulation<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: TimeWarpProcess> SequentialSimulation<P> {
+    pub fn new() -> Self {
+        Self {
+            machines: BTreeMap::new(),
+            events: BTreeMap::new(),
+            now: VirtualTime::ZERO,
+            events_processed: 0,
+            digests: None,
+        }
+    }
+
+    pub fn add_process(&mut self, machine_id: MachineId, process: P, state: P::State) {
+        self.machines.insert(
+            machine_id,
+            Sequential {
+                process,
+                state,
+                latencies: None,
+            },
+        );
+    }
+
+    // Takes the process, current state and latency models of a machine built for one of
+    // the other executors
+    pub fn add_machine(&mut self, machine: Machine<P>) {
+        let machine_id = machine.machine_id();
+        let latencies = machine.latencies().cloned();
+        let (process, state) = machine.into_process();
+        self.machines.insert(
+            machine_id,
+            Sequential {
+                process,
+                state,
+                latencies,
+            },
+        );
+    }
+
+    pub fn state(&self, machine_id: MachineId) -> Option<&P::State> {
+        self.machines.get(&machine_id).map(|machine| &machine.state)
+    }
+
+    pub fn states(&self) -> impl Iterator<Item = (MachineId, &P::State)> {
+        self.machines
+            .iter()
+            .map(|(machine_id, machine)| (*machine_id, &machine.state))
+    }
+
+    // Time of the last event that ran
+    pub fn now(&self) -> VirtualTime {
+        self.now
+    }
+
+    pub fn events_processed(&self) -> u64 {
+        self.events_processed
+    }
+
+    // Same as Simulation::record_digests, every event is final as soon as it runs
+    pub fn record_digests(&mut self, epoch_length: impl Into<Delay>) {
+        self.digests = Some(StateDigests::new(epoch_length));
+    }
+
+    pub fn take_digests(&mut self) -> Option<StateDigests> {
+        self.digests.take()
+    }
+
+    pub fn run_digest(&self) -> Option<RunDigest> {
+        self.digests.as_ref().map(StateDigests::run_digest)
+    }
+
+    // Messages in the past of the event list would break the ordering, they are dropped
+    pub fn inject(&mut self, message: Message) {
+        if message.sign == Sign::Antimessage {
+            return;
+        }
+        if message.rec_time < self.now {
+            trace_warn!(
+                receiver = message.receiver,
+                rec_time = %message.rec_time,
+                now = %self.now,
+                "Dropping message earlier than the sequential simulations clock"
+            );
+            return;
+        }
+        let key = (
+            message.rec_time,
+            message.generation,
+            message.receiver,
+            message.sender,
+            message.id,
+        );
+        self.events.insert(key, message);
+    }
+
+    pub fn next_time(&self) -> Option<VirtualTime> {
+        self.events.keys().next().map(|(rec_time, ..)| *rec_time)
+    }
+
+    // Runs the earliest event, false when there are none left
+    pub fn step(&mut self) -> bool {
+        let Some((_, message)) = self.events.pop_first() else {
+            return false;
+        };
+        self.now = message.rec_time;
+        let Some(machine) = self.machines.get_mut(&message.receiver) else {
+            trace_warn!(
+                receiver = message.receiver,
+                message_id = message.id,
+                "Dropping message for unknown machine"
+            );
+            return true;
+        };
+        let mut ctx = Context::new(message.receiver, message.rec_time)
+            .with_latencies(machine.latencies.clone())
+            .with_event(&message);
+        machine.process.on_message(&mut machine.state, &message, &mut ctx);
+        self.events_processed += 1;
+        if let Some(digests) = self.digests.as_mut() {
+            digests.record(message.receiver, message.rec_time, &machine.state);
+        }
+        for sent in ctx.into_outbox() {
+            self.inject(sent);
+        }
+        true
+    }
+
+    pub fn run(&mut self) {
+        while self.step() {}
+    }
+
+    pub fn run_until(&mut self, end_time: impl Into<VirtualTime>) {
+        let end_time = end_time.into();
+        while self.next_time().is_some_and(|next| next <= end_time) {
+            self.step();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+
+    #[test]
+    fn test_time_warp_agrees_with_the_sequential_run() {
+        let start = || {
+            vec![
+                Message::new(0, 5, 0, 0, Sign::Message, Arc::new("4".to_string())),
+                Message::new(0, 3, 2, 2, Sign::Message, Arc::new("6".to_string())),
+                Message::new(0, 1, 1, 1, Sign::Message, Arc::new("3".to_string())),
+            ]
+        };
+        let mut sequential = SequentialSimulation::new();
+        for machine in ring(3).into_machines().into_values() {
+            sequential.add_machine(machine);
+        }
+        let mut optimistic = ring(3);
+        for message in start() {
+            sequential.inject(message);
+        }
+        for message in start().into_iter().rev() {
+            optimistic.inject(message);
+        }
+        sequential.run();
+        optimistic.run();
+
+        assert_eq!(sequential.events_processed(), 16);
+        for (machine_id, state) in sequential.states() {
+            assert_eq!(*state, optimistic.machine(machine_id).unwrap().state);
+        }
+    }
+
+    // Logs every message and counts the payload down, sending itself the rest with no
+    // delay
+    struct Countdown;
+
+    impl TimeWarpProcess for Countdown {
+        type State = Vec<String>;
+
+        fn on_message(&self, log: &mut Vec<String>, message: &Message, ctx: &mut Context) {
+            log.push(format!("{}:{}", message.sender, message.message));
+            let left: usize = message.message.parse().unwrap();
+            if left > 0 {
+                ctx.send(ctx.machine_id(), 0, (left - 1).to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn test_same_time_events_run_in_the_same_order_everywhere() {
+        let countdown = Message::new(0, 3, 9, 0, Sign::Message, Arc::new("2".to_string()));
+        let late = Message::new(0, 3, 5, 0, Sign::Message, Arc::new("0".to_string()));
+
+        let mut sequential = SequentialSimulation::new();
+        sequential.add_process(0, Countdown, Vec::new());
+        sequential.inject(countdown.clone());
+        sequential.inject(late.clone());
+        sequential.run();
+        let expected = ["5:0", "9:2", "0:1", "0:0"].map(String::from);
+        assert_eq!(sequential.state(0).unwrap(), &expected);
+
+        // The countdown is over before the other message shows up, that goes first at
+        // time 3 so everything at 3 is done again
+        let mut optimistic = crate::runtime::Simulation::new();
+        optimistic.add_machine(Machine::with_process(0, 0, Countdown));
+        optimistic.inject(countdown);
+        while optimistic.step() {}
+        let machine = optimistic.machine(0).unwrap();
+        assert_eq!(machine.state, expected[1..]);
+        assert_eq!(machine.local_virtual_time(), 3);
+        optimistic.inject(late);
+        optimistic.run();
+        let machine = optimistic.machine(0).unwrap();
+        assert_eq!(machine.state, expected);
+        assert_eq!(machine.stats().rollbacks, 1);
+    }
+}
@@ -0,0 +1,85 @@
+use super::Simulation;
+use crate::process::TimeWarpProcess;
+use crate::stats::EventHorizon;
+use crate::time::message::VirtualTime;
+use crate::trace::trace_debug;
+
+// Throwing away the start of a run when measuring. Most models take a while to get to
+// their steady state (queues filling up, the first wave of events spreading out), and
+// counting that in skews the numbers. Once GVT reaches the warm up time everything up to
+// it is committed, every machines stats start over from zero and the hooks run so models
+// can reset whatever they measure themselves, like the counters behind a sink.
+//
+// The reset happens between events, at the first one that runs with GVT at or past the
+// warm up time. Anything a machine already ran speculatively past it was counted as
+// processed before the reset and isnt counted again.
+#[derive(Default)]
+pub(super) struct WarmUp {
+    at: Option<VirtualTime>,
+    done: bool,
+    hooks: Vec<Box<dyn FnMut()>>,
+}
+
+impl<P: TimeWarpProcess> Simulation<P> {
+    pub fn set_warm_up(&mut self, at: impl Into<VirtualTime>) {
+        self.warm_up.at = Some(at.into());
+        self.warm_up.done = false;
+    }
+
+    // Runs once the warm up is over
+    pub fn on_warm_up(&mut self, hook: impl FnMut() + 'static) {
+        self.warm_up.hooks.push(Box::new(hook));
+    }
+
+    pub fn is_warmed_up(&self) -> bool {
+        self.warm_up.at.is_none() || self.warm_up.done
+    }
+
+    // Called before every event
+    pub(super) fn end_warm_up_if_due(&mut self) {
+        let Some(at) = self.warm_up.at else {
+            return;
+        };
+        if self.warm_up.done || self.gvt().is_some_and(|gvt| gvt < at) {
+            return;
+        }
+        self.warm_up.done = true;
+        trace_debug!(%at, "Warm up is over, resetting statistics");
+        for machine in self.machines.values_mut() {
+            machine.commit(Some(at));
+            machine.reset_stats();
+        }
+        self.event_horizon = EventHorizon::default();
+        for hook in self.warm_up.hooks.iter_mut() {
+            hook();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::tests::ring;
+    use crate::time::message::{Message, Sign};
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_stats_only_count_after_the_warm_up() {
+        let mut simulation = ring(2);
+        simulation.set_warm_up(8);
+        let resets = Rc::new(Cell::new(0));
+        let counted = resets.clone();
+        simulation.on_warm_up(move || counted.set(counted.get() + 1));
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("5".to_string())));
+        simulation.run();
+
+        // Events at 1, 4 and 7 were the warm up, 10, 13 and 16 are what counts
+        assert!(simulation.is_warmed_up());
+        assert_eq!(resets.get(), 1);
+        let stats = simulation.stats();
+        assert_eq!(stats.total.events_processed, 3);
+        assert_eq!(stats.total.events_committed, 3);
+        assert_eq!(simulation.machine(0).unwrap().state, 3);
+    }
+}
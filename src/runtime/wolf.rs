@@ -0,0 +1,97 @@
+use super::Simulation;
+use crate::process::TimeWarpProcess;
+use crate::time::message::{MachineId, VirtualTime};
+use crate::trace::trace_debug;
+
+// Wolf calls, an early warning for deep rollbacks. A machine rolled back by at least
+// min_depth events is likely to cancel a lot of what it sent, and everything the other
+// machines do past the straggler on top of those sends is going to be undone once the
+// antimessages land. So instead of waiting for them the machine warns everyone right
+// away (it cant know who its messages ended up reaching), and until GVT has moved past
+// the time of the straggler the others only process events up to it. The machine that
+// called keeps going since it is the one that has to redo the work.
+#[derive(Debug, Default)]
+pub(super) struct WolfCalls {
+    min_depth: Option<usize>,
+    // Straggler times still being waited out and who called them
+    active: Vec<(VirtualTime, MachineId)>,
+    calls: u64,
+}
+
+impl WolfCalls {
+    pub(super) fn record_rollback(&mut self, machine_id: MachineId, at: VirtualTime, depth: usize) {
+        if self.min_depth.is_none_or(|min_depth| depth < min_depth) {
+            return;
+        }
+        trace_debug!(machine_id, %at, depth, "Wolf call");
+        self.calls += 1;
+        self.active.push((at, machine_id));
+    }
+
+    // Whether a machine may process an event at rec_time with the calls still active
+    pub(super) fn allows(&self, machine_id: MachineId, rec_time: VirtualTime) -> bool {
+        self.active
+            .iter()
+            .all(|(at, caller)| *caller == machine_id || rec_time <= *at)
+    }
+
+    fn release(&mut self, gvt: Option<VirtualTime>) {
+        self.active.retain(|(at, _)| gvt.is_some_and(|gvt| gvt <= *at));
+    }
+}
+
+impl<P: TimeWarpProcess> Simulation<P> {
+    // Turns wolf calls on for rollbacks of at least min_depth events
+    pub fn set_wolf_calls(&mut self, min_depth: usize) {
+        self.wolf_calls.min_depth = Some(min_depth.max(1));
+    }
+
+    // How many machines have called so far
+    pub fn wolf_calls(&self) -> u64 {
+        self.wolf_calls.calls
+    }
+
+    // Forgets about the calls GVT has moved past
+    pub(super) fn release_wolf_calls(&mut self) {
+        if !self.wolf_calls.active.is_empty() {
+            let gvt = self.gvt();
+            self.wolf_calls.release(gvt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::tests::ring;
+    use crate::time::message::{Message, Sign};
+    use std::sync::Arc;
+
+    fn at(rec_time: usize, machine_id: usize) -> Message {
+        Message::new(0, rec_time, machine_id, machine_id, Sign::Message, Arc::new("0".to_string()))
+    }
+
+    #[test]
+    fn test_deep_rollback_holds_the_others_back() {
+        let mut simulation = ring(3);
+        simulation.set_wolf_calls(2);
+        for rec_time in [10, 20, 30] {
+            simulation.inject(at(rec_time, 0));
+        }
+        simulation.inject(at(50, 1));
+        for _ in 0..3 {
+            assert!(simulation.step_machine(0));
+        }
+
+        // Machine 0 ran ahead and a straggler undoes all three of its events
+        simulation.inject(at(5, 0));
+        simulation.deliver_pending();
+        assert_eq!(simulation.wolf_calls(), 1);
+        assert!(!simulation.step_machine(1));
+
+        // Once machine 0 has caught up again GVT is past the straggler
+        simulation.run();
+        assert_eq!(simulation.machine(0).unwrap().state, 4);
+        assert_eq!(simulation.machine(1).unwrap().state, 1);
+        assert_eq!(simulation.stats().total.rollbacks, 1);
+    }
+}
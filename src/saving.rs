@@ -0,0 +1,268 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+
+// How often a machine saves its state. Saving before every event (what a machine without
+// a SavePolicy does) keeps rollbacks cheap since the state they go back to is right
+// there, but copies the state for every event even when hardly anything rolls back.
+// Saving every n-th event instead means a rollback restores the saved state before its
+// target and coasts forward over the events in between (see Machine::roll_back). So the
+// rarer and deeper the rollbacks and the bigger the state, the further apart the saves
+// should be. The policy picks the interval from what the machine has seen so far, it is
+// asked again after every save and every rollback.
+//
+// A state can only be saved before the first event at a time (rollbacks always go back
+// to the start of a time), so a save that is due at a time with more events waits for
+// the next one.
+pub trait SavePolicy: Send + Sync {
+    // Events between saved states, 1 saves before every event. 0 is taken as 1.
+    fn interval(&self, observed: &Observed) -> usize;
+}
+
+// What a machine has seen, for its SavePolicy. The averages move with every rollback,
+// the last one weighs SMOOTHING of the lot.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Observed {
+    // Events undone per rollback
+    pub rollback_depth: f64,
+    // Events processed between two rollbacks, None until the first rollback
+    pub events_between_rollbacks: Option<f64>,
+    // Size of the last saved state, see TimeWarpProcess::state_size
+    pub state_bytes: usize,
+    // What the policy picked last
+    pub interval: usize,
+}
+
+pub const SMOOTHING: f64 = 0.125;
+
+fn smooth(average: f64, sample: f64) -> f64 {
+    average + (sample - average) * SMOOTHING
+}
+
+// The same interval whatever happens
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Periodic {
+    pub interval: usize,
+}
+
+impl SavePolicy for Periodic {
+    fn interval(&self, _observed: &Observed) -> usize {
+        self.interval
+    }
+}
+
+// Picks the interval with the smallest cost per event. Saving every n events copies a
+// state once per n events and a rollback coasts over (n - 1) / 2 events on average, so
+// with events_between events per rollback the cost is smallest at
+// n = sqrt(2 * state_bytes / event_bytes * events_between). event_bytes is what running
+// an event costs, in the bytes of state that could be copied in the same time. That is
+// then capped at the rollback depth, coasting further than the rollbacks go back costs
+// more than the rollbacks themselves. A machine that never rolled back saves every
+// max_interval events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Adaptive {
+    pub max_interval: usize,
+    pub event_bytes: usize,
+}
+
+impl Default for Adaptive {
+    fn default() -> Self {
+        Self {
+            max_interval: 32,
+            event_bytes: 64,
+        }
+    }
+}
+
+impl SavePolicy for Adaptive {
+    fn interval(&self, observed: &Observed) -> usize {
+        let max_interval = self.max_interval.max(1);
+        let Some(events_between) = observed.events_between_rollbacks else {
+            return max_interval;
+        };
+        let state = observed.state_bytes as f64 / self.event_bytes.max(1) as f64;
+        let optimal = (2.0 * state * events_between).sqrt().min(observed.rollback_depth);
+        (optimal as usize).clamp(1, max_interval)
+    }
+}
+
+// The save policy of a machine in a config, for example in toml
+//
+//   saving = { policy = "adaptive", max_interval = 16 }
+//
+// "every" (the default) saves before every event, "periodic" every interval events and
+// "adaptive" picks the interval itself, see Adaptive.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "policy", rename_all = "lowercase")]
+pub enum SavingConfig {
+    #[default]
+    Every,
+    Periodic { interval: usize },
+    Adaptive(Adaptive),
+}
+
+impl SavingConfig {
+    pub fn policy(&self) -> Option<Arc<dyn SavePolicy>> {
+        match *self {
+            SavingConfig::Every => None,
+            SavingConfig::Periodic { interval } => Some(Arc::new(Periodic { interval })),
+            SavingConfig::Adaptive(adaptive) => Some(Arc::new(adaptive)),
+        }
+    }
+}
+
+// Kept by the machine, counts the events since the last save and keeps Observed up to
+// date. The policy isnt part of a checkpoint, set it again after restoring one.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct StateSaving {
+    observed: Observed,
+    since_save: usize,
+    since_rollback: u64,
+    #[serde(skip)]
+    policy: Option<Arc<dyn SavePolicy>>,
+}
+
+impl fmt::Debug for StateSaving {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StateSaving")
+            .field("observed", &self.observed)
+            .field("since_save", &self.since_save)
+            .field("since_rollback", &self.since_rollback)
+            .field("policy", &self.policy.is_some())
+            .finish()
+    }
+}
+
+impl StateSaving {
+    pub fn new(policy: Option<Arc<dyn SavePolicy>>) -> Self {
+        let mut saving = Self {
+            policy,
+            ..Self::default()
+        };
+        saving.reconsider();
+        saving
+    }
+
+    pub fn policy(&self) -> Option<&Arc<dyn SavePolicy>> {
+        self.policy.as_ref()
+    }
+
+    pub fn observed(&self) -> &Observed {
+        &self.observed
+    }
+
+    pub fn interval(&self) -> usize {
+        self.observed.interval.max(1)
+    }
+
+    // Counts an event about to run, true if the state before it should be saved
+    pub fn next_event(&mut self) -> bool {
+        self.since_save = self.since_save.saturating_add(1);
+        self.since_rollback += 1;
+        self.since_save >= self.interval()
+    }
+
+    // state_bytes is only needed with a policy, see wants_state_size
+    pub fn saved(&mut self, state_bytes: Option<usize>) {
+        self.since_save = 0;
+        if let Some(state_bytes) = state_bytes {
+            self.observed.state_bytes = state_bytes;
+        }
+        self.reconsider();
+    }
+
+    pub fn wants_state_size(&self) -> bool {
+        self.policy.is_some()
+    }
+
+    // The state rolled back to is saved on the next event whatever the interval, so the
+    // next rollback to about there doesnt have to coast all over again
+    pub fn rolled_back(&mut self, depth: usize) {
+        let observed = &mut self.observed;
+        let between = self.since_rollback as f64;
+        match observed.events_between_rollbacks {
+            Some(average) => {
+                observed.events_between_rollbacks = Some(smooth(average, between));
+                observed.rollback_depth = smooth(observed.rollback_depth, depth as f64);
+            }
+            None => {
+                observed.events_between_rollbacks = Some(between);
+                observed.rollback_depth = depth as f64;
+            }
+        }
+        self.since_rollback = 0;
+        self.since_save = usize::MAX;
+        self.reconsider();
+    }
+
+    fn reconsider(&mut self) {
+        self.observed.interval = self
+            .policy
+            .as_ref()
+            .map_or(1, |policy| policy.interval(&self.observed).max(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::runtime::tests::Ring;
+    use crate::time::message::{Message, Sign};
+
+    fn observed(depth: f64, between: Option<f64>, state_bytes: usize) -> Observed {
+        Observed {
+            rollback_depth: depth,
+            events_between_rollbacks: between,
+            state_bytes,
+            interval: 1,
+        }
+    }
+
+    #[test]
+    fn test_adaptive_saving_follows_the_rollbacks() {
+        let adaptive = Adaptive::default();
+        assert_eq!(adaptive.interval(&observed(0.0, None, 64)), 32);
+        // Shallow and frequent, save often
+        assert_eq!(adaptive.interval(&observed(1.5, Some(4.0), 64)), 1);
+        // Rare and deep, a big state is only saved now and then
+        assert_eq!(adaptive.interval(&observed(50.0, Some(200.0), 640)), 32);
+        assert_eq!(adaptive.interval(&observed(50.0, Some(200.0), 64)), 20);
+        // Never more than the rollbacks go back
+        assert_eq!(adaptive.interval(&observed(6.0, Some(200.0), 640)), 6);
+        let config: SavingConfig = serde_json::from_str(r#"{"policy": "adaptive"}"#).unwrap();
+        assert_eq!(config, SavingConfig::Adaptive(Adaptive::default()));
+    }
+
+    #[test]
+    fn test_sparse_states_coast_forward_on_rollbacks() {
+        let at = |rec_time: usize| {
+            Message::new(0, rec_time, 0, 0, Sign::Message, Arc::new("0".to_string()))
+        };
+        let mut machine = Machine::builder(0, Ring { machines: 1 })
+            .save_policy(Arc::new(Periodic { interval: 4 }))
+            .build();
+        for rec_time in (1..=10).filter(|rec_time| *rec_time != 5) {
+            machine.recieve_outer(at(rec_time));
+        }
+        while machine.peek_next_message().is_some() {
+            machine.recieve_inner();
+        }
+        assert_eq!(machine.state, 9);
+        // Before the events at 4 and 9, and the one from the start
+        assert_eq!(machine.stats().states_saved, 2);
+        assert_eq!(machine.memory_report().saved_states, 3);
+
+        // The state from before 4 coasts over 4 to get to before 5
+        machine.recieve_outer(at(5));
+        assert_eq!(machine.state, 4);
+        assert_eq!(machine.local_virtual_time(), 4);
+        assert_eq!(machine.saving().observed().rollback_depth, 5.0);
+        while machine.peek_next_message().is_some() {
+            machine.recieve_inner();
+        }
+        assert_eq!(machine.state, 10);
+        assert_eq!(machine.state_at(6), Some(6));
+    }
+}
@@ -0,0 +1,395 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::machine::{BlockReason, ExampleProcess, Machine, NextEvent};
+use crate::process::TimeWarpProcess;
+use crate::recorder::TraceEvent;
+use crate::runtime::Simulation;
+use crate::time::message::{MachineId, Message, MessagePayload, Sign, VirtualTime};
+
+// Small scripted runs that say what should happen along the way, instead of driving the
+// machines by hand and printing the queues to look at:
+//
+//   Scenario::new("straggler")
+//       .machine(Machine::new(1, 0))
+//       .receives(1, 5, "late")
+//       .step()
+//       .receives(1, 3, "early")
+//       .deliver()
+//       .expect_rollback(1, 0)
+//       .run_to_end()
+//       .expect_lvt(1, 5)
+//       .check()?;
+//
+// Steps and expectations run in the order they were added against one Simulation, and
+// check stops at the first expectation that doesnt hold and says which one it was. A
+// rollback expectation looks at the rollbacks the machine did since the last one that
+// was expected (the simulation records everything for that, see recorder.rs), states
+// are compared through their Debug output since that is all TimeWarpProcess asks of them.
+pub struct Scenario<P: TimeWarpProcess = ExampleProcess> {
+    name: String,
+    machines: Vec<Machine<P>>,
+    steps: Vec<Action<P>>,
+}
+
+enum Action<P: TimeWarpProcess> {
+    Inject(Message),
+    Deliver,
+    Step(usize),
+    RunToEnd,
+    Expect(Expectation<P>),
+}
+
+enum Expectation<P: TimeWarpProcess> {
+    Lvt(MachineId, VirtualTime),
+    Rollback(MachineId, VirtualTime),
+    NoRollback(MachineId),
+    Queued(MachineId, usize),
+    Processed(MachineId, usize),
+    AntimessageFirst(MachineId),
+    State(MachineId, P::State),
+}
+
+impl<P: TimeWarpProcess> fmt::Display for Expectation<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expectation::Lvt(machine_id, at) => write!(f, "machine {} at {}", machine_id, at),
+            Expectation::Rollback(machine_id, to) => {
+                write!(f, "machine {} rolling back to {}", machine_id, to)
+            }
+            Expectation::NoRollback(machine_id) => {
+                write!(f, "machine {} not rolling back", machine_id)
+            }
+            Expectation::Queued(machine_id, messages) => {
+                write!(f, "{} messages in the input queue of machine {}", messages, machine_id)
+            }
+            Expectation::Processed(machine_id, messages) => {
+                write!(f, "machine {} having processed {} messages", machine_id, messages)
+            }
+            Expectation::AntimessageFirst(machine_id) => {
+                write!(f, "machine {} waiting on an antimessage", machine_id)
+            }
+            Expectation::State(machine_id, state) => {
+                write!(f, "machine {} in state {:?}", machine_id, state)
+            }
+        }
+    }
+}
+
+// The expectation that didnt hold, steps count from 1 in the order they were added
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScenarioFailure {
+    pub scenario: String,
+    pub step: usize,
+    pub expected: String,
+    pub found: String,
+}
+
+impl fmt::Display for ScenarioFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "scenario {}, step {}: expected {}, found {}",
+            self.scenario, self.step, self.expected, self.found
+        )
+    }
+}
+
+impl std::error::Error for ScenarioFailure {}
+
+impl<P: TimeWarpProcess> Scenario<P> {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            machines: Vec::new(),
+            steps: Vec::new(),
+        }
+    }
+
+    pub fn machine(mut self, machine: Machine<P>) -> Self {
+        self.machines.push(machine);
+        self
+    }
+
+    // The machine gets a message at that time, sent by itself at 0
+    pub fn receives(
+        self,
+        machine_id: MachineId,
+        at: impl Into<VirtualTime>,
+        payload: impl Into<MessagePayload>,
+    ) -> Self {
+        let payload = Arc::new(payload.into());
+        self.inject(Message::new(0, at, machine_id, machine_id, Sign::Message, payload))
+    }
+
+    // Any message, antimessages and all. It is only in transit until the next step or
+    // deliver
+    pub fn inject(self, message: Message) -> Self {
+        self.then(Action::Inject(message))
+    }
+
+    // Hands everything in transit to the machines, which is where rollbacks for
+    // stragglers happen
+    pub fn deliver(self) -> Self {
+        self.then(Action::Deliver)
+    }
+
+    // Processes one message, see Simulation::step
+    pub fn step(self) -> Self {
+        self.steps(1)
+    }
+
+    pub fn steps(self, steps: usize) -> Self {
+        self.then(Action::Step(steps))
+    }
+
+    // Runs until there is nothing left to do and commits, see Simulation::run
+    pub fn run_to_end(self) -> Self {
+        self.then(Action::RunToEnd)
+    }
+
+    pub fn expect_lvt(self, machine_id: MachineId, at: impl Into<VirtualTime>) -> Self {
+        self.then(Action::Expect(Expectation::Lvt(machine_id, at.into())))
+    }
+
+    // Rolled back since the last rollback expected of it, to the local virtual time given.
+    // That is the time of the last event the rollback kept, not of the straggler.
+    pub fn expect_rollback(self, machine_id: MachineId, to: impl Into<VirtualTime>) -> Self {
+        self.then(Action::Expect(Expectation::Rollback(machine_id, to.into())))
+    }
+
+    pub fn expect_no_rollback(self, machine_id: MachineId) -> Self {
+        self.then(Action::Expect(Expectation::NoRollback(machine_id)))
+    }
+
+    // Everything in the input queue, processed or not, antimessages included
+    pub fn expect_queued(self, machine_id: MachineId, messages: usize) -> Self {
+        self.then(Action::Expect(Expectation::Queued(machine_id, messages)))
+    }
+
+    // Of the messages still in the input queue, how many are processed
+    pub fn expect_processed(self, machine_id: MachineId, messages: usize) -> Self {
+        self.then(Action::Expect(Expectation::Processed(machine_id, messages)))
+    }
+
+    // The next message is an antimessage, so the machine waits for its positive one
+    pub fn expect_antimessage_first(self, machine_id: MachineId) -> Self {
+        self.then(Action::Expect(Expectation::AntimessageFirst(machine_id)))
+    }
+
+    pub fn expect_state(self, machine_id: MachineId, state: P::State) -> Self {
+        self.then(Action::Expect(Expectation::State(machine_id, state)))
+    }
+
+    fn then(mut self, step: Action<P>) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    // Runs the scenario and hands back the simulation as it ended up, for looking at
+    // whatever the expectations dont cover
+    pub fn check(self) -> Result<Simulation<P>, ScenarioFailure> {
+        let mut simulation = Simulation::new();
+        for machine in self.machines {
+            simulation.add_machine(machine);
+        }
+        simulation.start_recording();
+        // Where in the trace each machine's next rollback expectation starts looking
+        let mut seen = BTreeMap::new();
+        for (index, step) in self.steps.into_iter().enumerate() {
+            let expectation = match step {
+                Action::Inject(message) => {
+                    simulation.inject(message);
+                    continue;
+                }
+                Action::Deliver => {
+                    simulation.deliver_pending();
+                    continue;
+                }
+                Action::Step(steps) => {
+                    for _ in 0..steps {
+                        simulation.step();
+                    }
+                    continue;
+                }
+                Action::RunToEnd => {
+                    simulation.run();
+                    continue;
+                }
+                Action::Expect(expectation) => expectation,
+            };
+            let Some(machine) = simulation.machine(expectation.machine_id()) else {
+                return Err(ScenarioFailure {
+                    scenario: self.name,
+                    step: index + 1,
+                    expected: expectation.to_string(),
+                    found: "no such machine".to_string(),
+                });
+            };
+            let machine_id = machine.machine_id();
+            let events = simulation.trace().map_or(&[][..], |trace| &trace.events[..]);
+            let from = seen.get(&machine_id).copied().unwrap_or(0);
+            let rollbacks: Vec<_> = events[from..]
+                .iter()
+                .enumerate()
+                .filter_map(|(offset, event)| match event {
+                    TraceEvent::Rollback { machine, to, .. } if *machine == machine_id => {
+                        Some((from + offset, *to))
+                    }
+                    _ => None,
+                })
+                .collect();
+            let found = match &expectation {
+                Expectation::Lvt(_, at) => {
+                    let lvt = machine.local_virtual_time();
+                    (lvt != *at).then(|| format!("it at {}", lvt))
+                }
+                Expectation::Rollback(_, to) => {
+                    match rollbacks.iter().find(|(_, rolled_back_to)| rolled_back_to == to) {
+                        Some((position, _)) => {
+                            seen.insert(machine_id, position + 1);
+                            None
+                        }
+                        None if rollbacks.is_empty() => Some("no rollback".to_string()),
+                        None => {
+                            let times: Vec<_> =
+                                rollbacks.iter().map(|(_, to)| to.to_string()).collect();
+                            Some(format!("rollbacks to {}", times.join(", ")))
+                        }
+                    }
+                }
+                Expectation::NoRollback(_) => {
+                    rollbacks.last().map(|(_, to)| format!("a rollback to {}", to))
+                }
+                Expectation::Queued(_, messages) => {
+                    let queued = machine.input_queue.len();
+                    (queued != *messages).then(|| format!("{}", queued))
+                }
+                Expectation::Processed(_, messages) => {
+                    let processed = machine.input_queue.count_processed(VirtualTime::ZERO, None);
+                    (processed != *messages).then(|| format!("{}", processed))
+                }
+                Expectation::AntimessageFirst(_) => match machine.next_event() {
+                    NextEvent::Blocked(BlockReason::Antimessage { .. }) => None,
+                    NextEvent::Blocked(BlockReason::Empty) => Some("nothing queued".to_string()),
+                    NextEvent::Ready(next) => Some(format!("message {} next", next.id)),
+                },
+                Expectation::State(_, state) => {
+                    let (expected, actual) =
+                        (format!("{:?}", state), format!("{:?}", machine.state));
+                    (expected != actual).then_some(actual)
+                }
+            };
+            if let Some(found) = found {
+                return Err(ScenarioFailure {
+                    scenario: self.name,
+                    step: index + 1,
+                    expected: expectation.to_string(),
+                    found,
+                });
+            }
+        }
+        Ok(simulation)
+    }
+}
+
+impl<P: TimeWarpProcess> Expectation<P> {
+    fn machine_id(&self) -> MachineId {
+        match self {
+            Expectation::Lvt(machine_id, _)
+            | Expectation::Rollback(machine_id, _)
+            | Expectation::NoRollback(machine_id)
+            | Expectation::Queued(machine_id, _)
+            | Expectation::Processed(machine_id, _)
+            | Expectation::AntimessageFirst(machine_id)
+            | Expectation::State(machine_id, _) => *machine_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+
+    #[test]
+    fn test_scenarios_check_what_happens_along_the_way() {
+        let mut machines = ring(2).into_machines();
+        let straggler = Scenario::new("extended rollback")
+            .machine(machines.remove(&1).unwrap())
+            .receives(1, 2, "0")
+            .receives(1, 5, "0")
+            .receives(1, 6, "0")
+            .receives(1, 7, "0")
+            .steps(4)
+            .expect_processed(1, 4)
+            .receives(1, 4, "0")
+            .deliver()
+            .expect_rollback(1, 2)
+            .expect_processed(1, 1)
+            .steps(4)
+            .receives(1, 3, "0")
+            .deliver()
+            .expect_rollback(1, 2)
+            .run_to_end()
+            .expect_no_rollback(1)
+            .expect_lvt(1, 7)
+            .expect_state(1, 6);
+        straggler.check().unwrap();
+
+        // Machine 0 forwards to 1, which handles it before the straggler rolls 0 back and
+        // the antimessage rolls back 1
+        let message = |rec_time, hops: &str| {
+            Message::new(0, rec_time, 0, 0, Sign::Message, Arc::new(hops.to_string()))
+        };
+        let mut ring = ring(2).into_machines().into_values();
+        let double = Scenario::new("double rollback")
+            .machine(ring.next().unwrap())
+            .machine(ring.next().unwrap())
+            .inject(message(5, "1"))
+            .steps(2)
+            .expect_lvt(1, 8)
+            .inject(message(2, "0"))
+            .deliver()
+            .expect_rollback(0, 0)
+            .expect_rollback(1, 0)
+            .expect_queued(1, 0)
+            .run_to_end()
+            .expect_lvt(1, 8)
+            .expect_state(0, 2)
+            .expect_state(1, 1);
+        double.check().unwrap();
+
+        let early = message(3, "");
+        let mut antimessage = early.clone();
+        antimessage.sign = Sign::Antimessage;
+        Scenario::new("antimessage first")
+            .machine(Machine::new(0, 0))
+            .inject(antimessage)
+            .deliver()
+            .expect_antimessage_first(0)
+            .expect_queued(0, 1)
+            .inject(early)
+            .deliver()
+            .expect_queued(0, 0)
+            .expect_lvt(0, 0)
+            .check()
+            .unwrap();
+
+        let in_order = Scenario::new("in order")
+            .machine(Machine::new(0, 0))
+            .receives(0, 3, "")
+            .receives(0, 5, "")
+            .run_to_end()
+            .expect_rollback(0, 3)
+            .check();
+        let Err(failure) = in_order else {
+            panic!("a rollback that didnt happen was found");
+        };
+        assert_eq!(
+            failure.to_string(),
+            "scenario in order, step 4: expected machine 0 rolling back to 3, found no rollback"
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::time::message::{MachineId, VirtualTime};
+
+// State shared by several machines, for models where passing messages around would be
+// awkward, like a scoreboard everyone reads. Like Jeffersons state variables the value is
+// kept as versions by the virtual time they were written at, so a read at some time sees
+// what was written before it whatever order the machines actually ran in. Reads and
+// writes go through Context::read and Context::write.
+//
+// Every read is remembered with the version it saw. A write at a time before a read that
+// saw an older version makes the read stale, and so does rolling back the write a read
+// saw. The machine that made a stale read has to roll back to it, the runtime does that
+// when it delivers messages (see Simulation::deliver_pending), so only Simulation keeps
+// shared variables consistent. A read sees the writes at earlier times and the writes the
+// reading machine made at the same time. Writes by different machines at the same time
+// count in machine id order, a read sees the ones by lower ids too and a write makes the
+// reads of higher ids at its time stale.
+//
+// Everything a machine touched is told about its rollbacks and commits. Versions and
+// reads below what is committed are merged away, so the memory used is about what is
+// still speculative.
+pub struct SharedVar<T> {
+    versions: Arc<Mutex<Versions<T>>>,
+}
+
+impl<T> Clone for SharedVar<T> {
+    fn clone(&self) -> Self {
+        Self {
+            versions: self.versions.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for SharedVar<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.versions.lock().unwrap().fmt(f)
+    }
+}
+
+// Written at this time by this machine
+type Version = (VirtualTime, MachineId);
+
+#[derive(Debug)]
+struct Read {
+    machine_id: MachineId,
+    rec_time: VirtualTime,
+    // None for the committed value
+    saw: Option<Version>,
+}
+
+struct Versions<T> {
+    // Everything committed so far merged together
+    committed: T,
+    writes: BTreeMap<Version, T>,
+    reads: Vec<Read>,
+    // The earliest stale read of every machine that has one
+    stale: HashMap<MachineId, VirtualTime>,
+}
+
+impl<T> fmt::Debug for Versions<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedVar")
+            .field("writes", &self.writes.keys())
+            .field("reads", &self.reads)
+            .field("stale", &self.stale)
+            .finish()
+    }
+}
+
+impl<T> Versions<T> {
+    fn visible(&self, machine_id: MachineId, rec_time: VirtualTime) -> Option<Version> {
+        self.writes.range(..=(rec_time, machine_id)).next_back().map(|(version, _)| *version)
+    }
+
+    fn mark_stale(&mut self, machine_id: MachineId, rec_time: VirtualTime) {
+        let earliest = self.stale.entry(machine_id).or_insert(rec_time);
+        *earliest = (*earliest).min(rec_time);
+    }
+}
+
+impl<T: Clone + Send + 'static> SharedVar<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            versions: Arc::new(Mutex::new(Versions {
+                committed: value,
+                writes: BTreeMap::new(),
+                reads: Vec::new(),
+                stale: HashMap::new(),
+            })),
+        }
+    }
+
+    // The newest value written, speculative or not, for looking at it from outside
+    pub fn latest(&self) -> T {
+        let versions = self.versions.lock().unwrap();
+        versions.writes.values().next_back().unwrap_or(&versions.committed).clone()
+    }
+
+    pub(crate) fn read(&self, machine_id: MachineId, rec_time: VirtualTime, record: bool) -> T {
+        let mut versions = self.versions.lock().unwrap();
+        let saw = versions.visible(machine_id, rec_time);
+        if record {
+            versions.reads.push(Read {
+                machine_id,
+                rec_time,
+                saw,
+            });
+        }
+        match saw {
+            Some(version) => versions.writes[&version].clone(),
+            None => versions.committed.clone(),
+        }
+    }
+
+    pub(crate) fn write(&self, machine_id: MachineId, rec_time: VirtualTime, value: T) {
+        let mut versions = self.versions.lock().unwrap();
+        let version = (rec_time, machine_id);
+        versions.writes.insert(version, value);
+        // Later reads that saw something older, or this version before it was written over.
+        // Reads at the same time count as later for higher machine ids, the reads the
+        // writer made itself came before the write.
+        let stale: Vec<_> = versions
+            .reads
+            .iter()
+            .filter(|read| (read.rec_time, read.machine_id) > version && read.saw <= Some(version))
+            .map(|read| (read.machine_id, read.rec_time))
+            .collect();
+        for (reader, read_at) in stale {
+            versions.mark_stale(reader, read_at);
+        }
+    }
+
+    pub(crate) fn handle(&self) -> Arc<dyn Versioned> {
+        self.versions.clone()
+    }
+}
+
+// What a machine needs to tell the shared variables it touched, whatever their type
+pub(crate) trait Versioned: Send + Sync + fmt::Debug {
+    // Takes back the writes and reads of the machine at or after from
+    fn roll_back(&self, machine_id: MachineId, from: VirtualTime);
+
+    // Nothing before horizon can change anymore
+    fn commit(&self, horizon: VirtualTime);
+
+    // Time of the earliest read of the machine that turned out stale
+    fn stale_since(&self, machine_id: MachineId) -> Option<VirtualTime>;
+}
+
+impl<T: Clone + Send + 'static> Versioned for Mutex<Versions<T>> {
+    fn roll_back(&self, machine_id: MachineId, from: VirtualTime) {
+        let mut versions = self.lock().unwrap();
+        let undone: Vec<_> = versions
+            .writes
+            .range((from, 0)..)
+            .map(|(version, _)| *version)
+            .filter(|(_, writer)| *writer == machine_id)
+            .collect();
+        for version in &undone {
+            versions.writes.remove(version);
+        }
+        versions
+            .reads
+            .retain(|read| read.machine_id != machine_id || read.rec_time < from);
+        if versions.stale.get(&machine_id).is_some_and(|stale| *stale >= from) {
+            versions.stale.remove(&machine_id);
+        }
+        let stale: Vec<_> = versions
+            .reads
+            .iter()
+            .filter(|read| read.saw.is_some_and(|saw| undone.contains(&saw)))
+            .map(|read| (read.machine_id, read.rec_time))
+            .collect();
+        for (reader, read_at) in stale {
+            versions.mark_stale(reader, read_at);
+        }
+    }
+
+    fn commit(&self, horizon: VirtualTime) {
+        let mut versions = self.lock().unwrap();
+        let speculative = versions.writes.split_off(&(horizon, 0));
+        let committed = std::mem::replace(&mut versions.writes, speculative);
+        if let Some((_, value)) = committed.into_iter().next_back() {
+            versions.committed = value;
+        }
+        versions.reads.retain(|read| read.rec_time >= horizon);
+        // What a read saw before the merge is the committed value now
+        for read in versions.reads.iter_mut() {
+            if read.saw.is_some_and(|(written, _)| written < horizon) {
+                read.saw = None;
+            }
+        }
+    }
+
+    fn stale_since(&self, machine_id: MachineId) -> Option<VirtualTime> {
+        self.lock().unwrap().stale.get(&machine_id).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::process::{Context, TimeWarpProcess};
+    use crate::runtime::Simulation;
+    use crate::time::message::{Message, Sign};
+
+    // "add" puts one on the scoreboard, anything else looks at it and keeps what it saw
+    struct Scorer {
+        board: SharedVar<u64>,
+    }
+
+    impl TimeWarpProcess for Scorer {
+        type State = u64;
+
+        fn on_message(&self, state: &mut u64, message: &Message, ctx: &mut Context) {
+            let score = ctx.read(&self.board);
+            if message.message.as_str() == "add" {
+                ctx.write(&self.board, score + 1);
+            } else {
+                *state = score;
+            }
+        }
+    }
+
+    fn message(receiver: MachineId, rec_time: usize, payload: &str) -> Message {
+        Message::new(0, rec_time, receiver, receiver, Sign::Message, Arc::new(payload.into()))
+    }
+
+    #[test]
+    fn test_stale_reads_are_rolled_back() {
+        let board = SharedVar::new(0);
+        let mut simulation = Simulation::new();
+        for machine_id in [0, 1] {
+            let process = Scorer {
+                board: board.clone(),
+            };
+            simulation.add_machine(Machine::with_process(machine_id, 0, process));
+        }
+        simulation.inject(message(0, 5, "look"));
+        simulation.inject(message(1, 3, "add"));
+        simulation.inject(message(1, 7, "add"));
+
+        // Machine 0 looks at 5 before the add at 3 has run, then has to look again
+        assert!(simulation.step_machine(0));
+        assert_eq!(simulation.machine(0).unwrap().state, 0);
+        simulation.run();
+        assert_eq!(simulation.machine(0).unwrap().state, 1);
+        assert_eq!(simulation.machine(0).unwrap().stats().rollbacks, 1);
+        assert_eq!(board.latest(), 2);
+
+        // All of it committed at the end, nothing speculative is left to keep
+        let versions = board.versions.lock().unwrap();
+        assert!(versions.writes.is_empty() && versions.reads.is_empty());
+    }
+
+    #[test]
+    fn test_undone_write_makes_its_readers_stale() {
+        let board = SharedVar::new(10);
+        let at = VirtualTime::new;
+        board.write(1, at(3), 11);
+        assert_eq!(board.read(0, at(5), true), 11);
+        assert_eq!(board.read(1, at(3), true), 11);
+        assert_eq!(board.read(2, at(3), true), 11);
+
+        board.handle().roll_back(1, at(3));
+        assert_eq!(board.handle().stale_since(0), Some(VirtualTime::new(5)));
+        assert_eq!(board.handle().stale_since(1), None);
+        assert_eq!(board.handle().stale_since(2), Some(VirtualTime::new(3)));
+        assert_eq!(board.read(0, at(5), false), 10);
+    }
+
+    #[test]
+    fn test_same_time_writes_count_in_machine_id_order() {
+        let board = SharedVar::new(0);
+        let at = VirtualTime::new;
+        assert_eq!(board.read(0, at(5), true), 0);
+        assert_eq!(board.read(2, at(5), true), 0);
+        board.write(1, at(5), 1);
+        assert_eq!(board.read(0, at(5), false), 0);
+        assert_eq!(board.read(1, at(5), false), 1);
+        assert_eq!(board.read(2, at(5), false), 1);
+        assert_eq!(board.handle().stale_since(0), None);
+        assert_eq!(board.handle().stale_since(1), None);
+        assert_eq!(board.handle().stale_since(2), Some(at(5)));
+
+        // Machine 1 adds at 5 after machine 2 looked at 5, machine 2 has to look again
+        let board = SharedVar::new(0);
+        let mut simulation = Simulation::new();
+        for machine_id in [1, 2] {
+            let process = Scorer {
+                board: board.clone(),
+            };
+            simulation.add_machine(Machine::with_process(machine_id, 0, process));
+        }
+        simulation.inject(message(2, 5, "look"));
+        simulation.inject(message(1, 5, "add"));
+        assert!(simulation.step_machine(2));
+        assert_eq!(simulation.machine(2).unwrap().state, 0);
+        simulation.run();
+        assert_eq!(simulation.machine(2).unwrap().state, 1);
+        assert_eq!(simulation.machine(2).unwrap().stats().rollbacks, 1);
+    }
+}
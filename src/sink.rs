@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::time::message::{MachineId, Message, MessageId, VirtualTime};
+
+// Output that is safe to act on. Anything a machine does can still be rolled back until
+// GVT passes it, so a sink only ever hears about events once they are committed, and the
+// runtime hands them over in timestamp order across all machines (ties go to the lower
+// machine id). Writing output from a sink instead of from inside on_message means it is
+// written once and never has to be taken back.
+pub trait EventSink: Send {
+    fn on_commit(&mut self, event: &CommittedEvent);
+
+    // Called after each batch of commits with the GVT they were committed at, no event
+    // earlier than it will be committed anymore
+    fn on_gvt(&mut self, _gvt: Option<VirtualTime>) {}
+
+    // Called when the run wants everything written out, like at the end of it
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CommittedEvent {
+    pub machine_id: MachineId,
+    // The message the event processed
+    pub message: Message,
+}
+
+// Keeps every committed event in memory. It is a handle, keep a clone to read the events
+// after giving the other one to the runtime.
+#[derive(Debug, Clone, Default)]
+pub struct VecSink {
+    events: Arc<Mutex<Vec<CommittedEvent>>>,
+}
+
+impl VecSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn events(&self) -> Vec<CommittedEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Why an event happened: the committed event with the message, the event that sent
+    // that message, the one that sent that, .. back to a message from outside
+    pub fn causal_chain(&self, message_id: MessageId) -> Vec<CommittedEvent> {
+        let events = self.events.lock().unwrap();
+        let by_id: HashMap<_, _> =
+            events.iter().map(|event| (event.message.id, event)).collect();
+        let mut chain = Vec::new();
+        let mut next = Some(message_id);
+        while let Some(event) = next.and_then(|message_id| by_id.get(&message_id)) {
+            chain.push((*event).clone());
+            next = event.message.parent;
+        }
+        chain
+    }
+
+    // The committed events whose messages the event with message_id sent
+    pub fn caused_by(&self, message_id: MessageId) -> Vec<CommittedEvent> {
+        let events = self.events.lock().unwrap();
+        events
+            .iter()
+            .filter(|event| event.message.parent == Some(message_id))
+            .cloned()
+            .collect()
+    }
+}
+
+impl EventSink for VecSink {
+    fn on_commit(&mut self, event: &CommittedEvent) {
+        self.events.lock().unwrap().push(event.clone());
+    }
+}
+
+// One row per committed event. on_commit cant fail so the first write error is kept and
+// returned from flush, nothing else is written after it.
+pub struct CsvSink<W: Write + Send> {
+    writer: W,
+    error: Option<io::Error>,
+}
+
+const CSV_HEADER: &str = "machine_id,rec_time,send_time,sender,message_id,payload";
+
+impl CsvSink<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write + Send> CsvSink<W> {
+    // Writes the header right away
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writeln!(writer, "{}", CSV_HEADER)?;
+        Ok(Self {
+            writer,
+            error: None,
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send> EventSink for CsvSink<W> {
+    fn on_commit(&mut self, event: &CommittedEvent) {
+        if self.error.is_some() {
+            return;
+        }
+        let message = &event.message;
+        let written = writeln!(
+            self.writer,
+            "{},{},{},{},{},{}",
+            event.machine_id,
+            message.rec_time,
+            message.send_time,
+            message.sender,
+            message.id,
+            csv_field(&message.message)
+        );
+        if let Err(error) = written {
+            self.error = Some(error);
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.writer.flush()
+    }
+}
+
+// Quotes a field if it has anything in it that would break the row
+pub(crate) fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use crate::time::message::Sign;
+
+    #[test]
+    fn test_sinks_get_committed_events_in_timestamp_order() {
+        let mut simulation = ring(2);
+        let events = VecSink::new();
+        simulation.add_sink(Box::new(events.clone()));
+        simulation.inject(Message::new(0, 2, 1, 1, Sign::Message, Arc::new("2".to_string())));
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("3".to_string())));
+        simulation.run();
+
+        let committed: Vec<_> = events
+            .events()
+            .iter()
+            .map(|event| (event.message.rec_time.ticks(), event.machine_id))
+            .collect();
+        assert_eq!(
+            committed,
+            vec![(1, 0), (2, 1), (4, 1), (5, 0), (7, 0), (8, 1), (10, 1)]
+        );
+
+        let mut csv = CsvSink::new(Vec::new()).unwrap();
+        for event in events.events().iter().take(2) {
+            csv.on_commit(event);
+        }
+        csv.on_commit(&CommittedEvent {
+            machine_id: 3,
+            message: Message::new(1, 6, 2, 3, Sign::Message, Arc::new("a, \"b\"".to_string())),
+        });
+        csv.flush().unwrap();
+        let written = String::from_utf8(csv.into_inner()).unwrap();
+        let rows: Vec<_> = written.lines().collect();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0], CSV_HEADER);
+        assert!(rows[1].starts_with("0,1,0,0,"));
+        assert!(rows[3].ends_with(",\"a, \"\"b\"\"\""));
+    }
+
+    #[test]
+    fn test_causal_chain_goes_back_to_the_injected_message() {
+        let mut simulation = ring(2);
+        let events = VecSink::new();
+        simulation.add_sink(Box::new(events.clone()));
+        let start = Message::new(0, 1, 0, 0, Sign::Message, Arc::new("3".to_string()));
+        let start_id = start.id;
+        simulation.inject(start);
+        simulation.run();
+
+        // The hops at 1, 4, 7 and 10 each sent the next
+        let last = events.events().last().unwrap().message.clone();
+        let chain: Vec<_> = events
+            .causal_chain(last.id)
+            .iter()
+            .map(|event| event.message.rec_time)
+            .collect();
+        assert_eq!(chain, [10, 7, 4, 1]);
+        assert_eq!(events.causal_chain(last.id).last().unwrap().message.id, start_id);
+        assert_eq!(events.caused_by(start_id)[0].message.rec_time, 4);
+        assert!(events.caused_by(last.id).is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter::Sum;
+
+use crate::time::message::{MachineId, Message, VirtualTime};
+
+// Counters kept by every machine. Processed counts every time a message is handed to the
+// process, including ones that later get rolled back and processed again, so it is always
+// at least committed + rolled back. Committed only counts messages below GVT since those
+// can never be undone.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MachineStats {
+    pub events_processed: u64,
+    pub events_rolled_back: u64,
+    pub events_committed: u64,
+    pub rollbacks: u64,
+    pub antimessages_sent: u64,
+    // Messages taken back by rollbacks without an antimessage, see
+    // Machine::set_direct_cancellation
+    #[serde(default)]
+    pub messages_cancelled: u64,
+    // Stragglers that left the state as it was so the machine didnt roll back for them, and
+    // the events after them it kept instead of running again, see
+    // Machine::set_lazy_reevaluation
+    #[serde(default)]
+    pub jumps: u64,
+    #[serde(default)]
+    pub events_jumped: u64,
+    // Rollbacks caused by an antimessage, so a rollback somewhere else rolling this one back
+    pub cascading_rollbacks: u64,
+    // Times the machine was throttled for being stuck in a rollback storm
+    pub storms: u64,
+    // Times a penalty window was put on the machine, see throttle.rs
+    #[serde(default)]
+    pub throttles: u64,
+    // Times the machine crashed, see Machine::crash
+    pub crashes: u64,
+    // States saved to roll back to, fewer than events with a SavePolicy, see saving.rs
+    #[serde(default)]
+    pub states_saved: u64,
+    // Saved states dropped to stay under the machines SnapshotLimits
+    pub states_evicted: u64,
+    // Messages the machine wouldnt take, duplicates or ones for a port it doesnt have
+    pub messages_refused: u64,
+    // Messages that arrived for a time before the commit horizon, which nothing should
+    // ever send once GVT is past it. Dropped, see Machine::recieve_outer.
+    #[serde(default)]
+    pub messages_past_gvt: u64,
+    // Antimessages thrown away once GVT passed them without their positive message
+    pub antimessages_discarded: u64,
+    // Messages sent with less delay than the machines lookahead. They are still sent, but
+    // the conservative machines counting on the lookahead may have gone past them.
+    #[serde(default)]
+    pub sends_under_lookahead: u64,
+    // Compensations run for the side effects of rolled back events, see effect.rs
+    pub compensations: u64,
+    // Number of rollbacks for every depth (events undone) seen
+    pub rollback_depths: BTreeMap<usize, u64>,
+}
+
+impl MachineStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_rollback(&mut self, depth: usize, antimessages: usize) {
+        self.rollbacks += 1;
+        self.events_rolled_back += depth as u64;
+        self.antimessages_sent += antimessages as u64;
+        *self.rollback_depths.entry(depth).or_insert(0) += 1;
+    }
+
+    // Fraction of the processing that was actually useful, 1.0 when nothing was processed
+    pub fn efficiency(&self) -> f64 {
+        if self.events_processed == 0 {
+            return 1.0;
+        }
+        self.events_committed as f64 / self.events_processed as f64
+    }
+
+    pub fn max_rollback_depth(&self) -> Option<usize> {
+        self.rollback_depths.keys().next_back().copied()
+    }
+
+    pub fn merge(&mut self, other: &MachineStats) {
+        self.events_processed += other.events_processed;
+        self.events_rolled_back += other.events_rolled_back;
+        self.events_committed += other.events_committed;
+        self.rollbacks += other.rollbacks;
+        self.antimessages_sent += other.antimessages_sent;
+        self.messages_cancelled += other.messages_cancelled;
+        self.jumps += other.jumps;
+        self.events_jumped += other.events_jumped;
+        self.cascading_rollbacks += other.cascading_rollbacks;
+        self.storms += other.storms;
+        self.throttles += other.throttles;
+        self.crashes += other.crashes;
+        self.states_saved += other.states_saved;
+        self.states_evicted += other.states_evicted;
+        self.messages_refused += other.messages_refused;
+        self.messages_past_gvt += other.messages_past_gvt;
+        self.antimessages_discarded += other.antimessages_discarded;
+        self.sends_under_lookahead += other.sends_under_lookahead;
+        self.compensations += other.compensations;
+        for (depth, count) in &other.rollback_depths {
+            *self.rollback_depths.entry(*depth).or_insert(0) += count;
+        }
+    }
+}
+
+impl<'a> Sum<&'a MachineStats> for MachineStats {
+    fn sum<I: Iterator<Item = &'a MachineStats>>(iter: I) -> Self {
+        let mut total = MachineStats::new();
+        for stats in iter {
+            total.merge(stats);
+        }
+        total
+    }
+}
+
+// What the runtime hands back, every machine on its own plus the totals
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SimulationStats {
+    pub total: MachineStats,
+    pub machines: BTreeMap<MachineId, MachineStats>,
+    // Only the optimistic Simulation works it out, see EventHorizon
+    #[serde(default)]
+    pub horizon: HorizonStats,
+}
+
+impl SimulationStats {
+    pub fn from_machines(machines: BTreeMap<MachineId, MachineStats>) -> Self {
+        Self {
+            total: machines.values().sum(),
+            machines,
+            horizon: HorizonStats::default(),
+        }
+    }
+}
+
+// How the committed events split up into event horizon cycles, see EventHorizon
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HorizonStats {
+    pub cycles: u64,
+    pub events: u64,
+    // Events in the biggest cycle
+    pub widest: u64,
+}
+
+impl HorizonStats {
+    // Events per cycle, which is as many as could ever run at the same time on average no
+    // matter the number of threads. 0.0 when nothing committed.
+    pub fn parallelism(&self) -> f64 {
+        if self.cycles == 0 {
+            return 0.0;
+        }
+        self.events as f64 / self.cycles as f64
+    }
+}
+
+// The event horizon of a cycle is the timestamp of the earliest event any event of the
+// cycle generates. Everything before it could have run at once, since nothing that runs
+// in the cycle can send anything into that part of the future, so the next cycle starts
+// at the first event that was generated in this one. Counting the cycles over the
+// committed history gives the parallelism a model has to offer at all: when there are
+// about as many cycles as events, every event depends on the one before it and more
+// threads cant speed it up, however well the rollbacks go.
+//
+// Fed the committed events in timestamp order, an event belongs to the cycle its sender
+// was in if the event that sent it is in the current cycle.
+#[derive(Debug, Default, Clone)]
+pub struct EventHorizon {
+    // The events of the current cycle, by machine and time
+    cycle: BTreeSet<(MachineId, VirtualTime)>,
+    stats: HorizonStats,
+}
+
+impl EventHorizon {
+    pub fn record(&mut self, machine_id: MachineId, message: &Message) {
+        if self.cycle.contains(&(message.sender, message.send_time)) {
+            self.close_cycle();
+        }
+        self.cycle.insert((machine_id, message.rec_time));
+        self.stats.events += 1;
+    }
+
+    fn close_cycle(&mut self) {
+        self.stats.cycles += 1;
+        self.stats.widest = self.stats.widest.max(self.cycle.len() as u64);
+        self.cycle.clear();
+    }
+
+    // The cycle that is still going counts as one
+    pub fn stats(&self) -> HorizonStats {
+        let mut stats = self.stats.clone();
+        if !self.cycle.is_empty() {
+            stats.cycles += 1;
+            stats.widest = stats.widest.max(self.cycle.len() as u64);
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use crate::time::message::Sign;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_event_horizon_cycles_follow_the_causal_chains() {
+        // A single ring chain is one event per cycle, nothing can run along with it
+        let mut simulation = ring(2);
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("3".into())));
+        simulation.run();
+        let horizon = simulation.stats().horizon;
+        assert_eq!((horizon.cycles, horizon.events, horizon.widest), (4, 4, 1));
+
+        // Two chains next to each other, every cycle has an event of both
+        let mut simulation = ring(4);
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("3".into())));
+        simulation.inject(Message::new(0, 2, 2, 2, Sign::Message, Arc::new("3".into())));
+        simulation.run();
+        let horizon = simulation.stats().horizon;
+        assert_eq!((horizon.cycles, horizon.events, horizon.widest), (4, 8, 2));
+        assert_eq!(horizon.parallelism(), 2.0);
+        assert_eq!(HorizonStats::default().parallelism(), 0.0);
+    }
+}
@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+
+// Notices a machine stuck in a rollback storm, where it keeps getting rolled back (often
+// by antimessages from machines that were rolled back themselves, which then send more
+// antimessages) without any of its work getting committed. Once that has happened
+// threshold times in a row the machine is throttled, it runs as if it were conservative
+// so it stops feeding the storm, until something it processed is committed again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StormDetector {
+    // None turns detection off
+    threshold: Option<u64>,
+    rollbacks_since_progress: u64,
+    throttled: bool,
+}
+
+pub const DEFAULT_STORM_THRESHOLD: u64 = 8;
+
+impl Default for StormDetector {
+    fn default() -> Self {
+        Self::new(Some(DEFAULT_STORM_THRESHOLD))
+    }
+}
+
+impl StormDetector {
+    pub fn new(threshold: Option<u64>) -> Self {
+        Self {
+            threshold,
+            rollbacks_since_progress: 0,
+            throttled: false,
+        }
+    }
+
+    pub fn threshold(&self) -> Option<u64> {
+        self.threshold
+    }
+
+    pub fn set_threshold(&mut self, threshold: Option<u64>) {
+        self.threshold = threshold;
+        if threshold.is_none() {
+            self.throttled = false;
+        }
+    }
+
+    pub fn is_throttled(&self) -> bool {
+        self.throttled
+    }
+
+    pub fn rollbacks_since_progress(&self) -> u64 {
+        self.rollbacks_since_progress
+    }
+
+    // Returns true if this rollback is the one that started a storm
+    pub fn record_rollback(&mut self) -> bool {
+        self.rollbacks_since_progress += 1;
+        let storm = !self.throttled
+            && self
+                .threshold
+                .is_some_and(|threshold| self.rollbacks_since_progress >= threshold);
+        if storm {
+            self.throttled = true;
+        }
+        storm
+    }
+
+    // Some work got committed, whatever storm there was is over
+    pub fn record_progress(&mut self) {
+        self.rollbacks_since_progress = 0;
+        self.throttled = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttles_after_threshold_until_progress() {
+        let mut detector = StormDetector::new(Some(3));
+        assert!(!detector.record_rollback());
+        assert!(!detector.record_rollback());
+        assert!(detector.record_rollback());
+        assert!(detector.is_throttled());
+        // Already throttled, it is the same storm
+        assert!(!detector.record_rollback());
+
+        detector.record_progress();
+        assert!(!detector.is_throttled());
+        assert_eq!(detector.rollbacks_since_progress(), 0);
+
+        let mut disabled = StormDetector::new(None);
+        for _ in 0..100 {
+            assert!(!disabled.record_rollback());
+        }
+        assert!(!disabled.is_throttled());
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+use crate::replication::{Outputs, ReplicationReport, Replications};
+
+// Running a model over every combination of a few parameters, like link latency x
+// machine count x window size. Every point of the grid is run as a set of replications
+// (see replication.rs) so each point gets its own means and confidence intervals, and
+// the result can be written out with one row per run for plotting.
+//
+// Parameters are numbers, whatever builds the model from a point casts them back to what
+// it needs.
+pub type Point = BTreeMap<String, f64>;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ParameterGrid {
+    axes: Vec<(String, Vec<f64>)>,
+}
+
+impl ParameterGrid {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn axis(mut self, name: impl Into<String>, values: impl IntoIterator<Item = f64>) -> Self {
+        self.axes.push((name.into(), values.into_iter().collect()));
+        self
+    }
+
+    // Number of points, an empty axis means there are none
+    pub fn len(&self) -> usize {
+        self.axes.iter().map(|(_, values)| values.len()).product()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Every combination, the last axis changing fastest
+    pub fn points(&self) -> Vec<Point> {
+        self.axes.iter().fold(vec![Point::new()], |points, (name, values)| {
+            points
+                .iter()
+                .flat_map(|point| {
+                    values.iter().map(move |value| {
+                        let mut point = point.clone();
+                        point.insert(name.clone(), *value);
+                        point
+                    })
+                })
+                .collect()
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SweepResult {
+    pub point: Point,
+    pub report: ReplicationReport,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sweep {
+    pub grid: ParameterGrid,
+    // Used for every point, so every point sees the same seeds
+    pub replications: Replications,
+}
+
+impl Sweep {
+    pub fn run<F>(&self, replicate: F) -> Vec<SweepResult>
+    where
+        F: Fn(&Point, u64) -> Outputs + Sync,
+    {
+        self.grid
+            .points()
+            .into_iter()
+            .map(|point| {
+                let report = self.replications.run(|seed| replicate(&point, seed));
+                SweepResult { point, report }
+            })
+            .collect()
+    }
+}
+
+// One CSV row per run with the parameters, the seed and every output, outputs a run
+// doesnt have are left empty
+pub fn write_csv<W: Write>(results: &[SweepResult], mut writer: W) -> io::Result<()> {
+    let parameters: BTreeSet<&String> =
+        results.iter().flat_map(|result| result.point.keys()).collect();
+    let outputs: BTreeSet<&String> = results
+        .iter()
+        .flat_map(|result| result.report.runs.iter())
+        .flat_map(|(_, outputs)| outputs.keys())
+        .collect();
+    let header: Vec<&str> = parameters
+        .iter()
+        .map(|name| name.as_str())
+        .chain(["seed"])
+        .chain(outputs.iter().map(|name| name.as_str()))
+        .collect();
+    writeln!(writer, "{}", header.join(","))?;
+    for result in results {
+        for (seed, values) in &result.report.runs {
+            let row: Vec<String> = parameters
+                .iter()
+                .map(|name| result.point.get(*name).map_or(String::new(), f64::to_string))
+                .chain([seed.to_string()])
+                .chain(
+                    outputs
+                        .iter()
+                        .map(|name| values.get(*name).map_or(String::new(), f64::to_string)),
+                )
+                .collect();
+            writeln!(writer, "{}", row.join(","))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phold::PholdConfig;
+    use crate::time::message::VirtualTime;
+
+    #[test]
+    fn test_sweep_runs_every_combination() {
+        let grid = ParameterGrid::new()
+            .axis("machines", [2.0, 4.0])
+            .axis("remote", [0.0, 0.5, 1.0]);
+        assert_eq!(grid.len(), 6);
+        let sweep = Sweep {
+            grid,
+            replications: Replications {
+                count: 2,
+                base_seed: 1,
+                threads: 2,
+            },
+        };
+        let results = sweep.run(|point, seed| {
+            let report = PholdConfig {
+                machines: point["machines"] as usize,
+                remote_fraction: point["remote"],
+                population: 1,
+                end_time: VirtualTime::new(300),
+                seed,
+                ..PholdConfig::default()
+            }
+            .run();
+            Outputs::from([(
+                "committed".to_string(),
+                report.stats.total.events_committed as f64,
+            )])
+        });
+        assert_eq!(results.len(), 6);
+        assert_eq!(results[1].point["machines"], 2.0);
+        assert_eq!(results[1].point["remote"], 0.5);
+
+        let mut csv = Vec::new();
+        write_csv(&results, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let rows: Vec<_> = csv.lines().collect();
+        assert_eq!(rows[0], "machines,remote,seed,committed");
+        assert_eq!(rows.len(), 1 + 6 * 2);
+        assert!(rows[1].starts_with("2,0,1,"));
+    }
+}
@@ -0,0 +1,397 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+use crate::latency::mix;
+use crate::machine::Machine;
+use crate::process::{Context, TimeWarpProcess};
+use crate::runtime::sequential::SequentialSimulation;
+use crate::runtime::Simulation;
+use crate::time::message::{Delay, MachineId, Message, Sign, VirtualTime};
+
+// Proptest strategies for property testing that optimistic execution commits exactly
+// what the sequential executor does. Topology, Schedule and Scenario implement
+// Arbitrary, with the ranges in Topologies, Schedules and Scenarios as their parameters,
+// so proptest generates them and shrinks a failing one down to what still fails:
+//
+//   proptest! {
+//       #[test]
+//       fn commits_like_sequential(scenario in any::<Scenario>()) {
+//           prop_assert_eq!(testing::check_against_sequential(&scenario), Ok(()));
+//       }
+//   }
+//
+// A scenario is a random topology of machines running FuzzProcess and a random schedule
+// of messages injected into it, some of them only after the optimistic run already went
+// ahead a few steps so they arrive as stragglers and force rollbacks. Downstream crates
+// turn this on with the testing feature and need the same proptest as this crate.
+
+// Every machine has at least one outgoing link, links are (to, delay) and a delay is
+// never 0 so a message cant come back to the same time it was sent at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topology {
+    pub links: Vec<Vec<(MachineId, Delay)>>,
+}
+
+impl Topology {
+    pub fn machines(&self) -> usize {
+        self.links.len()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Topologies {
+    pub machines: RangeInclusive<usize>,
+    pub links_per_machine: RangeInclusive<usize>,
+    pub delays: RangeInclusive<u64>,
+}
+
+impl Default for Topologies {
+    fn default() -> Self {
+        Self {
+            machines: 1..=6,
+            links_per_machine: 1..=3,
+            delays: 1..=10,
+        }
+    }
+}
+
+// Shrinks to fewer machines, fewer links and shorter delays
+impl Arbitrary for Topology {
+    type Parameters = Topologies;
+    type Strategy = BoxedStrategy<Topology>;
+
+    fn arbitrary_with(params: Topologies) -> Self::Strategy {
+        let (fewest, most) = params.links_per_machine.into_inner();
+        let links_per_machine = fewest.max(1)..=most.max(1);
+        let (shortest, longest) = params.delays.into_inner();
+        let delays = (shortest.max(1)..=longest.max(1)).prop_map(Delay::new);
+        let (fewest, most) = params.machines.into_inner();
+        (fewest.max(1)..=most.max(1))
+            .prop_flat_map(move |machines| {
+                let link = (0..machines, delays.clone());
+                vec(vec(link, links_per_machine.clone()), machines)
+            })
+            .prop_map(|links| Topology { links })
+            .boxed()
+    }
+}
+
+// A message to inject: hops is how many more times it is passed on, and it is injected
+// after the optimistic run took steps_before steps since the one before it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Injection {
+    pub to: MachineId,
+    pub at: VirtualTime,
+    pub hops: u32,
+    pub steps_before: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Schedule {
+    pub injections: Vec<Injection>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Schedules {
+    // The machines to inject into are the ones below this
+    pub machines: usize,
+    pub injections: RangeInclusive<usize>,
+    pub times: RangeInclusive<u64>,
+    pub hops: RangeInclusive<u32>,
+    pub steps_before: RangeInclusive<usize>,
+}
+
+impl Default for Schedules {
+    fn default() -> Self {
+        Self {
+            machines: 6,
+            injections: 1..=8,
+            times: 1..=40,
+            hops: 0..=6,
+            steps_before: 0..=10,
+        }
+    }
+}
+
+// Shrinks to fewer injections, with fewer hops, that dont wait
+impl Arbitrary for Schedule {
+    type Parameters = Schedules;
+    type Strategy = BoxedStrategy<Schedule>;
+
+    fn arbitrary_with(params: Schedules) -> Self::Strategy {
+        let times = params.times.prop_map(VirtualTime::new);
+        let injection = (0..params.machines.max(1), times, params.hops, params.steps_before)
+            .prop_map(|(to, at, hops, steps_before)| Injection {
+                to,
+                at,
+                hops,
+                steps_before,
+            });
+        vec(injection, params.injections)
+            .prop_map(|injections| Schedule { injections })
+            .boxed()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scenario {
+    pub topology: Topology,
+    pub schedule: Schedule,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Scenarios {
+    pub topologies: Topologies,
+    pub schedules: Schedules,
+}
+
+// The schedule only injects into machines the topology has
+impl Arbitrary for Scenario {
+    type Parameters = Scenarios;
+    type Strategy = BoxedStrategy<Scenario>;
+
+    fn arbitrary_with(params: Scenarios) -> Self::Strategy {
+        let schedules = params.schedules;
+        any_with::<Topology>(params.topologies)
+            .prop_flat_map(move |topology| {
+                let schedules = Schedules {
+                    machines: topology.machines(),
+                    ..schedules.clone()
+                };
+                (Just(topology), any_with::<Schedule>(schedules))
+            })
+            .prop_map(|(topology, schedule)| Scenario { topology, schedule })
+            .boxed()
+    }
+}
+
+// Passes a message with hops left on over one of its links, picked from the time and
+// the hops so a rerun after a rollback picks the same one, and every third hop over a
+// second link as well so the events fan out and overtake each other. The state is how
+// many events it handled and a hash of which, in the order it handled them, so a machine
+// that commits the same events in another order doesnt look the same.
+#[derive(Debug, Clone)]
+pub struct FuzzProcess {
+    pub links: Vec<(MachineId, Delay)>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FuzzState {
+    pub handled: u64,
+    pub hash: u64,
+}
+
+impl TimeWarpProcess for FuzzProcess {
+    type State = FuzzState;
+
+    fn on_message(&self, state: &mut FuzzState, message: &Message, ctx: &mut Context) {
+        let hops: u64 = message.message.parse().unwrap_or(0);
+        let event = [ctx.now().ticks(), message.sender as u64, hops];
+        state.handled += 1;
+        state.hash = event.into_iter().fold(state.hash, |hash, value| mix(hash ^ value));
+        if hops == 0 || self.links.is_empty() {
+            return;
+        }
+        let pick = mix(ctx.now().ticks() ^ hops) as usize;
+        let (to, delay) = self.links[pick % self.links.len()];
+        ctx.send(to, delay, (hops - 1).to_string());
+        if hops.is_multiple_of(3) {
+            let (to, delay) = self.links[(pick + 1) % self.links.len()];
+            ctx.send(to, delay, (hops - 1).to_string());
+        }
+    }
+
+    fn lookahead(&self) -> Delay {
+        self.links.iter().map(|(_, delay)| *delay).min().unwrap_or(Delay::ZERO)
+    }
+}
+
+impl Scenario {
+    pub fn simulation(&self) -> Simulation<FuzzProcess> {
+        let mut simulation = Simulation::new();
+        for machine in self.machines() {
+            simulation.add_machine(machine);
+        }
+        simulation
+    }
+
+    pub fn sequential(&self, messages: &[Message]) -> SequentialSimulation<FuzzProcess> {
+        let mut sequential = SequentialSimulation::new();
+        for machine in self.machines() {
+            sequential.add_machine(machine);
+        }
+        for message in messages {
+            sequential.inject(message.clone());
+        }
+        sequential
+    }
+
+    // The injected messages. Ties at the same time go by message id, so both runs have to
+    // get the same ones for them to agree
+    pub fn messages(&self) -> Vec<Message> {
+        let injections = self.schedule.injections.iter();
+        injections.map(Injection::message).collect()
+    }
+
+    fn machines(&self) -> impl Iterator<Item = Machine<FuzzProcess>> + '_ {
+        self.topology.links.iter().enumerate().map(|(id, links)| {
+            let process = FuzzProcess {
+                links: links.clone(),
+            };
+            Machine::with_process(id, 0, process)
+        })
+    }
+
+    // Steps before every injection like the schedule says, then runs to the end, and
+    // returns the messages it injected. Stepping only commits when a machine has to wait
+    // for it, but once it did nothing can go below GVT anymore, the receiver or whoever
+    // it passes the message on to would refuse it. Those are left out.
+    pub fn run(
+        &self,
+        simulation: &mut Simulation<FuzzProcess>,
+        messages: &[Message],
+    ) -> Vec<Message> {
+        let mut injected = Vec::new();
+        for (injection, message) in self.schedule.injections.iter().zip(messages) {
+            for _ in 0..injection.steps_before {
+                simulation.step();
+            }
+            let refused = (0..self.topology.machines())
+                .filter_map(|machine_id| simulation.machine(machine_id))
+                .any(|machine| message.rec_time < machine.commit_horizon());
+            if !refused {
+                simulation.inject(message.clone());
+                injected.push(message.clone());
+            }
+        }
+        simulation.run();
+        injected
+    }
+}
+
+impl Injection {
+    fn message(&self) -> Message {
+        let hops = Arc::new(self.hops.to_string());
+        Message::new(0, self.at, self.to, self.to, Sign::Message, hops)
+    }
+}
+
+// Where the optimistic run didnt commit what the sequential one did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    // The first time the committed states differ, for the epoch of length 1 it falls in
+    States {
+        at: VirtualTime,
+        optimistic: Option<u64>,
+        sequential: Option<u64>,
+    },
+    FinalState {
+        machine_id: MachineId,
+        optimistic: FuzzState,
+        sequential: FuzzState,
+    },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::States { at, optimistic, sequential } => write!(
+                f,
+                "committed states differ at {}: optimistic {:?}, sequential {:?}",
+                at, optimistic, sequential
+            ),
+            Mismatch::FinalState { machine_id, optimistic, sequential } => write!(
+                f,
+                "machine {} ends in {:?} optimistically and {:?} sequentially",
+                machine_id, optimistic, sequential
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+// The reference checker: runs the scenario optimistically and sequentially and compares
+// every committed state
+pub fn check_against_sequential(scenario: &Scenario) -> Result<(), Mismatch> {
+    let messages = scenario.messages();
+    let mut simulation = scenario.simulation();
+    simulation.record_digests(1);
+    let injected = scenario.run(&mut simulation, &messages);
+    let mut sequential = scenario.sequential(&injected);
+    sequential.record_digests(1);
+    sequential.run();
+
+    let (Some(optimistic), Some(reference)) = (simulation.take_digests(), sequential.take_digests())
+    else {
+        return Ok(());
+    };
+    if let Some(divergence) = optimistic.first_divergence(&reference) {
+        return Err(Mismatch::States {
+            at: divergence.epoch_start,
+            optimistic: divergence.first,
+            sequential: divergence.second,
+        });
+    }
+    for (machine_id, state) in sequential.states() {
+        let Some(machine) = simulation.machine(machine_id) else {
+            continue;
+        };
+        if machine.state != *state {
+            return Err(Mismatch::FinalState {
+                machine_id,
+                optimistic: machine.state.clone(),
+                sequential: state.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::test_runner::{Config, TestError, TestRunner};
+
+    proptest! {
+        #![proptest_config(Config::with_cases(200))]
+
+        #[test]
+        fn test_optimistic_runs_commit_what_sequential_ones_do(scenario in any::<Scenario>()) {
+            prop_assert_eq!(check_against_sequential(&scenario), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_stragglers_make_scenarios_roll_back() {
+        let mut runner = TestRunner::deterministic();
+        let rolled_back = (0..50).any(|_| {
+            let scenario = any::<Scenario>().new_tree(&mut runner).unwrap().current();
+            let mut simulation = scenario.simulation();
+            scenario.run(&mut simulation, &scenario.messages());
+            simulation.stats().total.rollbacks > 0
+        });
+        assert!(rolled_back);
+    }
+
+    #[test]
+    fn test_failing_cases_are_shrunk() {
+        // Fails as soon as any injection has more than 2 hops
+        let mut runner = TestRunner::new(Config::with_cases(100));
+        let result = runner.run(&any::<Schedule>(), |schedule| {
+            prop_assert!(schedule.injections.iter().all(|injection| injection.hops <= 2));
+            Ok(())
+        });
+        let Err(TestError::Fail(_, minimal)) = result else {
+            panic!("the property held for every schedule");
+        };
+        // Down to a single injection that doesnt wait, with just too many hops
+        assert_eq!(minimal.injections.len(), 1);
+        assert_eq!(minimal.injections[0].hops, 3);
+        assert_eq!(minimal.injections[0].steps_before, 0);
+    }
+}
@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+
+use crate::time::message::Delay;
+
+// Keeps a machine that keeps getting rolled back from running far ahead of everyone else.
+// Once it has been rolled back penalty_after times without any of its work committing in
+// between it gets a window of its own, how far past its commit horizon it may run, of
+// half as far as it had got. Every further rollback halves the window again, down to
+// min_window. The window grows back once the machine is doing useful work again: at a
+// commit where at least recover_at of the events it processed since the window last
+// changed are committed, it doubles, and once it is as wide as where the machine was
+// when the penalty started it goes away. On an imbalanced model the machines that run
+// ahead because they have little to do stop wasting time on work that only gets undone,
+// and the ones that hold everyone back get the processor instead.
+//
+// This works on virtual time where RollbackBudget (see budget.rs) works on the number of
+// uncommitted events, the two can be used together. A machine with an optimism window
+// (see MachineBuilder::optimism_window) never goes past the narrower of the two.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThrottleLimits {
+    pub penalty_after: u64,
+    // Committed events per processed one
+    pub recover_at: f64,
+    pub min_window: Delay,
+}
+
+impl Default for ThrottleLimits {
+    fn default() -> Self {
+        Self {
+            penalty_after: 3,
+            recover_at: 0.8,
+            min_window: Delay::new(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PenaltyThrottle {
+    // None turns it off
+    limits: Option<ThrottleLimits>,
+    rollbacks_in_row: u64,
+    // None while there is no penalty
+    window: Option<Delay>,
+    // How far ahead the machine was when the penalty started
+    relaxed: Delay,
+    // Events processed and committed since the window last changed
+    processed: u64,
+    committed: u64,
+}
+
+impl PenaltyThrottle {
+    pub fn new(limits: Option<ThrottleLimits>) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    pub fn limits(&self) -> Option<ThrottleLimits> {
+        self.limits
+    }
+
+    // How far past the commit horizon the machine may run right now
+    pub fn window(&self) -> Option<Delay> {
+        self.window
+    }
+
+    pub fn is_penalized(&self) -> bool {
+        self.window.is_some()
+    }
+
+    pub fn record_event(&mut self) {
+        self.processed += 1;
+    }
+
+    // ahead is how far past the commit horizon the machine had run. Returns true if this
+    // rollback is the one that started the penalty.
+    pub fn record_rollback(&mut self, ahead: Delay) -> bool {
+        let Some(limits) = self.limits else {
+            return false;
+        };
+        self.rollbacks_in_row += 1;
+        if self.rollbacks_in_row < limits.penalty_after {
+            return false;
+        }
+        let current = match self.window {
+            Some(window) => window,
+            None => {
+                self.relaxed = ahead.max(limits.min_window);
+                self.relaxed
+            }
+        };
+        let started = self.window.is_none();
+        self.set_window(Some(Delay::new(current.ticks() / 2).max(limits.min_window)));
+        started
+    }
+
+    // Some of the machines work got committed
+    pub fn record_progress(&mut self, committed: usize) {
+        self.rollbacks_in_row = 0;
+        self.committed += committed as u64;
+        let (Some(limits), Some(window)) = (self.limits, self.window) else {
+            return;
+        };
+        if (self.committed as f64) < limits.recover_at * self.processed as f64 {
+            return;
+        }
+        let grown = window.saturating_add(window);
+        self.set_window((grown < self.relaxed).then_some(grown));
+    }
+
+    fn set_window(&mut self, window: Option<Delay>) {
+        self.window = window;
+        self.processed = 0;
+        self.committed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::runtime::tests::Ring;
+    use crate::time::message::{Message, Sign, VirtualTime};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_throttle_narrows_on_rollbacks_and_relaxes_with_commits() {
+        let mut throttle = PenaltyThrottle::new(Some(ThrottleLimits {
+            penalty_after: 2,
+            recover_at: 0.5,
+            min_window: Delay::new(2),
+        }));
+        assert!(!throttle.record_rollback(Delay::new(40)));
+        assert!(throttle.record_rollback(Delay::new(40)));
+        assert_eq!(throttle.window(), Some(Delay::new(20)));
+        throttle.record_rollback(Delay::new(10));
+        throttle.record_rollback(Delay::new(5));
+        assert_eq!(throttle.window(), Some(Delay::new(5)));
+        throttle.record_rollback(Delay::new(5));
+        throttle.record_rollback(Delay::new(5));
+        assert_eq!(throttle.window(), Some(Delay::new(2)));
+
+        // Too little of what it ran stays to grow back
+        (0..10).for_each(|_| throttle.record_event());
+        throttle.record_progress(4);
+        assert_eq!(throttle.window(), Some(Delay::new(2)));
+        throttle.record_progress(1);
+        assert_eq!(throttle.window(), Some(Delay::new(4)));
+        for window in [8, 16, 32] {
+            throttle.record_progress(1);
+            assert_eq!(throttle.window(), Some(Delay::new(window)));
+        }
+        throttle.record_progress(1);
+        assert!(!throttle.is_penalized());
+        assert!(!PenaltyThrottle::default().record_rollback(Delay::new(40)));
+    }
+
+    #[test]
+    fn test_penalized_machines_wait_for_commits() {
+        let at = |rec_time: usize| {
+            Message::new(0, rec_time, 0, 0, Sign::Message, Arc::new("0".to_string()))
+        };
+        let limits = ThrottleLimits {
+            penalty_after: 1,
+            ..ThrottleLimits::default()
+        };
+        let mut machine = Machine::builder(0, Ring { machines: 1 }).throttle(limits).build();
+        for rec_time in (1..=20).filter(|rec_time| *rec_time != 2) {
+            machine.recieve_outer(at(rec_time));
+        }
+        while machine.can_execute(None) {
+            machine.recieve_inner();
+        }
+        assert_eq!(machine.local_virtual_time(), 20);
+
+        // Was 20 ahead, may run 10 now
+        machine.recieve_outer(at(2));
+        assert_eq!(machine.throttle().window(), Some(Delay::new(10)));
+        while machine.can_execute(None) {
+            machine.recieve_inner();
+        }
+        assert_eq!(machine.local_virtual_time(), 10);
+        assert!(machine.outside_window());
+        assert_eq!(machine.stats().throttles, 1);
+
+        // Everything it ran since commits, back to where it was
+        machine.commit(Some(VirtualTime::new(11)));
+        assert!(!machine.throttle().is_penalized());
+        while machine.can_execute(None) {
+            machine.recieve_inner();
+        }
+        assert_eq!(machine.local_virtual_time(), 20);
+    }
+}
@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+
+use super::message::{Message, MessageId, VirtualTime};
+
+// Keeps track of the antimessages a machine has sent out during rollbacks that
+// have not been confirmed as received yet. Once the originals are cancelled out
+// of the output queue this is the only record that the cancellation happened, so
+// anything computing GVT has to take the antimessages in here into account and
+// anything retransmitting over an unreliable link knows what still needs to go out.
+// They are kept by the id they share with the message they cancel, a message is only
+// ever cancelled once.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct InFlightAntimessages {
+    antimessages: BTreeMap<MessageId, Message>,
+}
+
+impl InFlightAntimessages {
+    pub fn new() -> Self {
+        Self {
+            antimessages: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, antimessage: Message) {
+        self.antimessages.insert(antimessage.id, antimessage);
+    }
+
+    // Called once the receiver has the antimessage, returns false if it was never
+    // being tracked (for example a duplicate acknowledgement)
+    pub fn acknowledge(&mut self, antimessage: &Message) -> bool {
+        match self.antimessages.entry(antimessage.id) {
+            Entry::Occupied(tracked) if tracked.get() == antimessage => {
+                tracked.remove();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // An unacknowledged antimessage can still cause a rollback at its receive time
+    // so GVT can never pass the smallest one
+    pub fn min_rec_time(&self) -> Option<VirtualTime> {
+        self.antimessages.values().map(|message| message.rec_time).min()
+    }
+
+    // Antimessages that still need to be delivered, for retransmission, by id
+    pub fn pending(&self) -> impl Iterator<Item = &Message> + '_ {
+        self.antimessages.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.antimessages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.antimessages.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::message::Sign;
+    use std::sync::Arc;
+
+    fn antimessage(rec_time: usize) -> Message {
+        let mut message = Message::new(0, rec_time, 0, 1, Sign::Message, Arc::new(String::new()));
+        message.sign = Sign::Antimessage;
+        message
+    }
+
+    #[test]
+    fn test_unknown_antimessages_arent_acknowledged() {
+        let mut in_flight = InFlightAntimessages::new();
+        let tracked = antimessage(4);
+        in_flight.record(tracked.clone());
+        assert!(!in_flight.acknowledge(&antimessage(4)));
+        // Same id but not the same message
+        let mut other = tracked.clone();
+        other.receiver = 2;
+        assert!(!in_flight.acknowledge(&other));
+        assert_eq!(in_flight.len(), 1);
+    }
+
+    #[test]
+    fn test_acknowledging_twice() {
+        let mut in_flight = InFlightAntimessages::new();
+        let tracked = antimessage(4);
+        in_flight.record(tracked.clone());
+        assert!(in_flight.acknowledge(&tracked));
+        assert!(!in_flight.acknowledge(&tracked));
+        assert!(in_flight.is_empty());
+        assert_eq!(in_flight.min_rec_time(), None);
+    }
+
+    #[test]
+    fn test_min_rec_time_after_some_acknowledgements() {
+        let mut in_flight = InFlightAntimessages::new();
+        let antimessages: Vec<_> = [7, 3, 5].into_iter().map(antimessage).collect();
+        for antimessage in &antimessages {
+            in_flight.record(antimessage.clone());
+        }
+        assert_eq!(in_flight.min_rec_time(), Some(VirtualTime::new(3)));
+        assert!(in_flight.acknowledge(&antimessages[1]));
+        assert_eq!(in_flight.min_rec_time(), Some(VirtualTime::new(5)));
+        let pending: Vec<_> = in_flight.pending().map(|message| message.rec_time.ticks()).collect();
+        assert_eq!(pending, [7, 5]);
+    }
+}
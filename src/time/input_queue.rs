@@ -1,111 +1,523 @@
-use super::message::Message;
+use super::message::{MachineId, Message, MessageId, Sign, Timestamp, VirtualTime};
+use super::pool;
+use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::{collections::BTreeMap, ops::Bound, sync::Arc};
 //
 // This is the queue of messages that are arriving to be processed by a machine. 
 // It is essentialy a priority queue with a pointer to some element in the queue. 
-// Elements are inserted according to their priority but on duplicate messages
-// both the original and new element are deleted to support message/antimessages. When polling 
+// Elements are inserted according to their priority but when a message meets its
+// antimessage both are deleted, what happens to other copies is up to the
+// DuplicatePolicy. When polling 
 // instead of returning and removing the element with the highest priority you just return the highest
 // priority above a certain threshold. Removing elements is done as a separate operation.
 
 // The purpose is to keep messages you have processed until you know you dont need
 // them anymore but you still want to read more messages to continue processing 
-// so you need to keep track of where you are currently in the queue.
-pub struct InputQueue {
-    map: BTreeMap<WrappedMessage, ()>,
-    threshold: usize,
+// so you need to keep track of where you are currently in the queue. The threshold is
+// the receive time of the last message processed, None while nothing has been, which is
+// what lets a message at time 0 be processed (and roll back to) like any other.
+//
+// Any number of messages can be received at the same time. Among themselves they go by
+// generation first (see Message::generation), so a message sent with no delay comes after
+// the event that sent it, then by sender and then in the order the sender sent them. None
+// of that depends on when a message arrived, so the same messages always run in the same
+// order whatever the executor. When only some of the messages at the threshold time have
+// been processed the queue also remembers how far it got among them.
+//
+// For models that assume FIFO channels the queue can also make sure that messages from
+// the same sender are presented in the order they were sent, see fifo_stamp.
+//
+// The queue works on any time axis (see Timestamp), machines use the VirtualTime one.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(deserialize = "T: Timestamp + Deserialize<'de>"))]
+pub struct InputQueue<T = VirtualTime> {
+    map: BTreeMap<WrappedMessage<T>, ()>,
+    threshold: Option<T>,
+    // Where among the messages at the threshold time processing got to, None when it
+    // counts as all of them
+    #[serde(default)]
+    processed_up_to: Option<Tie>,
+    #[serde(default)]
+    fifo: bool,
+    // Receive times given to messages that would have overtaken an earlier one from the
+    // same sender, so their antimessages can be moved to the same place
+    #[serde(default)]
+    restamped: HashMap<Key, T>,
+    #[serde(default)]
+    duplicates: DuplicatePolicy,
+    // Copies of a message beyond the one in the map, only with AllowDuplicates
+    #[serde(default)]
+    copies: HashMap<Key, usize>,
+}
+
+// What insert does with a second copy of a message that is already in the queue (same id
+// and times, same sign). A message and its antimessage always cancel whatever the policy,
+// that is the only thing that ever takes a message out on insert.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    // The copy is dropped, so a transport delivering a message twice changes nothing
+    #[default]
+    AnnihilateOppositeSignsOnly,
+    // The copy is refused with an error, for catching whatever sent it twice
+    RejectDuplicates,
+    // Every copy is kept and needs its own antimessage to go away. The copies have the
+    // same place in the queue, so they are still handed to the process as one event.
+    AllowDuplicates,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertError {
+    // A copy of a message already queued, with RejectDuplicates
+    Duplicate(MessageId),
+}
+
+impl fmt::Display for InsertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InsertError::Duplicate(message_id) => {
+                write!(f, "message {message_id} is already in the queue")
+            }
+        }
+    }
+}
+
+impl std::error::Error for InsertError {}
+
+// Order of a message among the ones received at the same time, see the top of the file
+type Tie = (u32, MachineId, MessageId);
+
+// Ids are only unique per sender, a message is its sender and id
+type Key = (MachineId, MessageId);
+
+fn key<T>(message: &Message<T>) -> Key {
+    (message.sender, message.id)
+}
+
+// What insert_batch did with a batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchInsert<T = VirtualTime> {
+    // Receive time of the earliest message in it, None for an empty batch
+    pub earliest: Option<T>,
+    // The messages insert would have refused, in queue order
+    pub refused: Vec<InsertError>,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 // Wrapper exists to have a custom ordering of messages since input is based on rec_time
-// and output is based off of send_time
-struct WrappedMessage(Message);
-impl WrappedMessage {
-    fn new(message: Message) -> Self {
+// and output is based off of send_time. A message and its antimessage are in the same
+// place, the sign doesnt count.
+struct WrappedMessage<T>(Message<T>);
+impl<T: Timestamp> WrappedMessage<T> {
+    fn new(message: Message<T>) -> Self {
         WrappedMessage(message)
     }
+
+    // Stands in for the first message at rec_time when looking the queue up, real ids
+    // start at 1 so it goes before all of them
+    fn probe(rec_time: T) -> Self {
+        let mut probe = Message::at(T::default(), rec_time, 0, 0, Sign::Message, Arc::default());
+        probe.id = 0;
+        WrappedMessage(probe)
+    }
+}
+
+fn tie<T>(message: &Message<T>) -> Tie {
+    (message.generation, message.sender, message.id)
+}
+
+fn queue_order<T: Timestamp>(a: &Message<T>, b: &Message<T>) -> Ordering {
+    a.rec_time.cmp(&b.rec_time).then_with(|| tie(a).cmp(&tie(b)))
 }
 
-impl Ord for WrappedMessage {
+impl<T: Timestamp> Ord for WrappedMessage<T> {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.0.rec_time.cmp(&other.0.rec_time)
+        queue_order(&self.0, &other.0)
     }
 }
 
-impl PartialOrd for WrappedMessage {
+impl<T: Timestamp> PartialEq for WrappedMessage<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T: Timestamp> Eq for WrappedMessage<T> {}
+
+impl<T: Timestamp> PartialOrd for WrappedMessage<T> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl fmt::Debug for InputQueue {
+impl<T: Timestamp> fmt::Debug for InputQueue<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("InputQueue")
             .field("threshold", &self.threshold)
+            .field("processed_up_to", &self.processed_up_to)
+            .field("fifo", &self.fifo)
+            .field("duplicates", &self.duplicates)
             .field("map", &self.map)
             .finish()
     }
 }
 
-impl InputQueue {
-    pub fn new(threshold: usize) -> Self {
+impl<T: Timestamp> InputQueue<T> {
+    pub fn new(threshold: Option<T>) -> Self {
         InputQueue {
             map: BTreeMap::new(),
             threshold,
+            processed_up_to: None,
+            fifo: false,
+            restamped: HashMap::new(),
+            duplicates: DuplicatePolicy::default(),
+            copies: HashMap::new(),
         }
     }
 
-    // Inserts into the queue, duplicates are eliminated from queue
-    pub fn insert(&mut self, message: Message) {
-        let wrapped_message = WrappedMessage(message);
-        if self.map.contains_key(&wrapped_message) {
-            self.map.remove(&wrapped_message);
-        } else {
-            self.map.insert(wrapped_message, ());
+    pub fn set_duplicate_policy(&mut self, duplicates: DuplicatePolicy) {
+        self.duplicates = duplicates;
+    }
+
+    pub fn duplicate_policy(&self) -> DuplicatePolicy {
+        self.duplicates
+    }
+
+    pub fn set_fifo(&mut self, fifo: bool) {
+        self.fifo = fifo;
+    }
+
+    pub fn is_fifo(&self) -> bool {
+        self.fifo
+    }
+
+    // Has to be called on every message before it goes in (and before deciding whether
+    // it is a straggler) when the queue is FIFO, does nothing otherwise. A message sent
+    // after an earlier message from the same sender that is still in the queue but with
+    // a receive time that isnt later than it gets moved to the time right after that one,
+    // and its antimessage follows it there. Messages between a pair arrive in the
+    // order they were sent with all the executors so this is enough to keep the order.
+    pub fn fifo_stamp(&mut self, message: &mut Message<T>) {
+        if !self.fifo {
+            return;
+        }
+        if message.sign == Sign::Antimessage {
+            if let Some(rec_time) = self.restamped.remove(&key(message)) {
+                message.rec_time = rec_time;
+            }
+            return;
         }
+        let latest = self
+            .map
+            .keys()
+            .map(|wrapped| &wrapped.0)
+            .filter(|queued| {
+                queued.sender == message.sender
+                    && queued.sign == Sign::Message
+                    && queued.send_time < message.send_time
+            })
+            .map(|queued| queued.rec_time)
+            .max();
+        let Some(latest) = latest.filter(|latest| *latest >= message.rec_time) else {
+            return;
+        };
+        let rec_time = latest.successor();
+        self.restamped.insert(key(message), rec_time);
+        message.rec_time = rec_time;
+    }
+
+    // Inserts into the queue, a message and its antimessage cancel each other out and
+    // copies are handled by the DuplicatePolicy
+    pub fn insert(&mut self, message: Message<T>) -> Result<(), InsertError> {
+        let wrapped = WrappedMessage::new(message);
+        let Some((queued, _)) = self.map.get_key_value(&wrapped) else {
+            self.map.insert(wrapped, ());
+            return Ok(());
+        };
+        let message = &wrapped.0;
+        if queued.0.sign != message.sign {
+            if !self.drop_copy(key(message)) {
+                // Usually both share the payload, it is free once the queued one is gone
+                self.map.remove(&wrapped);
+                pool::recycle(wrapped.0.message);
+            }
+            return Ok(());
+        }
+        match self.duplicates {
+            DuplicatePolicy::AnnihilateOppositeSignsOnly => Ok(()),
+            DuplicatePolicy::RejectDuplicates => Err(InsertError::Duplicate(message.id)),
+            DuplicatePolicy::AllowDuplicates => {
+                *self.copies.entry(key(message)).or_insert(0) += 1;
+                Ok(())
+            }
+        }
+    }
+
+    // Sorts messages into the order the queue has them in. If any of a sorted batch is
+    // at or before the threshold (see is_processed) the first one is, so that is the only
+    // one to check for a straggler.
+    pub fn sort_batch(messages: &mut [Message<T>]) {
+        messages.sort_by(queue_order);
+    }
+
+    // Inserts a burst of messages at once. A batch that goes after everything already
+    // queued, the usual case for a burst from a transport, is built into a tree of its
+    // own from sorted order and appended in one go instead of rebalancing for every
+    // message. Anything else (it goes in between, or a message and its antimessage are
+    // in it together) is inserted one message at a time with the same result.
+    pub fn insert_batch(
+        &mut self,
+        messages: impl IntoIterator<Item = Message<T>>,
+    ) -> BatchInsert<T> {
+        let mut batch: Vec<_> = messages.into_iter().collect();
+        Self::sort_batch(&mut batch);
+        let earliest = batch.first().map(|message| message.rec_time);
+        let after_queued = match (self.map.last_key_value(), batch.first()) {
+            (Some((last, ())), Some(first)) => queue_order(first, &last.0).is_gt(),
+            _ => true,
+        };
+        let distinct = batch.windows(2).all(|pair| queue_order(&pair[0], &pair[1]).is_lt());
+        if after_queued && distinct {
+            let mut appended: BTreeMap<_, _> =
+                batch.into_iter().map(|message| (WrappedMessage::new(message), ())).collect();
+            self.map.append(&mut appended);
+            return BatchInsert {
+                earliest,
+                refused: Vec::new(),
+            };
+        }
+        let refused = batch.into_iter().filter_map(|message| self.insert(message).err()).collect();
+        BatchInsert { earliest, refused }
+    }
+
+    // Takes away one of the extra copies of a message, false if it has none
+    fn drop_copy(&mut self, key: Key) -> bool {
+        let Entry::Occupied(mut copies) = self.copies.entry(key) else {
+            return false;
+        };
+        *copies.get_mut() -= 1;
+        if *copies.get() == 0 {
+            copies.remove();
+        }
+        true
+    }
+
+    // Whether the message (or its antimessage) is queued
+    pub fn contains(&self, message: &Message<T>) -> bool {
+        self.map.contains_key(&WrappedMessage::new(message.clone()))
+    }
+
+    // Takes one copy of the message out without an antimessage, false if it isnt queued
+    pub fn remove(&mut self, message: &Message<T>) -> bool {
+        let wrapped = WrappedMessage::new(message.clone());
+        if !self.map.contains_key(&wrapped) {
+            return false;
+        }
+        if !self.drop_copy(key(message)) {
+            self.map.remove(&wrapped);
+            self.restamped.remove(&key(message));
+        }
+        true
     }
 
     // Removes the smallest (highest priority) element, this is for purely for
     // Freeing up messages that have no chance of every being rolled back to
-    pub fn remove_smallest(&mut self) -> Option<Message> {
+    pub fn remove_smallest(&mut self) -> Option<Message<T>> {
         let smallest = self.map.keys().next().cloned();
         if let Some(ref removed) = &smallest {
+            // Only one of the copies goes
+            if self.drop_copy(key(&removed.0)) {
+                return smallest.map(|wrapped| wrapped.0);
+            }
             self.map.remove(removed);
+            self.restamped.remove(&key(&removed.0));
         }
         smallest.map(|wrapped| wrapped.0)
     }
 
+    // Throws away the processed messages received before gvt in one go, returns how many
+    // (copies included). Nothing can be rolled back to before GVT so they are never
+    // processed again, as long as no saved state still has to coast over them (see
+    // Machine::commit for what it passes). Anything not processed yet stays.
+    pub fn truncate_below(&mut self, gvt: T) -> usize {
+        let kept = self.map.split_off(&WrappedMessage::probe(gvt));
+        let below = std::mem::replace(&mut self.map, kept);
+        let mut removed = 0;
+        for (wrapped, ()) in below {
+            if !self.is_processed(&wrapped.0) {
+                self.map.insert(wrapped, ());
+                continue;
+            }
+            removed += 1 + self.copies.remove(&key(&wrapped.0)).unwrap_or(0);
+            self.restamped.remove(&key(&wrapped.0));
+            pool::recycle(wrapped.0.message);
+        }
+        removed
+    }
+
     // Remove the smallest element greater than the threshold, this
     // will end up being the next message that should be processed by the 
     // machine ie greater than the local time of the machine 
-    pub fn peek_smallest_greater(&mut self) -> Option<Message> {
-        let smallest_g = self
-            .map
+    pub fn peek_smallest_greater(&self) -> Option<Message<T>> {
+        let Some(threshold) = self.threshold else {
+            return self.map.keys().next().map(|wrapped| wrapped.0.clone());
+        };
+        self.map
             .range((
-                Bound::Excluded(&WrappedMessage(Message::new(
-                    0,
-                    self.threshold,
-                    0,
-                    0,
-                    super::message::Sign::Message,
-                    Arc::new("data".to_string()),
-                ))),
+                Bound::Included(&WrappedMessage::probe(threshold)),
                 Bound::Unbounded,
             ))
-            .next()
-            .map(|(msg, _)| msg.clone());
-        smallest_g.map(|wrapped| wrapped.0)
+            .map(|(wrapped, _)| &wrapped.0)
+            .find(|message| !self.is_processed(message))
+            .cloned()
     }
 
-    // Machine needs to reset its pointer when rolling back
-    pub fn update_threshold(&mut self, new_thresh : usize) {
+    // The earliest message not processed yet that isnt an antimessage
+    pub fn peek_positive(&self) -> Option<&Message<T>> {
+        self.map
+            .keys()
+            .map(|wrapped| &wrapped.0)
+            .skip_while(|message| self.is_processed(message))
+            .find(|message| message.sign == Sign::Message)
+    }
+
+    // Throws away the antimessages not processed yet that are received before bound (all
+    // of them with no bound), returns how many. Only for antimessages whose positive
+    // message is never coming, nothing else can be below GVT in front of the threshold.
+    pub fn discard_antimessages_below(&mut self, bound: Option<T>) -> usize {
+        let orphans: Vec<_> = self
+            .map
+            .keys()
+            .filter(|wrapped| wrapped.0.sign == Sign::Antimessage)
+            .filter(|wrapped| !self.is_processed(&wrapped.0))
+            .filter(|wrapped| bound.is_none_or(|bound| wrapped.0.rec_time < bound))
+            .cloned()
+            .collect();
+        for orphan in &orphans {
+            self.map.remove(orphan);
+            self.restamped.remove(&key(&orphan.0));
+            self.copies.remove(&key(&orphan.0));
+        }
+        orphans.len()
+    }
+
+    // The messages on one port (None for the ones without a port) not processed yet,
+    // in the order they will be
+    pub fn pending_on<'a>(
+        &'a self,
+        port: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a Message<T>> + 'a {
+        self.pending().filter(move |message| message.port() == port)
+    }
+
+    // How many messages at its receive time go before the message, whether it is in the
+    // queue or not. Antimessages waiting for their message dont count.
+    pub fn position(&self, message: &Message<T>) -> usize {
+        let start = WrappedMessage::probe(message.rec_time);
+        let end = WrappedMessage::new(message.clone());
+        self.map.range(start..end).filter(|(wrapped, _)| wrapped.0.sign == Sign::Message).count()
+    }
+
+    // Whether the message (or the one an antimessage is for) is at or before the place
+    // processing got to, a new one that is would be a straggler
+    pub fn is_processed(&self, message: &Message<T>) -> bool {
+        let Some(threshold) = self.threshold else {
+            return false;
+        };
+        match message.rec_time.cmp(&threshold) {
+            Ordering::Less => true,
+            Ordering::Equal => self.processed_up_to.is_none_or(|last| tie(message) <= last),
+            Ordering::Greater => false,
+        }
+    }
+
+    // Counts the messages that have already been processed (at or below the threshold)
+    // with a receive time of at least from and below to, if there is an upper bound
+    pub fn count_processed(&self, from: T, to: Option<T>) -> usize {
+        self.processed_from(from, to).count()
+    }
+
+    // The messages count_processed counts, in order
+    pub fn processed_from(
+        &self,
+        from: T,
+        to: Option<T>,
+    ) -> impl Iterator<Item = &Message<T>> + '_ {
+        self.map
+            .range((Bound::Included(&WrappedMessage::probe(from)), Bound::Unbounded))
+            .map(|(wrapped, _)| &wrapped.0)
+            .take_while(move |message| {
+                self.is_processed(message) && to.is_none_or(|to| message.rec_time < to)
+            })
+            .filter(|message| message.sign == super::message::Sign::Message)
+    }
+
+    // The processed messages received after `after` (from the start if None) and up to
+    // `up_to`, in order
+    pub fn processed_between(&self, after: Option<T>, up_to: T) -> Vec<Message<T>> {
+        self.map
+            .keys()
+            .map(|wrapped| &wrapped.0)
+            .skip_while(|message| after.is_some_and(|after| message.rec_time <= after))
+            .take_while(|message| message.rec_time <= up_to && self.is_processed(message))
+            .filter(|message| message.sign == super::message::Sign::Message)
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len() + self.copies.values().sum::<usize>()
+    }
+
+    // Everything in the queue in the order it is (or was) processed, antimessages and
+    // all. A message with extra copies (see DuplicatePolicy) shows up once.
+    pub fn iter(&self) -> impl Iterator<Item = &Message<T>> + '_ {
+        self.map.keys().map(|wrapped| &wrapped.0)
+    }
+
+    // The part of iter at or before the threshold, what has been processed so far
+    pub fn processed(&self) -> impl Iterator<Item = &Message<T>> + '_ {
+        self.iter().take_while(|message| self.is_processed(message))
+    }
+
+    // The rest, what is still to be processed in the order it will be
+    pub fn pending(&self) -> impl Iterator<Item = &Message<T>> + '_ {
+        self.iter().skip_while(|message| self.is_processed(message))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    // Largest message id in the queue
+    pub fn max_message_id(&self) -> Option<MessageId> {
+        self.map.keys().map(|wrapped| wrapped.0.id).max()
+    }
+
+    pub fn threshold(&self) -> Option<T> {
+        self.threshold
+    }
+
+    // Machine needs to reset its pointer when rolling back, everything at or before the
+    // new threshold counts as processed
+    pub fn update_threshold(&mut self, new_thresh : Option<T>) {
         self.threshold = new_thresh;
+        self.processed_up_to = None;
+    }
+
+    // Moves the threshold to just this message, the ones after it at the same time are
+    // still to be processed
+    pub fn advance_to(&mut self, message: &Message<T>) {
+        self.threshold = Some(message.rec_time);
+        self.processed_up_to = Some(tie(message));
     }
 
     // Print the priority queue (for debugging)
-    fn print(&self) {
+    pub fn print(&self) {
         for wrapped_message in self.map.keys() {
             println!("{:?}", wrapped_message);
         }
@@ -121,38 +533,56 @@ mod tests {
 
     #[test]
     fn test_priority_queue_operations() {
-        let mut priority_queue = InputQueue::new(5);
+        let mut priority_queue = InputQueue::new(Some(VirtualTime::new(5)));
 
         let message1 = Message {
-            send_time: 1,
-            rec_time: 10,
+            id: 1,
+            send_time: VirtualTime::new(1),
+            rec_time: VirtualTime::new(10),
             sender: 1,
             receiver: 2,
             sign: Sign::Message,
             message: Arc::new("Hello".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
         };
 
         let message2 = Message {
-            send_time: 2,
-            rec_time: 5,
+            id: 2,
+            send_time: VirtualTime::new(2),
+            rec_time: VirtualTime::new(5),
             sender: 2,
             receiver: 1,
             sign: Sign::Message,
             message: Arc::new("World".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
         };
 
         let message3 = Message {
-            send_time: 3,
-            rec_time: 8,
+            id: 3,
+            send_time: VirtualTime::new(3),
+            rec_time: VirtualTime::new(8),
             sender: 3,
             receiver: 2,
             sign: Sign::Message,
             message: Arc::new("!".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
         };
 
-        priority_queue.insert(message1.clone());
-        priority_queue.insert(message2.clone());
-        priority_queue.insert(message3.clone());
+        priority_queue.insert(message1.clone()).unwrap();
+        priority_queue.insert(message2.clone()).unwrap();
+        priority_queue.insert(message3.clone()).unwrap();
 
         assert_eq!(
             priority_queue.remove_smallest(),
@@ -172,84 +602,267 @@ mod tests {
 
     #[test]
     fn test_priority_queue_with_duplicates() {
-        let mut priority_queue = InputQueue::new(5);
+        let mut priority_queue = InputQueue::new(Some(VirtualTime::new(5)));
 
         // Define messages as variables
         let mut message1 = Message {
-            send_time: 2,
-            rec_time: 5,
+            id: 4,
+            send_time: VirtualTime::new(2),
+            rec_time: VirtualTime::new(5),
             sender: 1,
             receiver: 2,
             sign: Sign::Message,
             message: Arc::new("Duplicate".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
         };
 
-        priority_queue.insert(message1.clone());
-        priority_queue.insert(message1.clone());
-
- 
-        assert_eq!(priority_queue.remove_smallest(), None);
+        // A second copy of a positive message is dropped instead of cancelling it
+        priority_queue.insert(message1.clone()).unwrap();
+        priority_queue.insert(message1.clone()).unwrap();
+        assert_eq!(priority_queue.len(), 1);
 
-        priority_queue.insert(message1.clone());
         message1.sign = Sign::Antimessage;
-        priority_queue.insert(message1.clone());
+        priority_queue.insert(message1.clone()).unwrap();
 
  
         assert_eq!(priority_queue.remove_smallest(), None);
     }
 
+    #[test]
+    fn test_duplicate_policies() {
+        let message = Message::new(2, 5, 1, 2, Sign::Message, Arc::new("copy".to_string()));
+        let mut antimessage = message.clone();
+        antimessage.sign = Sign::Antimessage;
+
+        let mut rejecting = InputQueue::new(None);
+        rejecting.set_duplicate_policy(DuplicatePolicy::RejectDuplicates);
+        rejecting.insert(message.clone()).unwrap();
+        assert_eq!(rejecting.insert(message.clone()), Err(InsertError::Duplicate(message.id)));
+        rejecting.insert(antimessage.clone()).unwrap();
+        assert!(rejecting.is_empty());
+
+        // Two copies take two antimessages
+        let mut allowing = InputQueue::new(None);
+        allowing.set_duplicate_policy(DuplicatePolicy::AllowDuplicates);
+        allowing.insert(message.clone()).unwrap();
+        allowing.insert(message.clone()).unwrap();
+        assert_eq!(allowing.len(), 2);
+        allowing.insert(antimessage.clone()).unwrap();
+        assert_eq!(allowing.peek_smallest_greater(), Some(message.clone()));
+        allowing.insert(antimessage).unwrap();
+        assert!(allowing.is_empty());
+
+        // A different message at the same time is kept next to the queued one
+        let other = Message::new(3, 5, 3, 2, Sign::Message, Arc::new("other".to_string()));
+        let mut queue = InputQueue::new(None);
+        queue.insert(message.clone()).unwrap();
+        queue.insert(other.clone()).unwrap();
+        assert_eq!(queue.remove_smallest(), Some(message));
+        assert_eq!(queue.remove_smallest(), Some(other));
+    }
+
+    // A message with an id of its own choosing, ids are only unique per sender
+    fn with_id(id: MessageId, send_time: usize, rec_time: usize, sender: MachineId) -> Message {
+        let payload = Arc::default();
+        let mut message = Message::new(send_time, rec_time, sender, 2, Sign::Message, payload);
+        message.id = id;
+        message
+    }
+
+    fn antimessage(message: &Message) -> Message {
+        Message {
+            sign: Sign::Antimessage,
+            ..message.clone()
+        }
+    }
+
+    #[test]
+    fn test_copies_are_counted_per_sender() {
+        let mut queue = InputQueue::new(None);
+        queue.set_duplicate_policy(DuplicatePolicy::AllowDuplicates);
+        let copied = with_id(7, 1, 5, 1);
+        let same_id = with_id(7, 1, 6, 2);
+        for message in [copied.clone(), copied.clone(), same_id.clone()] {
+            queue.insert(message).unwrap();
+        }
+
+        // Cancels the message from sender 2, not one of the copies from sender 1
+        queue.insert(antimessage(&same_id)).unwrap();
+        assert!(!queue.contains(&same_id));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.remove_smallest(), Some(copied.clone()));
+        assert_eq!(queue.remove_smallest(), Some(copied));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_batches_end_up_like_single_inserts() {
+        let at = |rec_time: usize, sender| {
+            Message::new(0, rec_time, sender, 2, Sign::Message, Arc::new(String::new()))
+        };
+        let queued = [at(3, 1), at(5, 1)];
+        let burst = vec![at(9, 1), at(7, 2), at(7, 1), at(8, 3)];
+        let mut between = burst.clone();
+        between.push(at(4, 2));
+        let mut cancelled = at(6, 4);
+        let cancels = Message {
+            sign: Sign::Antimessage,
+            ..cancelled.clone()
+        };
+        between.extend([cancelled.clone(), cancels]);
+        cancelled.sign = Sign::Message;
+
+        for batch in [burst, between] {
+            let mut batched = InputQueue::new(None);
+            let mut single = InputQueue::new(None);
+            for queue in [&mut batched, &mut single] {
+                for message in &queued {
+                    queue.insert(message.clone()).unwrap();
+                }
+            }
+            let inserted = batched.insert_batch(batch.clone());
+            assert_eq!(inserted.earliest, batch.iter().map(|message| message.rec_time).min());
+            assert!(inserted.refused.is_empty());
+            for message in batch {
+                single.insert(message).unwrap();
+            }
+            assert!(batched.iter().eq(single.iter()));
+            assert!(batched.iter().all(|message| message.id != cancelled.id));
+        }
+    }
+
+    #[test]
+    fn test_truncate_below_only_takes_processed_messages() {
+        let at = |rec_time: usize| Message::new(0, rec_time, 1, 2, Sign::Message, Arc::default());
+        let mut queue = InputQueue::new(None);
+        queue.set_duplicate_policy(DuplicatePolicy::AllowDuplicates);
+        let copied = at(2);
+        for message in [copied.clone(), copied, at(4), at(6), at(9)] {
+            queue.insert(message).unwrap();
+        }
+        queue.update_threshold(Some(VirtualTime::new(4)));
+
+        // 6 is below GVT but hasnt been processed
+        assert_eq!(queue.truncate_below(VirtualTime::new(7)), 3);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.truncate_below(VirtualTime::new(7)), 0);
+        assert_eq!(queue.peek_smallest_greater().unwrap().rec_time, 6);
+    }
+
+    #[test]
+    fn test_same_time_messages_have_a_fixed_order() {
+        let at_four = |sender, generation| {
+            let mut message =
+                Message::new(1, 4, sender, 0, Sign::Message, Arc::new(String::new()));
+            message.generation = generation;
+            message
+        };
+        let sent_on = at_four(1, 1);
+        let (late, early) = (at_four(3, 0), at_four(2, 0));
+        let mut queue = InputQueue::new(None);
+        for message in [sent_on.clone(), late.clone(), early.clone()] {
+            queue.insert(message).unwrap();
+        }
+        assert_eq!(queue.peek_smallest_greater(), Some(early.clone()));
+        queue.advance_to(&early);
+        assert_eq!(queue.peek_smallest_greater(), Some(late.clone()));
+
+        // Only what goes before the place processing got to counts as processed
+        let straggler = at_four(1, 0);
+        assert!(queue.is_processed(&straggler));
+        assert!(!queue.is_processed(&late));
+        queue.advance_to(&late);
+        assert_eq!(queue.peek_smallest_greater(), Some(sent_on));
+        queue.update_threshold(Some(VirtualTime::new(4)));
+        assert_eq!(queue.peek_smallest_greater(), None);
+    }
+
     #[test]
     fn test_priority_queue_edge_cases() {
-        let mut priority_queue = InputQueue::new(5);
+        let mut priority_queue = InputQueue::new(Some(VirtualTime::new(5)));
 
         let message1 = Message {
-            send_time: 1,
-            rec_time: 7,
+            id: 5,
+            send_time: VirtualTime::new(1),
+            rec_time: VirtualTime::new(7),
             sender: 1,
             receiver: 2,
             sign: Sign::Message,
             message: Arc::new("Edge".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
         };
 
         let message2 = Message {
-            send_time: 2,
-            rec_time: 3,
+            id: 6,
+            send_time: VirtualTime::new(2),
+            rec_time: VirtualTime::new(3),
             sender: 2,
             receiver: 1,
             sign: Sign::Message,
             message: Arc::new("Cases".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
         };
 
         let message3 = Message {
-            send_time: 3,
-            rec_time: 8,
+            id: 7,
+            send_time: VirtualTime::new(3),
+            rec_time: VirtualTime::new(8),
             sender: 3,
             receiver: 2,
             sign: Sign::Message,
             message: Arc::new("Testing".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
         };
 
         let message4 = Message {
-            send_time: 4,
-            rec_time: 4,
+            id: 8,
+            send_time: VirtualTime::new(4),
+            rec_time: VirtualTime::new(4),
             sender: 4,
             receiver: 1,
             sign: Sign::Message,
             message: Arc::new("More".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
         };
         
         let message5 = Message {
-            send_time: 5,
-            rec_time: 6,
+            id: 9,
+            send_time: VirtualTime::new(5),
+            rec_time: VirtualTime::new(6),
             sender: 5,
             receiver: 2,
             sign: Sign::Message,
             message: Arc::new("Tests".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
         };
 
-        priority_queue.insert(message1.clone());
-        priority_queue.insert(message2.clone());
-        priority_queue.insert(message3.clone());
+        priority_queue.insert(message1.clone()).unwrap();
+        priority_queue.insert(message2.clone()).unwrap();
+        priority_queue.insert(message3.clone()).unwrap();
 
         // Remove smallest element >= threshold
         assert_eq!(
@@ -262,8 +875,8 @@ mod tests {
         );
 
         // Insert messages again
-        priority_queue.insert(message4.clone());
-        priority_queue.insert(message5.clone());
+        priority_queue.insert(message4.clone()).unwrap();
+        priority_queue.insert(message5.clone()).unwrap();
 
         // Remove smallest element
         assert_eq!(
@@ -288,4 +901,85 @@ mod tests {
         );
         assert_eq!(priority_queue.remove_smallest(), None);
     }
+
+    #[test]
+    fn test_fifo_keeps_send_order_per_sender() {
+        let mut queue = InputQueue::new(None);
+        queue.set_fifo(true);
+
+        let sent_first = Message::new(1, 9, 1, 2, Sign::Message, Arc::new("a".to_string()));
+        let other_sender = Message::new(2, 10, 3, 2, Sign::Message, Arc::new("b".to_string()));
+        let mut sent_later = Message::new(2, 4, 1, 2, Sign::Message, Arc::new("c".to_string()));
+        for mut message in [sent_first.clone(), other_sender.clone()] {
+            queue.fifo_stamp(&mut message);
+            queue.insert(message).unwrap();
+        }
+        queue.fifo_stamp(&mut sent_later);
+        // Right after the earlier message from the same sender
+        assert_eq!(sent_later.rec_time, 10);
+        queue.insert(sent_later.clone()).unwrap();
+
+        // The antimessage for it has to annihilate it where it was moved to
+        let mut antimessage = sent_later.clone();
+        antimessage.rec_time = VirtualTime::new(4);
+        antimessage.sign = Sign::Antimessage;
+        queue.fifo_stamp(&mut antimessage);
+        queue.insert(antimessage).unwrap();
+
+        assert_eq!(queue.remove_smallest(), Some(sent_first));
+        assert_eq!(queue.remove_smallest(), Some(other_sender));
+        assert_eq!(queue.remove_smallest(), None);
+    }
+
+    #[test]
+    fn test_restamps_are_kept_per_sender() {
+        let mut queue = InputQueue::new(None);
+        queue.set_fifo(true);
+        let sent_first = with_id(1, 1, 10, 1);
+        let restamped = with_id(2, 2, 5, 1);
+        let same_id = with_id(2, 1, 20, 2);
+        for mut message in [sent_first.clone(), restamped, same_id] {
+            queue.fifo_stamp(&mut message);
+            queue.insert(message).unwrap();
+        }
+
+        // Only the antimessage from sender 1 follows its message to 11
+        for cancelled in [with_id(2, 1, 20, 2), with_id(2, 2, 5, 1)] {
+            let mut antimessage = antimessage(&cancelled);
+            queue.fifo_stamp(&mut antimessage);
+            assert_eq!(antimessage.rec_time, if cancelled.sender == 1 { 11 } else { 20 });
+            queue.insert(antimessage).unwrap();
+        }
+        assert_eq!(queue.remove_smallest(), Some(sent_first));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_pending_messages_by_port() {
+        let mut ctx = crate::process::Context::new(0, 0);
+        ctx.send_to_port(1, "control", 2, "stop".to_string());
+        ctx.send_to_port(1, "data", 3, "x".to_string());
+        ctx.send(1, 4, "plain".to_string());
+        ctx.send_to_port(1, "data", 5, "y".to_string());
+
+        let mut queue = InputQueue::new(None);
+        for message in ctx.into_outbox() {
+            queue.insert(message).unwrap();
+        }
+        queue.update_threshold(Some(VirtualTime::new(3)));
+        let payloads = |port| -> Vec<String> {
+            queue.pending_on(port).map(|message| message.message.to_string()).collect()
+        };
+        assert_eq!(payloads(Some("data")), vec!["y"]);
+        assert_eq!(payloads(Some("control")), Vec::<String>::new());
+        assert_eq!(payloads(None), vec!["plain"]);
+        assert_eq!(queue.peek_smallest_greater().unwrap().port(), None);
+
+        let port = |message: &Message| message.port.clone().unwrap_or_default();
+        let processed: Vec<_> = queue.processed().map(port).collect();
+        let pending: Vec<_> = queue.pending().map(port).collect();
+        assert_eq!(processed, ["control".into(), "data".into()]);
+        assert_eq!(pending, ["".into(), "data".into()]);
+        assert_eq!(queue.iter().count(), 4);
+    }
 }
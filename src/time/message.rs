@@ -1,57 +1,223 @@
 
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 pub type MachineId = usize;
-pub type VirtualTime = usize;
+pub use super::timestamp::Timestamp;
+pub use super::virtual_time::{Delay, TimeOverflow, VirtualTime};
 pub type MessagePayload = String;
+pub type MessageId = usize;
+// Named inputs of a machine, see Message::port
+pub type Port = Arc<str>;
 
-// This is just wrapper around a payload that is being sent.
-#[derive(Debug, Clone, Hash)]
-pub struct Message {
-    pub send_time : VirtualTime,
-    pub rec_time : VirtualTime,
+// Ids are handed out from a single counter so every message created in this process
+// is unique, clones (including the antimessage made from a message) keep the id
+static NEXT_MESSAGE_ID: AtomicUsize = AtomicUsize::new(1);
+
+fn next_message_id() -> MessageId {
+    NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+// Makes sure ids handed out from now on are bigger than the given one, needed when
+// messages created by an earlier process (like a checkpoint) are loaded back in
+pub fn reserve_message_ids(used: MessageId) {
+    NEXT_MESSAGE_ID.fetch_max(used + 1, Ordering::Relaxed);
+}
+
+// This is just wrapper around a payload that is being sent. The times are VirtualTime
+// unless the message is on another time axis (see Timestamp), only the queues handle
+// those.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message<T = VirtualTime> {
+    pub id : MessageId,
+    pub send_time : T,
+    pub rec_time : T,
     pub sender : MachineId,
     pub receiver : MachineId,
     pub sign : Sign,
     pub message : Arc<MessagePayload>,
+    // Which input of the receiver the message is for, like "control" or "data". Most
+    // machines only have one and leave it None, the ones with several match on it.
+    #[serde(default)]
+    pub port : Option<Port>,
+    // The event that sent it, the id of the message its sender was processing. None for
+    // messages from outside the simulation.
+    #[serde(default)]
+    pub parent : Option<MessageId>,
+    // How many sends with no delay in a row led to this message, 0 when it was sent with
+    // a delay or came from outside. Messages received at the same time are processed by
+    // it first (see InputQueue), which puts one sent with no delay after the event that
+    // sent it.
+    #[serde(default)]
+    pub generation : u32,
+    // Binary payload, for models that send frames instead of text so they dont have to
+    // encode them into the string (see Context::send_bytes). A message carries one or the
+    // other, the text is left empty when this is set. Clones share the bytes, so
+    // antimessages, copies and whatever a runtime forwards dont copy them.
+    #[serde(default)]
+    pub data : Option<Bytes>,
+    // Set by a sender that cancels directly (see Machine::set_direct_cancellation), every
+    // clone shares it so the copy in the receivers queue is the one the sender flags.
+    // It only works within one process so it is never serialized.
+    #[serde(skip)]
+    pub handle : Option<CancelHandle>,
 }
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Sign {
     Message,
     Antimessage,
 }
 
+// Whether the sender took a message back without an antimessage, see Message::handle
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
 impl Message {
+    // The times can be given as plain integers, see VirtualTime
     pub fn new(
-        send_time: VirtualTime,
-        rec_time: VirtualTime,
+        send_time: impl Into<VirtualTime>,
+        rec_time: impl Into<VirtualTime>,
+        sender: MachineId,
+        receiver: MachineId,
+        sign: Sign,
+        message: Arc<MessagePayload>,
+    ) -> Self {
+        Message::at(send_time.into(), rec_time.into(), sender, receiver, sign, message)
+    }
+}
+
+impl<T: Timestamp> Message<T> {
+    // Message::new for any time axis
+    pub fn at(
+        send_time: T,
+        rec_time: T,
         sender: MachineId,
         receiver: MachineId,
         sign: Sign,
         message: Arc<MessagePayload>,
     ) -> Self {
         Self {
+            id: next_message_id(),
             send_time,
             rec_time,
             sender,
             receiver,
             sign,
             message,
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
+        }
+    }
+
+    pub fn with_port(mut self, port: impl Into<Port>) -> Self {
+        self.port = Some(port.into());
+        self
+    }
+
+    pub fn port(&self) -> Option<&str> {
+        self.port.as_deref()
+    }
+
+    pub fn with_data(mut self, data: impl Into<Bytes>) -> Self {
+        self.data = Some(data.into());
+        self
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.handle.as_ref().is_some_and(CancelHandle::is_cancelled)
+    }
+
+    // The payload as bytes, the binary one if there is one and the text otherwise
+    pub fn payload_bytes(&self) -> &[u8] {
+        match &self.data {
+            Some(data) => data,
+            None => self.message.as_bytes(),
         }
     }
 }
 // Messages with opposite signs are equivalent
-// This is because they should be treated as duplicates and 
+// This is because they should be treated as duplicates and
 // eliminated from the queues they are in.
-impl Eq for Message {}
+// The payload used to be compared by pointer but that doesnt survive being
+// serialized, so the id assigned when the message was created is compared instead.
+impl<T: Timestamp> Eq for Message<T> {}
 
-impl PartialEq for Message {
+impl<T: Timestamp> PartialEq for Message<T> {
     fn eq(&self, other: &Self) -> bool {
+        self.id == other.id &&
         self.send_time == other.send_time &&
         self.rec_time == other.rec_time &&
         self.sender == other.sender &&
-        self.receiver == other.receiver &&
-        Arc::ptr_eq(&self.message, &other.message)
+        self.receiver == other.receiver
+    }
+}
+
+// Hash has to agree with the equality above so the sign and payload are left out
+impl<T: Timestamp> Hash for Message<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+        self.send_time.hash(state);
+        self.rec_time.hash(state);
+        self.sender.hash(state);
+        self.receiver.hash(state);
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serde_round_trip_keeps_identity() {
+        let message = Message::new(1, 4, 1, 2, Sign::Message, Arc::new("payload".to_string()));
+
+        let encoded = serde_json::to_string(&message).unwrap();
+        let mut decoded: Message = serde_json::from_str(&encoded).unwrap();
+
+        assert_eq!(decoded, message);
+        assert_eq!(decoded.message, message.message);
+        assert!(!Arc::ptr_eq(&decoded.message, &message.message));
+
+        // An antimessage that went over the wire still cancels the original
+        decoded.sign = Sign::Antimessage;
+        assert_eq!(decoded, message);
+
+        let other = Message::new(1, 4, 1, 2, Sign::Message, Arc::new("payload".to_string()));
+        assert_ne!(other, message);
+    }
+
+    #[test]
+    fn test_binary_payloads_are_shared_not_copied() {
+        let frame = Bytes::from_static(&[0, 159, 146, 150]);
+        let message = Message::new(0, 2, 1, 2, Sign::Message, Arc::new(String::new()))
+            .with_data(frame.clone());
+        assert_eq!(message.payload_bytes(), &frame[..]);
+        let antimessage = Message {
+            sign: Sign::Antimessage,
+            ..message.clone()
+        };
+        assert_eq!(antimessage.payload_bytes().as_ptr(), frame.as_ptr());
+
+        let encoded = bincode::serialize(&message).unwrap();
+        let decoded: Message = bincode::deserialize(&encoded).unwrap();
+        assert_eq!(decoded.data, Some(frame));
+        let text = Message::new(0, 2, 1, 2, Sign::Message, Arc::new("ab".to_string()));
+        assert_eq!(text.payload_bytes(), b"ab");
+    }
+}
@@ -1,3 +1,7 @@
 pub mod output_queue;
 pub mod message;
-pub mod input_queue;
\ No newline at end of file
+pub mod input_queue;
+pub mod in_flight;
+pub mod pool;
+pub mod timestamp;
+pub mod virtual_time;
@@ -1,20 +1,40 @@
 use std::cmp::Ordering;
 use std::collections::BTreeSet;
-use std::ops::Bound::{Excluded, Included};
 use std::sync::Arc;
 
-use super::message::{self, Message};
-// Wrapper for sorting by send_time
-#[derive(Debug, Clone, Eq, PartialEq)]
-pub struct MessageBySendTime(pub Message);
+use serde::{Deserialize, Serialize};
 
-impl Ord for MessageBySendTime {
+use super::message::{Message, MessageId, Sign, Timestamp, VirtualTime};
+use super::pool;
+// Wrapper for sorting by send_time, and by id among the messages sent at the same time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageBySendTime<T = VirtualTime>(pub Message<T>);
+
+impl<T: Timestamp> MessageBySendTime<T> {
+    // Stands in for the first message sent at send_time when looking the queue up, real
+    // ids start at 1 so it goes before all of them
+    fn probe(send_time: T) -> Self {
+        let mut probe = Message::at(send_time, T::default(), 0, 0, Sign::Message, Arc::default());
+        probe.id = 0;
+        MessageBySendTime(probe)
+    }
+}
+
+impl<T: Timestamp> PartialEq for MessageBySendTime<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T: Timestamp> Eq for MessageBySendTime<T> {}
+
+impl<T: Timestamp> Ord for MessageBySendTime<T> {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.0.send_time.cmp(&other.0.send_time)
+        self.0.send_time.cmp(&other.0.send_time).then(self.0.id.cmp(&other.0.id))
     }
 }
 
-impl PartialOrd for MessageBySendTime {
+impl<T: Timestamp> PartialOrd for MessageBySendTime<T> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
@@ -23,20 +43,28 @@ impl PartialOrd for MessageBySendTime {
 // The output queue is just a priority queue of sent messages but is still
 // a little special because at times we need to access elemens that are not the lowest
 // priority element. This is where the range function comes in. Also like the input_queue
-// duplicates are always eliminated to support the message/antimessage system.
-#[derive(Debug, Default)]
-pub struct OutputQueue {
-    set: BTreeSet<MessageBySendTime>,
+// duplicates are always eliminated to support the message/antimessage system. Like the
+// input queue it works on any time axis.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound(deserialize = "T: Timestamp + Deserialize<'de>"))]
+pub struct OutputQueue<T = VirtualTime> {
+    set: BTreeSet<MessageBySendTime<T>>,
 }
 
-impl OutputQueue {
+impl<T: Timestamp> Default for OutputQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Timestamp> OutputQueue<T> {
     pub fn new() -> Self {
         Self {
             set: BTreeSet::new(),
         }
     }
 
-    pub fn push(&mut self, message: Message) {
+    pub fn push(&mut self, message: Message<T>) {
         let wrapped_message = MessageBySendTime(message);
         if self.set.contains(&wrapped_message) {
             self.set.remove(&wrapped_message);
@@ -45,7 +73,7 @@ impl OutputQueue {
         }
     }
 
-    pub fn pop(&mut self) -> Option<Message> {
+    pub fn pop(&mut self) -> Option<Message<T>> {
         if let Some(first) = self.set.iter().next().cloned() {
             self.set.remove(&first);
             Some(first.0)
@@ -54,31 +82,65 @@ impl OutputQueue {
         }
     }
 
-    // Get all the messages within a range, does not remove the elements
-    pub fn range(&self, start: usize, end: usize) -> Vec<Message> {
-        let start = MessageBySendTime(Message {
-            send_time: start,
-            rec_time: 0,
-            receiver: 0,
-            sender: 0,
-            sign: super::message::Sign::Message,
-            message: Arc::new(String::new()),
-        });
-        let end = MessageBySendTime(Message {
-            send_time: end,
-            rec_time: 0,
-            receiver: 0,
-            sender: 0,
-            sign: super::message::Sign::Message,
-            message: Arc::new(String::new()),
-        });
+    pub fn len(&self) -> usize {
+        self.set.len()
+    }
+
+    // Every logged send in send time order
+    pub fn iter(&self) -> impl Iterator<Item = &Message<T>> + '_ {
+        self.set.iter().map(|wrapped| &wrapped.0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+
+    // Get all the messages sent from start up to and including end, does not remove the
+    // elements
+    pub fn range(&self, start: T, end: T) -> Vec<Message<T>> {
+        let start = MessageBySendTime::probe(start);
 
         self.set
-            .range(&start..=&end)
+            .range(&start..)
+            .take_while(|element| element.0.send_time <= end)
             .cloned()
             .map(|element| element.0)
             .collect()
     }
+
+    // Largest message id in the queue
+    pub fn max_message_id(&self) -> Option<MessageId> {
+        self.set.iter().map(|wrapped| wrapped.0.id).max()
+    }
+
+    // Removes every message sent within the range and hands back the originals. This is
+    // what a rollback uses to unsend messages, the caller is responsible for turning the
+    // originals into antimessages and getting them to the receivers.
+    pub fn cancel_range(&mut self, start: T, end: T) -> Vec<Message<T>> {
+        let cancelled = self.range(start, end);
+        for message in &cancelled {
+            self.set.remove(&MessageBySendTime(message.clone()));
+        }
+        cancelled
+    }
+
+    // Throws away everything sent before gvt in one go and returns how many. No rollback
+    // can go back that far anymore, so none of it will ever have to be cancelled.
+    pub fn truncate_below(&mut self, gvt: T) -> usize {
+        let kept = self.set.split_off(&MessageBySendTime::probe(gvt));
+        let dropped = std::mem::replace(&mut self.set, kept);
+        let count = dropped.len();
+        for wrapped in dropped {
+            pool::recycle(wrapped.0.message);
+        }
+        count
+    }
+
+    // Roughly what the logged sends take in memory, the messages and their payloads
+    pub fn size_bytes(&self) -> usize {
+        let message = std::mem::size_of::<Message<T>>();
+        self.set.iter().map(|wrapped| message + wrapped.0.payload_bytes().len()).sum()
+    }
 }
 
 #[cfg(test)]
@@ -90,30 +152,48 @@ mod tests {
     #[test]
     fn test_push_pop_send_time() {
         let msg1 = Message {
-            send_time: 1,
-            rec_time: 0,
+            id: 1,
+            send_time: VirtualTime::new(1),
+            rec_time: VirtualTime::new(0),
             sender: 0,
             receiver: 0,
             sign: Sign::Message,
             message: Arc::new("Test".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
         };
 
         let msg2 = Message {
-            send_time: 2,
-            rec_time: 0,
+            id: 2,
+            send_time: VirtualTime::new(2),
+            rec_time: VirtualTime::new(0),
             sender: 0,
             receiver: 0,
             sign: Sign::Message,
             message: Arc::new("MessagePayload".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
         };
 
         let msg3 = Message {
-            send_time: 3,
-            rec_time: 0,
+            id: 3,
+            send_time: VirtualTime::new(3),
+            rec_time: VirtualTime::new(0),
             sender: 0,
             receiver: 0,
             sign: Sign::Message,
             message: Arc::new("MessagePayload".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
         };
 
         let mut pq = OutputQueue::new();
@@ -141,12 +221,18 @@ mod tests {
     #[test]
     fn test_push_duplicate() {
         let msg1 = Message {
-            send_time: 1,
-            rec_time: 5,
+            id: 4,
+            send_time: VirtualTime::new(1),
+            rec_time: VirtualTime::new(5),
             sender: 1,
             receiver: 2,
             sign: Sign::Message,
             message: Arc::new("MessagePayload".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
         };
         assert_eq!(msg1, msg1);
 
@@ -160,30 +246,48 @@ mod tests {
     #[test]
     fn test_range() {
         let msg1 = Message {
-            send_time: 1,
-            rec_time: 0,
+            id: 5,
+            send_time: VirtualTime::new(1),
+            rec_time: VirtualTime::new(0),
             sender: 0,
             receiver: 0,
             sign: Sign::Message,
             message: Arc::new("Test".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
         };
 
         let msg2 = Message {
-            send_time: 2,
-            rec_time: 0,
+            id: 6,
+            send_time: VirtualTime::new(2),
+            rec_time: VirtualTime::new(0),
             sender: 0,
             receiver: 0,
             sign: Sign::Message,
             message: Arc::new("MessagePayload".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
         };
 
         let msg3 = Message {
-            send_time: 3,
-            rec_time: 0,
+            id: 7,
+            send_time: VirtualTime::new(3),
+            rec_time: VirtualTime::new(0),
             sender: 0,
             receiver: 0,
             sign: Sign::Message,
             message: Arc::new("MessagePayload".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
         };
 
         let mut pq = OutputQueue::new();
@@ -191,17 +295,98 @@ mod tests {
         pq.push(msg2.clone());
         pq.push(msg3.clone());
 
-        let range_result = pq.range(1, 3);
+        let range_result = pq.range(VirtualTime::new(1), VirtualTime::new(3));
         assert_eq!(range_result, vec![msg1.clone(), msg2.clone(), msg3.clone()]);
 
-        let range_result = pq.range(2, 4);
+        let range_result = pq.range(VirtualTime::new(2), VirtualTime::new(4));
         assert_eq!(range_result, vec![msg2.clone(), msg3.clone()]);
 
-        let range_result = pq.range(1, 4);
+        let range_result = pq.range(VirtualTime::new(1), VirtualTime::new(4));
         assert_eq!(range_result, vec![msg1.clone(), msg2.clone(), msg3.clone()]);
 
-        let range_result = pq.range(4, 5);
+        let range_result = pq.range(VirtualTime::new(4), VirtualTime::new(5));
         assert_eq!(range_result, vec![]);
     }
 
+    #[test]
+    fn test_cancel_range() {
+        let msg1 = Message {
+            id: 8,
+            send_time: VirtualTime::new(1),
+            rec_time: VirtualTime::new(4),
+            sender: 0,
+            receiver: 1,
+            sign: Sign::Message,
+            message: Arc::new("Test".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
+        };
+
+        let msg2 = Message {
+            id: 9,
+            send_time: VirtualTime::new(2),
+            rec_time: VirtualTime::new(5),
+            sender: 0,
+            receiver: 1,
+            sign: Sign::Message,
+            message: Arc::new("MessagePayload".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
+        };
+
+        let msg3 = Message {
+            id: 10,
+            send_time: VirtualTime::new(3),
+            rec_time: VirtualTime::new(6),
+            sender: 0,
+            receiver: 1,
+            sign: Sign::Message,
+            message: Arc::new("MessagePayload".to_string()),
+            port: None,
+            parent: None,
+            generation: 0,
+            data: None,
+            handle: None,
+        };
+
+        let mut pq = OutputQueue::new();
+        pq.push(msg1.clone());
+        pq.push(msg2.clone());
+        pq.push(msg3.clone());
+
+        let cancelled = pq.cancel_range(VirtualTime::new(2), VirtualTime::new(5));
+        assert_eq!(cancelled, vec![msg2, msg3]);
+        // Originals are handed back untouched, the output queue no longer has them
+        assert!(cancelled.iter().all(|message| message.sign == Sign::Message));
+        assert_eq!(pq.range(VirtualTime::new(0), VirtualTime::new(5)), vec![msg1.clone()]);
+
+        assert_eq!(pq.cancel_range(VirtualTime::new(2), VirtualTime::new(5)), vec![]);
+        assert_eq!(pq.pop(), Some(msg1));
+    }
+
+    #[test]
+    fn test_truncate_below_keeps_what_gvt_hasnt_passed() {
+        let mut pq = OutputQueue::new();
+        for send_time in [1, 3, 3, 4, 7] {
+            pq.push(Message::new(send_time, 9, 0, 1, Sign::Message, Arc::new("ab".to_string())));
+        }
+        let one = std::mem::size_of::<Message>() + 2;
+        assert_eq!(pq.size_bytes(), 5 * one);
+
+        assert_eq!(pq.truncate_below(VirtualTime::new(3)), 1);
+        assert_eq!(pq.truncate_below(VirtualTime::new(3)), 0);
+        assert_eq!(pq.len(), 4);
+        let send_times: Vec<_> = pq.iter().map(|message| message.send_time.ticks()).collect();
+        assert_eq!(send_times, [3, 3, 4, 7]);
+        assert_eq!(pq.truncate_below(VirtualTime::new(5)), 3);
+        assert_eq!(pq.size_bytes(), one);
+        assert_eq!(pq.pop().unwrap().send_time, 7);
+    }
+
 }
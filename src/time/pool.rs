@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::sync::Arc;
+
+use super::message::MessagePayload;
+
+// Payload boxes (the Arc every message carries its payload in) that nothing points to
+// anymore, kept for the next send instead of going back to the allocator. A run creates,
+// clones and annihilates a lot of messages and the payloads are usually small, so the
+// Arc allocation is most of what a send costs the allocator.
+//
+// A payload goes back into the pool when the queues throw its message away for good,
+// committed sends, processed messages below GVT (machines with SnapshotLimits only, see
+// Machine::commit) and a message and its antimessage that cancelled each other. It is
+// only taken when that was the last reference to it, a payload still held by another
+// message (or by the process) stays where it is. Every thread has a pool of its own so
+// taking and returning never waits on a lock, a box returned on another thread than the
+// one it came from just joins that threads pool.
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+// Counters for tuning the pool, for the thread they are read on
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolStats {
+    // Payloads that needed a new allocation because the pool was empty
+    pub allocated: u64,
+    // Payloads that got a box from the pool
+    pub reused: u64,
+    // Boxes given back to the pool
+    pub recycled: u64,
+    // Payloads given back that something else still held, left alone
+    pub shared: u64,
+    // Boxes given back to a full pool, freed as usual
+    pub discarded: u64,
+    // Boxes in the pool right now
+    pub free: usize,
+}
+
+impl PoolStats {
+    // Share of payloads that didnt need an allocation, 0 when there were none
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.allocated + self.reused;
+        if total == 0 {
+            return 0.0;
+        }
+        self.reused as f64 / total as f64
+    }
+}
+
+#[derive(Debug)]
+struct Pool {
+    free: Vec<Arc<MessagePayload>>,
+    capacity: usize,
+    stats: PoolStats,
+}
+
+thread_local! {
+    static POOL: RefCell<Pool> = RefCell::new(Pool {
+        free: Vec::new(),
+        capacity: DEFAULT_CAPACITY,
+        stats: PoolStats::default(),
+    });
+}
+
+// The payload in a box from the pool, or in a new one if it is empty
+pub fn payload(payload: MessagePayload) -> Arc<MessagePayload> {
+    POOL.with_borrow_mut(|pool| match pool.free.pop() {
+        Some(mut boxed) => {
+            pool.stats.reused += 1;
+            // Only unique boxes go into the pool and nothing else can get at them there
+            *Arc::get_mut(&mut boxed).expect("pooled payloads arent shared") = payload;
+            boxed
+        }
+        None => {
+            pool.stats.allocated += 1;
+            Arc::new(payload)
+        }
+    })
+}
+
+// Gives a payload back, see the top of the file
+pub fn recycle(mut boxed: Arc<MessagePayload>) {
+    POOL.with_borrow_mut(|pool| {
+        let Some(payload) = Arc::get_mut(&mut boxed) else {
+            pool.stats.shared += 1;
+            return;
+        };
+        if pool.free.len() >= pool.capacity {
+            pool.stats.discarded += 1;
+            return;
+        }
+        // The string itself is freed now, the box is what is kept
+        *payload = MessagePayload::new();
+        pool.stats.recycled += 1;
+        pool.free.push(boxed);
+    })
+}
+
+pub fn stats() -> PoolStats {
+    POOL.with_borrow(|pool| PoolStats {
+        free: pool.free.len(),
+        ..pool.stats.clone()
+    })
+}
+
+pub fn reset_stats() {
+    POOL.with_borrow_mut(|pool| pool.stats = PoolStats::default());
+}
+
+// How many boxes the pool of this thread keeps at most, anything over it is freed
+pub fn set_capacity(capacity: usize) {
+    POOL.with_borrow_mut(|pool| {
+        pool.capacity = capacity;
+        pool.free.truncate(capacity);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::SnapshotLimits;
+    use crate::runtime::tests::ring;
+    use crate::time::message::{Message, Sign};
+
+    #[test]
+    fn test_only_unshared_payloads_come_back() {
+        reset_stats();
+        set_capacity(1);
+        let first = payload("a".to_string());
+        let held = first.clone();
+        recycle(first);
+        assert_eq!(stats().shared, 1);
+        recycle(held);
+        let second = payload("b".to_string());
+        let (third, fourth) = (payload("c".to_string()), payload("d".to_string()));
+        recycle(third);
+        recycle(fourth);
+
+        assert_eq!(second.as_str(), "b");
+        let stats = stats();
+        assert_eq!((stats.allocated, stats.reused), (3, 1));
+        assert_eq!((stats.recycled, stats.discarded, stats.free), (2, 1, 1));
+        assert_eq!(stats.hit_rate(), 0.25);
+        set_capacity(DEFAULT_CAPACITY);
+    }
+
+    #[test]
+    fn test_committed_sends_are_reused() {
+        reset_stats();
+        let mut simulation = ring(3);
+        let limits = SnapshotLimits {
+            max_saved_states: Some(4),
+            coast_forward: false,
+        };
+        for machine_id in 0..3 {
+            simulation.machine_mut(machine_id).unwrap().set_snapshot_limits(limits);
+        }
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("40".to_string())));
+        // Commits along the way free what the later sends can reuse
+        for end_time in [10, 20, 30] {
+            simulation.run_until(end_time);
+        }
+        simulation.run();
+        let stats = stats();
+        assert!(stats.reused > 0);
+        assert_eq!(stats.allocated + stats.reused, 40);
+    }
+}
@@ -0,0 +1,82 @@
+use std::fmt;
+use std::hash::Hash;
+
+use super::virtual_time::VirtualTime;
+
+// What messages and the queues need from a time axis, the queues only ever compare times
+// and step to the next one. Default is the start of time.
+//
+// Only the queues are generic over it. Machines, processes and the runtimes all run on
+// VirtualTime (whole ticks), so there is no fractional time end to end: a model with
+// events at fractional times scales them to ticks, like nanoseconds of a model in seconds.
+pub trait Timestamp:
+    Copy + Ord + Hash + fmt::Debug + fmt::Display + Default + Send + Sync + 'static
+{
+    // The next time after this one with nothing in between, where a FIFO queue moves a
+    // message that would have overtaken an earlier one (see InputQueue::fifo_stamp). The
+    // last time there is can stay where it is.
+    fn successor(self) -> Self;
+}
+
+impl Timestamp for VirtualTime {
+    fn successor(self) -> Self {
+        self.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::input_queue::InputQueue;
+    use crate::time::message::{Message, Sign};
+    use crate::time::output_queue::OutputQueue;
+    use std::sync::Arc;
+
+    // Another time axis, times in halves of a tick
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    struct Halves(u64);
+
+    impl fmt::Display for Halves {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}/2", self.0)
+        }
+    }
+
+    impl Timestamp for Halves {
+        fn successor(self) -> Self {
+            Self(self.0 + 1)
+        }
+    }
+
+    fn at(send_time: u64, rec_time: u64, sender: usize) -> Message<Halves> {
+        let payload = Arc::new(String::new());
+        Message::at(Halves(send_time), Halves(rec_time), sender, 0, Sign::Message, payload)
+    }
+
+    #[test]
+    fn test_queues_order_other_time_axes() {
+        let mut queue = InputQueue::<Halves>::new(None);
+        for rec_time in [5, 1, 3] {
+            queue.insert(at(0, rec_time, 0)).unwrap();
+        }
+        let first = queue.peek_smallest_greater().unwrap();
+        assert_eq!(first.rec_time, Halves(1));
+        queue.update_threshold(Some(first.rec_time));
+        assert_eq!(queue.peek_smallest_greater().unwrap().rec_time, Halves(3));
+
+        // A FIFO queue moves the overtaking message just past the earlier one
+        let mut fifo = InputQueue::<Halves>::new(None);
+        fifo.set_fifo(true);
+        fifo.insert(at(0, 6, 1)).unwrap();
+        let mut overtaking = at(1, 4, 1);
+        fifo.fifo_stamp(&mut overtaking);
+        assert_eq!(overtaking.rec_time, Halves(7));
+
+        let mut sent = OutputQueue::<Halves>::new();
+        for send_time in [1, 3, 5] {
+            sent.push(at(send_time, 8, 0));
+        }
+        assert_eq!(sent.cancel_range(Halves(2), Halves(6)).len(), 2);
+        assert_eq!(sent.len(), 1);
+    }
+}
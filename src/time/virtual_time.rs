@@ -0,0 +1,297 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::num::ParseIntError;
+use std::ops::{Add, AddAssign};
+use std::str::FromStr;
+
+// A point in virtual time. Times and the distances between them are different types so
+// a delay cant end up where a time is meant or the other way round, and going below
+// time zero has to be asked for (checked_sub, saturating_sub, prev) instead of wrapping
+// or panicking somewhere deep in a rollback. Written out (serde, Display) both are just
+// the number, so configs, checkpoints and traces look the same as with a plain integer.
+//
+// Both compare with plain integers as well so `machine.local_virtual_time() == 4` and
+// asserts like it dont need a conversion, going the other way takes VirtualTime::new
+// or .into().
+//
+// Ticks are a u64 whatever the platform, so a model that counts in nanoseconds has the
+// same room (about 584 years) on a 32 bit target as anywhere else. Going past the end of
+// time (MAX) with + stays at MAX instead of wrapping around to the start, use checked_add
+// or try_add to find out it happened.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct VirtualTime(u64);
+
+// How far apart two virtual times are, what a message is sent with and what a lookahead
+// is
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Delay(u64);
+
+// A time plus a delay that would be past the end of time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeOverflow {
+    pub time: VirtualTime,
+    pub delay: Delay,
+}
+
+impl fmt::Display for TimeOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} after {} is past the end of virtual time", self.delay, self.time)
+    }
+}
+
+impl std::error::Error for TimeOverflow {}
+
+impl VirtualTime {
+    pub const ZERO: VirtualTime = VirtualTime(0);
+    // The end of time, nothing is ever later
+    pub const MAX: VirtualTime = VirtualTime(u64::MAX);
+
+    pub const fn new(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    pub const fn ticks(self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, delay: Delay) -> Option<Self> {
+        self.0.checked_add(delay.0).map(Self)
+    }
+
+    pub fn saturating_add(self, delay: Delay) -> Self {
+        Self(self.0.saturating_add(delay.0))
+    }
+
+    // checked_add with an error saying what didnt fit
+    pub fn try_add(self, delay: Delay) -> Result<Self, TimeOverflow> {
+        self.checked_add(delay).ok_or(TimeOverflow { time: self, delay })
+    }
+
+    pub fn checked_sub(self, delay: Delay) -> Option<Self> {
+        self.0.checked_sub(delay.0).map(Self)
+    }
+
+    pub fn saturating_sub(self, delay: Delay) -> Self {
+        Self(self.0.saturating_sub(delay.0))
+    }
+
+    // The time just before this one, None at time zero where there is nothing before
+    pub fn prev(self) -> Option<Self> {
+        self.checked_sub(Delay(1))
+    }
+
+    pub fn next(self) -> Self {
+        self + Delay(1)
+    }
+
+    // How long after earlier this is, None if it is before it
+    pub fn since(self, earlier: VirtualTime) -> Option<Delay> {
+        self.0.checked_sub(earlier.0).map(Delay)
+    }
+
+    // Start of the span of the given length (counting from time zero) this time is in,
+    // a length of zero is taken as 1
+    pub fn align_down(self, length: Delay) -> Self {
+        let length = length.0.max(1);
+        Self(self.0 - self.0 % length)
+    }
+
+    // Same as since but zero when earlier is actually later
+    pub fn saturating_since(self, earlier: VirtualTime) -> Delay {
+        Delay(self.0.saturating_sub(earlier.0))
+    }
+}
+
+impl Delay {
+    pub const ZERO: Delay = Delay(0);
+    pub const MAX: Delay = Delay(u64::MAX);
+
+    pub const fn new(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    pub const fn ticks(self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Delay) -> Option<Self> {
+        self.0.checked_add(other.0).map(Self)
+    }
+
+    pub fn saturating_add(self, other: Delay) -> Self {
+        Self(self.0.saturating_add(other.0))
+    }
+
+    pub fn checked_sub(self, other: Delay) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Self)
+    }
+
+    pub fn saturating_sub(self, other: Delay) -> Self {
+        Self(self.0.saturating_sub(other.0))
+    }
+}
+
+// Saturates at the end of time, see the top of the file
+impl Add<Delay> for VirtualTime {
+    type Output = VirtualTime;
+
+    fn add(self, delay: Delay) -> VirtualTime {
+        self.saturating_add(delay)
+    }
+}
+
+impl AddAssign<Delay> for VirtualTime {
+    fn add_assign(&mut self, delay: Delay) {
+        *self = *self + delay;
+    }
+}
+
+impl Add for Delay {
+    type Output = Delay;
+
+    fn add(self, other: Delay) -> Delay {
+        self.saturating_add(other)
+    }
+}
+
+impl AddAssign for Delay {
+    fn add_assign(&mut self, other: Delay) {
+        *self = *self + other;
+    }
+}
+
+impl fmt::Display for VirtualTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for Delay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+// Parsed like the number, for command line arguments
+impl FromStr for VirtualTime {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+impl FromStr for Delay {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+// Only from usize and not from u64 as well, with two of them a plain integer literal
+// couldnt tell which one it is. A usize always fits in a u64.
+impl From<usize> for VirtualTime {
+    fn from(ticks: usize) -> Self {
+        Self(ticks as u64)
+    }
+}
+
+impl From<VirtualTime> for u64 {
+    fn from(vt: VirtualTime) -> Self {
+        vt.0
+    }
+}
+
+impl From<usize> for Delay {
+    fn from(ticks: usize) -> Self {
+        Self(ticks as u64)
+    }
+}
+
+impl From<Delay> for u64 {
+    fn from(delay: Delay) -> Self {
+        delay.0
+    }
+}
+
+impl PartialEq<usize> for VirtualTime {
+    fn eq(&self, other: &usize) -> bool {
+        self.0 == *other as u64
+    }
+}
+
+impl PartialOrd<usize> for VirtualTime {
+    fn partial_cmp(&self, other: &usize) -> Option<Ordering> {
+        self.0.partial_cmp(&(*other as u64))
+    }
+}
+
+impl PartialEq<usize> for Delay {
+    fn eq(&self, other: &usize) -> bool {
+        self.0 == *other as u64
+    }
+}
+
+impl PartialOrd<usize> for Delay {
+    fn partial_cmp(&self, other: &usize) -> Option<Ordering> {
+        self.0.partial_cmp(&(*other as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::Context;
+
+    #[test]
+    fn test_time_cant_go_below_zero_by_accident() {
+        let start = VirtualTime::ZERO;
+        assert_eq!(start.prev(), None);
+        assert_eq!(start.checked_sub(Delay::new(1)), None);
+        assert_eq!(start.saturating_sub(Delay::new(3)), VirtualTime::ZERO);
+        assert_eq!(VirtualTime::new(5).prev(), Some(VirtualTime::new(4)));
+
+        let later = start + Delay::new(7);
+        assert_eq!(later, 7);
+        assert_eq!(later.since(start), Some(Delay::new(7)));
+        assert_eq!(start.since(later), None);
+        assert_eq!(start.saturating_since(later), Delay::ZERO);
+        assert_eq!(VirtualTime::MAX.checked_add(Delay::new(1)), None);
+        assert_eq!(VirtualTime::MAX.saturating_add(Delay::new(1)), VirtualTime::MAX);
+        assert_eq!(VirtualTime::MAX + Delay::new(1), VirtualTime::MAX);
+        let late = VirtualTime::new(u64::MAX - 2);
+        assert_eq!(
+            late.try_add(Delay::new(5)),
+            Err(TimeOverflow {
+                time: late,
+                delay: Delay::new(5),
+            })
+        );
+        assert_eq!(VirtualTime::new(17).align_down(Delay::new(5)), 15);
+
+        // Written out it is only the number
+        assert_eq!(serde_json::to_string(&later).unwrap(), "7");
+        assert_eq!(serde_json::from_str::<Delay>("3").unwrap(), Delay::new(3));
+        assert_eq!(later.to_string(), "7");
+        assert_eq!("12".parse::<VirtualTime>().unwrap(), 12);
+    }
+
+    #[test]
+    fn test_sends_past_the_end_of_time_stop_there() {
+        let late = VirtualTime::new(u64::MAX - 2);
+        let mut ctx = Context::new(0, late);
+        assert!(ctx.try_send(1, 5, String::new()).is_err());
+        ctx.try_send(1, 2, String::new()).unwrap();
+        ctx.send(1, Delay::MAX, String::new());
+        let rec_times: Vec<_> = ctx.into_outbox().iter().map(|sent| sent.rec_time).collect();
+        assert_eq!(rec_times, [VirtualTime::MAX, VirtualTime::MAX]);
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use super::NodeId;
+use crate::time::message::MachineId;
+
+// Dynamic load balancing for distributed runs, built on TcpNode::migrate. The node with
+// the Balancer collects the load of every node after a GVT round and hands it to a policy,
+// which decides what to move where. A nodes load is what its machines did since the last
+// time, events committed and events rolled back (work that had to be redone), so a node
+// whose machines keep rolling back counts as busy even if little of it commits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MachineLoad {
+    pub machine_id: MachineId,
+    pub committed: u64,
+    pub rolled_back: u64,
+}
+
+impl MachineLoad {
+    pub fn work(&self) -> u64 {
+        self.committed + self.rolled_back
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeLoad {
+    pub node: NodeId,
+    pub machines: Vec<MachineLoad>,
+}
+
+impl NodeLoad {
+    pub fn work(&self) -> u64 {
+        self.machines.iter().map(MachineLoad::work).sum()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Move {
+    pub machine_id: MachineId,
+    pub from: NodeId,
+    pub to: NodeId,
+}
+
+// Decides which machines to move given what every node did over the last interval
+pub trait BalancePolicy: Send {
+    fn plan(&mut self, loads: &[NodeLoad]) -> Vec<Move>;
+}
+
+// Moves one machine per interval from the busiest node to the idlest one, if the busiest
+// did at least imbalance times as much work. The machine picked is the one that gets the
+// two closest to even, preferring the less busy of two equally good ones.
+#[derive(Debug, Clone, Copy)]
+pub struct MoveHottest {
+    pub imbalance: f64,
+}
+
+impl Default for MoveHottest {
+    fn default() -> Self {
+        Self { imbalance: 1.5 }
+    }
+}
+
+impl BalancePolicy for MoveHottest {
+    fn plan(&mut self, loads: &[NodeLoad]) -> Vec<Move> {
+        let (Some(hot), Some(idle)) = (
+            loads.iter().max_by_key(|load| load.work()),
+            loads.iter().min_by_key(|load| load.work()),
+        ) else {
+            return Vec::new();
+        };
+        if hot.node == idle.node
+            || hot.machines.len() < 2
+            || (hot.work() as f64) < self.imbalance * idle.work() as f64
+        {
+            return Vec::new();
+        }
+        let gap = hot.work() - idle.work();
+        hot.machines
+            .iter()
+            .filter(|machine| machine.work() > 0 && machine.work() < gap)
+            .min_by_key(|machine| ((2 * machine.work()).abs_diff(gap), machine.work()))
+            .map(|machine| Move {
+                machine_id: machine.machine_id,
+                from: hot.node,
+                to: idle.node,
+            })
+            .into_iter()
+            .collect()
+    }
+}
+
+// Keeps what a coordinator needs between rounds. Nodes report totals since the start,
+// the balancer turns them into the work done since it last looked.
+pub struct Balancer {
+    policy: Box<dyn BalancePolicy>,
+    // Only every this many GVT rounds
+    every: u64,
+    rounds: u64,
+    previous: HashMap<MachineId, MachineLoad>,
+    reports: BTreeMap<NodeId, NodeLoad>,
+    moves: u64,
+}
+
+impl Balancer {
+    pub fn new(policy: impl BalancePolicy + 'static, every: u64) -> Self {
+        Self {
+            policy: Box::new(policy),
+            every: every.max(1),
+            rounds: 0,
+            previous: HashMap::new(),
+            reports: BTreeMap::new(),
+            moves: 0,
+        }
+    }
+
+    // Number of moves the policy asked for so far
+    pub fn moves(&self) -> u64 {
+        self.moves
+    }
+
+    // Counts a GVT round, true if it is time to collect the loads
+    pub(super) fn round_done(&mut self) -> bool {
+        self.rounds += 1;
+        self.reports.clear();
+        self.rounds.is_multiple_of(self.every)
+    }
+
+    // Adds the totals of one node, once all nodes are in hands back the moves
+    pub(super) fn add(&mut self, totals: NodeLoad, nodes: usize) -> Option<Vec<Move>> {
+        self.reports.insert(totals.node, totals);
+        if self.reports.len() < nodes {
+            return None;
+        }
+        let loads: Vec<_> = std::mem::take(&mut self.reports)
+            .into_values()
+            .map(|totals| self.since_last_time(totals))
+            .collect();
+        let moves = self.policy.plan(&loads);
+        self.moves += moves.len() as u64;
+        Some(moves)
+    }
+
+    fn since_last_time(&mut self, totals: NodeLoad) -> NodeLoad {
+        let machines = totals
+            .machines
+            .into_iter()
+            .map(|machine| {
+                let before = self.previous.insert(machine.machine_id, machine);
+                let before = before.unwrap_or_default();
+                MachineLoad {
+                    machine_id: machine.machine_id,
+                    // Totals go down when statistics are reset after the warm up
+                    committed: machine.committed.saturating_sub(before.committed),
+                    rolled_back: machine.rolled_back.saturating_sub(before.rolled_back),
+                }
+            })
+            .collect();
+        NodeLoad {
+            node: totals.node,
+            machines,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(node: NodeId, machines: &[(MachineId, u64)]) -> NodeLoad {
+        let machines = machines
+            .iter()
+            .map(|(machine_id, committed)| MachineLoad {
+                machine_id: *machine_id,
+                committed: *committed,
+                rolled_back: 0,
+            })
+            .collect();
+        NodeLoad { node, machines }
+    }
+
+    #[test]
+    fn test_balancer_moves_from_the_busiest_node_by_rate() {
+        let mut balancer = Balancer::new(MoveHottest::default(), 1);
+        assert!(balancer.round_done());
+        assert_eq!(balancer.add(load(0, &[(1, 2), (3, 9)]), 2), None);
+        let moves = balancer.add(load(1, &[(2, 4)]), 2).unwrap();
+        assert_eq!(
+            moves,
+            vec![Move {
+                machine_id: 1,
+                from: 0,
+                to: 1,
+            }]
+        );
+
+        // Nothing happened on node 0 since, so it is the idle one now
+        assert!(balancer.round_done());
+        balancer.add(load(0, &[(1, 2), (3, 9)]), 2);
+        let moves = balancer.add(load(1, &[(2, 10), (4, 3)]), 2).unwrap();
+        assert_eq!(moves[0].machine_id, 4);
+        assert_eq!(moves[0].to, 0);
+        assert_eq!(balancer.moves(), 2);
+    }
+}
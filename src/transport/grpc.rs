@@ -0,0 +1,530 @@
+// The federation protocol as the gRPC service in proto/federation.proto, with tonic,
+// behind the grpc feature. A node bound with TcpNode::bind_grpc serves it instead of the
+// bincode frames and talks to its peers with it, everything above the link is the same
+// TcpNode. Every Frame is one call (see the proto), the server side turns the calls back
+// into frames and hands them to the node the way spawn_reader does for a TCP connection,
+// so any gRPC client that puts its node id in the vtw-node metadata can take part.
+//
+// There is no Hello, the node id comes with every call. Link compression doesnt apply,
+// the frames are protobuf on HTTP/2 and not codec frames.
+
+// Status is what tonic wants back from every call, boxing it would just undo it again
+#![allow(clippy::result_large_err)]
+
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{io, thread};
+
+use tonic::metadata::MetadataValue;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{Channel, Endpoint, Server};
+use tonic::{Request, Response, Status};
+
+use super::balance::{MachineLoad, NodeLoad};
+use super::gvt::GvtReport;
+use super::{Command, Frame, Member, NodeId};
+use crate::time::message::{Message, Sign, VirtualTime};
+use crate::trace::trace_warn;
+
+pub mod proto {
+    tonic::include_proto!("vtw.federation");
+}
+
+use proto::federation_client::FederationClient;
+use proto::federation_server::{Federation, FederationServer};
+
+// Metadata every call carries, the id of the calling node
+pub const NODE_HEADER: &str = "vtw-node";
+
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+fn ticks(time: Option<VirtualTime>) -> Option<u64> {
+    time.map(VirtualTime::ticks)
+}
+
+fn time(ticks: Option<u64>) -> Option<VirtualTime> {
+    ticks.map(VirtualTime::new)
+}
+
+impl From<&Message> for proto::Message {
+    fn from(message: &Message) -> Self {
+        let sign = match message.sign {
+            Sign::Message => proto::Sign::Message,
+            Sign::Antimessage => proto::Sign::Antimessage,
+        };
+        Self {
+            id: message.id as u64,
+            send_time: message.send_time.ticks(),
+            rec_time: message.rec_time.ticks(),
+            sender: message.sender as u64,
+            receiver: message.receiver as u64,
+            sign: sign as i32,
+            payload: message.message.to_string(),
+            port: message.port.as_deref().map(str::to_string),
+            parent: message.parent.map(|parent| parent as u64),
+            generation: message.generation,
+            data: message.data.as_ref().map(|data| data.to_vec()),
+        }
+    }
+}
+
+impl TryFrom<proto::Message> for Message {
+    type Error = Status;
+
+    fn try_from(message: proto::Message) -> Result<Self, Status> {
+        let sign = match proto::Sign::try_from(message.sign) {
+            Ok(proto::Sign::Message) => Sign::Message,
+            Ok(proto::Sign::Antimessage) => Sign::Antimessage,
+            Err(_) => return Err(Status::invalid_argument("unknown sign")),
+        };
+        Ok(Message {
+            id: message.id as usize,
+            send_time: VirtualTime::new(message.send_time),
+            rec_time: VirtualTime::new(message.rec_time),
+            sender: message.sender as usize,
+            receiver: message.receiver as usize,
+            sign,
+            message: Arc::new(message.payload),
+            port: message.port.map(Into::into),
+            parent: message.parent.map(|parent| parent as usize),
+            generation: message.generation,
+            data: message.data.map(Into::into),
+            handle: None,
+        })
+    }
+}
+
+impl From<&Member> for proto::Member {
+    fn from(member: &Member) -> Self {
+        Self {
+            node: member.node as u64,
+            addr: member.addr.to_string(),
+            machines: member.machines.iter().map(|&machine_id| machine_id as u64).collect(),
+        }
+    }
+}
+
+impl TryFrom<proto::Member> for Member {
+    type Error = Status;
+
+    fn try_from(member: proto::Member) -> Result<Self, Status> {
+        let addr = member
+            .addr
+            .parse()
+            .map_err(|_| Status::invalid_argument(format!("bad address {}", member.addr)))?;
+        Ok(Member {
+            node: member.node as usize,
+            addr,
+            machines: member.machines.into_iter().map(|machine_id| machine_id as usize).collect(),
+        })
+    }
+}
+
+impl From<&GvtReport> for proto::GvtReport {
+    fn from(report: &GvtReport) -> Self {
+        Self {
+            epoch: report.epoch,
+            sent: report.sent,
+            received: report.received,
+            local_min: ticks(report.local_min),
+            red_min: ticks(report.red_min),
+        }
+    }
+}
+
+impl From<proto::GvtReport> for GvtReport {
+    fn from(report: proto::GvtReport) -> Self {
+        GvtReport {
+            epoch: report.epoch,
+            sent: report.sent,
+            received: report.received,
+            local_min: time(report.local_min),
+            red_min: time(report.red_min),
+        }
+    }
+}
+
+impl From<&NodeLoad> for proto::NodeLoad {
+    fn from(load: &NodeLoad) -> Self {
+        let machines = load
+            .machines
+            .iter()
+            .map(|machine| proto::MachineLoad {
+                machine_id: machine.machine_id as u64,
+                committed: machine.committed,
+                rolled_back: machine.rolled_back,
+            })
+            .collect();
+        Self {
+            node: load.node as u64,
+            machines,
+        }
+    }
+}
+
+impl From<proto::NodeLoad> for NodeLoad {
+    fn from(load: proto::NodeLoad) -> Self {
+        let machines = load
+            .machines
+            .into_iter()
+            .map(|machine| MachineLoad {
+                machine_id: machine.machine_id as usize,
+                committed: machine.committed,
+                rolled_back: machine.rolled_back,
+            })
+            .collect();
+        NodeLoad {
+            node: load.node as usize,
+            machines,
+        }
+    }
+}
+
+impl From<&Command> for proto::Command {
+    fn from(command: &Command) -> Self {
+        use proto::command::Command as Kind;
+        let command = match command {
+            Command::Pause => Kind::Pause(proto::Empty {}),
+            Command::Resume => Kind::Resume(proto::Empty {}),
+            Command::Checkpoint { dir } => Kind::CheckpointDir(dir.display().to_string()),
+            Command::Migrate { machine_id, to } => Kind::Migrate(proto::Placement {
+                machine_id: *machine_id as u64,
+                node: *to as u64,
+            }),
+        };
+        Self {
+            command: Some(command),
+        }
+    }
+}
+
+impl TryFrom<proto::Command> for Command {
+    type Error = Status;
+
+    fn try_from(command: proto::Command) -> Result<Self, Status> {
+        use proto::command::Command as Kind;
+        match command.command {
+            Some(Kind::Pause(_)) => Ok(Command::Pause),
+            Some(Kind::Resume(_)) => Ok(Command::Resume),
+            Some(Kind::CheckpointDir(dir)) => Ok(Command::Checkpoint { dir: dir.into() }),
+            Some(Kind::Migrate(placement)) => Ok(Command::Migrate {
+                machine_id: placement.machine_id as usize,
+                to: placement.node as usize,
+            }),
+            None => Err(Status::invalid_argument("a command needs a command")),
+        }
+    }
+}
+
+type Reply = Result<Response<proto::Empty>, Status>;
+
+// Turns the calls on this node back into frames for TcpNode::poll
+struct FederationService {
+    frames: Sender<(NodeId, Frame)>,
+}
+
+impl FederationService {
+    fn forward<T>(&self, request: Request<T>, frame: Frame) -> Reply {
+        let node = request
+            .metadata()
+            .get(NODE_HEADER)
+            .and_then(|node| node.to_str().ok()?.parse().ok())
+            .ok_or_else(|| Status::invalid_argument(format!("no node id in {}", NODE_HEADER)))?;
+        self.frames
+            .send((node, frame))
+            .map_err(|_| Status::unavailable("the node is gone"))?;
+        Ok(Response::new(proto::Empty {}))
+    }
+}
+
+fn data(data: proto::Data) -> Result<Frame, Status> {
+    let mut messages: Vec<Message> =
+        data.messages.into_iter().map(Message::try_from).collect::<Result<_, _>>()?;
+    Ok(match messages.len() {
+        1 => Frame::Data {
+            seq: data.seq,
+            epoch: data.epoch,
+            message: messages.remove(0),
+        },
+        _ => Frame::Antimessages {
+            seq: data.seq,
+            epoch: data.epoch,
+            antimessages: messages,
+        },
+    })
+}
+
+#[tonic::async_trait]
+impl Federation for FederationService {
+    async fn join(&self, request: Request<proto::Member>) -> Reply {
+        let member = request.get_ref().clone().try_into()?;
+        self.forward(request, Frame::Join(member))
+    }
+
+    async fn update_members(&self, request: Request<proto::Members>) -> Reply {
+        let members = request.get_ref().members.iter().cloned().map(Member::try_from);
+        let members = members.collect::<Result<_, _>>()?;
+        self.forward(request, Frame::Members { members })
+    }
+
+    async fn deliver(&self, request: Request<proto::Data>) -> Reply {
+        let frame = data(request.get_ref().clone())?;
+        self.forward(request, frame)
+    }
+
+    async fn acknowledge(&self, request: Request<proto::Ack>) -> Reply {
+        let seq = request.get_ref().seq;
+        self.forward(request, Frame::Ack { seq })
+    }
+
+    async fn migrate(&self, request: Request<proto::Migration>) -> Reply {
+        let migration = request.get_ref().clone();
+        let frame = Frame::Migrate {
+            seq: migration.seq,
+            epoch: migration.epoch,
+            machine: migration.machine,
+        };
+        self.forward(request, frame)
+    }
+
+    async fn placed(&self, request: Request<proto::Placement>) -> Reply {
+        let placement = request.get_ref();
+        let frame = Frame::Placed {
+            machine_id: placement.machine_id as usize,
+            node: placement.node as usize,
+        };
+        self.forward(request, frame)
+    }
+
+    async fn request_gvt(&self, request: Request<proto::GvtRequest>) -> Reply {
+        let epoch = request.get_ref().epoch;
+        self.forward(request, Frame::GvtRequest { epoch })
+    }
+
+    async fn report_gvt(&self, request: Request<proto::GvtReport>) -> Reply {
+        let report = (*request.get_ref()).into();
+        self.forward(request, Frame::GvtReport(report))
+    }
+
+    async fn announce_gvt(&self, request: Request<proto::Gvt>) -> Reply {
+        let gvt = time(request.get_ref().gvt);
+        self.forward(request, Frame::Gvt { gvt })
+    }
+
+    async fn request_load(&self, request: Request<proto::Empty>) -> Reply {
+        self.forward(request, Frame::LoadRequest)
+    }
+
+    async fn report_load(&self, request: Request<proto::NodeLoad>) -> Reply {
+        let load = request.get_ref().clone().into();
+        self.forward(request, Frame::Load(load))
+    }
+
+    async fn control(&self, request: Request<proto::Command>) -> Reply {
+        let command = request.get_ref().clone().try_into()?;
+        self.forward(request, Frame::Control(command))
+    }
+}
+
+// Serves the node on its own thread until shutdown is set, like spawn_acceptor
+pub(super) fn spawn_server(
+    listener: TcpListener,
+    frames: Sender<(NodeId, Frame)>,
+    shutdown: Arc<AtomicBool>,
+) -> io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    listener.set_nonblocking(true)?;
+    let listener = {
+        let _guard = runtime.enter();
+        tokio::net::TcpListener::from_std(listener)?
+    };
+    let incoming = TcpIncoming::from_listener(listener, true, None).map_err(io::Error::other)?;
+    thread::spawn(move || {
+        let stopped = async move {
+            while !shutdown.load(Ordering::Relaxed) {
+                tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+            }
+        };
+        let server = Server::builder()
+            .add_service(FederationServer::new(FederationService { frames }))
+            .serve_with_incoming_shutdown(incoming, stopped);
+        if let Err(_error) = runtime.block_on(server) {
+            trace_warn!(error = %_error, "gRPC server stopped");
+        }
+    });
+    Ok(())
+}
+
+// The outgoing side of a link to a peer, every frame is one call it waits for
+pub(super) struct GrpcLink {
+    runtime: tokio::runtime::Runtime,
+    client: FederationClient<Channel>,
+    local: NodeId,
+}
+
+impl GrpcLink {
+    pub(super) fn connect(addr: SocketAddr, local: NodeId, timeout: Duration) -> Option<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().ok()?;
+        let endpoint = Endpoint::from_shared(format!("http://{}", addr)).ok()?;
+        let endpoint = endpoint.connect_timeout(timeout);
+        let channel = runtime.block_on(endpoint.connect()).ok()?;
+        Some(Self {
+            runtime,
+            client: FederationClient::new(channel),
+            local,
+        })
+    }
+
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request.metadata_mut().insert(NODE_HEADER, MetadataValue::from(self.local as u64));
+        request
+    }
+
+    // Whether the peer took the frame, like writing it to a TCP connection
+    pub(super) fn send(&mut self, frame: &Frame) -> bool {
+        macro_rules! call {
+            ($method:ident, $message:expr) => {{
+                let request = self.request($message);
+                self.runtime.block_on(self.client.$method(request)).is_ok()
+            }};
+        }
+        match frame {
+            Frame::Hello { .. } => true,
+            Frame::Data {
+                seq,
+                epoch,
+                message,
+            } => call!(
+                deliver,
+                proto::Data {
+                    seq: *seq,
+                    epoch: *epoch,
+                    messages: vec![message.into()],
+                }
+            ),
+            Frame::Antimessages {
+                seq,
+                epoch,
+                antimessages,
+            } => call!(
+                deliver,
+                proto::Data {
+                    seq: *seq,
+                    epoch: *epoch,
+                    messages: antimessages.iter().map(Into::into).collect(),
+                }
+            ),
+            Frame::Ack { seq } => call!(acknowledge, proto::Ack { seq: *seq }),
+            Frame::GvtRequest { epoch } => call!(request_gvt, proto::GvtRequest { epoch: *epoch }),
+            Frame::GvtReport(report) => call!(report_gvt, proto::GvtReport::from(report)),
+            Frame::Gvt { gvt } => call!(announce_gvt, proto::Gvt { gvt: ticks(*gvt) }),
+            Frame::Migrate {
+                seq,
+                epoch,
+                machine,
+            } => call!(
+                migrate,
+                proto::Migration {
+                    seq: *seq,
+                    epoch: *epoch,
+                    machine: machine.clone(),
+                }
+            ),
+            Frame::Placed { machine_id, node } => call!(
+                placed,
+                proto::Placement {
+                    machine_id: *machine_id as u64,
+                    node: *node as u64,
+                }
+            ),
+            Frame::LoadRequest => call!(request_load, proto::Empty {}),
+            Frame::Load(load) => call!(report_load, proto::NodeLoad::from(load)),
+            Frame::Join(member) => call!(join, proto::Member::from(member)),
+            Frame::Members { members } => call!(
+                update_members,
+                proto::Members {
+                    members: members.iter().map(Into::into).collect(),
+                }
+            ),
+            Frame::Control(command) => call!(control, proto::Command::from(command)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::transport::tcp::TcpNode;
+    use std::time::Instant;
+
+    fn poll_until(nodes: &mut [&mut TcpNode], mut done: impl FnMut(&mut [&mut TcpNode]) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done(nodes) {
+            assert!(Instant::now() < deadline, "nodes never reached the expected state");
+            for node in nodes.iter_mut() {
+                node.poll(Duration::from_millis(5)).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_nodes_federate_over_grpc() {
+        let mut node_a = TcpNode::bind_grpc(0, "127.0.0.1:0").unwrap();
+        let mut node_b = TcpNode::bind_grpc(1, "127.0.0.1:0").unwrap();
+        node_a.add_machine(Machine::new(1, 0));
+        node_b.add_machine(Machine::new(2, 0));
+        node_b.join(0, node_a.local_addr()).unwrap();
+        poll_until(&mut [&mut node_a, &mut node_b], |nodes| {
+            nodes.iter().all(|node| node.members().len() == 2)
+        });
+
+        // A send the straggler after it takes back again, on the way the antimessage and
+        // the acks for both go over the same calls as everything else
+        node_a
+            .route(Message::new(0, 3, 1, 1, Sign::Message, Arc::new("first".to_string())))
+            .unwrap();
+        node_a.machine_mut(1).unwrap().recieve_inner();
+        let message = Message::new(3, 8, 1, 2, Sign::Message, Arc::new("remote".to_string()));
+        node_a.send(message.clone().with_port("data")).unwrap();
+        poll_until(&mut [&mut node_a, &mut node_b], |nodes| nodes[0].unacked(1) == 0);
+        let received = node_b.machine(2).unwrap().input_queue.peek_smallest_greater().unwrap();
+        assert_eq!(received.port(), Some("data"));
+        assert_eq!(received.rec_time, message.rec_time);
+
+        node_a
+            .route(Message::new(0, 2, 1, 1, Sign::Message, Arc::new("straggler".to_string())))
+            .unwrap();
+        poll_until(&mut [&mut node_a, &mut node_b], |nodes| {
+            nodes[0].unacked(1) == 0 && nodes[0].machine(1).unwrap().in_flight.is_empty()
+        });
+        assert!(node_b.machine(2).unwrap().input_queue.is_empty());
+
+        node_b.start_gvt();
+        poll_until(&mut [&mut node_a, &mut node_b], |nodes| {
+            nodes.iter().all(|node| node.gvt_rounds() == 1)
+        });
+        assert_eq!(node_a.gvt(), Some(VirtualTime::new(2)));
+    }
+
+    #[test]
+    fn test_any_grpc_client_can_send_commands() {
+        let mut node = TcpNode::bind_grpc(0, "127.0.0.1:0").unwrap();
+        let addr = format!("http://{}", node.local_addr());
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let mut client = runtime.block_on(FederationClient::connect(addr)).unwrap();
+
+        let pause = proto::Command::from(&Command::Pause);
+        let status = runtime.block_on(client.control(pause.clone())).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let mut request = Request::new(pause);
+        request.metadata_mut().insert(NODE_HEADER, MetadataValue::from(7u64));
+        runtime.block_on(client.control(request)).unwrap();
+        poll_until(&mut [&mut node], |nodes| nodes[0].is_paused());
+    }
+}
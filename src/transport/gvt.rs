@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::NodeId;
+use crate::time::message::VirtualTime;
+
+pub type Epoch = u64;
+
+// Mattern style GVT for nodes that only talk through messages. Every message between
+// nodes is coloured with the epoch its sender was in when sending it and every node
+// counts how many messages of each epoch it sent and received. A GVT round for epoch e
+// moves every node on to e + 1, after which it only sends messages of the new epoch and
+// keeps track of the earliest one it sent. Once the nodes' counts for e add up, no
+// message of epoch e is in transit anymore, each one is in some machines queues or was
+// processed and only caused messages of the new epoch. GVT is then the minimum of what
+// every node still has to do and of the new epoch messages, none of which can be lost
+// between the reports since they are covered by their senders red minimum.
+//
+// If the counts dont add up yet the controller just asks again, the nodes stay in the
+// new epoch and report their updated counts.
+#[derive(Debug, Default, Clone)]
+pub struct MatternCounter {
+    epoch: Epoch,
+    sent: BTreeMap<Epoch, u64>,
+    received: BTreeMap<Epoch, u64>,
+    // Earliest receive time sent since moving on to the current epoch
+    red_min: Option<VirtualTime>,
+}
+
+// What a node answers a GVT request with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GvtReport {
+    pub epoch: Epoch,
+    pub sent: u64,
+    pub received: u64,
+    // Earliest message any of the nodes machines still has to process or get acked
+    pub local_min: Option<VirtualTime>,
+    pub red_min: Option<VirtualTime>,
+}
+
+impl MatternCounter {
+    pub fn epoch(&self) -> Epoch {
+        self.epoch
+    }
+
+    // Counts a message going to another node, returns the epoch to colour it with
+    pub fn on_send(&mut self, rec_time: VirtualTime) -> Epoch {
+        *self.sent.entry(self.epoch).or_insert(0) += 1;
+        self.red_min = Some(self.red_min.map_or(rec_time, |min| min.min(rec_time)));
+        self.epoch
+    }
+
+    pub fn on_receive(&mut self, epoch: Epoch) {
+        *self.received.entry(epoch).or_insert(0) += 1;
+    }
+
+    // Moves on past the epoch a GVT round is asking about, asking again for the same one
+    // changes nothing
+    pub fn advance_past(&mut self, epoch: Epoch) {
+        if self.epoch <= epoch {
+            self.epoch = epoch + 1;
+            self.red_min = None;
+        }
+    }
+
+    pub fn report(&self, epoch: Epoch, local_min: Option<VirtualTime>) -> GvtReport {
+        GvtReport {
+            epoch,
+            sent: self.sent.get(&epoch).copied().unwrap_or(0),
+            received: self.received.get(&epoch).copied().unwrap_or(0),
+            local_min,
+            red_min: self.red_min,
+        }
+    }
+
+    // A finished round means nothing older can be asked about again
+    pub fn forget_before(&mut self, epoch: Epoch) {
+        self.sent = self.sent.split_off(&epoch);
+        self.received = self.received.split_off(&epoch);
+    }
+}
+
+// The controllers side of one round, the reports that came back so far
+#[derive(Debug, Clone)]
+pub struct GvtRound {
+    pub epoch: Epoch,
+    reports: BTreeMap<NodeId, GvtReport>,
+}
+
+impl GvtRound {
+    pub fn new(epoch: Epoch) -> Self {
+        Self {
+            epoch,
+            reports: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, node: NodeId, report: GvtReport) {
+        if report.epoch == self.epoch {
+            self.reports.insert(node, report);
+        }
+    }
+
+    pub fn has_all(&self, nodes: usize) -> bool {
+        self.reports.len() >= nodes
+    }
+
+    // Some(gvt) once every message of the epoch has been received, the inner None meaning
+    // there is nothing left anywhere. None means the round has to be asked again.
+    pub fn gvt(&self) -> Option<Option<VirtualTime>> {
+        let sent: u64 = self.reports.values().map(|report| report.sent).sum();
+        let received: u64 = self.reports.values().map(|report| report.received).sum();
+        if sent != received {
+            return None;
+        }
+        Some(
+            self.reports
+                .values()
+                .flat_map(|report| [report.local_min, report.red_min])
+                .flatten()
+                .min(),
+        )
+    }
+
+    pub fn retry(&mut self) {
+        self.reports.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_waits_for_transient_messages() {
+        let mut node_a = MatternCounter::default();
+        let mut node_b = MatternCounter::default();
+
+        // A sends in epoch 0 and the round starts before B has the message
+        let epoch = node_a.on_send(VirtualTime::new(8));
+        node_a.advance_past(0);
+        node_b.advance_past(0);
+        // After moving on, sends are tracked by the red minimum
+        node_b.on_send(VirtualTime::new(20));
+
+        let mut round = GvtRound::new(0);
+        round.add(0, node_a.report(0, None));
+        round.add(1, node_b.report(0, None));
+        assert!(round.has_all(2));
+        assert_eq!(round.gvt(), None);
+
+        // Asking again once B received it, the message now shows up in B's local minimum
+        node_b.on_receive(epoch);
+        round.retry();
+        round.add(0, node_a.report(0, None));
+        round.add(1, node_b.report(0, Some(VirtualTime::new(8))));
+        assert_eq!(round.gvt(), Some(Some(VirtualTime::new(8))));
+    }
+}
@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::time::message::{MachineId, Message, VirtualTime};
+
+pub mod balance;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod gvt;
+pub mod ranks;
+pub mod tcp;
+
+use balance::NodeLoad;
+use gvt::{Epoch, GvtReport};
+
+pub type NodeId = usize;
+pub type SequenceNumber = u64;
+
+// Everything a node puts on a connection to another node. Messages and antimessages
+// both travel as Data, the sequence number is per (sender node, receiver node) pair
+// and lets the receiver drop retransmissions it has already delivered. The epoch is the
+// senders GVT epoch, see gvt.rs, the rest of the frames are the GVT rounds themselves.
+// A rollback that cancels several sends to machines on the same node sends all those
+// antimessages as one Antimessages frame, with one sequence number for the lot, and the
+// receiving node handles them one by one in order as if they had come separately.
+//
+// Migrate carries a machine moving to the receiving node, encoded with bincode, and is
+// sequenced and acked like data. Its epoch is None if the machine had nothing left to do,
+// then it doesnt count towards GVT. Placed tells the nodes where it ended up.
+//
+// LoadRequest and Load are how a node with a Balancer finds out what the others are
+// doing, see balance.rs.
+//
+// Join, Members and Control make up the federation side, see TcpNode::join. The whole
+// protocol is also the gRPC service in proto/federation.proto, which nodes bound with
+// TcpNode::bind_grpc speak instead of these frames (see grpc.rs).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Frame {
+    Hello { node: NodeId },
+    Data {
+        seq: SequenceNumber,
+        epoch: Epoch,
+        message: Message,
+    },
+    Antimessages {
+        seq: SequenceNumber,
+        epoch: Epoch,
+        antimessages: Vec<Message>,
+    },
+    Ack { seq: SequenceNumber },
+    GvtRequest { epoch: Epoch },
+    GvtReport(GvtReport),
+    Gvt { gvt: Option<VirtualTime> },
+    Migrate {
+        seq: SequenceNumber,
+        epoch: Option<Epoch>,
+        machine: Vec<u8>,
+    },
+    Placed { machine_id: MachineId, node: NodeId },
+    LoadRequest,
+    Load(NodeLoad),
+    Join(Member),
+    // Everyone in the federation, sent to all the nodes whenever one joins
+    Members { members: Vec<Member> },
+    Control(Command),
+}
+
+// A node in a federation, where it listens and which machines it hosts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Member {
+    pub node: NodeId,
+    pub addr: SocketAddr,
+    pub machines: Vec<MachineId>,
+}
+
+// What a coordinator can tell the nodes to do
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+    // Whoever drives the node should stop processing events until Resume, see
+    // TcpNode::is_paused. Messages keep being delivered and GVT rounds keep running.
+    Pause,
+    Resume,
+    // Every node writes its machines to node-<id>.checkpoint in the directory, in the
+    // format of a simulation checkpoint so vtw inspect can read it. What the peers of a
+    // node havent acknowledged yet goes in as in transit. The nodes write when the
+    // command gets to them, not at one consistent cut, and a machine that is being
+    // migrated is in neither file, so the files are for looking at and not for
+    // restarting a federation from (there is no way to restore a TcpNode either).
+    Checkpoint { dir: PathBuf },
+    // Only for the node hosting the machine, see TcpNode::migrate
+    Migrate { machine_id: MachineId, to: NodeId },
+}
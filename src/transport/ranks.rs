@@ -0,0 +1,343 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+
+use super::tcp::TransportError;
+use super::NodeId;
+use crate::machine::Machine;
+use crate::process::TimeWarpProcess;
+use crate::time::message::{MachineId, Message, Sign, VirtualTime};
+
+// Distributed runs the way HPC codes do them, a fixed set of ranks started together that
+// each host a partition of the machines. Messages between ranks are fire and forget
+// sends and GVT is a collective: every rank stops, counts what it sent and received
+// and what it still has to do, and an allreduce combines that. If the totals of sent
+// and received match nothing was on its way and the minimum is GVT, otherwise the
+// ranks drain what arrived and reduce again.
+//
+// Communicator is the part that talks to the other ranks, shaped after the MPI calls it
+// maps to so a backend on top of an MPI binding is a thin wrapper (encode the message
+// with codec.rs, MPI_Isend it, MPI_Iprobe and receive, MPI_Allreduce the counts).
+//
+// That backend isnt here. There is no mpi feature and nothing in this crate talks to MPI,
+// so this cant run across the machines of a cluster yet, only ThreadComm exists and it
+// runs the ranks as threads of one process. A real one needs an MPI binding (rsmpi) and
+// an MPI installation to build and test against, which this crate doesnt have.
+pub trait Communicator: Send {
+    fn rank(&self) -> NodeId;
+
+    fn size(&self) -> usize;
+
+    // Returns right away, the message gets to the rank eventually
+    fn send(&mut self, to: NodeId, message: Message);
+
+    fn try_recv(&mut self) -> Option<Message>;
+
+    // Blocks until every rank has made its contribution and hands all of them the
+    // combination of everything
+    fn allreduce(&mut self, contribution: Reduction) -> Reduction;
+}
+
+// What goes into the GVT allreduce
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Reduction {
+    pub min: Option<VirtualTime>,
+    pub sent: u64,
+    pub received: u64,
+}
+
+impl Reduction {
+    pub fn combine(self, other: Reduction) -> Reduction {
+        Reduction {
+            min: [self.min, other.min].into_iter().flatten().min(),
+            sent: self.sent + other.sent,
+            received: self.received + other.received,
+        }
+    }
+}
+
+pub struct RankNode<P: TimeWarpProcess, C> {
+    comm: C,
+    machines: BTreeMap<MachineId, Machine<P>>,
+    placement: HashMap<MachineId, NodeId>,
+    sent: u64,
+    received: u64,
+    gvt: Option<VirtualTime>,
+    gvt_rounds: u64,
+}
+
+impl<P: TimeWarpProcess, C: Communicator> RankNode<P, C> {
+    pub fn new(comm: C) -> Self {
+        Self {
+            comm,
+            machines: BTreeMap::new(),
+            placement: HashMap::new(),
+            sent: 0,
+            received: 0,
+            gvt: None,
+            gvt_rounds: 0,
+        }
+    }
+
+    pub fn rank(&self) -> NodeId {
+        self.comm.rank()
+    }
+
+    pub fn add_machine(&mut self, machine: Machine<P>) {
+        self.placement.insert(machine.machine_id(), self.comm.rank());
+        self.machines.insert(machine.machine_id(), machine);
+    }
+
+    // Records which rank hosts a remote machine
+    pub fn place(&mut self, machine_id: MachineId, rank: NodeId) {
+        self.placement.insert(machine_id, rank);
+    }
+
+    pub fn machine(&self, machine_id: MachineId) -> Option<&Machine<P>> {
+        self.machines.get(&machine_id)
+    }
+
+    pub fn machines(&self) -> impl Iterator<Item = &Machine<P>> {
+        self.machines.values()
+    }
+
+    pub fn gvt(&self) -> Option<VirtualTime> {
+        self.gvt
+    }
+
+    pub fn gvt_rounds(&self) -> u64 {
+        self.gvt_rounds
+    }
+
+    // Delivers a message wherever its receiver lives, like TcpNode::route
+    pub fn route(&mut self, message: Message) -> Result<(), TransportError> {
+        let mut pending = VecDeque::from([message]);
+        while let Some(message) = pending.pop_front() {
+            let rank = *self
+                .placement
+                .get(&message.receiver)
+                .ok_or(TransportError::UnknownMachine(message.receiver))?;
+            if rank == self.comm.rank() {
+                if let Some(machine) = self.machines.get_mut(&message.receiver) {
+                    pending.extend(machine.recieve_outer(message.clone()).unwrap_or_default());
+                }
+            } else {
+                self.comm.send(rank, message.clone());
+                self.sent += 1;
+            }
+            // The GVT reduction counts antimessages between ranks along with everything
+            // else, so there is no need to wait for the other rank to confirm them
+            if message.sign == Sign::Antimessage {
+                if let Some(machine) = self.machines.get_mut(&message.sender) {
+                    machine.acknowledge_antimessage(&message);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Delivers whatever the other ranks have sent so far, returns how many messages that was
+    pub fn poll(&mut self) -> Result<usize, TransportError> {
+        let mut handled = 0;
+        while let Some(message) = self.comm.try_recv() {
+            self.received += 1;
+            self.route(message)?;
+            handled += 1;
+        }
+        Ok(handled)
+    }
+
+    // Processes the earliest event on this rank, false if there was none
+    pub fn step(&mut self) -> Result<bool, TransportError> {
+        self.poll()?;
+        let next = self
+            .machines
+            .values()
+            .filter(|machine| machine.can_execute(None))
+            .min_by_key(|machine| machine.peek_next_message().map(|message| message.rec_time));
+        let Some(machine_id) = next.map(|machine| machine.machine_id()) else {
+            return Ok(false);
+        };
+        let sent = self.machines.get_mut(&machine_id).unwrap().recieve_inner();
+        for message in sent {
+            self.route(message)?;
+        }
+        Ok(true)
+    }
+
+    // Works out GVT together with the other ranks and commits up to it. Every rank has to
+    // call this the same number of times, it blocks until all of them have.
+    pub fn compute_gvt(&mut self) -> Result<Option<VirtualTime>, TransportError> {
+        loop {
+            self.poll()?;
+            let local = Reduction {
+                min: self.local_min(),
+                sent: self.sent,
+                received: self.received,
+            };
+            let total = self.comm.allreduce(local);
+            if total.sent == total.received {
+                self.gvt = total.min;
+                self.gvt_rounds += 1;
+                for machine in self.machines.values_mut() {
+                    machine.commit(total.min);
+                }
+                return Ok(total.min);
+            }
+        }
+    }
+
+    // Processes up to batch events between GVT rounds until GVT says everyone is done
+    pub fn run(&mut self, batch: usize) -> Result<(), TransportError> {
+        loop {
+            for _ in 0..batch {
+                if !self.step()? {
+                    break;
+                }
+            }
+            if self.compute_gvt()?.is_none() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn local_min(&self) -> Option<VirtualTime> {
+        self.machines
+            .values()
+            .filter_map(|machine| machine.peek_next_message())
+            .map(|message| message.rec_time)
+            .min()
+    }
+}
+
+#[derive(Debug, Default)]
+struct ReduceState {
+    generation: u64,
+    arrived: usize,
+    combined: Reduction,
+    result: Reduction,
+}
+
+// Ranks as threads of one process, for running the rank code without MPI
+pub struct ThreadComm {
+    rank: NodeId,
+    ranks: Vec<Sender<Message>>,
+    incoming: Receiver<Message>,
+    reduce: Arc<(Mutex<ReduceState>, Condvar)>,
+}
+
+impl ThreadComm {
+    // One communicator per rank, hand each to its own thread
+    pub fn group(size: usize) -> Vec<ThreadComm> {
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..size).map(|_| mpsc::channel()).unzip();
+        let reduce = Arc::new((Mutex::new(ReduceState::default()), Condvar::new()));
+        receivers
+            .into_iter()
+            .enumerate()
+            .map(|(rank, incoming)| ThreadComm {
+                rank,
+                ranks: senders.clone(),
+                incoming,
+                reduce: reduce.clone(),
+            })
+            .collect()
+    }
+}
+
+impl Communicator for ThreadComm {
+    fn rank(&self) -> NodeId {
+        self.rank
+    }
+
+    fn size(&self) -> usize {
+        self.ranks.len()
+    }
+
+    fn send(&mut self, to: NodeId, message: Message) {
+        // A rank that is gone has nothing left to receive
+        let _ = self.ranks[to].send(message);
+    }
+
+    fn try_recv(&mut self) -> Option<Message> {
+        self.incoming.try_recv().ok()
+    }
+
+    fn allreduce(&mut self, contribution: Reduction) -> Reduction {
+        let (state, arrived) = &*self.reduce;
+        let mut state = state.lock().unwrap();
+        let generation = state.generation;
+        state.combined = state.combined.combine(contribution);
+        state.arrived += 1;
+        if state.arrived == self.ranks.len() {
+            state.result = std::mem::take(&mut state.combined);
+            state.arrived = 0;
+            state.generation += 1;
+            arrived.notify_all();
+        } else {
+            // Nobody can start the next round before everyone has left this one, so the
+            // result is still there when we wake up
+            while state.generation == generation {
+                state = arrived.wait(state).unwrap();
+            }
+        }
+        state.result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::tests::ring;
+    use std::thread;
+
+    #[test]
+    fn test_ranks_match_a_single_process_run() {
+        let start = || {
+            [
+                Message::new(0, 1, 0, 0, Sign::Message, Arc::new("7".to_string())),
+                Message::new(0, 2, 1, 1, Sign::Message, Arc::new("5".to_string())),
+            ]
+        };
+        let mut expected = ring(4);
+        for message in start() {
+            expected.inject(message);
+        }
+        expected.run();
+
+        // Even machines on rank 0 and odd ones on rank 1, so every hop crosses over
+        let mut machines = ring(4).into_machines();
+        let handles: Vec<_> = ThreadComm::group(2)
+            .into_iter()
+            .map(|comm| {
+                let mut node = RankNode::new(comm);
+                for machine_id in 0..4 {
+                    match machine_id % 2 == node.rank() {
+                        true => node.add_machine(machines.remove(&machine_id).unwrap()),
+                        false => node.place(machine_id, machine_id % 2),
+                    }
+                }
+                let starting: Vec<_> =
+                    start().into_iter().filter(|m| m.receiver % 2 == node.rank()).collect();
+                thread::spawn(move || {
+                    for message in starting {
+                        node.route(message).unwrap();
+                    }
+                    node.run(2).unwrap();
+                    node
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            let node = handle.join().unwrap();
+            assert_eq!(node.gvt(), None);
+            assert!(node.gvt_rounds() > 1);
+            for machine in node.machines() {
+                let reference = expected.machine(machine.machine_id()).unwrap();
+                assert_eq!(machine.state, reference.state);
+                let stats = machine.stats();
+                assert_eq!(stats.events_committed, reference.stats().events_committed);
+            }
+        }
+    }
+}
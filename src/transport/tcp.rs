@@ -0,0 +1,1308 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::balance::{Balancer, MachineLoad, NodeLoad};
+use super::gvt::{Epoch, GvtRound, MatternCounter};
+use super::{Command, Frame, Member, NodeId, SequenceNumber};
+use crate::codec::{self, CodecError, Compression};
+use crate::runtime::checkpoint::write_checkpoint;
+use crate::machine::{Machine, SendError};
+use crate::metrics::{MetricsHandle, MetricsSnapshot};
+use crate::router::{Router, RouterError};
+use crate::time::message::{MachineId, Message, Sign, VirtualTime};
+use crate::trace::{trace_debug, trace_warn};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug)]
+pub enum TransportError {
+    Io(io::Error),
+    Codec(CodecError),
+    UnknownMachine(MachineId),
+    UnknownPeer(NodeId),
+    InvalidSend(SendError),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Io(error) => write!(f, "io error: {}", error),
+            TransportError::Codec(error) => write!(f, "codec error: {}", error),
+            TransportError::UnknownMachine(id) => write!(f, "no node is hosting machine {}", id),
+            TransportError::UnknownPeer(node) => write!(f, "node {} is not a known peer", node),
+            TransportError::InvalidSend(error) => write!(f, "invalid send: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+impl From<io::Error> for TransportError {
+    fn from(error: io::Error) -> Self {
+        TransportError::Io(error)
+    }
+}
+
+impl From<CodecError> for TransportError {
+    fn from(error: CodecError) -> Self {
+        TransportError::Codec(error)
+    }
+}
+
+impl From<SendError> for TransportError {
+    fn from(error: SendError) -> Self {
+        TransportError::InvalidSend(error)
+    }
+}
+
+// A node is one process in a distributed run. It hosts some of the machines and knows
+// which node hosts every other machine. Sends to a local machine are delivered directly,
+// sends to a remote machine go over a persistent connection to the node hosting it.
+//
+// Every node keeps one outgoing connection per peer and accepts the peers incoming
+// connections on its listener, so data flows out on one connection and acks come back
+// on the other. Each outgoing message gets the next sequence number for that peer and
+// is kept until the peer acknowledges it. If the connection drops the node reconnects
+// on the next send or poll and retransmits everything unacknowledged, the receiver uses
+// the sequence numbers to throw away anything it already delivered. This matters more
+// than usual here since delivering the same message twice would annihilate it.
+//
+// Any node can compute GVT with start_gvt, the round runs while the nodes poll and
+// accounts for the messages still on their way between nodes (see gvt.rs). When it
+// finishes the result is sent to every node and all the machines commit up to it. A
+// control frame lost to a dropped connection just stalls the round, starting it again
+// picks up where it was.
+//
+// Nodes can also be put together as a federation instead of by hand. A node joins by
+// telling a coordinator (any node already in) where it listens and what it hosts, the
+// coordinator then sends the full list of members to everyone so every node ends up
+// with every other one as a peer. The coordinator, or any other node, can send control
+// commands to all of them, see Command.
+//
+// A machine can move to another node, say to even out the load. The move happens once
+// the next GVT round finishes on this node, when committing has thrown away what the
+// machine doesnt need anymore, and takes the whole machine with its saved states and
+// queues so it can still roll back on the other side. Messages for it that still reach
+// this node are passed on, and once it is running on the new node that node tells
+// everyone it is there. A machine still waiting on acks for its antimessages stays
+// until they came back since the acks are sent to this node.
+pub struct TcpNode {
+    node_id: NodeId,
+    machines: BTreeMap<MachineId, Machine>,
+    placement: Router<NodeId>,
+    peers: HashMap<NodeId, Peer>,
+    // Highest sequence number delivered from each peer
+    delivered: HashMap<NodeId, SequenceNumber>,
+    incoming: Receiver<(NodeId, Frame)>,
+    local_addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    counter: MatternCounter,
+    // The round this node started and is waiting on reports for
+    round: Option<GvtRound>,
+    gvt: Option<VirtualTime>,
+    gvt_rounds: u64,
+    metrics: Option<MetricsHandle>,
+    paused: bool,
+    // Machines to move at the next GVT and where to
+    migrations: Vec<(MachineId, NodeId)>,
+    balancer: Option<Balancer>,
+    checkpoint_compression: Option<Compression>,
+    protocol: Protocol,
+}
+
+// What the links of a node speak, every node of a federation has to speak the same
+#[derive(Debug, Clone, Copy)]
+enum Protocol {
+    Tcp,
+    #[cfg(feature = "grpc")]
+    Grpc,
+}
+
+// The outgoing connection to a peer
+enum Link {
+    Tcp(BufWriter<TcpStream>),
+    #[cfg(feature = "grpc")]
+    Grpc(Box<super::grpc::GrpcLink>),
+}
+
+struct Peer {
+    addr: SocketAddr,
+    protocol: Protocol,
+    stream: Option<Link>,
+    next_seq: SequenceNumber,
+    unacked: BTreeMap<SequenceNumber, Unacked>,
+    // How frames to this peer are compressed, see set_link_compression
+    compression: Option<Compression>,
+}
+
+// What went out under a sequence number, several messages are a batch of antimessages
+enum Unacked {
+    Messages(Epoch, Vec<Message>),
+    Machine(Option<Epoch>, Vec<u8>),
+}
+
+impl Unacked {
+    fn messages(&self) -> &[Message] {
+        match self {
+            Unacked::Messages(_, messages) => messages,
+            Unacked::Machine(..) => &[],
+        }
+    }
+}
+
+fn data_frame(seq: SequenceNumber, unacked: &Unacked) -> Frame {
+    match unacked {
+        Unacked::Messages(epoch, messages) if messages.len() == 1 => Frame::Data {
+            seq,
+            epoch: *epoch,
+            message: messages[0].clone(),
+        },
+        Unacked::Messages(epoch, messages) => Frame::Antimessages {
+            seq,
+            epoch: *epoch,
+            antimessages: messages.clone(),
+        },
+        Unacked::Machine(epoch, machine) => Frame::Migrate {
+            seq,
+            epoch: *epoch,
+            machine: machine.clone(),
+        },
+    }
+}
+
+impl Peer {
+    fn new(addr: SocketAddr, protocol: Protocol) -> Self {
+        Self {
+            addr,
+            protocol,
+            stream: None,
+            next_seq: 1,
+            unacked: BTreeMap::new(),
+            compression: None,
+        }
+    }
+
+    // Opens the connection if there isnt one, introducing ourselves and resending
+    // everything the peer hasnt acknowledged yet in order
+    fn ensure_connected(&mut self, local: NodeId) -> bool {
+        if self.stream.is_some() {
+            return true;
+        }
+        let link = match self.protocol {
+            Protocol::Tcp => {
+                let Ok(stream) = TcpStream::connect_timeout(&self.addr, CONNECT_TIMEOUT) else {
+                    return false;
+                };
+                let _ = stream.set_nodelay(true);
+                Link::Tcp(BufWriter::new(stream))
+            }
+            #[cfg(feature = "grpc")]
+            Protocol::Grpc => {
+                match super::grpc::GrpcLink::connect(self.addr, local, CONNECT_TIMEOUT) {
+                    Some(link) => Link::Grpc(Box::new(link)),
+                    None => return false,
+                }
+            }
+        };
+        self.stream = Some(link);
+        if !self.write(&Frame::Hello { node: local }) {
+            return false;
+        }
+        let retransmit: Vec<_> = self
+            .unacked
+            .iter()
+            .map(|(seq, unacked)| data_frame(*seq, unacked))
+            .collect();
+        retransmit.iter().all(|frame| self.write(frame))
+    }
+
+    // Writes a frame, a failure drops the connection so the next attempt reconnects
+    fn write(&mut self, frame: &Frame) -> bool {
+        let Some(stream) = self.stream.as_mut() else {
+            return false;
+        };
+        let written = match stream {
+            Link::Tcp(stream) => {
+                codec::write_frame_with(stream, frame, self.compression).is_ok()
+                    && stream.flush().is_ok()
+            }
+            #[cfg(feature = "grpc")]
+            Link::Grpc(link) => link.send(frame),
+        };
+        if !written {
+            self.stream = None;
+        }
+        written
+    }
+
+    fn send(&mut self, local: NodeId, frame: &Frame) -> bool {
+        let was_connected = self.stream.is_some();
+        if !self.ensure_connected(local) {
+            return false;
+        }
+        // A fresh connection already retransmitted every unacked data frame
+        let is_data = matches!(
+            frame,
+            Frame::Data { .. } | Frame::Antimessages { .. } | Frame::Migrate { .. }
+        );
+        if !was_connected && is_data {
+            return true;
+        }
+        self.write(frame)
+    }
+}
+
+impl TcpNode {
+    pub fn bind<A: ToSocketAddrs>(node_id: NodeId, addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let (frames, incoming) = mpsc::channel();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        spawn_acceptor(listener, frames, shutdown.clone());
+        Ok(Self::new(node_id, local_addr, incoming, shutdown, Protocol::Tcp))
+    }
+
+    // A node that serves the gRPC service in proto/federation.proto and talks to its
+    // peers with it, see grpc.rs. Its peers have to be bound with bind_grpc too.
+    #[cfg(feature = "grpc")]
+    pub fn bind_grpc<A: ToSocketAddrs>(node_id: NodeId, addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let (frames, incoming) = mpsc::channel();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        super::grpc::spawn_server(listener, frames, shutdown.clone())?;
+        Ok(Self::new(node_id, local_addr, incoming, shutdown, Protocol::Grpc))
+    }
+
+    fn new(
+        node_id: NodeId,
+        local_addr: SocketAddr,
+        incoming: Receiver<(NodeId, Frame)>,
+        shutdown: Arc<AtomicBool>,
+        protocol: Protocol,
+    ) -> Self {
+        Self {
+            node_id,
+            machines: BTreeMap::new(),
+            placement: Router::new(),
+            peers: HashMap::new(),
+            delivered: HashMap::new(),
+            incoming,
+            local_addr,
+            shutdown,
+            counter: MatternCounter::default(),
+            round: None,
+            gvt: None,
+            gvt_rounds: 0,
+            metrics: None,
+            paused: false,
+            migrations: Vec::new(),
+            balancer: None,
+            checkpoint_compression: None,
+            protocol,
+        }
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn add_peer(&mut self, node: NodeId, addr: SocketAddr) {
+        self.peers.insert(node, Peer::new(addr, self.protocol));
+    }
+
+    // Compresses big frames to the peer, for links that are slow or carry big payloads.
+    // Each direction of a link is set on its own, a node reads compressed frames whether
+    // it compresses its own or not. It doesnt do anything on gRPC links.
+    pub fn set_link_compression(
+        &mut self,
+        node: NodeId,
+        compression: Option<Compression>,
+    ) -> Result<(), TransportError> {
+        let peer = self.peers.get_mut(&node).ok_or(TransportError::UnknownPeer(node))?;
+        peer.compression = compression;
+        Ok(())
+    }
+
+    // How Command::Checkpoint writes the checkpoint of this node
+    pub fn set_checkpoint_compression(&mut self, compression: Option<Compression>) {
+        self.checkpoint_compression = compression;
+    }
+
+    // Asks the node at addr to let this one into its federation, the members come back
+    // while polling. The machines to host have to be added before joining.
+    pub fn join(&mut self, coordinator: NodeId, addr: SocketAddr) -> Result<(), TransportError> {
+        let protocol = self.protocol;
+        self.peers.entry(coordinator).or_insert_with(|| Peer::new(addr, protocol));
+        let member = self.member();
+        self.send_control_frame(coordinator, &Frame::Join(member))
+    }
+
+    // This node and every peer it knows of, with the machines placed on them
+    pub fn members(&self) -> Vec<Member> {
+        let mut addrs: BTreeMap<NodeId, SocketAddr> =
+            self.peers.iter().map(|(node, peer)| (*node, peer.addr)).collect();
+        addrs.insert(self.node_id, self.local_addr);
+        addrs
+            .into_iter()
+            .map(|(node, addr)| {
+                let machines: Vec<_> = self
+                    .placement
+                    .iter()
+                    .filter(|(_, placed_on)| **placed_on == node)
+                    .map(|(machine_id, _)| machine_id)
+                    .collect();
+                Member {
+                    node,
+                    addr,
+                    machines,
+                }
+            })
+            .collect()
+    }
+
+    fn member(&self) -> Member {
+        Member {
+            node: self.node_id,
+            addr: self.local_addr,
+            machines: self.machines.keys().copied().collect(),
+        }
+    }
+
+    fn add_member(&mut self, member: Member) {
+        if member.node == self.node_id {
+            return;
+        }
+        self.peers
+            .entry(member.node)
+            .or_insert_with(|| Peer::new(member.addr, self.protocol));
+        for machine_id in member.machines {
+            self.placement.insert(machine_id, member.node);
+        }
+    }
+
+    // Runs a command here and on every peer
+    pub fn send_control(&mut self, command: Command) -> Result<(), TransportError> {
+        let frame = Frame::Control(command.clone());
+        let nodes: Vec<_> = self.peers.keys().copied().collect();
+        for node in nodes {
+            self.send_control_frame(node, &frame)?;
+        }
+        self.apply_command(command)
+    }
+
+    fn send_control_frame(&mut self, node: NodeId, frame: &Frame) -> Result<(), TransportError> {
+        let local = self.node_id;
+        let peer = self
+            .peers
+            .get_mut(&node)
+            .ok_or(TransportError::UnknownPeer(node))?;
+        peer.send(local, frame);
+        Ok(())
+    }
+
+    // Whether a Pause command came in and no Resume after it
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    fn apply_command(&mut self, command: Command) -> Result<(), TransportError> {
+        match command {
+            Command::Pause => self.paused = true,
+            Command::Resume => self.paused = false,
+            Command::Checkpoint { dir } => {
+                let path = dir.join(format!("node-{}.checkpoint", self.node_id));
+                let compression = self.checkpoint_compression;
+                // What the peers havent acknowledged yet is still on its way
+                let mut in_transit: VecDeque<_> = self
+                    .peers
+                    .values()
+                    .flat_map(|peer| peer.unacked.values().flat_map(Unacked::messages))
+                    .cloned()
+                    .collect();
+                in_transit.make_contiguous().sort_by_key(|message| message.id);
+                let names = self.router().names();
+                write_checkpoint(path, self.gvt, &self.machines, &in_transit, names, compression)?;
+            }
+            // Sent to everyone by send_control, the other nodes only need Placed later
+            Command::Migrate { machine_id, to } => {
+                if self.machines.contains_key(&machine_id) {
+                    self.migrate(machine_id, to)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_machine(&mut self, machine: Machine) {
+        self.placement.insert(machine.machine_id(), self.node_id);
+        self.machines.insert(machine.machine_id(), machine);
+    }
+
+    // Records which node hosts a remote machine
+    pub fn place(&mut self, machine_id: MachineId, node: NodeId) {
+        self.placement.insert(machine_id, node);
+    }
+
+    // Names are local to this node, peers dont hear about them
+    pub fn name_machine(&mut self, machine_id: MachineId, name: &str) -> Result<(), RouterError> {
+        self.placement.set_name(machine_id, name)
+    }
+
+    // Which node hosts every machine this node knows of, and their names
+    pub fn router(&self) -> &Router<NodeId> {
+        &self.placement
+    }
+
+    pub fn machine(&self, machine_id: MachineId) -> Option<&Machine> {
+        self.machines.get(&machine_id)
+    }
+
+    pub fn machine_mut(&mut self, machine_id: MachineId) -> Option<&mut Machine> {
+        self.machines.get_mut(&machine_id)
+    }
+
+    // Number of messages that are waiting on an ack from a peer
+    pub fn unacked(&self, node: NodeId) -> usize {
+        self.peers
+            .get(&node)
+            .map_or(0, |peer| peer.unacked.values().map(|unacked| unacked.messages().len()).sum())
+    }
+
+    // Number of data frames sent to a peer so far, a batch of antimessages is one
+    pub fn frames_sent(&self, node: NodeId) -> u64 {
+        self.peers.get(&node).map_or(0, |peer| peer.next_seq - 1)
+    }
+
+    // Closes the outgoing connection to a peer, it is reopened on the next send or poll
+    pub fn disconnect(&mut self, node: NodeId) {
+        if let Some(peer) = self.peers.get_mut(&node) {
+            peer.stream = None;
+        }
+    }
+
+    // Sends a message from one of the machines on this node, it is logged in the
+    // senders output queue like any other send before being routed. The machine refuses
+    // it if it couldnt have been sent at its local virtual time, see SendError.
+    pub fn send(&mut self, message: Message) -> Result<(), TransportError> {
+        let message = match self.machines.get_mut(&message.sender) {
+            Some(machine) => machine.send_outer(message)?,
+            None => message,
+        };
+        self.route(message)
+    }
+
+    // Delivers a message wherever its receiver lives without logging it as a send, this
+    // is how messages from outside the simulation enter it. Antimessages for other nodes
+    // are held back until everything the message set off locally is done, then each node
+    // gets its antimessages in one frame.
+    pub fn route(&mut self, message: Message) -> Result<(), TransportError> {
+        let mut pending = VecDeque::from([message]);
+        let mut batches: BTreeMap<NodeId, Vec<Message>> = BTreeMap::new();
+        while let Some(message) = pending.pop_front() {
+            let node = *self
+                .placement
+                .lookup(message.receiver)
+                .ok_or(TransportError::UnknownMachine(message.receiver))?;
+            if node == self.node_id {
+                let is_antimessage = message.sign == Sign::Antimessage;
+                let sender = message.sender;
+                pending.extend(self.deliver_local(message.clone()));
+                if is_antimessage {
+                    if let Some(machine) = self.machines.get_mut(&sender) {
+                        machine.acknowledge_antimessage(&message);
+                    }
+                }
+            } else if message.sign == Sign::Antimessage {
+                if !self.peers.contains_key(&node) {
+                    return Err(TransportError::UnknownPeer(node));
+                }
+                batches.entry(node).or_default().push(message);
+            } else {
+                // Keep the order of what goes to the node
+                if let Some(batch) = batches.remove(&node) {
+                    self.send_remote(node, batch)?;
+                }
+                self.send_remote(node, vec![message])?;
+            }
+        }
+        for (node, batch) in batches {
+            self.send_remote(node, batch)?;
+        }
+        Ok(())
+    }
+
+    fn send_remote(&mut self, node: NodeId, messages: Vec<Message>) -> Result<(), TransportError> {
+        let local = self.node_id;
+        let peer = self
+            .peers
+            .get_mut(&node)
+            .ok_or(TransportError::UnknownPeer(node))?;
+        let seq = peer.next_seq;
+        peer.next_seq += 1;
+        let mut epoch = self.counter.epoch();
+        for message in &messages {
+            epoch = self.counter.on_send(message.rec_time);
+        }
+        let unacked = Unacked::Messages(epoch, messages);
+        let frame = data_frame(seq, &unacked);
+        peer.unacked.insert(seq, unacked);
+        // If this fails the messages stay unacked and go out after reconnecting
+        peer.send(local, &frame);
+        Ok(())
+    }
+
+    // Moves one of the machines on this node to a peer once the next GVT round is done
+    pub fn migrate(&mut self, machine_id: MachineId, to: NodeId) -> Result<(), TransportError> {
+        if !self.machines.contains_key(&machine_id) {
+            return Err(TransportError::UnknownMachine(machine_id));
+        }
+        if !self.peers.contains_key(&to) {
+            return Err(TransportError::UnknownPeer(to));
+        }
+        self.migrations.push((machine_id, to));
+        Ok(())
+    }
+
+    fn run_migrations(&mut self) {
+        let migrations = std::mem::take(&mut self.migrations);
+        for (machine_id, to) in migrations {
+            let Some(machine) = self.machines.get(&machine_id) else {
+                continue;
+            };
+            if !machine.in_flight.is_empty() {
+                self.migrations.push((machine_id, to));
+                continue;
+            }
+            let Ok(encoded) = bincode::serialize(machine) else {
+                trace_warn!(machine_id, "Machine cant be encoded, not moving it");
+                continue;
+            };
+            let epoch = machine.earliest_pending().map(|rec_time| self.counter.on_send(rec_time));
+            self.machines.remove(&machine_id);
+            self.placement.insert(machine_id, to);
+
+            let local = self.node_id;
+            let peer = self.peers.get_mut(&to).unwrap();
+            let seq = peer.next_seq;
+            peer.next_seq += 1;
+            let unacked = Unacked::Machine(epoch, encoded);
+            let frame = data_frame(seq, &unacked);
+            peer.unacked.insert(seq, unacked);
+            peer.send(local, &frame);
+        }
+    }
+
+    // Handles whatever the peers have sent, waiting up to the timeout for the first frame.
+    // Also reconnects to any peer that still has unacknowledged messages. Returns the
+    // number of frames handled.
+    pub fn poll(&mut self, timeout: Duration) -> Result<usize, TransportError> {
+        let local = self.node_id;
+        for peer in self.peers.values_mut() {
+            if !peer.unacked.is_empty() {
+                peer.ensure_connected(local);
+            }
+        }
+
+        let mut handled = 0;
+        let mut next = match self.incoming.recv_timeout(timeout) {
+            Ok(frame) => Some(frame),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        };
+        while let Some((node, frame)) = next {
+            self.handle(node, frame)?;
+            handled += 1;
+            next = self.incoming.try_recv().ok();
+        }
+        Ok(handled)
+    }
+
+    fn handle(&mut self, node: NodeId, frame: Frame) -> Result<(), TransportError> {
+        match frame {
+            Frame::Hello { .. } => {}
+            Frame::Data {
+                seq,
+                epoch,
+                message,
+            } => self.receive_data(node, seq, epoch, vec![message])?,
+            Frame::Antimessages {
+                seq,
+                epoch,
+                antimessages,
+            } => self.receive_data(node, seq, epoch, antimessages)?,
+            Frame::Ack { seq } => {
+                let Some(peer) = self.peers.get_mut(&node) else {
+                    return Err(TransportError::UnknownPeer(node));
+                };
+                let still_unacked = peer.unacked.split_off(&(seq + 1));
+                let acked = std::mem::replace(&mut peer.unacked, still_unacked);
+                for unacked in acked.into_values() {
+                    for message in unacked.messages() {
+                        if message.sign != Sign::Antimessage {
+                            continue;
+                        }
+                        if let Some(machine) = self.machines.get_mut(&message.sender) {
+                            machine.acknowledge_antimessage(message);
+                        }
+                    }
+                }
+            }
+            Frame::GvtRequest { epoch } => {
+                self.counter.advance_past(epoch);
+                let report = self.counter.report(epoch, self.local_min());
+                let local = self.node_id;
+                let peer = self
+                    .peers
+                    .get_mut(&node)
+                    .ok_or(TransportError::UnknownPeer(node))?;
+                peer.send(local, &Frame::GvtReport(report));
+            }
+            Frame::GvtReport(report) => {
+                if let Some(round) = self.round.as_mut() {
+                    round.add(node, report);
+                    self.finish_gvt_round();
+                }
+            }
+            Frame::Gvt { gvt } => self.apply_gvt(gvt),
+            Frame::Migrate {
+                seq,
+                epoch,
+                machine,
+            } => {
+                if self.in_sequence(node, seq) {
+                    self.install(epoch, &machine)?;
+                }
+                self.ack(node);
+            }
+            Frame::LoadRequest => {
+                let load = Frame::Load(self.load());
+                self.send_control_frame(node, &load)?;
+            }
+            Frame::Load(load) => self.add_load(load)?,
+            Frame::Placed { machine_id, node } => {
+                if !self.machines.contains_key(&machine_id) {
+                    self.placement.insert(machine_id, node);
+                }
+            }
+            Frame::Join(member) => {
+                self.add_member(member);
+                let members = self.members();
+                let nodes: Vec<_> = self.peers.keys().copied().collect();
+                for node in nodes {
+                    let frame = Frame::Members {
+                        members: members.clone(),
+                    };
+                    self.send_control_frame(node, &frame)?;
+                }
+            }
+            Frame::Members { members } => members.into_iter().for_each(|m| self.add_member(m)),
+            Frame::Control(command) => self.apply_command(command)?,
+        }
+        Ok(())
+    }
+
+    fn receive_data(
+        &mut self,
+        node: NodeId,
+        seq: SequenceNumber,
+        epoch: Epoch,
+        messages: Vec<Message>,
+    ) -> Result<(), TransportError> {
+        if self.in_sequence(node, seq) {
+            // Whatever is for a machine here goes in as one batch per machine, so a frame
+            // full of stragglers rolls each of them back once
+            let mut local: BTreeMap<MachineId, Vec<Message>> = BTreeMap::new();
+            for message in messages {
+                self.counter.on_receive(epoch);
+                let here = self.placement.lookup(message.receiver) == Some(&self.node_id);
+                if here && self.machines.contains_key(&message.receiver) {
+                    local.entry(message.receiver).or_default().push(message);
+                } else {
+                    self.route(message)?;
+                }
+            }
+            for (machine_id, batch) in local {
+                self.deliver_batch(machine_id, batch)?;
+            }
+        }
+        self.ack(node);
+        Ok(())
+    }
+
+    // Like route for a batch of messages to one local machine
+    fn deliver_batch(
+        &mut self,
+        machine_id: MachineId,
+        batch: Vec<Message>,
+    ) -> Result<(), TransportError> {
+        let acknowledged: Vec<_> =
+            batch.iter().filter(|message| message.sign == Sign::Antimessage).cloned().collect();
+        let Some(machine) = self.machines.get_mut(&machine_id) else {
+            return Ok(());
+        };
+        let antimessages = machine.recieve_batch(batch).unwrap_or_default();
+        for antimessage in &acknowledged {
+            if let Some(sender) = self.machines.get_mut(&antimessage.sender) {
+                sender.acknowledge_antimessage(antimessage);
+            }
+        }
+        for antimessage in antimessages {
+            self.route(antimessage)?;
+        }
+        Ok(())
+    }
+
+    // Anything at or below what was delivered is a retransmission, anything past the next
+    // expected number will be resent in order so it is dropped for now
+    fn in_sequence(&mut self, node: NodeId, seq: SequenceNumber) -> bool {
+        let delivered = self.delivered.entry(node).or_insert(0);
+        if seq == *delivered + 1 {
+            *delivered = seq;
+            return true;
+        }
+        false
+    }
+
+    fn ack(&mut self, node: NodeId) {
+        let ack = Frame::Ack {
+            seq: self.delivered[&node],
+        };
+        let local = self.node_id;
+        if let Some(peer) = self.peers.get_mut(&node) {
+            peer.send(local, &ack);
+        }
+    }
+
+    // Takes in a machine that moved here and tells everyone
+    fn install(&mut self, epoch: Option<Epoch>, encoded: &[u8]) -> Result<(), TransportError> {
+        let machine: Machine = bincode::deserialize(encoded).map_err(CodecError::from)?;
+        if let Some(epoch) = epoch {
+            self.counter.on_receive(epoch);
+        }
+        let machine_id = machine.machine_id();
+        self.add_machine(machine);
+        let frame = Frame::Placed {
+            machine_id,
+            node: self.node_id,
+        };
+        let nodes: Vec<_> = self.peers.keys().copied().collect();
+        for node in nodes {
+            self.send_control_frame(node, &frame)?;
+        }
+        Ok(())
+    }
+
+    // Machines waiting to be moved by migrate
+    pub fn pending_migrations(&self) -> usize {
+        self.migrations.len()
+    }
+
+    // Makes this node balance the load of the whole run, see balance.rs. The moves it
+    // comes up with after a GVT round are carried out at the round after.
+    pub fn set_balancer(&mut self, balancer: Balancer) {
+        self.balancer = Some(balancer);
+    }
+
+    pub fn balancer(&self) -> Option<&Balancer> {
+        self.balancer.as_ref()
+    }
+
+    // What the machines on this node have done since the start
+    pub fn load(&self) -> NodeLoad {
+        let machines = self
+            .machines
+            .values()
+            .map(|machine| MachineLoad {
+                machine_id: machine.machine_id(),
+                committed: machine.stats().events_committed,
+                rolled_back: machine.stats().events_rolled_back,
+            })
+            .collect();
+        NodeLoad {
+            node: self.node_id,
+            machines,
+        }
+    }
+
+    fn collect_load(&mut self) -> Result<(), TransportError> {
+        if !self.balancer.as_mut().is_some_and(Balancer::round_done) {
+            return Ok(());
+        }
+        let nodes: Vec<_> = self.peers.keys().copied().collect();
+        for node in nodes {
+            self.send_control_frame(node, &Frame::LoadRequest)?;
+        }
+        self.add_load(self.load())
+    }
+
+    fn add_load(&mut self, load: NodeLoad) -> Result<(), TransportError> {
+        let nodes = self.peers.len() + 1;
+        let Some(moves) = self.balancer.as_mut().and_then(|balancer| balancer.add(load, nodes))
+        else {
+            return Ok(());
+        };
+        for step in moves {
+            trace_debug!(step.machine_id, step.from, step.to, "Rebalancing");
+            let command = Command::Migrate {
+                machine_id: step.machine_id,
+                to: step.to,
+            };
+            if step.from == self.node_id {
+                self.apply_command(command)?;
+            } else {
+                self.send_control_frame(step.from, &Frame::Control(command))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Last GVT a round came up with, None before the first one or when there was nothing
+    // left to do
+    pub fn gvt(&self) -> Option<VirtualTime> {
+        self.gvt
+    }
+
+    // Number of GVT rounds this node has seen finish
+    pub fn gvt_rounds(&self) -> u64 {
+        self.gvt_rounds
+    }
+
+    // Publishes a snapshot of this nodes machines after every GVT round
+    pub fn set_metrics(&mut self, metrics: MetricsHandle) {
+        metrics.publish(self.metrics());
+        self.metrics = Some(metrics);
+    }
+
+    pub fn metrics(&self) -> MetricsSnapshot {
+        MetricsSnapshot::new(self.gvt, self.machines.values())
+    }
+
+    // Starts a GVT round with this node as the controller, or asks again if one is
+    // already running
+    pub fn start_gvt(&mut self) {
+        let epoch = match &self.round {
+            Some(round) => round.epoch,
+            None => self.counter.epoch(),
+        };
+        self.round = Some(GvtRound::new(epoch));
+        self.ask_for_gvt(epoch);
+    }
+
+    fn ask_for_gvt(&mut self, epoch: Epoch) {
+        self.counter.advance_past(epoch);
+        let report = self.counter.report(epoch, self.local_min());
+        if let Some(round) = self.round.as_mut() {
+            round.add(self.node_id, report);
+        }
+        let local = self.node_id;
+        for peer in self.peers.values_mut() {
+            peer.send(local, &Frame::GvtRequest { epoch });
+        }
+        self.finish_gvt_round();
+    }
+
+    // Once every node answered either hands out the result or, if some message of the
+    // epoch is still on its way, asks everyone again
+    fn finish_gvt_round(&mut self) {
+        let Some(round) = self.round.as_mut() else {
+            return;
+        };
+        if !round.has_all(self.peers.len() + 1) {
+            return;
+        }
+        let epoch = round.epoch;
+        match round.gvt() {
+            Some(gvt) => {
+                self.round = None;
+                let local = self.node_id;
+                for peer in self.peers.values_mut() {
+                    peer.send(local, &Frame::Gvt { gvt });
+                }
+                self.apply_gvt(gvt);
+            }
+            None => {
+                round.retry();
+                self.ask_for_gvt(epoch);
+            }
+        }
+    }
+
+    fn apply_gvt(&mut self, gvt: Option<VirtualTime>) {
+        self.gvt = gvt;
+        self.gvt_rounds += 1;
+        self.counter.forget_before(self.counter.epoch());
+        for machine in self.machines.values_mut() {
+            machine.commit(gvt);
+        }
+        self.run_migrations();
+        if self.collect_load().is_err() {
+            trace_warn!(node = self.node_id, "Couldnt ask the other nodes for their load");
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.publish(self.metrics());
+        }
+    }
+
+    // Earliest thing any machine on this node still has to process or get acked, like
+    // Simulation::gvt an antimessage waiting for its positive message doesnt count
+    fn local_min(&self) -> Option<VirtualTime> {
+        self.machines
+            .values()
+            .flat_map(|machine| [machine.earliest_pending(), machine.in_flight.min_rec_time()])
+            .flatten()
+            .min()
+    }
+
+    // Incoming messages and antimessages go through recieve_outer exactly like local
+    // ones, any antimessages produced by a rollback are handed back to be routed
+    fn deliver_local(&mut self, message: Message) -> Vec<Message> {
+        match self.machines.get_mut(&message.receiver) {
+            Some(machine) => machine.recieve_outer(message).unwrap_or_default(),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Drop for TcpNode {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+    }
+}
+
+fn spawn_acceptor(listener: TcpListener, frames: Sender<(NodeId, Frame)>, shutdown: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        while !shutdown.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if stream.set_nonblocking(false).is_ok() {
+                        spawn_reader(stream, frames.clone());
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                }
+                Err(_) => return,
+            }
+        }
+    });
+}
+
+// Reads frames off one incoming connection until it closes, the first frame has to say
+// which node is on the other end
+fn spawn_reader(stream: TcpStream, frames: Sender<(NodeId, Frame)>) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let node = match codec::read_frame(&mut reader) {
+            Ok(Frame::Hello { node }) => node,
+            _ => return,
+        };
+        while let Ok(frame) = codec::read_frame::<_, Frame>(&mut reader) {
+            if frames.send((node, frame)).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::ExampleProcess;
+    use crate::transport::balance::MoveHottest;
+    use crate::runtime::checkpoint::Checkpoint;
+    use std::time::Instant;
+
+    fn poll_until(nodes: &mut [&mut TcpNode], mut done: impl FnMut(&mut [&mut TcpNode]) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(nodes) {
+            assert!(Instant::now() < deadline, "nodes never reached the expected state");
+            for node in nodes.iter_mut() {
+                node.poll(Duration::from_millis(5)).unwrap();
+            }
+        }
+    }
+
+    fn pair() -> (TcpNode, TcpNode) {
+        let mut node_a = TcpNode::bind(0, "127.0.0.1:0").unwrap();
+        let mut node_b = TcpNode::bind(1, "127.0.0.1:0").unwrap();
+        node_a.add_peer(1, node_b.local_addr());
+        node_b.add_peer(0, node_a.local_addr());
+        node_a.add_machine(Machine::new(1, 0));
+        node_a.place(2, 1);
+        node_b.add_machine(Machine::new(2, 0));
+        node_b.place(1, 0);
+        (node_a, node_b)
+    }
+
+    #[test]
+    fn test_remote_send_and_antimessage() {
+        let (mut node_a, mut node_b) = pair();
+
+        // Machine 1 processes an event at 3 and sends to machine 2 on the other node
+        node_a
+            .route(Message::new(0, 3, 1, 1, Sign::Message, Arc::new("first".to_string())))
+            .unwrap();
+        node_a.machine_mut(1).unwrap().recieve_inner();
+        let message = Message::new(3, 8, 1, 2, Sign::Message, Arc::new("remote".to_string()));
+        node_a.send(message.clone()).unwrap();
+        poll_until(&mut [&mut node_a, &mut node_b], |nodes| nodes[0].unacked(1) == 0);
+
+        let machine2 = node_b.machine_mut(2).unwrap();
+        assert_eq!(machine2.input_queue.peek_smallest_greater(), Some(message.clone()));
+
+        // A straggler on machine 1 rolls back the send, the antimessage crosses the
+        // connection and annihilates the original in machine 2's input queue
+        node_a
+            .route(Message::new(0, 2, 1, 1, Sign::Message, Arc::new("straggler".to_string())))
+            .unwrap();
+        assert_eq!(node_a.machine(1).unwrap().in_flight.len(), 1);
+
+        poll_until(&mut [&mut node_a, &mut node_b], |nodes| {
+            nodes[0].unacked(1) == 0 && nodes[0].machine(1).unwrap().in_flight.is_empty()
+        });
+        let machine2 = node_b.machine_mut(2).unwrap();
+        assert_eq!(machine2.input_queue.remove_smallest(), None);
+    }
+
+    #[test]
+    fn test_rollback_batches_antimessages_per_node() {
+        let (mut node_a, mut node_b) = pair();
+        node_b.add_machine(Machine::new(3, 0));
+        node_a.place(3, 1);
+
+        // Machine 1 sends to both machines on the other node from two different events
+        for (rec_time, to) in [(3, 2), (4, 3)] {
+            let local = Message::new(0, rec_time, 1, 1, Sign::Message, Arc::new("local".to_string()));
+            node_a.route(local).unwrap();
+            node_a.machine_mut(1).unwrap().recieve_inner();
+            let payload = Arc::new("remote".to_string());
+            node_a
+                .send(Message::new(rec_time, 10 + to, 1, to, Sign::Message, payload))
+                .unwrap();
+        }
+        assert_eq!(node_a.frames_sent(1), 2);
+
+        // Rolling both events back cancels both sends with a single frame
+        node_a
+            .route(Message::new(0, 2, 1, 1, Sign::Message, Arc::new("straggler".to_string())))
+            .unwrap();
+        assert_eq!(node_a.frames_sent(1), 3);
+        assert_eq!(node_a.machine(1).unwrap().in_flight.len(), 2);
+
+        poll_until(&mut [&mut node_a, &mut node_b], |nodes| {
+            nodes[0].unacked(1) == 0 && nodes[0].machine(1).unwrap().in_flight.is_empty()
+        });
+        assert!(node_b.machine(2).unwrap().input_queue.is_empty());
+        assert!(node_b.machine(3).unwrap().input_queue.is_empty());
+    }
+
+    #[test]
+    fn test_gvt_counts_messages_between_nodes() {
+        let (mut node_a, mut node_b) = pair();
+
+        // Machine 1 is done with everything, only the message to machine 2 is left
+        node_a
+            .route(Message::new(0, 3, 1, 1, Sign::Message, Arc::new("first".to_string())))
+            .unwrap();
+        node_a.machine_mut(1).unwrap().recieve_inner();
+        node_a
+            .send(Message::new(3, 8, 1, 2, Sign::Message, Arc::new("remote".to_string())))
+            .unwrap();
+
+        node_a.start_gvt();
+        poll_until(&mut [&mut node_a, &mut node_b], |nodes| {
+            nodes.iter().all(|node| node.gvt_rounds() == 1)
+        });
+        assert_eq!(node_a.gvt(), Some(VirtualTime::new(8)));
+        assert_eq!(node_b.gvt(), Some(VirtualTime::new(8)));
+        assert_eq!(node_a.machine(1).unwrap().stats().events_committed, 1);
+
+        node_b.machine_mut(2).unwrap().recieve_inner();
+        node_b.start_gvt();
+        poll_until(&mut [&mut node_a, &mut node_b], |nodes| {
+            nodes.iter().all(|node| node.gvt_rounds() == 2)
+        });
+        assert_eq!(node_a.gvt(), None);
+        assert_eq!(node_b.machine(2).unwrap().stats().events_committed, 1);
+    }
+
+    #[test]
+    fn test_antimessages_on_their_own_dont_hold_up_gvt() {
+        let (mut node_a, mut node_b) = pair();
+
+        // Its positive message is never coming, there is nothing left to do
+        let mut antimessage = Message::new(0, 2, 1, 2, Sign::Message, Arc::new("x".to_string()));
+        antimessage.sign = Sign::Antimessage;
+        node_b.route(antimessage).unwrap();
+        assert!(node_b.machine(2).unwrap().peek_next_message().is_some());
+
+        node_a.start_gvt();
+        poll_until(&mut [&mut node_a, &mut node_b], |nodes| {
+            nodes.iter().all(|node| node.gvt_rounds() == 1)
+        });
+        assert_eq!(node_a.gvt(), None);
+    }
+
+    #[test]
+    fn test_node_checkpoints_keep_what_peers_havent_acknowledged() {
+        let (mut node_a, _node_b) = pair();
+        let message = Message::new(0, 5, 1, 2, Sign::Message, Arc::new("unacked".to_string()));
+        node_a.send(message.clone()).unwrap();
+        assert_eq!(node_a.unacked(1), 1);
+
+        let dir = std::env::temp_dir().join(format!("vtw-unacked-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        node_a.apply_command(Command::Checkpoint { dir: dir.clone() }).unwrap();
+        let checkpoint: Checkpoint<ExampleProcess> =
+            Checkpoint::read(dir.join("node-0.checkpoint")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(checkpoint.in_transit, VecDeque::from([message]));
+    }
+
+    #[test]
+    fn test_reconnect_does_not_duplicate() {
+        let (mut node_a, mut node_b) = pair();
+
+        let first = Message::new(0, 5, 1, 2, Sign::Message, Arc::new("first".to_string()));
+        let second = Message::new(0, 6, 1, 2, Sign::Message, Arc::new("second".to_string()));
+
+        node_a.send(first.clone()).unwrap();
+        // Drop the connection before the ack is processed so first is retransmitted
+        node_a.disconnect(1);
+        node_a.send(second.clone()).unwrap();
+        assert_eq!(node_a.unacked(1), 2);
+
+        poll_until(&mut [&mut node_a, &mut node_b], |nodes| nodes[0].unacked(1) == 0);
+
+        let machine2 = node_b.machine_mut(2).unwrap();
+        assert_eq!(machine2.input_queue.remove_smallest(), Some(first));
+        assert_eq!(machine2.input_queue.remove_smallest(), Some(second));
+        assert_eq!(machine2.input_queue.remove_smallest(), None);
+    }
+
+    #[test]
+    fn test_machine_moves_at_the_next_gvt() {
+        let (mut node_a, mut node_b) = pair();
+
+        // Machine 1 processed an event at 3 and still has one at 20 to go
+        for rec_time in [3, 20] {
+            let payload = Arc::new("local".to_string());
+            node_a.route(Message::new(0, rec_time, 1, 1, Sign::Message, payload)).unwrap();
+        }
+        node_a.machine_mut(1).unwrap().recieve_inner();
+        node_a
+            .send(Message::new(3, 8, 1, 2, Sign::Message, Arc::new("remote".to_string())))
+            .unwrap();
+        node_a.migrate(1, 1).unwrap();
+        assert!(matches!(node_a.migrate(5, 1), Err(TransportError::UnknownMachine(5))));
+        assert_eq!(node_a.pending_migrations(), 1);
+
+        node_a.start_gvt();
+        poll_until(&mut [&mut node_a, &mut node_b], |nodes| {
+            nodes[1].machine(1).is_some() && nodes[0].unacked(1) == 0
+        });
+        assert_eq!(node_a.gvt(), Some(VirtualTime::new(8)));
+        assert!(node_a.machine(1).is_none());
+        assert_eq!(node_a.pending_migrations(), 0);
+        let machine = node_b.machine(1).unwrap();
+        assert_eq!(machine.stats().events_processed, 1);
+        assert_eq!(machine.input_queue.peek_smallest_greater().unwrap().rec_time, 20);
+
+        // Whatever still reaches the old node is passed on
+        node_a
+            .route(Message::new(0, 25, 2, 1, Sign::Message, Arc::new("late".to_string())))
+            .unwrap();
+        poll_until(&mut [&mut node_a, &mut node_b], |nodes| {
+            nodes[0].unacked(1) == 0 && nodes[1].machine(1).unwrap().input_queue.len() == 3
+        });
+
+        // The machine still counts towards GVT while it is on the new node
+        node_b.start_gvt();
+        poll_until(&mut [&mut node_a, &mut node_b], |nodes| {
+            nodes.iter().all(|node| node.gvt_rounds() == 2)
+        });
+        assert_eq!(node_a.gvt(), Some(VirtualTime::new(8)));
+    }
+
+    #[test]
+    fn test_balancer_moves_work_to_the_idle_node() {
+        let (mut node_a, mut node_b) = pair();
+        node_a.add_machine(Machine::new(3, 0));
+        node_b.place(3, 0);
+        node_a.set_balancer(Balancer::new(MoveHottest::default(), 1));
+        for (machine_id, events) in [(1, 3), (3, 1)] {
+            for rec_time in 1..=events {
+                let payload = Arc::new("work".to_string());
+                let id = machine_id;
+                node_a.route(Message::new(0, rec_time, id, id, Sign::Message, payload)).unwrap();
+                node_a.machine_mut(machine_id).unwrap().recieve_inner();
+            }
+        }
+
+        // The first round finds node 1 idle, the second one does the move
+        node_a.start_gvt();
+        poll_until(&mut [&mut node_a, &mut node_b], |nodes| nodes[0].pending_migrations() == 1);
+        assert_eq!(node_a.balancer().unwrap().moves(), 1);
+        node_a.start_gvt();
+        poll_until(&mut [&mut node_a, &mut node_b], |nodes| nodes[1].machine(3).is_some());
+        assert_eq!(node_b.load().work(), 1);
+        assert_eq!(node_a.load().work(), 3);
+    }
+
+    #[test]
+    fn test_nodes_join_a_federation_and_obey_commands() {
+        let mut nodes: Vec<_> = (0..3)
+            .map(|node| {
+                let mut tcp_node = TcpNode::bind(node, "127.0.0.1:0").unwrap();
+                tcp_node.add_machine(Machine::new(node + 1, 0));
+                tcp_node
+            })
+            .collect();
+        let coordinator = nodes[0].local_addr();
+        nodes[1].join(0, coordinator).unwrap();
+        nodes[2].join(0, coordinator).unwrap();
+        let [node_a, node_b, node_c] = &mut nodes[..] else {
+            unreachable!()
+        };
+        poll_until(&mut [node_a, node_b, node_c], |nodes| {
+            nodes.iter().all(|node| node.members().len() == 3)
+        });
+        let machines: Vec<_> = node_c.members().into_iter().map(|m| m.machines).collect();
+        assert_eq!(machines, vec![vec![1], vec![2], vec![3]]);
+
+        // The two nodes that joined only ever talked to the coordinator
+        let message = Message::new(0, 4, 2, 3, Sign::Message, Arc::new("hi".to_string()));
+        node_b.send(message.clone()).unwrap();
+        node_a.send_control(Command::Pause).unwrap();
+        poll_until(&mut [node_a, node_b, node_c], |nodes| {
+            nodes.iter().all(|node| node.is_paused()) && nodes[1].unacked(2) == 0
+        });
+        let machine3 = node_c.machine_mut(3).unwrap();
+        assert_eq!(machine3.input_queue.remove_smallest(), Some(message));
+
+        let dir = std::env::temp_dir().join(format!("vtw-federation-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        node_a.send_control(Command::Checkpoint { dir: dir.clone() }).unwrap();
+        node_a.send_control(Command::Resume).unwrap();
+        poll_until(&mut [node_a, node_b, node_c], |nodes| {
+            nodes.iter().all(|node| !node.is_paused())
+        });
+        let checkpoint: Checkpoint<ExampleProcess> =
+            Checkpoint::read(dir.join("node-2.checkpoint")).unwrap();
+        assert_eq!(checkpoint.machines.keys().collect::<Vec<_>>(), vec![&3]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
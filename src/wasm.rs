@@ -0,0 +1,246 @@
+// WebAssembly plugin machines, behind the wasm feature. WasmModule runs a module with
+// wasmi and speaks the plugin ABI (see plugin.rs) with the exports of its instance, at
+// the wasm level that is
+//
+//   memory                                the linear memory everything goes through
+//   alloc(len: i32) -> i32                room for len bytes the host writes into
+//   on_message(ptr: i32, len: i32) -> i64  runs the json PluginEvent at ptr
+//   set_state(ptr: i32, len: i32)         optional, replace the state with the bytes
+//   get_state() -> i64                    optional, where the state is
+//
+// where an i64 coming back is a pointer in the upper 32 bits and a length in the lower.
+// Without set_state and get_state the state of the machine is the whole linear memory,
+// saving it is copying the memory out and a rollback copies the old bytes back in (the
+// empty first state is the memory the module started with). Such a module has to keep
+// everything in memory, mutable globals like a stack pointer arent saved.
+//
+// A trap or a bad pointer makes on_message an empty reply, which PluginProcess counts as
+// an invalid reply and sends nothing for, see PluginModule::on_message.
+use wasmi::{Engine, Error, Linker, Memory, Module, Store, TypedFunc};
+
+use crate::plugin::PluginModule;
+use crate::trace::trace_warn;
+
+const PAGE_SIZE: usize = 64 * 1024;
+
+pub struct WasmModule {
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    on_message: TypedFunc<(i32, i32), i64>,
+    state: Option<StateExports>,
+    // What the memory started as, for the empty state of a module without get_state
+    initial_memory: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+struct StateExports {
+    set_state: TypedFunc<(i32, i32), ()>,
+    get_state: TypedFunc<(), i64>,
+}
+
+impl std::fmt::Debug for WasmModule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WasmModule")
+            .field("memory", &self.memory.data_size(&self.store))
+            .field("exports_state", &self.state.is_some())
+            .finish()
+    }
+}
+
+impl WasmModule {
+    // The module as wasm bytes or in the text format
+    pub fn new(wasm: impl AsRef<[u8]>) -> Result<Self, Error> {
+        let engine = Engine::default();
+        let module = Module::new(&engine, wasm)?;
+        let mut store = Store::new(&engine, ());
+        let instance = Linker::<()>::new(&engine).instantiate_and_start(&mut store, &module)?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| Error::new("the module doesnt export its memory"))?;
+        let alloc = instance.get_typed_func(&store, "alloc")?;
+        let on_message = instance.get_typed_func(&store, "on_message")?;
+        let exports_state = ["set_state", "get_state"]
+            .iter()
+            .any(|name| instance.get_func(&store, name).is_some());
+        let state = match exports_state {
+            true => Some(StateExports {
+                set_state: instance.get_typed_func(&store, "set_state")?,
+                get_state: instance.get_typed_func(&store, "get_state")?,
+            }),
+            false => None,
+        };
+        let initial_memory = memory.data(&store).to_vec();
+        Ok(Self {
+            store,
+            memory,
+            alloc,
+            on_message,
+            state,
+            initial_memory,
+        })
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<i32, Error> {
+        let ptr = self.alloc.call(&mut self.store, bytes.len() as i32)?;
+        self.memory.write(&mut self.store, ptr as u32 as usize, bytes)?;
+        Ok(ptr)
+    }
+
+    fn read(&self, packed: i64) -> Result<Vec<u8>, Error> {
+        let (ptr, len) = ((packed as u64 >> 32) as usize, packed as u32 as usize);
+        let mut bytes = vec![0; len];
+        self.memory.read(&self.store, ptr, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn call(&mut self, event: &[u8]) -> Result<Vec<u8>, Error> {
+        let ptr = self.write(event)?;
+        let reply = self.on_message.call(&mut self.store, (ptr, event.len() as i32))?;
+        self.read(reply)
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), Error> {
+        if let Some(exports) = self.state {
+            let ptr = self.write(state)?;
+            return exports.set_state.call(&mut self.store, (ptr, state.len() as i32));
+        }
+        let state = match state.is_empty() {
+            true => self.initial_memory.as_slice(),
+            false => state,
+        };
+        let size = self.memory.data_size(&self.store);
+        if state.len() > size {
+            let pages = (state.len() - size).div_ceil(PAGE_SIZE);
+            self.memory.grow(&mut self.store, pages as u64)?;
+        }
+        // Memory cant shrink, whatever it grew by since is zeroed like fresh pages are
+        let memory = self.memory.data_mut(&mut self.store);
+        memory[..state.len()].copy_from_slice(state);
+        memory[state.len()..].fill(0);
+        Ok(())
+    }
+}
+
+impl PluginModule for WasmModule {
+    fn set_state(&mut self, state: &[u8]) {
+        if let Err(_error) = self.restore(state) {
+            trace_warn!(error = %_error, "Couldnt hand the state to the wasm module");
+        }
+    }
+
+    fn get_state(&mut self) -> Vec<u8> {
+        let Some(exports) = self.state else {
+            return self.memory.data(&self.store).to_vec();
+        };
+        match exports.get_state.call(&mut self.store, ()).and_then(|state| self.read(state)) {
+            Ok(state) => state,
+            Err(_error) => {
+                trace_warn!(error = %_error, "Couldnt get the state of the wasm module");
+                Vec::new()
+            }
+        }
+    }
+
+    fn on_message(&mut self, event: &[u8]) -> Vec<u8> {
+        match self.call(event) {
+            Ok(reply) => reply,
+            Err(_error) => {
+                trace_warn!(error = %_error, "Wasm module failed");
+                Vec::new()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::plugin::PluginProcess;
+    use crate::runtime::Simulation;
+    use crate::time::message::{Message, Sign};
+    use std::sync::Arc;
+
+    // Counts its events in the first 4 bytes of memory and sends itself a tick 2 later
+    // until it has seen 3, everything including the allocators next free byte (at 4) is
+    // in memory so the memory is the whole state
+    const TICKER: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 4) "\00\01\00\00")
+          (data (i32.const 64) "[{\"receiver\":0,\"delay\":2,\"payload\":\"tick\"}]")
+          (data (i32.const 128) "[]")
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (i32.load (i32.const 4)))
+            (i32.store (i32.const 4) (i32.add (local.get $ptr) (local.get $len)))
+            (local.get $ptr))
+          (func (export "on_message") (param $ptr i32) (param $len i32) (result i64)
+            (local $count i32)
+            (local.set $count (i32.add (i32.load (i32.const 0)) (i32.const 1)))
+            (i32.store (i32.const 0) (local.get $count))
+            (if (result i64) (i32.lt_u (local.get $count) (i32.const 3))
+              (then (i64.or (i64.shl (i64.const 64) (i64.const 32)) (i64.const 43)))
+              (else (i64.or (i64.shl (i64.const 128) (i64.const 32)) (i64.const 2))))))
+    "#;
+
+    fn count(simulation: &Simulation<PluginProcess<WasmModule>>) -> u32 {
+        let state = &simulation.machine(0).unwrap().state;
+        u32::from_le_bytes(state[..4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_wasm_memory_is_the_state() {
+        let mut simulation = Simulation::new();
+        let process = PluginProcess::new(WasmModule::new(TICKER).unwrap());
+        simulation.add_machine(Machine::with_process(0, 0, process));
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("go".to_string())));
+        while simulation.step() {}
+        assert_eq!(count(&simulation), 3);
+        assert_eq!(simulation.machine(0).unwrap().local_virtual_time(), 5);
+
+        // Rolls back the ticks at 3 and 5, the count has to go back with the memory
+        simulation.inject(Message::new(0, 2, 0, 0, Sign::Message, Arc::new("go".to_string())));
+        simulation.run();
+        let machine = simulation.machine(0).unwrap();
+        assert_eq!(machine.stats().events_rolled_back, 2);
+        assert_eq!(count(&simulation), 4);
+        assert_eq!(machine.process().invalid_replies(), 0);
+    }
+
+    // Keeps its state where set_state put it and traps on every event
+    const TRAPPING: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 64))
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (local.get $ptr) (local.get $len)))
+            (local.get $ptr))
+          (func (export "set_state") (param $ptr i32) (param $len i32)
+            (i32.store (i32.const 0) (local.get $ptr))
+            (i32.store (i32.const 4) (local.get $len)))
+          (func (export "get_state") (result i64)
+            (i64.or
+              (i64.shl (i64.extend_i32_u (i32.load (i32.const 0))) (i64.const 32))
+              (i64.extend_i32_u (i32.load (i32.const 4)))))
+          (func (export "on_message") (param $ptr i32) (param $len i32) (result i64)
+            unreachable))
+    "#;
+
+    #[test]
+    fn test_traps_are_invalid_replies() {
+        let mut simulation = Simulation::new();
+        let process = PluginProcess::new(WasmModule::new(TRAPPING).unwrap());
+        simulation.add_machine(Machine::with_state(0, 0, process, Arc::new(vec![7, 8])));
+        simulation.inject(Message::new(0, 1, 0, 0, Sign::Message, Arc::new("go".to_string())));
+        simulation.run();
+
+        let machine = simulation.machine(0).unwrap();
+        assert_eq!(machine.process().invalid_replies(), 1);
+        assert_eq!(*machine.state, vec![7, 8]);
+        assert!(WasmModule::new("(module)").is_err());
+    }
+}